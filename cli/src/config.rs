@@ -11,7 +11,7 @@ use bitcoincore_rpc_async as rpc;
 
 use directories::BaseDirs;
 use emulator_connect::connections::federated::FederatedEmulatorConnection;
-use emulator_connect::connections::hd::HDOracleEmulatorConnection;
+use emulator_connect::connections::hd::{HDOracleEmulatorConnection, ThreadRngEntropy};
 use emulator_connect::CTVEmulator;
 use schemars::JsonSchema;
 use serde::*;
@@ -64,9 +64,13 @@ impl EmulatorConfig {
                         handle,
                         runtime: rt.clone(),
                         connection: Mutex::new(None),
-                        reconnect: host.to_socket_addrs()?.next().unwrap(),
+                        reconnect: host.to_socket_addrs()?.next().unwrap().into(),
                         root: *epk,
                         secp: secp.clone(),
+                        socket_options: Default::default(),
+                        backoff: Default::default(),
+                        max_frame: emulator_connect::msgs::MAX_FRAME,
+                        entropy: Arc::new(ThreadRngEntropy),
                     })
                 });
         Ok(if self.emulators.len() == 1 {
@@ -0,0 +1,130 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Adapter for using blocking emulator connections from async contexts
+
+use super::hd::HDOracleEmulatorConnection;
+use super::*;
+use tokio::runtime::Runtime;
+
+/// AsyncEmulatorAdapter wraps a CTVEmulator so that the (sync) CTVEmulator
+/// trait may be called safely from inside of a running tokio runtime,
+/// including a `current_thread` runtime.
+///
+/// The adapter owns a dedicated runtime with a single worker thread that
+/// drives any IO for connections created through it, and each call to `sign`
+/// is made from a fresh (non-runtime) thread. Because of this, the wrapped
+/// emulator's `block_in_place`/`block_on` never nests within the caller's
+/// runtime.
+pub struct AsyncEmulatorAdapter {
+    inner: Arc<dyn CTVEmulator>,
+    /// only None while being dropped
+    runtime: Option<Arc<Runtime>>,
+}
+
+impl AsyncEmulatorAdapter {
+    /// create a dedicated single worker thread runtime suitable for use with
+    /// an adapter
+    pub fn new_runtime() -> Result<Arc<Runtime>, std::io::Error> {
+        Ok(Arc::new(
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .enable_all()
+                .build()?,
+        ))
+    }
+    /// wrap an existing emulator. Any IO the emulator performs must be driven
+    /// by `runtime` (or some other runtime not owned by the caller).
+    pub fn new(inner: Arc<dyn CTVEmulator>, runtime: Arc<Runtime>) -> Self {
+        AsyncEmulatorAdapter {
+            inner,
+            runtime: Some(runtime),
+        }
+    }
+    /// create an adapter around a HDOracleEmulatorConnection which performs all
+    /// of its IO on the adapter's own runtime.
+    pub fn hd(
+        reconnect: SocketAddr,
+        root: ExtendedPubKey,
+        secp: Arc<Secp256k1<All>>,
+    ) -> Result<Self, std::io::Error> {
+        let runtime = Self::new_runtime()?;
+        let conn = HDOracleEmulatorConnection::with_handle(
+            reconnect.into(),
+            root,
+            Some(runtime.clone()),
+            runtime.handle().clone(),
+            secp,
+        );
+        Ok(Self::new(Arc::new(conn), runtime))
+    }
+    /// the runtime owned by this adapter
+    pub fn runtime(&self) -> &Arc<Runtime> {
+        self.runtime.as_ref().expect("Only None during Drop")
+    }
+}
+
+impl Drop for AsyncEmulatorAdapter {
+    fn drop(&mut self) {
+        // Dropping a Runtime from within an async context panics, so release
+        // our references to it (including any held by inner) from a separate
+        // thread.
+        let inner = std::mem::replace(&mut self.inner, Arc::new(CTVAvailable));
+        let runtime = self.runtime.take();
+        let _ = std::thread::spawn(move || drop((inner, runtime))).join();
+    }
+}
+
+impl CTVEmulator for AsyncEmulatorAdapter {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        self.inner.get_signer_for(h)
+    }
+    fn get_attester_for(&self, m: Sha256) -> Result<Clause, EmulatorError> {
+        self.inner.get_attester_for(m)
+    }
+    fn identity(&self) -> Option<String> {
+        self.inner.identity()
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        std::thread::scope(|s| {
+            s.spawn(|| self.inner.sign(b)).join().unwrap_or_else(|_| {
+                input_error("Emulator Panicked While Signing").map_err(Into::into)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::servers::hd::HDOracleEmulator;
+    use crate::tests::signable_psbt;
+    #[tokio::test]
+    async fn test_sign_in_current_thread_runtime() {
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[9u8; 32]).unwrap();
+        let secp = Arc::new(Secp256k1::new());
+        // bound here, but served from the adapter's runtime, as this
+        // (current_thread) runtime is blocked while signing
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let adapter =
+            AsyncEmulatorAdapter::hd(addr, ExtendedPubKey::from_priv(&secp, &root), secp.clone())
+                .unwrap();
+        let server = adapter.runtime().spawn(async move {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            HDOracleEmulator::new(root, false)
+                .bind_listener(listener)
+                .await
+        });
+        let signed = adapter.sign(signable_psbt(&root)).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+        server.abort();
+    }
+}
@@ -6,20 +6,329 @@
 
 //! join together CTVEmulators as a multisig
 
+use super::hd::HDOracleEmulatorConnection;
 use super::*;
-/// Creates a multi-condition emulator with a certain threshold.
+use std::sync::RwLock;
+use std::time::Duration;
+/// Combines the Clauses of each emulator in a federation (in the order the
+/// emulators were given) into the federation's Clause.
+pub type ClauseCombiner = Arc<dyn Fn(Vec<Clause>) -> Clause + Send + Sync>;
+
+/// How a federation retries requests to one of its members, see
+/// [`FederatedEmulatorConnection::with_retry_policy`]. The default is to try
+/// once and wait as long as it takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct RetryPolicy {
+    /// how many times a failed request is retried
+    pub retries: u32,
+    /// how long to wait before each retry
+    pub delay: Duration,
+    /// how long each attempt may take before it is treated as failed.
+    ///
+    /// A timed out attempt is abandoned rather than cancelled, so it may
+    /// still complete in the background.
+    pub timeout: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// call `f` on `emulator` until it succeeds or the retries run out,
+    /// returning the last error.
+    fn run<T, F>(&self, emulator: &Arc<dyn CTVEmulator>, f: F) -> Result<T, EmulatorError>
+    where
+        T: Send + 'static,
+        F: Fn(&dyn CTVEmulator) -> Result<T, EmulatorError> + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let mut attempt = 0;
+        loop {
+            let res = match self.timeout {
+                None => f(emulator.as_ref()),
+                Some(timeout) => {
+                    let (tx, rx) = std::sync::mpsc::channel();
+                    let (e, f) = (emulator.clone(), f.clone());
+                    std::thread::spawn(move || tx.send(f(e.as_ref())));
+                    rx.recv_timeout(timeout).unwrap_or_else(|_| {
+                        Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into())
+                    })
+                }
+            };
+            match res {
+                Err(_) if attempt < self.retries => {
+                    attempt += 1;
+                    std::thread::sleep(self.delay);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Creates a multi-condition emulator with a certain threshold, or any other
+/// policy over its emulators.
 /// It implements CTVEmulator so that it itself can be used as a trait object.
+///
+/// Members may be added or removed while it is in use, see
+/// [`FederatedEmulatorConnection::add_emulator`].
 pub struct FederatedEmulatorConnection {
-    emulators: Vec<Arc<dyn CTVEmulator>>,
-    threshold: u8,
+    members: RwLock<Vec<(Arc<dyn CTVEmulator>, RetryPolicy)>>,
+    combine: ClauseCombiner,
+    /// how many members must sign, or None if every member must
+    threshold: Option<usize>,
 }
 
 impl FederatedEmulatorConnection {
     /// create a new federated emulator connection from a list + threshold of emulators
     pub fn new(emulators: Vec<Arc<dyn CTVEmulator>>, threshold: u8) -> Self {
-        FederatedEmulatorConnection {
+        let mut f = Self::with_policy(
             emulators,
-            threshold,
+            Arc::new(move |v| Clause::Threshold(threshold as usize, v)),
+        );
+        f.threshold = Some(threshold as usize);
+        f
+    }
+    /// create a threshold federation of HD oracles from each oracle's address
+    /// and root key.
+    ///
+    /// As with [`HDOracleEmulatorConnection::new`], the addresses are resolved
+    /// now but no connections are opened until they are needed. `runtime` is
+    /// required if not called from within a tokio runtime.
+    pub fn from_addresses<A: std::net::ToSocketAddrs + std::fmt::Display>(
+        oracles: &[(A, ExtendedPubKey)],
+        threshold: u8,
+        runtime: Option<Arc<tokio::runtime::Runtime>>,
+        secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    ) -> Result<Self, std::io::Error> {
+        if threshold == 0 || threshold as usize > oracles.len() {
+            return input_error(&format!(
+                "Threshold {} Invalid For {} Oracles",
+                threshold,
+                oracles.len()
+            ));
+        }
+        let emulators = oracles
+            .iter()
+            .map(
+                |(address, root)| -> Result<Arc<dyn CTVEmulator>, std::io::Error> {
+                    let reconnect = address.to_socket_addrs()?.next().ok_or_else(|| {
+                        input_err(&format!("Bad Lookup Could Not Resolve Address {}", address))
+                    })?;
+                    Ok(Arc::new(HDOracleEmulatorConnection::with_address(
+                        reconnect.into(),
+                        *root,
+                        runtime.clone(),
+                        secp.clone(),
+                    )))
+                },
+            )
+            .collect::<Result<_, _>>()?;
+        Ok(Self::new(emulators, threshold))
+    }
+    /// create a new federated emulator connection whose Clause is built by
+    /// `combine` from the emulators' Clauses, e.g. "A and (B or C)".
+    ///
+    /// `sign` asks the emulators (which haven't already signed) to sign, in
+    /// order, until those which signed satisfy the policy. Members which fail
+    /// are skipped. Whether the policy is satisfied is decided by passing
+    /// `combine` a [`Clause::Trivial`] for each member which signed and a
+    /// [`Clause::Unsatisfiable`] for each which didn't, so `combine` should
+    /// only arrange the Clauses it is given (e.g., not add timelocks).
+    ///
+    /// If members may be added or removed, `combine` must accept any number
+    /// of Clauses.
+    pub fn with_policy(emulators: Vec<Arc<dyn CTVEmulator>>, combine: ClauseCombiner) -> Self {
+        FederatedEmulatorConnection {
+            members: RwLock::new(
+                emulators
+                    .into_iter()
+                    .map(|e| (e, Default::default()))
+                    .collect(),
+            ),
+            combine,
+            threshold: None,
+        }
+    }
+    /// retry requests to the `member`th emulator according to `policy`, e.g.
+    /// retrying a flaky remote oracle more than a local signer.
+    ///
+    /// Panics if there is no `member`th emulator.
+    pub fn with_retry_policy(mut self, member: usize, policy: RetryPolicy) -> Self {
+        self.members.get_mut().expect("Members Poisoned")[member].1 = policy;
+        self
+    }
+    /// Add `emulator` as the last member of the federation, retrying its
+    /// requests according to `policy`.
+    ///
+    /// A threshold federation keeps its threshold, and one built with
+    /// [`FederatedEmulatorConnection::with_policy`] passes the new member's
+    /// Clause to its policy too.
+    ///
+    /// This changes the Clause given by `get_signer_for` (and so the
+    /// addresses of contracts compiled with it) from now on. Contracts
+    /// compiled earlier still require the old members' signatures.
+    pub fn add_emulator(&self, emulator: Arc<dyn CTVEmulator>, policy: RetryPolicy) {
+        self.members
+            .write()
+            .expect("Members Poisoned")
+            .push((emulator, policy));
+    }
+    /// Remove the `member`th emulator from the federation, returning it.
+    ///
+    /// Errors (leaving the federation as it was) if there is no such member,
+    /// or if removing it would leave fewer members than the threshold (or
+    /// none at all).
+    ///
+    /// As with [`FederatedEmulatorConnection::add_emulator`], this changes
+    /// the Clause given by `get_signer_for` from now on.
+    pub fn remove_emulator(&self, member: usize) -> Result<Arc<dyn CTVEmulator>, std::io::Error> {
+        let mut members = self.members.write().expect("Members Poisoned");
+        if member >= members.len() {
+            return input_error(&format!(
+                "No Member {} Of {} To Remove",
+                member,
+                members.len()
+            ));
+        }
+        let needed = self.threshold.unwrap_or(1).max(1);
+        if members.len() - 1 < needed {
+            return input_error(&format!(
+                "Removing A Member Would Leave {} Members, Fewer Than The {} Needed",
+                members.len() - 1,
+                needed
+            ));
+        }
+        Ok(members.remove(member).0)
+    }
+    /// the emulators paired with their retry policies, as of now
+    fn members(&self) -> Vec<(Arc<dyn CTVEmulator>, RetryPolicy)> {
+        self.members.read().expect("Members Poisoned").clone()
+    }
+    /// whether the members marked in `signed` satisfy the federation's
+    /// policy, see [`FederatedEmulatorConnection::with_policy`]
+    fn satisfied(&self, signed: &[bool]) -> bool {
+        fn holds(c: &Clause) -> bool {
+            match c {
+                Clause::Trivial => true,
+                Clause::And(v) => v.iter().all(holds),
+                Clause::Or(v) => v.iter().any(|(_, c)| holds(c)),
+                Clause::Threshold(k, v) => v.iter().filter(|c| holds(c)).count() >= *k,
+                _ => false,
+            }
+        }
+        let marked = signed
+            .iter()
+            .map(|s| {
+                if *s {
+                    Clause::Trivial
+                } else {
+                    Clause::Unsatisfiable
+                }
+            })
+            .collect();
+        holds(&(self.combine)(marked))
+    }
+    /// Run [`CTVEmulator::confirm_key`] against every member concurrently,
+    /// e.g. when bringing up a federation.
+    ///
+    /// Errors with [`EmulatorError::ConfirmationFailed`] listing every member
+    /// which failed, by its index in the federation.
+    pub fn confirm_all(&self) -> Result<(), EmulatorError> {
+        let failed: Vec<(usize, EmulatorError)> = std::thread::scope(|s| {
+            let confirming: Vec<_> = self
+                .members()
+                .into_iter()
+                .map(|(e, p)| s.spawn(move || p.run(&e, |e| e.confirm_key())))
+                .collect();
+            confirming
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, h)| h.join().expect("Member Panicked").err().map(|e| (i, e)))
+                .collect()
+        });
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(EmulatorError::ConfirmationFailed(failed))
+        }
+    }
+    /// Sign each of `psbts` in place, as with [`CTVEmulator::sign`].
+    ///
+    /// Stops at the first PSBT which doesn't reach quorum, but every PSBT
+    /// keeps the signatures collected so far. Calling again with the same
+    /// PSBTs resumes where it left off, as members which already signed
+    /// aren't asked again.
+    pub fn sign_batch(
+        &self,
+        psbts: &mut [PartiallySignedTransaction],
+    ) -> Result<(), EmulatorError> {
+        psbts.iter_mut().try_for_each(|b| self.sign_into(b))
+    }
+    /// whether `emulator` already signed `b`, i.e. some input which isn't
+    /// finalized has a signature by the key it signs that input with: a
+    /// Taproot script path signature by the key, a key path signature
+    /// spending the key's output key, or a Segwit v0 signature by the key's
+    /// even form.
+    ///
+    /// A member whose Clause isn't a single key is never treated as having
+    /// signed, as its signatures can't be told apart from anyone else's.
+    fn has_signed(
+        emulator: &Arc<dyn CTVEmulator>,
+        policy: &RetryPolicy,
+        b: &PartiallySignedTransaction,
+    ) -> bool {
+        b.inputs
+            .iter()
+            .enumerate()
+            .filter(|(_, inp)| inp.final_script_witness.is_none() && inp.final_script_sig.is_none())
+            .any(|(i, inp)| {
+                let h = b.unsigned_tx.get_ctv_hash(i as u32);
+                match policy.run(emulator, move |e| e.get_signer_for(h)) {
+                    Ok(Clause::Key(k)) => {
+                        use bitcoin::schnorr::TapTweak;
+                        let output_key = SECP.with(|secp| k.tap_tweak(secp, inp.tap_merkle_root).0);
+                        let even =
+                            bitcoin::PublicKey::new(k.public_key(bitcoin::secp256k1::Parity::Even));
+                        inp.tap_script_sigs.keys().any(|(pk, _)| *pk == k)
+                            || inp.partial_sigs.contains_key(&even)
+                            || (inp.tap_key_sig.is_some()
+                                && inp.witness_utxo.as_ref().is_some_and(|u| {
+                                    u.script_pubkey
+                                        == bitcoin::Script::new_v1_p2tr_tweaked(output_key)
+                                }))
+                    }
+                    _ => false,
+                }
+            })
+    }
+    /// collect signatures into `b` from the members which haven't signed it
+    /// yet, counting those which already had towards the quorum (or policy).
+    ///
+    /// A threshold federation asks every such member, while one built with
+    /// [`FederatedEmulatorConnection::with_policy`] stops once its policy is
+    /// satisfied.
+    fn sign_into(&self, b: &mut PartiallySignedTransaction) -> Result<(), EmulatorError> {
+        // members which fail are skipped, as long as enough others sign
+        let members = self.members();
+        let mut signed: Vec<bool> = members
+            .iter()
+            .map(|(emulator, policy)| Self::has_signed(emulator, policy, b))
+            .collect();
+        for (i, (emulator, policy)) in members.iter().enumerate() {
+            if signed[i] || (self.threshold.is_none() && self.satisfied(&signed)) {
+                continue;
+            }
+            let unsigned = b.clone();
+            if let Ok(s) = policy.run(emulator, move |e| e.sign(unsigned.clone())) {
+                *b = s;
+                signed[i] = true;
+            }
+        }
+        let got = signed.iter().filter(|s| **s).count();
+        match self.threshold {
+            Some(needed) if got < needed => Err(EmulatorError::QuorumNotReached { got, needed }),
+            None if !self.satisfied(&signed) => Err(EmulatorError::PolicyNotSatisfied {
+                signed: (0..signed.len()).filter(|i| signed[*i]).collect(),
+            }),
+            _ => Ok(()),
         }
     }
 }
@@ -27,19 +336,543 @@ impl FederatedEmulatorConnection {
 impl CTVEmulator for FederatedEmulatorConnection {
     fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
         let v = self
-            .emulators
+            .members()
+            .iter()
+            .map(|(e, p)| p.run(e, move |e| e.get_signer_for(h)))
+            .collect::<Result<Vec<Clause>, EmulatorError>>()?;
+        Ok((self.combine)(v))
+    }
+    fn get_attester_for(&self, m: Sha256) -> Result<Clause, EmulatorError> {
+        let v = self
+            .members()
             .iter()
-            .map(|e| e.get_signer_for(h))
+            .map(|(e, p)| p.run(e, move |e| e.get_attester_for(m)))
             .collect::<Result<Vec<Clause>, EmulatorError>>()?;
-        Ok(Clause::Threshold(self.threshold as usize, v))
+        Ok((self.combine)(v))
+    }
+    /// The federation's policy over its members' identities, if every
+    /// member has one.
+    fn identity(&self) -> Option<String> {
+        let ids = self
+            .members()
+            .iter()
+            .map(|(e, _)| {
+                e.identity()
+                    .map(|id| Clause::Sha256(Sha256::hash(id.as_bytes())))
+            })
+            .collect::<Option<Vec<Clause>>>()?;
+        Some(format!("federation {}", (self.combine)(ids)))
     }
+    fn confirm_key(&self) -> Result<(), EmulatorError> {
+        self.confirm_all()
+    }
+    /// Asks the members to sign, erroring if fewer than the quorum do (or, for
+    /// a federation built with [`FederatedEmulatorConnection::with_policy`],
+    /// if those which sign don't satisfy its policy).
+    ///
+    /// `b` may already be signed by some of the members, e.g. by an earlier
+    /// call which was interrupted, in which case only the missing signatures
+    /// are collected.
     fn sign(
         &self,
         mut b: PartiallySignedTransaction,
     ) -> Result<PartiallySignedTransaction, EmulatorError> {
-        for emulator in self.emulators.iter() {
-            b = emulator.sign(b)?;
-        }
+        self.sign_into(&mut b)?;
         Ok(b)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::XOnlyPublicKey;
+    use miniscript::policy::Liftable;
+
+    /// an emulator which always signs with the same key
+    struct Fixed(XOnlyPublicKey);
+    impl CTVEmulator for Fixed {
+        fn get_signer_for(&self, _h: Sha256) -> Result<Clause, EmulatorError> {
+            Ok(Clause::Key(self.0))
+        }
+        fn identity(&self) -> Option<String> {
+            Some(self.0.to_string())
+        }
+        fn sign(
+            &self,
+            b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            Ok(b)
+        }
+    }
+
+    /// an emulator which is always unreachable
+    struct Offline;
+    impl CTVEmulator for Offline {
+        fn get_signer_for(&self, _h: Sha256) -> Result<Clause, EmulatorError> {
+            Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into())
+        }
+        fn confirm_key(&self) -> Result<(), EmulatorError> {
+            Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into())
+        }
+        fn sign(
+            &self,
+            _b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into())
+        }
+    }
+
+    /// an emulator which fails to sign `failures` times, then signs after
+    /// waiting `delay`, counting every attempt
+    struct Flaky {
+        failures: std::sync::atomic::AtomicUsize,
+        attempts: std::sync::atomic::AtomicUsize,
+        delay: Duration,
+    }
+    impl Flaky {
+        fn new(failures: usize, delay: Duration) -> Arc<Self> {
+            Arc::new(Flaky {
+                failures: failures.into(),
+                attempts: 0.into(),
+                delay,
+            })
+        }
+        fn attempts(&self) -> usize {
+            self.attempts.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+    impl CTVEmulator for Flaky {
+        fn get_signer_for(&self, _h: Sha256) -> Result<Clause, EmulatorError> {
+            Ok(Clause::Trivial)
+        }
+        fn sign(
+            &self,
+            b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            use std::sync::atomic::Ordering;
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            let failing = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if failing {
+                return Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into());
+            }
+            std::thread::sleep(self.delay);
+            Ok(b)
+        }
+    }
+
+    /// how a [`Marking`] emulator signs
+    #[derive(Clone, Copy)]
+    enum Spend {
+        ScriptPath,
+        KeyPath,
+        SegwitV0,
+    }
+
+    /// an emulator which adds a (meaningless) signature by its key to every
+    /// input, counting how many times it was asked to sign
+    struct Marking {
+        key: bitcoin::KeyPair,
+        spend: Spend,
+        signs: std::sync::atomic::AtomicUsize,
+    }
+    impl Marking {
+        fn new(i: u8) -> Arc<Self> {
+            Self::spending(i, Spend::ScriptPath)
+        }
+        fn spending(i: u8, spend: Spend) -> Arc<Self> {
+            let key =
+                SECP.with(|secp| bitcoin::KeyPair::from_seckey_slice(secp, &[i; 32]).unwrap());
+            Arc::new(Marking {
+                key,
+                spend,
+                signs: 0.into(),
+            })
+        }
+        fn signs(&self) -> usize {
+            self.signs.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+    impl CTVEmulator for Marking {
+        fn get_signer_for(&self, _h: Sha256) -> Result<Clause, EmulatorError> {
+            Ok(Clause::Key(XOnlyPublicKey::from_keypair(&self.key).0))
+        }
+        fn sign(
+            &self,
+            mut b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            use bitcoin::util::taproot::{LeafVersion, TapLeafHash};
+            self.signs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let msg = bitcoin::secp256k1::Message::from_digest_slice(&[1; 32]).unwrap();
+            let sig = bitcoin::SchnorrSig {
+                sig: SECP.with(|secp| secp.sign_schnorr_no_aux_rand(&msg, &self.key)),
+                hash_ty: bitcoin::util::sighash::SchnorrSighashType::Default,
+            };
+            let tlh = TapLeafHash::from_script(&bitcoin::Script::new(), LeafVersion::TapScript);
+            let (pk, _) = XOnlyPublicKey::from_keypair(&self.key);
+            for inp in b.inputs.iter_mut() {
+                match self.spend {
+                    Spend::ScriptPath => {
+                        inp.tap_script_sigs.insert((pk, tlh), sig);
+                    }
+                    Spend::KeyPath => inp.tap_key_sig = Some(sig),
+                    Spend::SegwitV0 => {
+                        let even = pk.public_key(bitcoin::secp256k1::Parity::Even);
+                        let sig = bitcoin::EcdsaSig::sighash_all(
+                            SECP.with(|secp| secp.sign_ecdsa(&msg, &self.key.secret_key())),
+                        );
+                        inp.partial_sigs.insert(bitcoin::PublicKey::new(even), sig);
+                    }
+                }
+            }
+            Ok(b)
+        }
+    }
+
+    fn keys(n: u8) -> Vec<XOnlyPublicKey> {
+        (1u8..=n)
+            .map(|i| {
+                let root =
+                    ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[i; 32]).unwrap();
+                SECP.with(|secp| ExtendedPubKey::from_priv(secp, &root).to_x_only_pub())
+            })
+            .collect()
+    }
+
+    fn empty_psbt() -> PartiallySignedTransaction {
+        PartiallySignedTransaction::from_unsigned_tx(bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        })
+        .unwrap()
+    }
+
+    fn one_input_psbt() -> PartiallySignedTransaction {
+        PartiallySignedTransaction::from_unsigned_tx(bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default()],
+            output: vec![],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_resume_signing() {
+        let (a, b) = (Marking::new(1), Marking::new(2));
+        // b is unreachable the first time around, so the batch is
+        // interrupted with only a's signatures
+        let interrupted = FederatedEmulatorConnection::new(vec![a.clone(), Arc::new(Offline)], 2);
+        let mut psbts = vec![one_input_psbt(), one_input_psbt()];
+        match interrupted.sign_batch(&mut psbts) {
+            Err(EmulatorError::QuorumNotReached { got, needed }) => {
+                assert_eq!((got, needed), (1, 2))
+            }
+            _ => panic!("expected QuorumNotReached"),
+        }
+        assert_eq!(a.signs(), 1);
+        assert_eq!(psbts[0].inputs[0].tap_script_sigs.len(), 1);
+        assert!(psbts[1].inputs[0].tap_script_sigs.is_empty());
+
+        let federation = FederatedEmulatorConnection::new(vec![a.clone(), b.clone()], 2);
+        // a's signature is picked up, so only b is asked to complete the
+        // quorum
+        let signed = federation.sign(psbts[0].clone()).unwrap();
+        assert_eq!(signed.inputs[0].tap_script_sigs.len(), 2);
+        assert_eq!((a.signs(), b.signs()), (1, 1));
+        // signing again is a no-op
+        assert_eq!(federation.sign(signed.clone()).unwrap(), signed);
+        assert_eq!((a.signs(), b.signs()), (1, 1));
+        // resuming the batch only asks for what is missing
+        federation.sign_batch(&mut psbts).unwrap();
+        assert_eq!(psbts[0], signed);
+        assert_eq!(psbts[1].inputs[0].tap_script_sigs.len(), 2);
+        assert_eq!((a.signs(), b.signs()), (2, 3));
+    }
+
+    #[test]
+    fn test_resume_signing_key_path_and_segwit_v0() {
+        let key = |i: u8| {
+            SECP.with(|secp| {
+                let kp = bitcoin::KeyPair::from_seckey_slice(secp, &[i; 32]).unwrap();
+                XOnlyPublicKey::from_keypair(&kp).0
+            })
+        };
+        // a key path spend of a's output, which b's signature can't be
+        // mistaken for
+        let key_path = |i: u8| {
+            use bitcoin::schnorr::TapTweak;
+            let (output_key, _) = SECP.with(|secp| key(i).tap_tweak(secp, None));
+            bitcoin::Script::new_v1_p2tr_tweaked(output_key)
+        };
+        // a NestedSegwit input, spending a 2-of-2 of both members' keys
+        let ws = bitcoin::blockdata::script::Builder::new()
+            .push_int(2)
+            .push_key(&bitcoin::PublicKey::new(
+                key(1).public_key(bitcoin::secp256k1::Parity::Even),
+            ))
+            .push_key(&bitcoin::PublicKey::new(
+                key(2).public_key(bitcoin::secp256k1::Parity::Even),
+            ))
+            .push_int(2)
+            .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKMULTISIG)
+            .into_script();
+        let nested = ws.to_v0_p2wsh().to_p2sh();
+        for (spend, script_pubkey) in [(Spend::KeyPath, key_path(1)), (Spend::SegwitV0, nested)] {
+            let (a, b) = (Marking::spending(1, spend), Marking::spending(2, spend));
+            let mut psbt = one_input_psbt();
+            psbt.inputs[0].witness_utxo = Some(bitcoin::TxOut {
+                value: 1000,
+                script_pubkey,
+            });
+            let interrupted =
+                FederatedEmulatorConnection::new(vec![a.clone(), Arc::new(Offline)], 2);
+            let mut psbts = vec![psbt];
+            assert!(interrupted.sign_batch(&mut psbts).is_err());
+            assert_eq!(a.signs(), 1);
+            // a's signature is picked up, so only b is asked to complete the
+            // quorum
+            let federation = FederatedEmulatorConnection::new(vec![a.clone(), b.clone()], 2);
+            federation.sign_batch(&mut psbts).unwrap();
+            assert_eq!((a.signs(), b.signs()), (1, 1));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_from_addresses() {
+        let roots: Vec<ExtendedPubKey> = (1u8..=3)
+            .map(|i| {
+                let root =
+                    ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[i; 32]).unwrap();
+                SECP.with(|secp| ExtendedPubKey::from_priv(secp, &root))
+            })
+            .collect();
+        let oracles: Vec<(String, ExtendedPubKey)> = roots
+            .iter()
+            .enumerate()
+            .map(|(i, root)| (format!("127.0.0.1:{}", 18750 + i), *root))
+            .collect();
+        let secp = Arc::new(bitcoin::secp256k1::Secp256k1::new());
+        for bad in [0, 4] {
+            assert!(
+                FederatedEmulatorConnection::from_addresses(&oracles, bad, None, secp.clone())
+                    .is_err()
+            );
+        }
+        let federation =
+            FederatedEmulatorConnection::from_addresses(&oracles, 2, None, secp).unwrap();
+        let h = Sha256::hash(b"template");
+        let expected: Vec<Clause> = roots
+            .iter()
+            .map(|root| {
+                let c = hash_to_child_vec(h).unwrap();
+                SECP.with(|secp| Clause::Key(root.derive_pub(secp, &c).unwrap().to_x_only_pub()))
+            })
+            .collect();
+        assert_eq!(
+            federation.get_signer_for(h).unwrap(),
+            Clause::Threshold(2, expected)
+        );
+    }
+
+    #[test]
+    fn test_quorum_not_reached() {
+        let keys = keys(2);
+        let federation = |online: usize, offline: usize| {
+            let mut emulators: Vec<Arc<dyn CTVEmulator>> = keys[..online]
+                .iter()
+                .map(|k| -> Arc<dyn CTVEmulator> { Arc::new(Fixed(*k)) })
+                .collect();
+            emulators.extend((0..offline).map(|_| -> Arc<dyn CTVEmulator> { Arc::new(Offline) }));
+            FederatedEmulatorConnection::new(emulators, 2)
+        };
+        assert!(federation(2, 1).sign(empty_psbt()).is_ok());
+        match federation(1, 2).sign(empty_psbt()) {
+            Err(EmulatorError::QuorumNotReached { got, needed }) => {
+                assert_eq!((got, needed), (1, 2))
+            }
+            _ => panic!("expected QuorumNotReached"),
+        }
+        let either = FederatedEmulatorConnection::with_policy(
+            vec![Arc::new(Fixed(keys[0])), Arc::new(Offline)],
+            Arc::new(|v| Clause::Or(v.into_iter().map(|c| (1, c)).collect())),
+        );
+        assert!(either.sign(empty_psbt()).is_ok());
+        let both = FederatedEmulatorConnection::with_policy(
+            vec![Arc::new(Fixed(keys[0])), Arc::new(Offline)],
+            Arc::new(Clause::And),
+        );
+        match both.sign(empty_psbt()) {
+            Err(EmulatorError::PolicyNotSatisfied { signed }) => assert_eq!(signed, vec![0]),
+            _ => panic!("expected PolicyNotSatisfied"),
+        }
+    }
+
+    #[test]
+    fn test_confirm_all() {
+        let keys = keys(2);
+        let emulators: Vec<Arc<dyn CTVEmulator>> = vec![
+            Arc::new(Fixed(keys[0])),
+            Arc::new(Offline),
+            Arc::new(Fixed(keys[1])),
+        ];
+        let federation = FederatedEmulatorConnection::new(emulators, 2);
+        match federation.confirm_all() {
+            Err(EmulatorError::ConfirmationFailed(failed)) => {
+                assert_eq!(failed.len(), 1);
+                assert_eq!(failed[0].0, 1);
+                assert!(matches!(failed[0].1, EmulatorError::NetworkIssue(_)));
+            }
+            _ => panic!("expected ConfirmationFailed"),
+        }
+        let honest = FederatedEmulatorConnection::new(
+            keys.iter()
+                .map(|k| -> Arc<dyn CTVEmulator> { Arc::new(Fixed(*k)) })
+                .collect(),
+            2,
+        );
+        assert!(honest.confirm_all().is_ok());
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let remote = Flaky::new(2, Duration::ZERO);
+        let local = Flaky::new(1, Duration::ZERO);
+        let slow = Flaky::new(0, Duration::from_millis(500));
+        let federation =
+            FederatedEmulatorConnection::new(vec![remote.clone(), local.clone(), slow.clone()], 1)
+                .with_retry_policy(
+                    0,
+                    RetryPolicy {
+                        retries: 2,
+                        ..Default::default()
+                    },
+                )
+                .with_retry_policy(
+                    2,
+                    RetryPolicy {
+                        retries: 1,
+                        timeout: Some(Duration::from_millis(50)),
+                        ..Default::default()
+                    },
+                );
+        // only the remote member signs, on its last retry
+        assert!(federation.sign(empty_psbt()).is_ok());
+        assert_eq!(remote.attempts(), 3);
+        assert_eq!(local.attempts(), 1);
+        assert_eq!(slow.attempts(), 2);
+    }
+
+    #[test]
+    fn test_add_remove_emulator() {
+        let keys = keys(3);
+        let federation = FederatedEmulatorConnection::new(
+            keys[..2]
+                .iter()
+                .map(|k| -> Arc<dyn CTVEmulator> { Arc::new(Fixed(*k)) })
+                .collect(),
+            2,
+        );
+        let h = Sha256::hash(b"template");
+        federation.add_emulator(Arc::new(Fixed(keys[2])), Default::default());
+        assert_eq!(
+            federation.get_signer_for(h).unwrap(),
+            Clause::Threshold(2, keys.iter().map(|k| Clause::Key(*k)).collect())
+        );
+        // the federation still signs, and with one member offline
+        assert!(federation.sign(empty_psbt()).is_ok());
+        federation.add_emulator(Arc::new(Offline), Default::default());
+        assert!(federation.sign(empty_psbt()).is_ok());
+
+        assert!(federation.remove_emulator(4).is_err());
+        assert!(federation.remove_emulator(3).is_ok());
+        assert!(federation.remove_emulator(0).is_ok());
+        assert_eq!(
+            federation.get_signer_for(h).unwrap(),
+            Clause::Threshold(2, vec![Clause::Key(keys[1]), Clause::Key(keys[2])])
+        );
+        // the threshold can't be left unreachable
+        assert!(federation.remove_emulator(0).is_err());
+        assert_eq!(
+            federation.get_signer_for(h).unwrap(),
+            Clause::Threshold(2, vec![Clause::Key(keys[1]), Clause::Key(keys[2])])
+        );
+    }
+
+    #[test]
+    fn test_identity() {
+        let keys = keys(2);
+        let fixed = |k: &XOnlyPublicKey| -> Arc<dyn CTVEmulator> { Arc::new(Fixed(*k)) };
+        let federation = |threshold| {
+            FederatedEmulatorConnection::new(keys.iter().map(fixed).collect(), threshold)
+        };
+        let id = federation(2).identity().unwrap();
+        assert_eq!(federation(2).identity().unwrap(), id);
+        assert_ne!(federation(1).identity().unwrap(), id);
+        let reordered = FederatedEmulatorConnection::new(keys.iter().rev().map(fixed).collect(), 2);
+        assert_ne!(reordered.identity().unwrap(), id);
+        // a member without an identity leaves the federation without one
+        let with_offline = federation(2);
+        with_offline.add_emulator(Arc::new(Offline), Default::default());
+        assert!(with_offline.identity().is_none());
+    }
+
+    #[test]
+    fn test_with_policy() {
+        let keys = keys(3);
+        let emulators = keys
+            .iter()
+            .map(|k| -> Arc<dyn CTVEmulator> { Arc::new(Fixed(*k)) })
+            .collect();
+        // A and (B or C)
+        let policy: ClauseCombiner = Arc::new(|mut v| {
+            let c = v.pop().unwrap();
+            let b = v.pop().unwrap();
+            let a = v.pop().unwrap();
+            Clause::And(vec![a, Clause::Or(vec![(1, b), (1, c)])])
+        });
+        let federation = FederatedEmulatorConnection::with_policy(emulators, policy.clone());
+        let clause = federation
+            .get_signer_for(Sha256::hash(b"template"))
+            .unwrap();
+        assert_eq!(
+            clause,
+            Clause::And(vec![
+                Clause::Key(keys[0]),
+                Clause::Or(vec![(1, Clause::Key(keys[1])), (1, Clause::Key(keys[2]))]),
+            ])
+        );
+        assert!(clause.lift().is_ok());
+        assert!(clause.compile::<miniscript::Tap>().is_ok());
+
+        // signing stops once A and B have signed, without asking C
+        let (a, b, c) = (Marking::new(1), Marking::new(2), Marking::new(3));
+        let federation = FederatedEmulatorConnection::with_policy(
+            vec![a.clone(), b.clone(), c.clone()],
+            policy.clone(),
+        );
+        let signed = federation.sign(one_input_psbt()).unwrap();
+        assert_eq!(signed.inputs[0].tap_script_sigs.len(), 2);
+        assert_eq!((a.signs(), b.signs(), c.signs()), (1, 1, 0));
+        // C stands in for B while it is offline
+        let federation = FederatedEmulatorConnection::with_policy(
+            vec![a.clone(), Arc::new(Offline), c.clone()],
+            policy.clone(),
+        );
+        assert!(federation.sign(one_input_psbt()).is_ok());
+        assert_eq!((a.signs(), c.signs()), (2, 1));
+        // but nothing stands in for A
+        let federation = FederatedEmulatorConnection::with_policy(
+            vec![Arc::new(Offline), b.clone(), c.clone()],
+            policy,
+        );
+        match federation.sign(one_input_psbt()) {
+            Err(EmulatorError::PolicyNotSatisfied { signed }) => assert_eq!(signed, vec![1, 2]),
+            _ => panic!("expected PolicyNotSatisfied"),
+        }
+    }
+}
@@ -7,7 +7,116 @@
 //! Hierarchical Deterministic Emulator Connection
 
 use super::*;
-/// HDOracleEmulatorConnection wraps a tokio runtime and a TCPStream
+use rand::{Rng, RngCore};
+use std::time::Duration;
+
+/// A bidirectional byte stream an oracle can be reached over
+pub trait OracleStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> OracleStream for T {}
+
+/// Where to (re)connect to an oracle
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OracleAddress {
+    /// a TCP socket
+    Tcp(SocketAddr),
+    /// a Unix domain socket
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl From<SocketAddr> for OracleAddress {
+    fn from(a: SocketAddr) -> Self {
+        OracleAddress::Tcp(a)
+    }
+}
+
+impl OracleAddress {
+    /// open a new connection to the oracle
+    async fn connect(
+        &self,
+        options: &SocketOptions,
+    ) -> Result<Box<dyn OracleStream>, std::io::Error> {
+        Ok(match self {
+            OracleAddress::Tcp(a) => Box::new(options.connect(a).await?),
+            #[cfg(unix)]
+            OracleAddress::Unix(p) => Box::new(tokio::net::UnixStream::connect(p).await?),
+        })
+    }
+}
+
+/// How long to wait between failed attempts to (re)connect to an oracle.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backoff {
+    /// the delay before the second attempt
+    pub initial: Duration,
+    /// the delay doubles after each failed attempt, up to `max`
+    pub max: Duration,
+    /// how many attempts to make before giving up
+    pub attempts: u32,
+    /// the fraction (between 0 and 1) of each delay which is random, so that
+    /// connections which lost an oracle at the same time (e.g., because it
+    /// restarted) don't all retry at the same time
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+            attempts: 3,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    /// the delay after failed attempt number `attempt` (from 0), which is
+    /// between `(1 - jitter) * d` and `d` for the un-jittered delay `d`.
+    pub fn delay<R: Rng>(&self, attempt: u32, rng: &mut R) -> Duration {
+        let d = self
+            .initial
+            .checked_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX))
+            .unwrap_or(self.max)
+            .min(self.max);
+        d.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * rng.gen::<f64>())
+    }
+}
+
+/// A source of randomness for key confirmation challenges, e.g. a hardware
+/// RNG, see [`HDOracleEmulatorConnection::with_entropy`].
+pub trait Entropy: Send + Sync {
+    /// fill `buf` with random bytes
+    fn fill(&self, buf: &mut [u8]);
+}
+
+/// [`Entropy`] from `rand::thread_rng`, the default.
+pub struct ThreadRngEntropy;
+impl Entropy for ThreadRngEntropy {
+    fn fill(&self, buf: &mut [u8]) {
+        rand::thread_rng().fill_bytes(buf)
+    }
+}
+
+/// [`Entropy`] from any RNG, e.g. a seeded one so that the challenges are the
+/// same every run.
+pub struct RngEntropy<R>(std::sync::Mutex<R>);
+impl<R: RngCore + Send> RngEntropy<R> {
+    /// draw challenges from `rng`
+    pub fn new(rng: R) -> Self {
+        RngEntropy(std::sync::Mutex::new(rng))
+    }
+}
+impl<R: RngCore + Send> Entropy for RngEntropy<R> {
+    fn fill(&self, buf: &mut [u8]) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .fill_bytes(buf)
+    }
+}
+
+/// HDOracleEmulatorConnection wraps a tokio runtime and a stream
 /// with a key to be able to talk to an Oracle server.
 ///
 /// Note that because HDOracleEmulatorConnection uses block_in_place/block_on
@@ -20,22 +129,114 @@ pub struct HDOracleEmulatorConnection {
     pub runtime: Option<Arc<tokio::runtime::Runtime>>,
     /// handle to either current_runtime or the runtime owned above
     pub handle: tokio::runtime::Handle,
-    /// connection to the reconnect SocketAddr
-    pub connection: Mutex<Option<TcpStream>>,
+    /// connection to the reconnect address
+    pub connection: Mutex<Option<Box<dyn OracleStream>>>,
     /// resolved address to the oracle
-    pub reconnect: SocketAddr,
+    pub reconnect: OracleAddress,
     /// the root key signatures will come from
     pub root: ExtendedPubKey,
     /// a secp context
     pub secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    /// options for TCP connections to the oracle
+    pub socket_options: SocketOptions,
+    /// how to retry connecting to the oracle
+    pub backoff: Backoff,
+    /// the longest frame which will be sent to or read from the oracle
+    pub max_frame: usize,
+    /// where key confirmation challenges come from
+    pub entropy: Arc<dyn Entropy>,
 }
 
 impl HDOracleEmulatorConnection {
     /// Helper function to derive an EPK
     fn derive(&self, h: Sha256) -> Result<ExtendedPubKey, Error> {
-        let c = hash_to_child_vec(h);
+        let c = hash_to_child_vec(h)?;
         self.root.derive_pub(&self.secp, &c)
     }
+    /// Like [`CTVEmulator::get_signer_for`], but also returns the derivation
+    /// path (from `root`) of the key in the Clause, so that which key was
+    /// used for a template can be audited later.
+    pub fn get_signer_with_path(
+        &self,
+        h: Sha256,
+    ) -> Result<(Clause, Vec<ChildNumber>), EmulatorError> {
+        let c = hash_to_child_vec(h)?;
+        let key = self.root.derive_pub(&self.secp, &c)?.to_x_only_pub();
+        Ok((Clause::Key(key), c))
+    }
+    /// Helper function to derive the EPK attesting to `m`
+    fn derive_attester(&self, m: Sha256) -> Result<ExtendedPubKey, Error> {
+        let c = attestation_child_vec(m)?;
+        self.root.derive_pub(&self.secp, &c)
+    }
+    /// Request the oracle's attestation to the message hash `m`.
+    ///
+    /// The returned signature is checked against the attestation key before
+    /// it is returned.
+    pub fn attest(&self, m: Sha256) -> Result<schnorr::Signature, EmulatorError> {
+        let sig: schnorr::Signature = self.call(msgs::Request::Attest(m))?;
+        self.verify_attestation(m, &sig)?;
+        Ok(sig)
+    }
+    /// Check that `sig` is the oracle's attestation to the message hash `m`.
+    pub fn verify_attestation(
+        &self,
+        m: Sha256,
+        sig: &schnorr::Signature,
+    ) -> Result<(), EmulatorError> {
+        let key = self.derive_attester(m)?.to_x_only_pub();
+        let msg = Message::from_digest_slice(&m[..]).expect("Size must be correct.");
+        self.secp
+            .verify_schnorr(sig, &msg, &key)
+            .or_else(|_| input_error("Invalid Attestation"))?;
+        Ok(())
+    }
+    /// Have the oracle sign `b`, along with a receipt for the signed PSBT.
+    ///
+    /// The PSBT is returned exactly as the oracle signed it, since that is
+    /// what the receipt commits to. The receipt is checked (see
+    /// [`Self::verify_receipt`]) before it is returned.
+    pub fn sign_with_receipt(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<(PartiallySignedTransaction, schnorr::Signature), EmulatorError> {
+        let msgs::Receipted {
+            psbt: msgs::PSBT(signed),
+            receipt,
+        } = self.call(msgs::Request::SignPSBTWithReceipt(msgs::PSBT(b)))?;
+        self.verify_receipt(&signed, &receipt)?;
+        Ok((signed, receipt))
+    }
+    /// Check that `receipt` is the oracle's signature, under its root key,
+    /// for returning `psbt`.
+    pub fn verify_receipt(
+        &self,
+        psbt: &PartiallySignedTransaction,
+        receipt: &schnorr::Signature,
+    ) -> Result<(), EmulatorError> {
+        let msg =
+            Message::from_digest_slice(&receipt_message(psbt)[..]).expect("Size must be correct.");
+        self.secp
+            .verify_schnorr(receipt, &msg, &self.root.to_x_only_pub())
+            .or_else(|_| input_error("Invalid Receipt"))?;
+        Ok(())
+    }
+    /// Confirm the oracle holds the key for `root` by having it answer a
+    /// random challenge.
+    ///
+    /// The answer is only accepted if it is for the challenge just sent, so
+    /// a confirmation captured earlier can't be replayed.
+    pub fn confirm_key(&self) -> Result<(), EmulatorError> {
+        let challenge = self.challenge();
+        let sig: schnorr::Signature = self.call(msgs::Request::ConfirmKey(challenge))?;
+        self.verify_attestation(confirmation_message(challenge), &sig)
+    }
+    /// a new key confirmation challenge, from `entropy`
+    fn challenge(&self) -> Sha256 {
+        let mut buf = [0u8; 32];
+        self.entropy.fill(&mut buf);
+        Sha256::hash(&buf)
+    }
     /// Creates a new instance of a HDOracleEmulatorConnection.
     ///
     /// Note that the runtime and secp can be shared with other instances as it is Arc.
@@ -54,77 +255,378 @@ impl HDOracleEmulatorConnection {
         runtime: Option<Arc<tokio::runtime::Runtime>>,
         secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
     ) -> Result<Self, std::io::Error> {
-        Ok(HDOracleEmulatorConnection {
+        let reconnect = tokio::net::lookup_host(address.clone())
+            .await?
+            .next()
+            .ok_or_else(|| {
+                input_error::<()>(&format!("Bad Lookup Could Not Resolve Address {}", address))
+                    .unwrap_err()
+            })?;
+        Ok(Self::with_address(reconnect.into(), root, runtime, secp))
+    }
+
+    /// Creates a new instance of a HDOracleEmulatorConnection talking to an
+    /// oracle on this host via the Unix domain socket at `path`.
+    ///
+    /// As with [`HDOracleEmulatorConnection::new`], no connection is opened
+    /// until one is needed.
+    #[cfg(unix)]
+    pub fn new_unix<P: Into<std::path::PathBuf>>(
+        path: P,
+        root: ExtendedPubKey,
+        runtime: Option<Arc<tokio::runtime::Runtime>>,
+        secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    ) -> Self {
+        Self::with_address(OracleAddress::Unix(path.into()), root, runtime, secp)
+    }
+
+    pub(crate) fn with_address(
+        reconnect: OracleAddress,
+        root: ExtendedPubKey,
+        runtime: Option<Arc<tokio::runtime::Runtime>>,
+        secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    ) -> Self {
+        let handle = Handle::try_current().unwrap_or_else(|_e| {
+            runtime
+                .as_ref()
+                .expect("Must pass a runtime if not in async context")
+                .handle()
+                .clone()
+        });
+        Self::with_handle(reconnect, root, runtime, handle, secp)
+    }
+
+    /// like [`HDOracleEmulatorConnection::with_address`], but performing IO
+    /// on `handle` even when called from within some other runtime.
+    pub(crate) fn with_handle(
+        reconnect: OracleAddress,
+        root: ExtendedPubKey,
+        runtime: Option<Arc<tokio::runtime::Runtime>>,
+        handle: Handle,
+        secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    ) -> Self {
+        HDOracleEmulatorConnection {
             connection: Mutex::new(None),
-            reconnect: tokio::net::lookup_host(address.clone())
-                .await?
-                .next()
-                .ok_or_else(|| {
-                    input_error::<()>(&format!("Bad Lookup Could Not Resolve Address {}", address))
-                        .unwrap_err()
-                })?,
-            handle: Handle::try_current().unwrap_or_else(|_e| {
-                runtime
-                    .as_ref()
-                    .expect("Must pass a runtime if not in async context")
-                    .handle()
-                    .clone()
-            }),
+            reconnect,
+            handle,
             runtime,
             root,
             secp,
-        })
+            socket_options: Default::default(),
+            backoff: Default::default(),
+            max_frame: msgs::MAX_FRAME,
+            entropy: Arc::new(ThreadRngEntropy),
+        }
+    }
+
+    /// use `options` for (future) TCP connections to the oracle.
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
     }
 
-    /// make a request via the tcpstream.
+    /// draw key confirmation challenges from `entropy` rather than
+    /// [`ThreadRngEntropy`].
+    pub fn with_entropy(mut self, entropy: Arc<dyn Entropy>) -> Self {
+        self.entropy = entropy;
+        self
+    }
+
+    /// retry connections to the oracle according to `backoff`.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// refuse to send or read frames longer than `max` bytes (rather than
+    /// [`msgs::MAX_FRAME`]), e.g. to match an oracle configured with
+    /// `HDOracleEmulator::with_max_frame`.
+    pub fn with_max_frame(mut self, max: usize) -> Self {
+        self.max_frame = max;
+        self
+    }
+
+    /// open a connection to the oracle, retrying according to `backoff`.
+    async fn connect(&self) -> Result<Box<dyn OracleStream>, std::io::Error> {
+        let mut attempt = 0;
+        loop {
+            match self.reconnect.connect(&self.socket_options).await {
+                Ok(conn) => return Ok(conn),
+                Err(e) if attempt + 1 >= self.backoff.attempts => return Err(e),
+                Err(_) => {
+                    let delay = self.backoff.delay(attempt, &mut rand::thread_rng());
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// make a request via the stream.
     /// wire format: length:u32 data:[u8;length]
-    async fn request(t: &mut TcpStream, r: &msgs::Request) -> Result<(), std::io::Error> {
-        let v = serde_json::to_vec(r)?;
-        t.write_u32(v.len() as u32).await?;
-        t.write_all(&v[..]).await
+    ///
+    /// requests are sent in a versioned [`msgs::Envelope`]. Fails with
+    /// [`ConnectionClosed`] if the oracle has closed the connection. Requests
+    /// longer than `max_frame` bytes fail without anything being sent.
+    async fn request<S: AsyncWrite + Unpin + ?Sized>(
+        t: &mut S,
+        r: &msgs::Request,
+        max_frame: usize,
+    ) -> Result<(), std::io::Error> {
+        let frame = msgs::encode_frame_within(&msgs::Envelope::new(r)?, max_frame)?;
+        async {
+            t.write_all(&frame).await?;
+            t.flush().await
+        }
+        .await
+        .map_err(ConnectionClosed::detect)
     }
-    /// receive a response via the tcpstream.
+    /// receive a response via the stream.
     /// wire format: length:u32 data:[u8;length]
     ///
-    /// TODO: secure response by limiting the length to a max value.
-    /// This is not super critical because presumably the oracles are not trying to OOM your system.
-    async fn response<T: DeserializeOwned + Clone>(t: &mut TcpStream) -> Result<T, std::io::Error> {
-        let l = t.read_u32().await? as usize;
-        let mut v = vec![0u8; l];
-        t.read_exact(&mut v[..]).await?;
+    /// Fails with [`ConnectionClosed`] if the oracle closes the connection
+    /// rather than responding. Responses longer than `max_frame` bytes are
+    /// rejected before reading them, so a misbehaving oracle can't make us
+    /// allocate an arbitrarily large buffer.
+    async fn response<S: AsyncRead + Unpin + ?Sized, T: DeserializeOwned + Clone>(
+        t: &mut S,
+        max_frame: usize,
+    ) -> Result<T, std::io::Error> {
+        let v = async {
+            let l = msgs::check_frame_len_within(t.read_u32().await?, max_frame)?;
+            let mut v = vec![0u8; l];
+            t.read_exact(&mut v[..]).await?;
+            Ok::<_, std::io::Error>(v)
+        }
+        .await
+        .map_err(ConnectionClosed::detect)?;
         let t: T = serde_json::from_slice::<T>(&v[..])?;
         Ok(t)
     }
+    /// Close the cached connection to the oracle, if there is one, e.g. to
+    /// release the socket during an idle period without dropping `self`.
+    ///
+    /// The protocol has no close message, so the write half is shut down,
+    /// which the oracle sees as the client hanging up. The next request
+    /// transparently reconnects.
+    pub async fn close(&self) {
+        if let Some(mut conn) = self.connection.lock().await.take() {
+            // the connection is dropped whether or not this succeeds
+            let _ = conn.shutdown().await;
+        }
+    }
+    /// make a request to the oracle, (re)connecting if required, and wait for
+    /// the result.
+    ///
+    /// If an existing connection turns out to have been closed by the oracle
+    /// (e.g., it restarted since the last call) the request is retried once
+    /// on a new connection.
+    ///
+    /// An error response from the oracle is returned as an io::Error wrapping
+    /// the [`msgs::ProtocolError`], see [`msgs::ProtocolError::find`].
+    fn call<T: DeserializeOwned + Clone>(&self, r: msgs::Request) -> Result<T, std::io::Error> {
+        tokio::task::block_in_place(|| {
+            self.handle.block_on(async {
+                let mut mconn = self.connection.lock().await;
+                let mut fresh = false;
+                loop {
+                    if mconn.is_none() {
+                        *mconn = Some(self.connect().await?);
+                        fresh = true;
+                    }
+                    let conn = mconn.as_mut().expect("Connected Above");
+                    let res = async {
+                        Self::request(conn, &r, self.max_frame).await?;
+                        Self::response::<_, msgs::Response<T>>(conn, self.max_frame).await
+                    }
+                    .await;
+                    // drop a broken connection so that the next attempt
+                    // reconnects
+                    if let Err(e) = &res {
+                        *mconn = None;
+                        if !fresh && ConnectionClosed::find(e).is_some() {
+                            continue;
+                        }
+                    }
+                    return match res? {
+                        msgs::Response::Result(t) => Ok(t),
+                        msgs::Response::Error(e) => Err(e.into()),
+                    };
+                }
+            })
+        })
+    }
 }
 
+use bitcoin::secp256k1::{schnorr, Message};
 use tokio::{runtime::Handle, sync::Mutex};
 impl CTVEmulator for HDOracleEmulatorConnection {
     fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
         Ok(Clause::Key(self.derive(h)?.to_x_only_pub()))
     }
+    fn get_attester_for(&self, m: Sha256) -> Result<Clause, EmulatorError> {
+        Ok(Clause::Key(self.derive_attester(m)?.to_x_only_pub()))
+    }
+    fn identity(&self) -> Option<String> {
+        Some(format!("hd {}", self.root))
+    }
+    fn confirm_key(&self) -> Result<(), EmulatorError> {
+        HDOracleEmulatorConnection::confirm_key(self)
+    }
     fn sign(
         &self,
         mut b: PartiallySignedTransaction,
     ) -> Result<PartiallySignedTransaction, EmulatorError> {
-        let inp: Result<PartiallySignedTransaction, std::io::Error> =
-            tokio::task::block_in_place(|| {
-                self.handle.block_on(async {
-                    let mut mconn = self.connection.lock().await;
-                    loop {
-                        if let Some(conn) = &mut *mconn {
-                            Self::request(conn, &msgs::Request::SignPSBT(msgs::PSBT(b.clone())))
-                                .await?;
-                            conn.flush().await?;
-                            return Ok(Self::response::<msgs::PSBT>(conn).await?.0);
-                        } else {
-                            *mconn = Some(TcpStream::connect(&self.reconnect).await?);
-                        }
-                    }
-                })
-            });
-
-        b.combine(inp?)
+        let inp: PartiallySignedTransaction = self
+            .call::<msgs::PSBT>(msgs::Request::SignPSBT(msgs::PSBT(b.clone())))?
+            .0;
+        b.combine(inp)
             .or_else(|_e| input_error("Fault Signed PSBT"))?;
         Ok(b)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_backoff_jitter() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+            attempts: 10,
+            jitter: 0.25,
+        };
+        let mut rng = rand::thread_rng();
+        for attempt in 0..40 {
+            let d = Duration::from_millis((100u64 << attempt.min(4)).min(1000));
+            let samples: Vec<Duration> = (0..1000)
+                .map(|_| backoff.delay(attempt, &mut rng))
+                .collect();
+            assert!(samples.iter().all(|s| *s <= d && *s >= d.mul_f64(0.75)));
+            // the delays really are spread out
+            assert!(samples.iter().any(|s| *s != samples[0]));
+        }
+        let none = Backoff {
+            jitter: 0.0,
+            ..backoff
+        };
+        assert_eq!(none.delay(2, &mut rng), Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed() {
+        // the oracle reads the request, then closes without responding
+        let (mut client, mut oracle) = tokio::io::duplex(1024);
+        HDOracleEmulatorConnection::request(
+            &mut client,
+            &msgs::Request::Attest(Sha256::hash(b"m")),
+            msgs::MAX_FRAME,
+        )
+        .await
+        .unwrap();
+        let mut l = [0u8; 4];
+        oracle.read_exact(&mut l).await.unwrap();
+        drop(oracle);
+        let e = HDOracleEmulatorConnection::response::<_, serde_json::Value>(
+            &mut client,
+            msgs::MAX_FRAME,
+        )
+        .await
+        .unwrap_err();
+        assert!(ConnectionClosed::find(&e).is_some());
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionAborted);
+        // and further requests fail the same way
+        let e = HDOracleEmulatorConnection::request(
+            &mut client,
+            &msgs::Request::Attest(Sha256::hash(b"m")),
+            msgs::MAX_FRAME,
+        )
+        .await
+        .unwrap_err();
+        assert!(ConnectionClosed::find(&e).is_some());
+    }
+
+    /// counts up from 0, one byte at a time
+    struct Counter(std::sync::Mutex<u8>);
+    impl Entropy for Counter {
+        fn fill(&self, buf: &mut [u8]) {
+            let mut n = self.0.lock().unwrap();
+            for b in buf.iter_mut() {
+                *b = *n;
+                *n = n.wrapping_add(1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_entropy() {
+        let rt = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[3u8; 32]).unwrap();
+        let secp = Arc::new(Secp256k1::new());
+        let conn = || {
+            HDOracleEmulatorConnection::with_address(
+                "127.0.0.1:0".parse::<SocketAddr>().unwrap().into(),
+                ExtendedPubKey::from_priv(&secp, &root),
+                Some(rt.clone()),
+                secp.clone(),
+            )
+            .with_entropy(Arc::new(Counter(Default::default())))
+        };
+        let (a, b) = (conn(), conn());
+        let first: Vec<u8> = (0..32).collect();
+        let second: Vec<u8> = (32..64).collect();
+        for expected in [first, second] {
+            let h = a.challenge();
+            assert_eq!(h, Sha256::hash(&expected));
+            assert_eq!(h, b.challenge());
+        }
+        assert_ne!(
+            conn().challenge(),
+            conn().with_entropy(Arc::new(ThreadRngEntropy)).challenge()
+        );
+    }
+
+    #[test]
+    fn test_seeded_entropy() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+        let rt = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[3u8; 32]).unwrap();
+        let secp = Arc::new(Secp256k1::new());
+        let conn = |seed| {
+            HDOracleEmulatorConnection::with_address(
+                "127.0.0.1:0".parse::<SocketAddr>().unwrap().into(),
+                ExtendedPubKey::from_priv(&secp, &root),
+                Some(rt.clone()),
+                secp.clone(),
+            )
+            .with_entropy(Arc::new(RngEntropy::new(StdRng::seed_from_u64(seed))))
+        };
+        let (a, b) = (conn(7), conn(7));
+        let challenges: Vec<Sha256> = (0..3).map(|_| a.challenge()).collect();
+        assert_eq!(
+            challenges,
+            (0..3).map(|_| b.challenge()).collect::<Vec<_>>()
+        );
+        assert_ne!(challenges[0], challenges[1]);
+        assert_ne!(challenges[0], conn(8).challenge());
+    }
+
+    #[test]
+    fn test_get_signer_with_path() {
+        let rt = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[3u8; 32]).unwrap();
+        let secp = Arc::new(Secp256k1::new());
+        let conn = HDOracleEmulatorConnection::with_address(
+            "127.0.0.1:0".parse::<SocketAddr>().unwrap().into(),
+            ExtendedPubKey::from_priv(&secp, &root),
+            Some(rt),
+            secp,
+        );
+        let h = Sha256::hash(b"template");
+        let (clause, path) = conn.get_signer_with_path(h).unwrap();
+        assert_eq!(path, hash_to_child_vec(h).unwrap());
+        assert_eq!(clause, conn.get_signer_for(h).unwrap());
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! spread requests across replicas of the same oracle
+
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Spreads requests across several replicas of the same oracle (i.e. sharing
+/// a root), e.g. to run more than one HD oracle server behind a single
+/// emulator.
+///
+/// Requests go to each replica in turn, failing over to the next replica if
+/// one errors. Unlike a `FederatedEmulatorConnection`, the replicas are
+/// interchangeable, so `get_signer_for` is a single replica's Clause.
+pub struct LoadBalancedEmulator {
+    replicas: Vec<Arc<dyn CTVEmulator>>,
+    next: AtomicUsize,
+}
+
+impl LoadBalancedEmulator {
+    /// create an emulator balancing across `replicas`, which must all share
+    /// a root (see [`CTVEmulator::confirm_key`]).
+    ///
+    /// Panics if `replicas` is empty.
+    pub fn new(replicas: Vec<Arc<dyn CTVEmulator>>) -> Self {
+        assert!(
+            !replicas.is_empty(),
+            "LoadBalancedEmulator requires a replica"
+        );
+        LoadBalancedEmulator {
+            replicas,
+            next: AtomicUsize::new(0),
+        }
+    }
+    /// call `f` on the next replica in turn, trying the others in order
+    /// until one succeeds, returning the last error if none do.
+    fn balance<T, F>(&self, f: F) -> Result<T, EmulatorError>
+    where
+        F: Fn(&dyn CTVEmulator) -> Result<T, EmulatorError>,
+    {
+        let n = self.replicas.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut last = None;
+        for i in 0..n {
+            match f(self.replicas[(start + i) % n].as_ref()) {
+                Ok(t) => return Ok(t),
+                Err(e) => last = Some(e),
+            }
+        }
+        Err(last.expect("LoadBalancedEmulator has a replica"))
+    }
+}
+
+impl CTVEmulator for LoadBalancedEmulator {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        self.balance(|e| e.get_signer_for(h))
+    }
+    fn get_attester_for(&self, m: Sha256) -> Result<Clause, EmulatorError> {
+        self.balance(|e| e.get_attester_for(m))
+    }
+    /// The first replica's identity, as the replicas share a root.
+    fn identity(&self) -> Option<String> {
+        self.replicas[0].identity()
+    }
+    /// Confirms every replica, as each must hold the key.
+    fn confirm_key(&self) -> Result<(), EmulatorError> {
+        self.replicas.iter().try_for_each(|e| e.confirm_key())
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        self.balance(|e| e.sign(b.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a replica which counts the PSBTs it signs, or is unreachable
+    struct Replica {
+        online: bool,
+        signs: AtomicUsize,
+    }
+    impl Replica {
+        fn new(online: bool) -> Arc<Self> {
+            Arc::new(Replica {
+                online,
+                signs: 0.into(),
+            })
+        }
+        fn signs(&self) -> usize {
+            self.signs.load(Ordering::SeqCst)
+        }
+    }
+    impl CTVEmulator for Replica {
+        fn get_signer_for(&self, _h: Sha256) -> Result<Clause, EmulatorError> {
+            Ok(Clause::Trivial)
+        }
+        fn sign(
+            &self,
+            b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            if !self.online {
+                return Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into());
+            }
+            self.signs.fetch_add(1, Ordering::SeqCst);
+            Ok(b)
+        }
+    }
+
+    fn empty_psbt() -> PartiallySignedTransaction {
+        PartiallySignedTransaction::from_unsigned_tx(bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_load_balanced() {
+        let (a, b) = (Replica::new(true), Replica::new(true));
+        let balanced = LoadBalancedEmulator::new(vec![a.clone(), b.clone()]);
+        for _ in 0..4 {
+            assert!(balanced.sign(empty_psbt()).is_ok());
+        }
+        assert_eq!((a.signs(), b.signs()), (2, 2));
+        assert_eq!(
+            balanced.get_signer_for(Sha256::hash(b"template")).unwrap(),
+            Clause::Trivial
+        );
+
+        // requests fail over to the replica which is up
+        let up = Replica::new(true);
+        let balanced = LoadBalancedEmulator::new(vec![Replica::new(false), up.clone()]);
+        for _ in 0..4 {
+            assert!(balanced.sign(empty_psbt()).is_ok());
+        }
+        assert_eq!(up.signs(), 4);
+        // and only fail if every replica does
+        let down = LoadBalancedEmulator::new(vec![Replica::new(false), Replica::new(false)]);
+        assert!(down.sign(empty_psbt()).is_err());
+    }
+}
@@ -0,0 +1,49 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! sign in process, without an oracle server
+
+use super::*;
+use crate::servers::hd::HDOracleEmulator;
+
+/// Signs with a root key held in process, rather than asking an oracle
+/// server, e.g. for tests or when the emulator's operator is the only party
+/// to a contract.
+///
+/// Its Clauses and signatures are the same as those of an
+/// [`HDOracleEmulator`] server holding the same root (and so of an
+/// `HDOracleEmulatorConnection` to it), so contracts compiled with either
+/// can be signed by the other.
+pub struct LocalSigningEmulator {
+    oracle: HDOracleEmulator,
+}
+
+impl LocalSigningEmulator {
+    /// create an emulator signing with keys derived from `root`
+    pub fn new(root: ExtendedPrivKey) -> Self {
+        LocalSigningEmulator {
+            oracle: HDOracleEmulator::new(root, false),
+        }
+    }
+}
+
+impl CTVEmulator for LocalSigningEmulator {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        self.oracle.get_signer_for(h)
+    }
+    fn get_attester_for(&self, m: Sha256) -> Result<Clause, EmulatorError> {
+        self.oracle.get_attester_for(m)
+    }
+    fn identity(&self) -> Option<String> {
+        self.oracle.identity()
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        CTVEmulator::sign(&self.oracle, b)
+    }
+}
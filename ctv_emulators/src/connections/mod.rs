@@ -7,5 +7,9 @@
 //! Connections to emulators
 
 use super::*;
+pub mod adapter;
 pub mod federated;
 pub mod hd;
+pub mod load_balanced;
+pub mod local;
+pub mod per_input;
@@ -0,0 +1,167 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! sign each input of a transaction with a different CTVEmulator
+
+use super::*;
+use std::collections::BTreeMap;
+
+/// Signs each input of a PSBT with the emulator assigned to it, e.g. for a
+/// contract funded by two UTXOs whose contracts were compiled against
+/// different oracles.
+///
+/// Inputs without an assigned emulator are signed by the default emulator,
+/// which also provides the Clauses and attestations. It implements
+/// CTVEmulator so that it can be passed wherever a single emulator is, e.g.
+/// to `Object::presign_all` or `Object::bind_psbt`.
+pub struct PerInputEmulator {
+    default: Arc<dyn CTVEmulator>,
+    inputs: BTreeMap<usize, Arc<dyn CTVEmulator>>,
+}
+
+impl PerInputEmulator {
+    /// create an emulator which signs every input with `default`
+    pub fn new(default: Arc<dyn CTVEmulator>) -> Self {
+        PerInputEmulator {
+            default,
+            inputs: BTreeMap::new(),
+        }
+    }
+    /// sign the `input`th input with `emulator` instead of the default
+    pub fn with_input(mut self, input: usize, emulator: Arc<dyn CTVEmulator>) -> Self {
+        self.inputs.insert(input, emulator);
+        self
+    }
+}
+
+impl CTVEmulator for PerInputEmulator {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        self.default.get_signer_for(h)
+    }
+    fn get_attester_for(&self, m: Sha256) -> Result<Clause, EmulatorError> {
+        self.default.get_attester_for(m)
+    }
+    fn identity(&self) -> Option<String> {
+        self.default.identity()
+    }
+    fn confirm_key(&self) -> Result<(), EmulatorError> {
+        self.default.confirm_key()?;
+        self.inputs.values().try_for_each(|e| e.confirm_key())
+    }
+    /// Each emulator signs the whole PSBT, but only the inputs assigned to
+    /// it are kept. Errors if an emulator is assigned to an input the PSBT
+    /// does not have.
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        if let Some(i) = self.inputs.keys().find(|i| **i >= b.inputs.len()) {
+            return Ok(input_error(&format!(
+                "Emulator Assigned To Input {} Of {}",
+                i,
+                b.inputs.len()
+            ))?);
+        }
+        let unassigned: Vec<usize> = (0..b.inputs.len())
+            .filter(|i| !self.inputs.contains_key(i))
+            .collect();
+        let assignments = std::iter::once((&self.default, unassigned))
+            .chain(self.inputs.iter().map(|(i, e)| (e, vec![*i])))
+            .filter(|(_, inputs)| !inputs.is_empty());
+        let mut signed = b.clone();
+        for (emulator, inputs) in assignments {
+            let mut by_emulator = emulator.sign(b.clone())?;
+            for i in inputs {
+                signed.inputs[i] = std::mem::take(&mut by_emulator.inputs[i]);
+            }
+        }
+        Ok(signed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::servers::hd::HDOracleEmulator;
+    use bitcoin::schnorr::TapTweak;
+    use bitcoin::util::sighash::{Prevouts, SighashCache};
+    use bitcoin::{Script, Transaction, TxOut, XOnlyPublicKey};
+
+    #[test]
+    fn test_per_input_emulator() {
+        let roots: Vec<ExtendedPrivKey> = [1u8, 2]
+            .iter()
+            .map(|s| ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[*s; 32]).unwrap())
+            .collect();
+        let oracles: Vec<HDOracleEmulator> = roots
+            .iter()
+            .map(|r| HDOracleEmulator::new(*r, false))
+            .collect();
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default(), Default::default()],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new_op_return(&[]),
+            }],
+        };
+        // input i pays to the key oracle i uses for it
+        let keys: Vec<XOnlyPublicKey> = roots
+            .iter()
+            .enumerate()
+            .map(|(i, root)| {
+                SECP.with(|secp| {
+                    let c = crate::hash_to_child_vec(tx.get_ctv_hash(i as u32)).unwrap();
+                    let kp = root.derive_priv(secp, &c).unwrap().to_keypair(secp);
+                    XOnlyPublicKey::from_keypair(&kp).0
+                })
+            })
+            .collect();
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        for (inp, key) in psbt.inputs.iter_mut().zip(keys.iter()) {
+            inp.witness_utxo = Some(TxOut {
+                value: 6_000,
+                script_pubkey: SECP.with(|secp| Script::new_v1_p2tr(secp, *key, None)),
+            });
+        }
+
+        // neither oracle alone can sign both inputs
+        for (i, o) in oracles.iter().enumerate() {
+            let alone = CTVEmulator::sign(o, psbt.clone()).unwrap();
+            assert!(alone.inputs[1 - i].tap_key_sig.is_none());
+        }
+
+        let [a, b]: [Arc<dyn CTVEmulator>; 2] =
+            [Arc::new(oracles[0].clone()), Arc::new(oracles[1].clone())];
+        let signed = PerInputEmulator::new(a.clone())
+            .with_input(1, b)
+            .sign(psbt.clone())
+            .unwrap();
+        let utxos: Vec<TxOut> = signed
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.clone().unwrap())
+            .collect();
+        let tx = signed.clone().extract_tx();
+        let mut cache = SighashCache::new(&tx);
+        for (i, key) in keys.iter().enumerate() {
+            let sig = signed.inputs[i].tap_key_sig.unwrap();
+            let sighash = cache
+                .taproot_key_spend_signature_hash(i, &Prevouts::All(&utxos), sig.hash_ty)
+                .unwrap();
+            let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..]).unwrap();
+            SECP.with(|secp| {
+                let tweaked = key.tap_tweak(secp, None).0.to_inner();
+                secp.verify_schnorr(&sig.sig, &msg, &tweaked).unwrap()
+            });
+        }
+
+        // an emulator for an input the PSBT doesn't have is an error
+        let extra = PerInputEmulator::new(a.clone()).with_input(2, a);
+        assert!(extra.sign(psbt).is_err());
+    }
+}
@@ -16,18 +16,19 @@ use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, ToSocketAddrs};
 
 use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::util::psbt;
+use bitcoin::util::psbt::raw::ProprietaryKey;
 use bitcoin::util::psbt::PartiallySignedTransaction;
 
 use sapio_base::CTVHash;
 use std::sync::Arc;
-const MAX_MSG: usize = 1_000_000;
 
 pub mod connections;
-mod msgs;
+pub mod msgs;
 pub mod servers;
 
 thread_local! {
@@ -43,6 +44,85 @@ fn input_err(s: &str) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::InvalidInput, s)
 }
 
+/// An oracle could not derive the key to sign with, e.g. because the
+/// derivation hit one of the (astronomically rare) invalid children.
+///
+/// Oracles report it as the inner error of a [`std::io::Error`], which can be
+/// recovered with [`DerivationFailed::find`].
+#[derive(Debug)]
+pub struct DerivationFailed(pub Error);
+
+impl DerivationFailed {
+    /// get the DerivationFailed an oracle's error wraps, if it is one
+    pub fn find(e: &std::io::Error) -> Option<&DerivationFailed> {
+        e.get_ref().and_then(|e| e.downcast_ref())
+    }
+}
+
+impl std::fmt::Display for DerivationFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Key Derivation Failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for DerivationFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl From<DerivationFailed> for std::io::Error {
+    fn from(e: DerivationFailed) -> Self {
+        std::io::Error::other(e)
+    }
+}
+
+/// The peer closed the connection before a whole message was exchanged, e.g.
+/// because the oracle restarted.
+///
+/// Reported as the inner error of a [`std::io::Error`] of kind
+/// [`std::io::ErrorKind::ConnectionAborted`], which can be recovered with
+/// [`ConnectionClosed::find`].
+#[derive(Debug)]
+pub struct ConnectionClosed;
+
+impl ConnectionClosed {
+    /// get the ConnectionClosed an error wraps, if it is one
+    pub fn find(e: &std::io::Error) -> Option<&ConnectionClosed> {
+        e.get_ref().and_then(|e| e.downcast_ref())
+    }
+    /// convert an error from reading or writing a stream into a
+    /// ConnectionClosed if it means the peer has gone away.
+    ///
+    /// A closed peer shows up as a read of zero bytes (`UnexpectedEof`) or,
+    /// once the peer has noticed writes to a closed connection, as a reset.
+    pub fn detect(e: std::io::Error) -> std::io::Error {
+        use std::io::ErrorKind::*;
+        match e.kind() {
+            UnexpectedEof | BrokenPipe | ConnectionReset | ConnectionAborted
+                if ConnectionClosed::find(&e).is_none() =>
+            {
+                ConnectionClosed.into()
+            }
+            _ => e,
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectionClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Connection Closed By Peer")
+    }
+}
+
+impl std::error::Error for ConnectionClosed {}
+
+impl From<ConnectionClosed> for std::io::Error {
+    fn from(e: ConnectionClosed) -> Self {
+        std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e)
+    }
+}
+
 /// Compute a derivation path from a sha256 hash.
 ///
 /// Format is a bit peculiar, it's 9 u32's with the top bit as 0 (for unhardened
@@ -51,23 +131,245 @@ fn input_err(s: &str) -> std::io::Error {
 ///
 /// This is because the ChildNumber is a enum u31 where the top bit is used to
 /// indicate hardened or not, so we can't just do the simple thing.
-fn hash_to_child_vec(h: Sha256) -> Vec<ChildNumber> {
+///
+/// Every element is constructed with [`ChildNumber::from_normal_idx`], so if an
+/// index would ever land in the hardened range an error is returned instead.
+fn hash_to_child_vec(h: Sha256) -> Result<Vec<ChildNumber>, Error> {
     let a: [u8; 32] = h.into_inner();
     let b: [[u8; 4]; 8] = unsafe { std::mem::transmute(a) };
     let mut c: Vec<ChildNumber> = b
         .iter()
         // Note: We mask off the top bit. This removes 8 bits of entropy from the hash,
         // but we add it back in later.
-        .map(|x| (u32::from_be_bytes(*x) << 1) >> 1)
-        .map(ChildNumber::from)
-        .collect();
+        .map(|x| ChildNumber::from_normal_idx(u32::from_be_bytes(*x) & !(1 << 31)))
+        .collect::<Result<_, _>>()?;
     // Add a unique 9th path for the MSB's
-    c.push(
+    c.push(ChildNumber::from_normal_idx(
         b.iter()
             .enumerate()
             .map(|(i, x)| (u32::from_be_bytes(*x) >> 31) << i)
-            .sum::<u32>()
-            .into(),
-    );
-    c
+            .sum::<u32>(),
+    )?);
+    Ok(c)
+}
+
+/// The first (unhardened) child index of every attestation key path, keeping
+/// attestation keys separate from the keys used to sign templates.
+const ATTESTATION_INDEX: u32 = 0x4154_5354;
+
+/// Compute the derivation path of the key attesting to the message hash `m`.
+///
+/// The path is [`ATTESTATION_INDEX`] followed by [`hash_to_child_vec`] of `m`.
+fn attestation_child_vec(m: Sha256) -> Result<Vec<ChildNumber>, Error> {
+    let mut c = vec![ChildNumber::from_normal_idx(ATTESTATION_INDEX)?];
+    c.extend(hash_to_child_vec(m)?);
+    Ok(c)
+}
+
+/// The message an oracle attests to to confirm its key for the challenge
+/// `h`, which is domain separated so a confirmation can't be mistaken for an
+/// attestation to `h` itself.
+fn confirmation_message(h: Sha256) -> Sha256 {
+    Sha256::hash(&[&b"sapio/confirm_key"[..], &h[..]].concat())
+}
+
+/// The message an oracle signs with its root key as a receipt for returning
+/// `psbt`, committing to the serialized PSBT so the oracle can't later deny
+/// having produced it.
+fn receipt_message(psbt: &PartiallySignedTransaction) -> Sha256 {
+    Sha256::hash(
+        &[
+            &b"sapio/receipt"[..],
+            &bitcoin::consensus::serialize(psbt)[..],
+        ]
+        .concat(),
+    )
+}
+
+/// Proprietary PSBT key prefix for fields understood by sapio oracles
+pub const PSBT_PROPRIETARY_PREFIX: &[u8] = b"sapio";
+/// Proprietary PSBT subtype (on input 0) requesting that the oracle sign with
+/// the key at a given path rather than the CTV-hash-derived key
+pub const PSBT_PATH_OVERRIDE_SUBTYPE: u8 = 0;
+
+/// The (hardened) child index of the subtree path overrides are derived
+/// under, so an override can never alias a CTV or attestation key.
+pub const PATH_OVERRIDE_INDEX: u32 = 0x4f56_5244;
+
+fn path_override_key() -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: PSBT_PROPRIETARY_PREFIX.to_vec(),
+        subtype: PSBT_PATH_OVERRIDE_SUBTYPE,
+        key: vec![],
+    }
+}
+
+/// Request that an oracle sign `inp` (which must be input 0) with the key at
+/// `m/PATH_OVERRIDE_INDEX'/path` instead of the CTV-hash-derived key.
+///
+/// Oracles only honor the request if they are configured to allow overrides.
+pub fn set_path_override(inp: &mut psbt::Input, path: &[ChildNumber]) {
+    let v = path
+        .iter()
+        .flat_map(|c| u32::from(*c).to_be_bytes())
+        .collect();
+    inp.proprietary.insert(path_override_key(), v);
+}
+
+/// Read the path set with [`set_path_override`], if any.
+fn get_path_override(inp: &psbt::Input) -> Result<Option<Vec<ChildNumber>>, std::io::Error> {
+    match inp.proprietary.get(&path_override_key()) {
+        None => Ok(None),
+        Some(v) if v.len() % 4 == 0 => Ok(Some(
+            v.chunks(4)
+                .map(|c| ChildNumber::from(u32::from_be_bytes([c[0], c[1], c[2], c[3]])))
+                .collect(),
+        )),
+        Some(_) => input_error("Malformed Path Override"),
+    }
+}
+
+/// TCP options for the sockets of oracle servers and connections.
+///
+/// By default Nagle's algorithm is disabled (`TCP_NODELAY`), since the oracle
+/// protocol is made of small request/response messages where batching only
+/// adds latency, and the OS default buffer sizes are used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// set `TCP_NODELAY` on every stream
+    pub nodelay: bool,
+    /// the `SO_RCVBUF` size, if not the OS default
+    pub recv_buffer_size: Option<u32>,
+    /// the `SO_SNDBUF` size, if not the OS default
+    pub send_buffer_size: Option<u32>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// create a socket with the buffer sizes applied
+    fn socket(&self, addr: &SocketAddr) -> Result<TcpSocket, std::io::Error> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(socket)
+    }
+    /// apply the per-stream options to a connected (or accepted) stream
+    fn apply(&self, stream: &TcpStream) -> Result<(), std::io::Error> {
+        stream.set_nodelay(self.nodelay)
+    }
+    /// open a connection to `addr`
+    async fn connect(&self, addr: &SocketAddr) -> Result<TcpStream, std::io::Error> {
+        let stream = self.socket(addr)?.connect(*addr).await?;
+        self.apply(&stream)?;
+        Ok(stream)
+    }
+    /// listen on the first of `a`'s addresses which can be bound. Accepted
+    /// streams inherit the buffer sizes, but [`SocketOptions::apply`] must be
+    /// called on them for the rest.
+    async fn listen<A: ToSocketAddrs>(&self, a: A) -> Result<TcpListener, std::io::Error> {
+        let mut last_err = input_err("Could Not Resolve Address");
+        for addr in tokio::net::lookup_host(a).await? {
+            let r = self.socket(&addr).and_then(|socket| {
+                #[cfg(unix)]
+                socket.set_reuseaddr(true)?;
+                socket.bind(addr)?;
+                socket.listen(1024)
+            });
+            match r {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use bitcoin::{Script, Transaction, TxIn, TxOut, XOnlyPublicKey};
+    use rand::Rng;
+    /// creates a PSBT spending a key-path only output which the oracle with
+    /// key `root` is able to sign.
+    pub(crate) fn signable_psbt(root: &ExtendedPrivKey) -> PartiallySignedTransaction {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new_op_return(&[]),
+            }],
+        };
+        let spk = SECP.with(|secp| {
+            let c = hash_to_child_vec(tx.get_ctv_hash(0)).unwrap();
+            let kp = root.derive_priv(secp, &c).unwrap().to_keypair(secp);
+            let (xonly, _) = XOnlyPublicKey::from_keypair(&kp);
+            Script::new_v1_p2tr(secp, xonly, None)
+        });
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 20_000,
+            script_pubkey: spk,
+        });
+        psbt
+    }
+    #[test]
+    fn test_hash_to_child_vec_is_normal() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10_000 {
+            let h = Sha256::hash(&rng.gen::<[u8; 32]>());
+            let v = hash_to_child_vec(h).unwrap();
+            assert_eq!(v.len(), 9);
+            assert!(v.iter().all(|c| c.is_normal()));
+        }
+        let v = hash_to_child_vec(Sha256::from_inner([0xff; 32])).unwrap();
+        assert!(v.iter().all(|c| c.is_normal()));
+        assert_eq!(v[8], ChildNumber::from_normal_idx(0xff).unwrap());
+    }
+
+    #[test]
+    fn test_derivation_failed() {
+        let cause = Error::InvalidChildNumber(1 << 31);
+        let e: std::io::Error = DerivationFailed(cause.clone()).into();
+        assert!(matches!(DerivationFailed::find(&e), Some(DerivationFailed(c)) if *c == cause));
+        assert!(e.to_string().contains(&cause.to_string()));
+        assert!(DerivationFailed::find(&input_err("Could Not Derive Key")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        for nodelay in [true, false] {
+            let opts = SocketOptions {
+                nodelay,
+                recv_buffer_size: Some(1 << 16),
+                send_buffer_size: Some(1 << 16),
+            };
+            let listener = opts.listen("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = opts.connect(&addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            opts.apply(&server).unwrap();
+            assert_eq!(client.nodelay().unwrap(), nodelay);
+            assert_eq!(server.nodelay().unwrap(), nodelay);
+        }
+        assert!(SocketOptions::default().nodelay);
+    }
 }
@@ -4,6 +4,11 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! the emulator wire protocol
+//!
+//! Every message is sent as a frame, `length:u32 data:[u8;length]`, where
+//! data is JSON. [`decode_request`] and [`encode_frame`] are pure so that the
+//! parser can be tested (and fuzzed) without any I/O.
 use super::*;
 use bitcoin::consensus::encode::{Decodable, Encodable};
 use miniscript::serde;
@@ -12,7 +17,80 @@ use serde::de::*;
 use serde::*;
 use std::fmt;
 
-const MAX_MSG: usize = 1_000_000;
+/// The largest serialized PSBT which will be sent or decoded
+pub const MAX_MSG: usize = 1_000_000;
+
+/// The largest frame payload which will be sent or decoded, unless a peer is
+/// configured otherwise (e.g. `HDOracleEmulator::with_max_frame`). JSON
+/// encodes each byte of a (at most [`MAX_MSG`] sized) PSBT as up to 4
+/// characters, so this leaves room for a maximum size PSBT and the rest of
+/// the message.
+pub const MAX_FRAME: usize = 5 * MAX_MSG;
+
+/// check the length prefix of a frame, before reading the payload
+pub fn check_frame_len(len: u32) -> Result<usize, std::io::Error> {
+    check_frame_len_within(len, MAX_FRAME)
+}
+
+/// like [`check_frame_len`], but for a peer configured to accept frames of
+/// up to `max` bytes rather than [`MAX_FRAME`]
+pub fn check_frame_len_within(len: u32, max: usize) -> Result<usize, std::io::Error> {
+    let len = len as usize;
+    if len > max {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Frame of {} bytes exceeds maximum of {}", len, max),
+        ))
+    } else {
+        Ok(len)
+    }
+}
+
+/// Decode a complete request frame, as received by a server.
+///
+/// Fails if the frame is truncated, has trailing bytes, is longer than
+/// [`MAX_FRAME`], or does not contain a valid request.
+pub fn decode_request(bytes: &[u8]) -> Result<Incoming, std::io::Error> {
+    if bytes.len() < 4 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let len = check_frame_len(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))?;
+    match bytes.len() - 4 {
+        l if l < len => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        l if l > len => Err(input_err("Trailing Bytes After Frame")),
+        _ => decode_payload(&bytes[4..]),
+    }
+}
+
+/// Decode the payload of a request frame (i.e., without the length prefix).
+pub fn decode_payload(payload: &[u8]) -> Result<Incoming, std::io::Error> {
+    Ok(serde_json::from_slice(payload)?)
+}
+
+/// Encode a message as a frame.
+///
+/// Fails, without anything having been sent, if the payload would be longer
+/// than [`MAX_FRAME`] or contains a PSBT longer than [`MAX_MSG`], as the peer
+/// would refuse it.
+pub fn encode_frame<T: Serialize>(r: &T) -> Result<Vec<u8>, std::io::Error> {
+    encode_frame_within(r, MAX_FRAME)
+}
+
+/// like [`encode_frame`], but for a peer configured to accept frames of up
+/// to `max` bytes rather than [`MAX_FRAME`]
+pub fn encode_frame_within<T: Serialize>(r: &T, max: usize) -> Result<Vec<u8>, std::io::Error> {
+    let mut v = vec![0u8; 4];
+    serde_json::to_writer(&mut v, r)?;
+    let len = v.len() - 4;
+    if len > max {
+        return Err(input_err(&format!(
+            "Frame of {} bytes exceeds maximum of {}",
+            len, max
+        )));
+    }
+    v[..4].copy_from_slice(&(len as u32).to_be_bytes());
+    Ok(v)
+}
 
 /// a PSBT Wrapper type. Note that Serialize/Deserialize are manually implemented
 /// limited to 1MB in size.
@@ -22,7 +100,167 @@ pub struct PSBT(pub PartiallySignedTransaction);
 /// Wrapper for message serialization
 #[derive(Serialize, Deserialize)]
 pub enum Request {
+    /// sign the PSBT for the template it spends to
     SignPSBT(PSBT),
+    /// sign the PSBT, and sign a receipt for the signed PSBT with the root key
+    SignPSBTWithReceipt(PSBT),
+    /// attest to the message hash
+    Attest(Sha256),
+    /// prove control of the oracle's root key by answering the challenge
+    ConfirmKey(Sha256),
+}
+
+/// The version of the emulator protocol spoken by this crate
+pub const PROTOCOL_VERSION: u32 = 1;
+
+impl Request {
+    /// the method name this request is sent under in an [`Envelope`]
+    pub fn method(&self) -> &'static str {
+        match self {
+            Request::SignPSBT(_) => "sign_psbt",
+            Request::SignPSBTWithReceipt(_) => "sign_psbt_with_receipt",
+            Request::Attest(_) => "attest",
+            Request::ConfirmKey(_) => "confirm_key",
+        }
+    }
+}
+
+/// Versioned envelope every request is sent in.
+#[derive(Serialize, Deserialize)]
+pub struct Envelope {
+    /// the protocol version the client speaks
+    pub version: u32,
+    /// the name of the request, see [`Request::method`]
+    pub method: String,
+    /// the method's parameters
+    pub params: serde_json::Value,
+}
+
+impl Envelope {
+    /// wrap a request for the current [`PROTOCOL_VERSION`]
+    pub fn new(r: &Request) -> Result<Self, serde_json::Error> {
+        let params = match r {
+            Request::SignPSBT(psbt) => serde_json::to_value(psbt)?,
+            Request::SignPSBTWithReceipt(psbt) => serde_json::to_value(psbt)?,
+            Request::Attest(m) => serde_json::to_value(m)?,
+            Request::ConfirmKey(h) => serde_json::to_value(h)?,
+        };
+        Ok(Envelope {
+            version: PROTOCOL_VERSION,
+            method: r.method().into(),
+            params,
+        })
+    }
+    /// unwrap the request, checking the version and method are understood
+    pub fn into_request(self) -> Result<Request, ProtocolError> {
+        if self.version != PROTOCOL_VERSION {
+            return Err(ProtocolError::VersionMismatch {
+                expected: PROTOCOL_VERSION,
+                got: self.version,
+            });
+        }
+        let invalid = |e: serde_json::Error| ProtocolError::InvalidParams(e.to_string());
+        match self.method.as_str() {
+            "sign_psbt" => Ok(Request::SignPSBT(
+                serde_json::from_value(self.params).map_err(invalid)?,
+            )),
+            "sign_psbt_with_receipt" => Ok(Request::SignPSBTWithReceipt(
+                serde_json::from_value(self.params).map_err(invalid)?,
+            )),
+            "attest" => Ok(Request::Attest(
+                serde_json::from_value(self.params).map_err(invalid)?,
+            )),
+            "confirm_key" => Ok(Request::ConfirmKey(
+                serde_json::from_value(self.params).map_err(invalid)?,
+            )),
+            _ => Err(ProtocolError::UnknownMethod(self.method)),
+        }
+    }
+}
+
+/// The answer to [`Request::SignPSBTWithReceipt`]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Receipted {
+    /// the signed PSBT
+    pub psbt: PSBT,
+    /// the oracle's signature, with its root key, committing to `psbt`
+    pub receipt: bitcoin::secp256k1::schnorr::Signature,
+}
+
+/// A message a server may receive.
+///
+/// TODO: The `Legacy` flat format is accepted for compatibility with
+/// unversioned clients and should be removed in the next release.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Incoming {
+    /// a versioned request
+    Envelope(Envelope),
+    /// an unversioned request
+    Legacy(Request),
+}
+
+/// Structured errors returned to clients sending an [`Envelope`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The method is not known to this server
+    UnknownMethod(String),
+    /// The client speaks a different protocol version than the server
+    VersionMismatch {
+        /// the server's version
+        expected: u32,
+        /// the client's version
+        got: u32,
+    },
+    /// The params could not be parsed for the method
+    InvalidParams(String),
+    /// The key confirmation challenge has already been answered
+    ReplayedChallenge(Sha256),
+    /// The request frame did not contain a valid request
+    MalformedRequest(String),
+}
+
+impl ProtocolError {
+    /// get the ProtocolError a server's response wraps, if it is one
+    pub fn find(e: &std::io::Error) -> Option<&ProtocolError> {
+        e.get_ref().and_then(|e| e.downcast_ref())
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownMethod(m) => write!(f, "Unknown Method: {}", m),
+            ProtocolError::VersionMismatch { expected, got } => write!(
+                f,
+                "Protocol Version Mismatch: server speaks {}, client sent {}",
+                expected, got
+            ),
+            ProtocolError::InvalidParams(e) => write!(f, "Invalid Params: {}", e),
+            ProtocolError::ReplayedChallenge(h) => {
+                write!(f, "Key Confirmation Challenge {} Already Answered", h)
+            }
+            ProtocolError::MalformedRequest(e) => write!(f, "Malformed Request: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for std::io::Error {
+    fn from(e: ProtocolError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// Response to an [`Envelope`] request
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Response<T> {
+    /// the request succeeded
+    Result(T),
+    /// the request could not be understood
+    Error(ProtocolError),
 }
 
 /// A visitor tage for a SafePSBT type that is size limited
@@ -76,8 +314,15 @@ impl Serialize for PSBT {
         self.0
             .consensus_encode(&mut m)
             .map_err(ser::Error::custom)?;
-        let len = m.len();
-        m[..4].copy_from_slice(&((len - 4) as u32).to_be_bytes()[..]);
+        let len = m.len() - 4;
+        // the peer would refuse it, so refuse to send it
+        if len > MAX_MSG {
+            return Err(ser::Error::custom(format!(
+                "PSBT of {} bytes exceeds maximum of {}",
+                len, MAX_MSG
+            )));
+        }
+        m[..4].copy_from_slice(&(len as u32).to_be_bytes()[..]);
         serializer.serialize_bytes(&m)
     }
 }
@@ -90,3 +335,85 @@ impl<'de> Deserialize<'de> for PSBT {
         d.deserialize_bytes(SafePSBT(MAX_MSG))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn kind(r: Result<Incoming, std::io::Error>) -> std::io::ErrorKind {
+        match r {
+            Ok(_) => panic!("decoded an invalid frame"),
+            Err(e) => e.kind(),
+        }
+    }
+    #[test]
+    fn test_decode_request() {
+        let frame =
+            encode_frame(&Envelope::new(&Request::Attest(Sha256::hash(b"m"))).unwrap()).unwrap();
+        assert!(matches!(
+            decode_request(&frame).unwrap(),
+            Incoming::Envelope(e) if e.method == "attest"
+        ));
+        // truncated, in the prefix or in the payload
+        for l in [0, 3, 4, frame.len() - 1] {
+            assert_eq!(
+                kind(decode_request(&frame[..l])),
+                std::io::ErrorKind::UnexpectedEof
+            );
+        }
+        // trailing bytes
+        let mut long = frame.clone();
+        long.push(b' ');
+        assert!(decode_request(&long).is_err());
+        // oversized: rejected from the prefix alone, before any payload
+        let oversized = ((MAX_FRAME + 1) as u32).to_be_bytes();
+        assert_eq!(
+            kind(decode_request(&oversized)),
+            std::io::ErrorKind::InvalidData
+        );
+        // garbage payloads
+        for payload in [
+            &b"{"[..],
+            b"null",
+            b"{\"SignPSBT\":[0,0,0,9,1]}",
+            &[0xff; 16],
+        ] {
+            let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+            frame.extend_from_slice(payload);
+            assert!(decode_request(&frame).is_err());
+        }
+    }
+
+    #[test]
+    fn test_oversized_psbt_refused_locally() {
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default()],
+            output: vec![bitcoin::TxOut {
+                value: 0,
+                script_pubkey: bitcoin::Script::from(vec![0u8; MAX_MSG]),
+            }],
+        };
+        let psbt = PSBT(PartiallySignedTransaction::from_unsigned_tx(tx).unwrap());
+        let e = Envelope::new(&Request::SignPSBT(psbt.clone()))
+            .err()
+            .map(std::io::Error::from)
+            .unwrap();
+        assert!(e.to_string().contains("exceeds maximum"));
+        // a server can't send one either
+        let e = encode_frame(&Response::Result(psbt)).unwrap_err();
+        assert!(e.to_string().contains("exceeds maximum"));
+    }
+
+    #[test]
+    fn test_frame_limit() {
+        let r = Response::Result(Sha256::hash(b"m"));
+        let frame = encode_frame(&r).unwrap();
+        let len = frame.len() - 4;
+        assert_eq!(encode_frame_within(&r, len).unwrap(), frame);
+        let e = encode_frame_within(&r, len - 1).unwrap_err();
+        assert!(e.to_string().contains("exceeds maximum"));
+        assert_eq!(check_frame_len_within(len as u32, len).unwrap(), len);
+        assert!(check_frame_len_within(len as u32 + 1, len).is_err());
+    }
+}
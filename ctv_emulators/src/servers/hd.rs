@@ -5,28 +5,281 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! definitions for oracle servers
+use super::secret::Zeroizing;
 use super::*;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::secp256k1::Parity;
 use bitcoin::util::sighash::Prevouts;
+use bitcoin::util::sighash::SchnorrSighashType;
 use bitcoin::util::taproot::TapLeafHash;
 use bitcoin::util::taproot::TapSighashHash;
+use bitcoin::EcdsaSig;
+use bitcoin::EcdsaSighashType;
 use bitcoin::SchnorrSig;
 use bitcoin::Script;
 use bitcoin::TxOut;
 use bitcoin::XOnlyPublicKey;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::Semaphore;
 
 /// hierarchical deterministic oracle emulator
 #[derive(Clone)]
 pub struct HDOracleEmulator {
-    root: ExtendedPrivKey,
+    roots: Vec<Zeroizing<ExtendedPrivKey>>,
     debug: bool,
+    idle_timeout: Option<Duration>,
+    allow_path_override: bool,
+    low_r: bool,
+    socket_options: SocketOptions,
+    max_frame: usize,
+    ctv_hash: Option<sapio_base::CTVHashFn>,
+    challenges: Arc<Mutex<ChallengeCache>>,
+    derivations: Arc<Mutex<DerivationCache>>,
+    signers: Arc<Semaphore>,
 }
 
+/// The number of key confirmation challenges an [`HDOracleEmulator`]
+/// remembers by default, see [`HDOracleEmulator::with_challenge_cache`].
+pub const DEFAULT_CHALLENGE_CACHE: usize = 10_000;
+
+/// The number of PSBTs an [`HDOracleEmulator`] signs at once by default (the
+/// available parallelism), see [`HDOracleEmulator::with_signing_threads`].
+pub fn default_signing_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// The most recently answered key confirmation challenges, oldest first.
+struct ChallengeCache {
+    seen: HashSet<Sha256>,
+    order: VecDeque<Sha256>,
+    capacity: usize,
+}
+
+impl ChallengeCache {
+    fn new(capacity: usize) -> Self {
+        ChallengeCache {
+            seen: Default::default(),
+            order: Default::default(),
+            capacity,
+        }
+    }
+    /// record `h`, returning false if it is already recorded
+    fn insert(&mut self, h: Sha256) -> bool {
+        if !self.seen.insert(h) {
+            return false;
+        }
+        self.order.push_back(h);
+        while self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+/// The number of derived keys an [`HDOracleEmulator`] caches by default, see
+/// [`HDOracleEmulator::with_derivation_cache`].
+pub const DEFAULT_DERIVATION_CACHE: usize = 1_000;
+
+/// The keys derived (from every root) for the most recently used derivation
+/// paths, evicting the least recently used.
+///
+/// Entries are keyed by the full derivation path rather than the hash it came
+/// from, so keys derived for different purposes (e.g. templates and
+/// attestations) can never be confused for one another.
+///
+/// Each use of an entry tags it with a new generation and queues it again,
+/// leaving its older queue entries stale (to be skipped on eviction), so
+/// marking an entry as used is O(1).
+struct DerivationCache {
+    keys: HashMap<Vec<ChildNumber>, (u64, DerivedKeys)>,
+    order: VecDeque<(u64, Vec<ChildNumber>)>,
+    generation: u64,
+    capacity: usize,
+    hits: usize,
+}
+
+impl DerivationCache {
+    fn new(capacity: usize) -> Self {
+        DerivationCache {
+            keys: Default::default(),
+            order: Default::default(),
+            generation: 0,
+            capacity,
+            hits: 0,
+        }
+    }
+    /// get the keys for `path`, marking them as recently used
+    fn get(&mut self, path: &[ChildNumber]) -> Option<DerivedKeys> {
+        let generation = self.generation;
+        let entry = self.keys.get_mut(path)?;
+        entry.0 = generation;
+        let keys = entry.1.clone();
+        self.generation += 1;
+        self.order.push_back((generation, path.to_vec()));
+        self.hits += 1;
+        self.compact();
+        Some(keys)
+    }
+    /// cache the keys for `path`, evicting the least recently used entries
+    /// to stay within capacity
+    fn insert(&mut self, path: Vec<ChildNumber>, keys: DerivedKeys) {
+        if self.capacity == 0 || self.keys.contains_key(&path) {
+            return;
+        }
+        let generation = self.generation;
+        self.generation += 1;
+        self.order.push_back((generation, path.clone()));
+        self.keys.insert(path, (generation, keys));
+        while self.keys.len() > self.capacity {
+            self.evict();
+        }
+    }
+    /// is the queue entry for `path` at `generation` its most recent use?
+    fn is_live(&self, generation: u64, path: &[ChildNumber]) -> bool {
+        self.keys.get(path).map(|e| e.0) == Some(generation)
+    }
+    /// drop the stale queue entries once they outnumber the live ones, so
+    /// the queue stays within twice the capacity
+    fn compact(&mut self) {
+        if self.order.len() > 2 * self.keys.len().max(1) {
+            let order = std::mem::take(&mut self.order);
+            self.order = order
+                .into_iter()
+                .filter(|(g, p)| self.is_live(*g, p))
+                .collect();
+        }
+    }
+    /// remove the least recently used entry (its keys are wiped once no
+    /// longer in use), returning whether there was one
+    fn evict(&mut self) -> bool {
+        while let Some((generation, path)) = self.order.pop_front() {
+            if self.is_live(generation, &path) {
+                return self.keys.remove(&path).is_some();
+            }
+        }
+        false
+    }
+}
+
+/// the keys derived from every root, wiped when dropped
+type DerivedKeys = Vec<Zeroizing<ExtendedPrivKey>>;
+
 impl HDOracleEmulator {
     /// create a new HDOracleEmulator
     ///
     /// if debug is set, runs in a "single threaded" mode where we can observe errors on connections rather than ignoring them.
     pub fn new(root: ExtendedPrivKey, debug: bool) -> Self {
-        HDOracleEmulator { root, debug }
+        Self::with_roots(vec![root], debug)
+    }
+    /// create a new HDOracleEmulator which holds several independent roots
+    /// (e.g., separate hot and cold keys).
+    ///
+    /// Every root must sign for a CTV hash, so the oracle's Clause is the
+    /// n-of-n of the keys derived from each root, and PSBTs are signed with
+    /// every root which can sign them. The first root is used for
+    /// attestations.
+    ///
+    /// Panics if `roots` is empty.
+    pub fn with_roots(roots: Vec<ExtendedPrivKey>, debug: bool) -> Self {
+        assert!(!roots.is_empty(), "HDOracleEmulator requires a root");
+        HDOracleEmulator {
+            roots: roots.into_iter().map(Zeroizing::new).collect(),
+            debug,
+            idle_timeout: None,
+            allow_path_override: false,
+            low_r: false,
+            socket_options: Default::default(),
+            max_frame: msgs::MAX_FRAME,
+            ctv_hash: None,
+            challenges: Arc::new(Mutex::new(ChallengeCache::new(DEFAULT_CHALLENGE_CACHE))),
+            derivations: Arc::new(Mutex::new(DerivationCache::new(DEFAULT_DERIVATION_CACHE))),
+            signers: Arc::new(Semaphore::new(default_signing_threads())),
+        }
+    }
+    /// close connections which do not send a request within `timeout`.
+    ///
+    /// The timer restarts after every request, so clients which are actively
+    /// making requests are unaffected. It only runs until the first byte of a
+    /// request arrives, so a slow upload of a large request is not cut off.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+    /// honor path overrides set with [`crate::set_path_override`].
+    ///
+    /// Overrides are derived under the hardened `m/PATH_OVERRIDE_INDEX'`
+    /// subtree, so they can never produce a CTV or attestation key, but they
+    /// do let any client get a signature on any transaction for keys in that
+    /// subtree. Only enable for testing or for trusted clients.
+    pub fn with_path_overrides(mut self, allow: bool) -> Self {
+        self.allow_path_override = allow;
+        self
+    }
+    /// grind the nonces of ECDSA signatures (for Segwit v0 inputs) until R
+    /// is low, so every signature is at most 71 bytes (with its sighash
+    /// byte) and the weight of a spend can be known in advance.
+    ///
+    /// Schnorr signatures are always 65 bytes, so are unaffected.
+    pub fn with_low_r(mut self, low_r: bool) -> Self {
+        self.low_r = low_r;
+        self
+    }
+    /// remember the last `capacity` key confirmation challenges answered
+    /// (rather than [`DEFAULT_CHALLENGE_CACHE`]), refusing to answer them
+    /// again.
+    pub fn with_challenge_cache(mut self, capacity: usize) -> Self {
+        self.challenges = Arc::new(Mutex::new(ChallengeCache::new(capacity)));
+        self
+    }
+    /// cache the keys derived for the last `capacity` CTV hashes signed for
+    /// (rather than [`DEFAULT_DERIVATION_CACHE`]), so repeated hashes skip
+    /// the derivation. 0 disables the cache.
+    ///
+    /// Evicted keys are wiped before they are freed.
+    pub fn with_derivation_cache(mut self, capacity: usize) -> Self {
+        self.derivations = Arc::new(Mutex::new(DerivationCache::new(capacity)));
+        self
+    }
+    /// sign at most `n` PSBTs at once (rather than
+    /// [`default_signing_threads`]), across all connections.
+    ///
+    /// Signing is CPU-bound, so it runs on tokio's blocking pool rather than
+    /// the reactor, and further requests wait for a signing slot to free up.
+    ///
+    /// Panics if `n` is 0.
+    pub fn with_signing_threads(mut self, n: usize) -> Self {
+        assert!(n > 0, "HDOracleEmulator requires a signing thread");
+        self.signers = Arc::new(Semaphore::new(n));
+        self
+    }
+    /// use `options` for the listening socket and accepted connections.
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+    /// refuse to read or write frames longer than `max` bytes (rather than
+    /// [`msgs::MAX_FRAME`]), e.g. to bound the memory used per connection.
+    ///
+    /// Clients must be configured with a limit no larger than this (see
+    /// `HDOracleEmulatorConnection::with_max_frame`), or their larger requests
+    /// are refused by the oracle rather than before being sent.
+    pub fn with_max_frame(mut self, max: usize) -> Self {
+        self.max_frame = max;
+        self
+    }
+    /// use `f` in place of BIP-119 to compute the CTV hash of the
+    /// transactions it signs, which must match the function the contracts
+    /// were compiled with (see `Context::ctv_hash_fn`).
+    pub fn with_ctv_hash(mut self, f: sapio_base::CTVHashFn) -> Self {
+        self.ctv_hash = Some(f);
+        self
     }
     /// binds a HDOracleEmulator to a socket interface and runs the server
     ///
@@ -34,122 +287,1735 @@ impl HDOracleEmulator {
     /// When debug = true, then we join each connection one at a time and return
     /// any errors.
     pub async fn bind<A: ToSocketAddrs>(self, a: A) -> std::io::Result<()> {
-        let listener = TcpListener::bind(a).await?;
+        let listener = self.socket_options.listen(a).await?;
+        self.bind_listener(listener).await
+    }
+    /// runs the server on an already bound `listener`, e.g. one bound to port
+    /// 0 so that the OS picks a free port. Otherwise the same as
+    /// [`HDOracleEmulator::bind`].
+    pub async fn bind_listener(self, listener: TcpListener) -> std::io::Result<()> {
         loop {
-            let (mut socket, _) = listener.accept().await?;
-            {
-                let this = self.clone();
-                let j: tokio::task::JoinHandle<Result<(), std::io::Error>> =
-                    tokio::spawn(async move {
-                        loop {
-                            socket.readable().await?;
-                            this.handle(&mut socket).await?;
-                        }
-                    });
-                if self.debug {
-                    tokio::join!(j).0??;
+            let (socket, _) = listener.accept().await?;
+            self.socket_options.apply(&socket)?;
+            let j = self.spawn(socket);
+            if self.debug {
+                j.await??;
+            }
+        }
+    }
+    /// binds a HDOracleEmulator to a Unix domain socket at `path` and runs the
+    /// server, for clients on the same host.
+    ///
+    /// `path` must not already exist. Otherwise the same as
+    /// [`HDOracleEmulator::bind`].
+    #[cfg(unix)]
+    pub async fn bind_unix<P: AsRef<std::path::Path>>(self, path: P) -> std::io::Result<()> {
+        let listener = tokio::net::UnixListener::bind(path)?;
+        loop {
+            let (socket, _) = listener.accept().await?;
+            let j = self.spawn(socket);
+            if self.debug {
+                j.await??;
+            }
+        }
+    }
+    /// serve a newly accepted connection on its own task
+    fn spawn<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        &self,
+        socket: S,
+    ) -> tokio::task::JoinHandle<Result<(), std::io::Error>> {
+        let this = self.clone();
+        tokio::spawn(async move { this.serve(socket).await })
+    }
+    /// handle requests on a connection until it is closed (or idles out).
+    ///
+    /// Reads are buffered, so a client may pipeline many requests and they
+    /// are processed back-to-back, each answered in the order received.
+    /// The client closing the connection between requests is a clean close.
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        socket: S,
+    ) -> Result<(), std::io::Error> {
+        let mut socket = tokio::io::BufStream::new(socket);
+        loop {
+            // only waiting for the start of a request counts as idle, so a
+            // slow upload of a large request is not cut off
+            let waiting = async { Ok::<_, std::io::Error>(socket.fill_buf().await?.is_empty()) };
+            let closed = match self.idle_timeout {
+                Some(t) => match tokio::time::timeout(t, waiting).await {
+                    // timing out is a clean close, not an error
+                    Err(_) => return Ok(()),
+                    Ok(closed) => closed?,
+                },
+                None => waiting.await?,
+            };
+            if closed {
+                return Ok(());
+            }
+            match self.requested(&mut socket).await? {
+                Ok(incoming) => self.handle(&mut socket, incoming).await?,
+                // the frame was read whole, so the connection can carry on
+                Err(e) => {
+                    self.respond(&mut socket, &msgs::Response::<()>::Error(e))
+                        .await?
                 }
             }
         }
     }
-    /// helper to get an EPK for the oracle.
-    fn derive(&self, h: Sha256, secp: &Secp256k1<All>) -> Result<ExtendedPrivKey, Error> {
-        let c = hash_to_child_vec(h);
-        self.root.derive_priv(secp, &c)
+    /// helper to get an EPK for the oracle from each root, see
+    /// [`HDOracleEmulator::with_derivation_cache`].
+    fn derive(&self, h: Sha256, secp: &Secp256k1<All>) -> Result<DerivedKeys, Error> {
+        let c = hash_to_child_vec(h)?;
+        let cached = self
+            .derivations
+            .lock()
+            .expect("Derivation Cache Poisoned")
+            .get(&c);
+        if let Some(keys) = cached {
+            return Ok(keys);
+        }
+        let keys = self
+            .roots
+            .iter()
+            .map(|r| r.derive_priv(secp, &c).map(Zeroizing::new))
+            .collect::<Result<DerivedKeys, _>>()?;
+        self.derivations
+            .lock()
+            .expect("Derivation Cache Poisoned")
+            .insert(c, keys.clone());
+        Ok(keys)
+    }
+
+    /// helper to get the EPK for a path override.
+    fn derive_override(
+        &self,
+        path: &[ChildNumber],
+        secp: &Secp256k1<All>,
+    ) -> Result<DerivedKeys, Error> {
+        let mut c = vec![ChildNumber::from_hardened_idx(PATH_OVERRIDE_INDEX)?];
+        c.extend_from_slice(path);
+        self.roots
+            .iter()
+            .map(|r| r.derive_priv(secp, &c).map(Zeroizing::new))
+            .collect()
+    }
+
+    /// the CTV hash of `tx` for `input_index`, see
+    /// [`HDOracleEmulator::with_ctv_hash`].
+    fn ctv_hash(&self, tx: &bitcoin::Transaction, input_index: u32) -> Sha256 {
+        match &self.ctv_hash {
+            Some(f) => f(tx, input_index),
+            None => tx.get_ctv_hash(input_index),
+        }
     }
 
     /// Signs a PSBT with the correct derived key.
     ///
-    /// Always signs for spending index 0.
+    /// Each input is signed with the key for the CTV hash of the transaction
+    /// at that input's index, as that is what a contract spent by that input
+    /// commits to, derived from each of the oracle's roots. Inputs which
+    /// don't pay to a key (or have no leaf containing it) are left unsigned
+    /// by that key.
+    ///
+    /// Signing fails if a leaf containing a key isn't committed to by the
+    /// output being spent (checked with the leaf's control block), so the
+    /// oracle can't be tricked into signing for a script which the contract
+    /// never contained.
+    ///
+    /// Inputs with a `witness_script` containing the key (with even parity,
+    /// as Segwit v0 contracts are compiled) are signed with ECDSA, if the
+    /// output being spent is the P2WSH (or P2SH wrapped P2WSH) of that
+    /// script. See [`HDOracleEmulator::with_low_r`] to bound their size.
+    ///
+    /// Inputs which are already finalized (have a `final_script_witness` or
+    /// `final_script_sig`) are skipped and returned as they are, so a client
+    /// can merge the result back into its PSBT.
     ///
     /// May fail to sign if the PSBT is not properly formatted
     fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+        secp: &Secp256k1<All>,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        self.sign_inner(b, secp, false)
+    }
+
+    /// Signs a PSBT with the correct derived key, but only adds signatures
+    /// which are not already present in the PSBT.
+    ///
+    /// Repeated calls are idempotent, which makes it cheap to call when
+    /// merging partially signed PSBTs from a federation.
+    pub fn sign_missing(
+        &self,
+        b: PartiallySignedTransaction,
+        secp: &Secp256k1<All>,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        self.sign_inner(b, secp, true)
+    }
+
+    fn sign_inner(
         &self,
         mut b: PartiallySignedTransaction,
         secp: &Secp256k1<All>,
+        only_missing: bool,
     ) -> Result<PartiallySignedTransaction, std::io::Error> {
         let tx = b.clone().extract_tx();
-        let h = tx.get_ctv_hash(0);
         let utxos: Vec<TxOut> = b
             .inputs
             .iter()
             .map(|o| o.witness_utxo.clone())
             .collect::<Option<Vec<TxOut>>>()
             .ok_or_else(|| input_err("Could not find one of the UTXOs to be signed over"))?;
-        let key = self
-            .derive(h, secp)
-            .map_err(|_| input_err("Could Not Derive Key"))?;
-        let untweaked = key.to_keypair(secp);
-        let pk = XOnlyPublicKey::from_keypair(&untweaked);
-        let mut sighash = bitcoin::util::sighash::SighashCache::new(&tx);
-        let input_zero = &mut b.inputs[0];
-        use bitcoin::schnorr::TapTweak;
-        let tweaked = untweaked
-            .tap_tweak(secp, input_zero.tap_merkle_root)
-            .into_inner();
-        let tweaked_pk = tweaked.public_key();
-        let hash_ty = bitcoin::util::sighash::SchnorrSighashType::All;
+        let first = b
+            .inputs
+            .first()
+            .ok_or_else(|| input_err("No Inputs To Sign"))?;
+        let path_override = get_path_override(first)?;
+        if path_override.is_some() && !self.allow_path_override {
+            return Err(input_err("Path Overrides Not Allowed"));
+        }
         let prevouts = &Prevouts::All(&utxos);
-        let mut get_sig = |path, kp| {
-            let annex = None;
-            let sighash: TapSighashHash = sighash
-                .taproot_signature_hash(0, prevouts, annex, path, hash_ty)
-                .expect("Signature hash cannot fail...");
-            let msg = bitcoin::secp256k1::Message::from_slice(&sighash[..])
-                .expect("Size must be correct.");
-            let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
-            SchnorrSig { sig, hash_ty }
-        };
-        if let Some(true) = input_zero.witness_utxo.as_ref().map(|v| {
-            v.script_pubkey
-                == Script::new_v1_p2tr_tweaked(
-                    XOnlyPublicKey::from(tweaked_pk).dangerous_assume_tweaked(),
-                )
-        }) {
-            let sig = get_sig(None, &tweaked);
-            input_zero.tap_key_sig = Some(sig);
-        }
-        for tlh in input_zero
-            .tap_scripts
-            .values()
-            .map(|(script, ver)| TapLeafHash::from_script(script, *ver))
-        {
-            let sig = get_sig(Some((tlh, 0xffffffff)), &untweaked);
-            input_zero.tap_script_sigs.insert((pk.0, tlh), sig);
+        let mut sighash = bitcoin::util::sighash::SighashCache::new(&tx);
+        for (i, inp) in b.inputs.iter_mut().enumerate() {
+            if inp.final_script_witness.is_some() || inp.final_script_sig.is_some() {
+                continue;
+            }
+            let keys = match &path_override {
+                Some(path) if i == 0 => self.derive_override(path, secp),
+                _ => self.derive(self.ctv_hash(&tx, i as u32), secp),
+            }
+            .map_err(DerivationFailed)?;
+            let hash_ty = sighash_type(inp)?;
+            // the Taproot output key of the output being spent, if it is one
+            let output_key = inp
+                .witness_utxo
+                .as_ref()
+                .filter(|u| u.script_pubkey.is_v1_p2tr())
+                .and_then(|u| XOnlyPublicKey::from_slice(&u.script_pubkey.as_bytes()[2..]).ok());
+            for key in keys {
+                let untweaked = Zeroizing::new(key.to_keypair(secp));
+                let pk = XOnlyPublicKey::from_keypair(&untweaked);
+                use bitcoin::schnorr::TapTweak;
+                let tweaked =
+                    Zeroizing::new(untweaked.tap_tweak(secp, inp.tap_merkle_root).into_inner());
+                let tweaked_pk = tweaked.public_key();
+                let mut get_sig = |path, kp| {
+                    let annex = None;
+                    let sighash: TapSighashHash = sighash
+                        .taproot_signature_hash(i, prevouts, annex, path, hash_ty)
+                        .expect("Signature hash cannot fail...");
+                    let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..])
+                        .expect("Size must be correct.");
+                    let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
+                    SchnorrSig { sig, hash_ty }
+                };
+                if let Some(true) = inp.witness_utxo.as_ref().map(|v| {
+                    v.script_pubkey
+                        == Script::new_v1_p2tr_tweaked(
+                            XOnlyPublicKey::from(tweaked_pk).dangerous_assume_tweaked(),
+                        )
+                }) {
+                    if !(only_missing && inp.tap_key_sig.is_some()) {
+                        let sig = get_sig(None, &*tweaked);
+                        inp.tap_key_sig = Some(sig);
+                    }
+                }
+                let pk_bytes = pk.0.serialize();
+                let mut tlhs = vec![];
+                for (control, (script, ver)) in &inp.tap_scripts {
+                    let has_key = script.instructions().any(
+                        |ins| matches!(ins, Ok(Instruction::PushBytes(b)) if b == &pk_bytes[..]),
+                    );
+                    if !has_key {
+                        continue;
+                    }
+                    // a leaf the spent output doesn't commit to could be any
+                    // script at all, so refuse rather than sign for it
+                    if !output_key
+                        .is_some_and(|k| control.verify_taproot_commitment(secp, k, script))
+                    {
+                        return Err(input_err("Leaf Not Committed To By Spent Output"));
+                    }
+                    let tlh = TapLeafHash::from_script(script, *ver);
+                    if !(only_missing && inp.tap_script_sigs.contains_key(&(pk.0, tlh))) {
+                        tlhs.push(tlh);
+                    }
+                }
+                for tlh in tlhs {
+                    let sig = get_sig(Some((tlh, 0xffffffff)), &*untweaked);
+                    inp.tap_script_sigs.insert((pk.0, tlh), sig);
+                }
+                // Segwit v0 scripts contain the key with even parity, so sign
+                // with the secret key for that point
+                let mut even = [2u8; 33];
+                even[1..].copy_from_slice(&pk_bytes);
+                let ws = match &inp.witness_script {
+                    Some(ws)
+                        if ws.instructions().any(
+                            |ins| matches!(ins, Ok(Instruction::PushBytes(b)) if b == &even[..]),
+                        ) =>
+                    {
+                        ws
+                    }
+                    _ => continue,
+                };
+                let wsh = ws.to_v0_p2wsh();
+                let spent = inp.witness_utxo.as_ref().map(|u| &u.script_pubkey);
+                if spent != Some(&wsh) && spent != Some(&Script::new_p2sh(&wsh.script_hash())) {
+                    return Err(input_err("Witness Script Not Committed To By Spent Output"));
+                }
+                let even_pk = bitcoin::PublicKey::from_slice(&even).expect("Valid x-only key");
+                if only_missing && inp.partial_sigs.contains_key(&even_pk) {
+                    continue;
+                }
+                let sk = Zeroizing::new(match pk.1 {
+                    Parity::Even => key.private_key,
+                    Parity::Odd => key.private_key.negate(),
+                });
+                let hash_ty = EcdsaSighashType::from_standard(hash_ty as u32)
+                    .expect("All and AllPlusAnyoneCanPay are standard");
+                let value = inp.witness_utxo.as_ref().map_or(0, |u| u.value);
+                let digest = sighash
+                    .segwit_signature_hash(i, ws, value, hash_ty)
+                    .expect("Signature hash cannot fail...");
+                let msg = bitcoin::secp256k1::Message::from_digest_slice(&digest[..])
+                    .expect("Size must be correct.");
+                let sig = if self.low_r {
+                    secp.sign_ecdsa_low_r(&msg, &sk)
+                } else {
+                    secp.sign_ecdsa(&msg, &sk)
+                };
+                inp.partial_sigs.insert(even_pk, EcdsaSig { sig, hash_ty });
+            }
         }
         Ok(b)
     }
 
+    /// Attest to the message hash `m` by signing it with the attestation key
+    /// for `m`.
+    ///
+    /// This is separate from (and never signs for) any PSBT.
+    pub fn attest(
+        &self,
+        m: Sha256,
+        secp: &Secp256k1<All>,
+    ) -> Result<bitcoin::secp256k1::schnorr::Signature, std::io::Error> {
+        let key = attestation_child_vec(m)
+            .and_then(|c| self.roots[0].derive_priv(secp, &c))
+            .map(Zeroizing::new)
+            .map_err(DerivationFailed)?;
+        let msg =
+            bitcoin::secp256k1::Message::from_digest_slice(&m[..]).expect("Size must be correct.");
+        let kp = Zeroizing::new(key.to_keypair(secp));
+        Ok(secp.sign_schnorr_no_aux_rand(&msg, &kp))
+    }
+
+    /// Sign a receipt for returning the (signed) `psbt`, with the root key.
+    ///
+    /// The receipt commits to the serialized PSBT, so a client can prove
+    /// which response the oracle gave it.
+    pub fn sign_receipt(
+        &self,
+        psbt: &PartiallySignedTransaction,
+        secp: &Secp256k1<All>,
+    ) -> bitcoin::secp256k1::schnorr::Signature {
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&receipt_message(psbt)[..])
+            .expect("Size must be correct.");
+        let kp = Zeroizing::new(self.roots[0].to_keypair(secp));
+        secp.sign_schnorr_no_aux_rand(&msg, &kp)
+    }
+
+    /// Answer the key confirmation challenge `h` by attesting to its
+    /// confirmation message.
+    ///
+    /// Each challenge is only answered once (among the recently answered
+    /// ones, see [`HDOracleEmulator::with_challenge_cache`]), so a
+    /// confirmation can't be obtained for a replayed challenge.
+    pub fn confirm_key(
+        &self,
+        h: Sha256,
+        secp: &Secp256k1<All>,
+    ) -> Result<bitcoin::secp256k1::schnorr::Signature, msgs::ProtocolError> {
+        let fresh = self
+            .challenges
+            .lock()
+            .expect("Challenge Cache Poisoned")
+            .insert(h);
+        if !fresh {
+            return Err(msgs::ProtocolError::ReplayedChallenge(h));
+        }
+        self.attest(confirmation_message(h), secp)
+            .map_err(|e| msgs::ProtocolError::InvalidParams(e.to_string()))
+    }
+
     /// the main server business logic.
     ///
     /// - on receiving Request::SignPSBT, signs the PSBT.
-    async fn handle(&self, t: &mut TcpStream) -> Result<(), std::io::Error> {
-        let request = Self::requested(t).await?;
+    /// - on receiving Request::SignPSBTWithReceipt, signs the PSBT and a
+    ///   receipt for it.
+    /// - on receiving Request::Attest, signs the attested message hash.
+    /// - on receiving Request::ConfirmKey, answers the challenge once.
+    ///
+    /// Requests in an [`msgs::Envelope`] are answered with a
+    /// [`msgs::Response`], so unknown methods or versions get a structured
+    /// error. Legacy flat requests are answered with the bare result.
+    async fn handle<S: AsyncWrite + Unpin>(
+        &self,
+        t: &mut S,
+        incoming: msgs::Incoming,
+    ) -> Result<(), std::io::Error> {
+        let (request, legacy) = match incoming {
+            msgs::Incoming::Envelope(envelope) => match envelope.into_request() {
+                Ok(request) => (request, false),
+                Err(e) => return self.respond(t, &msgs::Response::<()>::Error(e)).await,
+            },
+            msgs::Incoming::Legacy(request) => (request, true),
+        };
         match request {
             msgs::Request::SignPSBT(msgs::PSBT(unsigned)) => {
-                let psbt = SECP.with(|secp| self.sign(unsigned, secp))?;
-                Self::respond(t, &msgs::PSBT(psbt)).await
+                let psbt = self.sign_blocking(unsigned).await?;
+                self.reply(t, legacy, msgs::PSBT(psbt)).await
+            }
+            msgs::Request::SignPSBTWithReceipt(msgs::PSBT(unsigned)) => {
+                let psbt = self.sign_blocking(unsigned).await?;
+                let receipt = SECP.with(|secp| self.sign_receipt(&psbt, secp));
+                self.reply(
+                    t,
+                    legacy,
+                    msgs::Receipted {
+                        psbt: msgs::PSBT(psbt),
+                        receipt,
+                    },
+                )
+                .await
+            }
+            msgs::Request::Attest(m) => {
+                let sig = SECP.with(|secp| self.attest(m, secp))?;
+                self.reply(t, legacy, sig).await
             }
+            msgs::Request::ConfirmKey(h) => match SECP.with(|secp| self.confirm_key(h, secp)) {
+                Ok(sig) => self.reply(t, legacy, sig).await,
+                Err(e) if legacy => input_error(&e.to_string()),
+                Err(e) => self.respond(t, &msgs::Response::<()>::Error(e)).await,
+            },
+        }
+    }
+
+    /// sign a PSBT on the blocking pool, once one of the signing slots (see
+    /// [`HDOracleEmulator::with_signing_threads`]) is free, so that signing
+    /// doesn't stall other connections.
+    async fn sign_blocking(
+        &self,
+        unsigned: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let permit = self
+            .signers
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Signing Slots Never Closed");
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            SECP.with(|secp| this.sign(unsigned, secp))
+        })
+        .await?
+    }
+
+    /// respond with a result, wrapped in a [`msgs::Response`] unless the
+    /// request was `legacy`.
+    async fn reply<S: AsyncWrite + Unpin, T: Serialize>(
+        &self,
+        t: &mut S,
+        legacy: bool,
+        r: T,
+    ) -> Result<(), std::io::Error> {
+        if legacy {
+            self.respond(t, &r).await
+        } else {
+            self.respond(t, &msgs::Response::Result(r)).await
         }
     }
 
-    /// receive a request via the tcpstream.
+    /// receive a request via the stream.
     /// wire format: length:u32 data:[u8;length]
     ///
-    /// TODO: DoS Critical: limit the allowed max length we will attempt to derserialize
-    async fn requested(t: &mut TcpStream) -> Result<msgs::Request, std::io::Error> {
-        let l = t.read_u32().await? as usize;
+    /// frames longer than the limit (see [`HDOracleEmulator::with_max_frame`])
+    /// are rejected before reading them.
+    /// Fails with [`ConnectionClosed`] if the client has closed the connection.
+    /// A whole frame which isn't a valid request is returned as a
+    /// [`msgs::ProtocolError::MalformedRequest`], as the next frame can still
+    /// be read.
+    async fn requested<S: AsyncRead + Unpin>(
+        &self,
+        t: &mut S,
+    ) -> Result<Result<msgs::Incoming, msgs::ProtocolError>, std::io::Error> {
+        let l = t.read_u32().await.map_err(ConnectionClosed::detect)?;
+        let l = msgs::check_frame_len_within(l, self.max_frame)?;
         let mut v = vec![0u8; l];
-        t.read_exact(&mut v[..]).await?;
-        Ok(serde_json::from_slice(&v[..])?)
+        t.read_exact(&mut v[..])
+            .await
+            .map_err(ConnectionClosed::detect)?;
+        Ok(msgs::decode_payload(&v[..])
+            .map_err(|e| msgs::ProtocolError::MalformedRequest(e.to_string())))
     }
 
-    /// respond via the tcpstream.
+    /// respond via the stream.
     /// wire format: length:u32 data:[u8;length]
-    async fn respond<T: Serialize>(t: &mut TcpStream, r: &T) -> Result<(), std::io::Error> {
-        let v = serde_json::to_vec(r)?;
-        t.write_u32(v.len() as u32).await?;
-        t.write_all(&v[..]).await?;
-        t.flush().await
+    ///
+    /// Fails with [`ConnectionClosed`] if the client has closed the connection.
+    async fn respond<S: AsyncWrite + Unpin, T: Serialize>(
+        &self,
+        t: &mut S,
+        r: &T,
+    ) -> Result<(), std::io::Error> {
+        let frame = msgs::encode_frame_within(r, self.max_frame)?;
+        async {
+            t.write_all(&frame).await?;
+            t.flush().await
+        }
+        .await
+        .map_err(ConnectionClosed::detect)
+    }
+}
+
+/// An oracle can also be used in process, e.g. for testing or when the
+/// oracle's operator is the only party to a contract.
+impl CTVEmulator for HDOracleEmulator {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        SECP.with(|secp| {
+            let mut keys: Vec<Clause> = self
+                .derive(h, secp)?
+                .iter()
+                .map(|k| Clause::Key(ExtendedPubKey::from_priv(secp, k).to_x_only_pub()))
+                .collect();
+            Ok(if keys.len() == 1 {
+                keys.remove(0)
+            } else {
+                Clause::Threshold(keys.len(), keys)
+            })
+        })
+    }
+    fn identity(&self) -> Option<String> {
+        SECP.with(|secp| {
+            let roots: Vec<String> = self
+                .roots
+                .iter()
+                .map(|k| ExtendedPubKey::from_priv(secp, k).to_string())
+                .collect();
+            Some(format!("hd {}", roots.join(" ")))
+        })
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        Ok(SECP.with(|secp| HDOracleEmulator::sign(self, b, secp))?)
+    }
+}
+
+/// The sighash type the oracle signs the input `inp` with, `All` unless the
+/// PSBT hints otherwise in `inp`'s own `sighash_type`, so each input may
+/// request its own.
+///
+/// A PSBT which anticipates inputs being added after signing (e.g., to bump
+/// the fee) may request `AllPlusAnyoneCanPay`, which still commits to every
+/// output of the template. Types which do not commit to all of the outputs
+/// (`None`, `Single`) would let the signature be reused for transactions
+/// other than the template, so they are refused.
+fn sighash_type(inp: &psbt::Input) -> Result<SchnorrSighashType, std::io::Error> {
+    match inp.schnorr_hash_ty() {
+        Ok(SchnorrSighashType::Default) | Ok(SchnorrSighashType::All) => {
+            Ok(SchnorrSighashType::All)
+        }
+        Ok(SchnorrSighashType::AllPlusAnyoneCanPay) => Ok(SchnorrSighashType::AllPlusAnyoneCanPay),
+        Ok(_) => Err(input_err("Sighash Type Does Not Commit To All Outputs")),
+        Err(_) => Err(input_err("Invalid Sighash Type")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::hd::HDOracleEmulatorConnection;
+    use crate::tests::signable_psbt;
+    use bitcoin::schnorr::TapTweak;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::util::sighash::Prevouts;
+    use miniscript::psbt::PsbtExt;
+    use sapio::contract::*;
+    use sapio::*;
+    use sapio_base::effects::EffectPath;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    fn oracle() -> HDOracleEmulator {
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[7u8; 32]).unwrap();
+        HDOracleEmulator::new(root, false)
+    }
+    /// run `oracle` on a free local port, returning the address it listens on
+    async fn serve(
+        oracle: HDOracleEmulator,
+    ) -> (SocketAddr, tokio::task::JoinHandle<std::io::Result<()>>) {
+        let listener = oracle.socket_options.listen("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (addr, tokio::spawn(oracle.bind_listener(listener)))
+    }
+    #[test]
+    fn test_sign_missing_is_idempotent() {
+        let oracle = oracle();
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..2)
+                .map(|vout| bitcoin::TxIn {
+                    previous_output: bitcoin::OutPoint {
+                        vout,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new_op_return(&[]),
+            }],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx.clone()).unwrap();
+        for (i, inp) in psbt.inputs.iter_mut().enumerate() {
+            let spk = SECP.with(|secp| {
+                let keys = oracle.derive(oracle.ctv_hash(&tx, i as u32), secp).unwrap();
+                let (xonly, _) = XOnlyPublicKey::from_keypair(&keys[0].to_keypair(secp));
+                Script::new_v1_p2tr(secp, xonly, None)
+            });
+            inp.witness_utxo = Some(TxOut {
+                value: 20_000,
+                script_pubkey: spk,
+            });
+        }
+        // input 0 already carries a signature the oracle would never make,
+        // input 1 is unsigned
+        let marker = SchnorrSig {
+            sig: bitcoin::secp256k1::schnorr::Signature::from_slice(&[1u8; 64]).unwrap(),
+            hash_ty: SchnorrSighashType::All,
+        };
+        psbt.inputs[0].tap_key_sig = Some(marker);
+        let once = SECP
+            .with(|secp| oracle.sign_missing(psbt.clone(), secp))
+            .unwrap();
+        assert_eq!(once.inputs[0], psbt.inputs[0]);
+        assert!(once.inputs[1].tap_key_sig.is_some());
+        assert_ne!(once.inputs[1], psbt.inputs[1]);
+        // whereas sign replaces the marker
+        let resigned = SECP.with(|secp| oracle.sign(psbt, secp)).unwrap();
+        assert_ne!(resigned.inputs[0].tap_key_sig, Some(marker));
+        let twice = SECP
+            .with(|secp| oracle.sign_missing(once.clone(), secp))
+            .unwrap();
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn test_path_override() {
+        let oracle = oracle();
+        let path = [
+            ChildNumber::from_normal_idx(1).unwrap(),
+            ChildNumber::from_hardened_idx(2).unwrap(),
+        ];
+        let mut psbt = signable_psbt(&oracle.roots[0]);
+        set_path_override(&mut psbt.inputs[0], &path);
+        assert!(SECP.with(|secp| oracle.sign(psbt.clone(), secp)).is_err());
+        let oracle = oracle.with_path_overrides(true);
+        let key = SECP.with(|secp| {
+            let mut c = vec![ChildNumber::from_hardened_idx(PATH_OVERRIDE_INDEX).unwrap()];
+            c.extend_from_slice(&path);
+            oracle.roots[0]
+                .derive_priv(secp, &c)
+                .unwrap()
+                .to_keypair(secp)
+        });
+        let (xonly, _) = XOnlyPublicKey::from_keypair(&key);
+        // pay to the override key so the oracle signs the key path
+        psbt.inputs[0].witness_utxo.as_mut().unwrap().script_pubkey =
+            SECP.with(|secp| Script::new_v1_p2tr(secp, xonly, None));
+        let signed = SECP.with(|secp| oracle.sign(psbt, secp)).unwrap();
+        let sig = signed.inputs[0].tap_key_sig.unwrap();
+        let utxos = [signed.inputs[0].witness_utxo.clone().unwrap()];
+        let tx = signed.extract_tx();
+        let sighash = bitcoin::util::sighash::SighashCache::new(&tx)
+            .taproot_key_spend_signature_hash(0, &Prevouts::All(&utxos), sig.hash_ty)
+            .unwrap();
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..]).unwrap();
+        SECP.with(|secp| {
+            let tweaked = xonly.tap_tweak(secp, None).0.to_inner();
+            secp.verify_schnorr(&sig.sig, &msg, &tweaked).unwrap()
+        });
+    }
+
+    #[test]
+    fn test_sign_no_inputs() {
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        })
+        .unwrap();
+        let err = SECP.with(|secp| oracle().sign(psbt, secp)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_sign_multiple_inputs() {
+        let oracle = oracle();
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default(), Default::default()],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new_op_return(&[]),
+            }],
+        };
+        assert_ne!(tx.get_ctv_hash(0), tx.get_ctv_hash(1));
+        let keys: Vec<_> = (0..2)
+            .map(|i| {
+                SECP.with(|secp| {
+                    let c = hash_to_child_vec(tx.get_ctv_hash(i)).unwrap();
+                    let kp = oracle.roots[0]
+                        .derive_priv(secp, &c)
+                        .unwrap()
+                        .to_keypair(secp);
+                    XOnlyPublicKey::from_keypair(&kp).0
+                })
+            })
+            .collect();
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        for (inp, key) in psbt.inputs.iter_mut().zip(keys.iter()) {
+            inp.witness_utxo = Some(TxOut {
+                value: 6_000,
+                script_pubkey: SECP.with(|secp| Script::new_v1_p2tr(secp, *key, None)),
+            });
+        }
+        let signed = SECP.with(|secp| oracle.sign(psbt, secp)).unwrap();
+        let utxos: Vec<TxOut> = signed
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.clone().unwrap())
+            .collect();
+        let tx = signed.clone().extract_tx();
+        let mut cache = bitcoin::util::sighash::SighashCache::new(&tx);
+        for (i, key) in keys.iter().enumerate() {
+            let sig = signed.inputs[i].tap_key_sig.unwrap();
+            let sighash = cache
+                .taproot_key_spend_signature_hash(i, &Prevouts::All(&utxos), sig.hash_ty)
+                .unwrap();
+            let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..]).unwrap();
+            SECP.with(|secp| {
+                let tweaked = key.tap_tweak(secp, None).0.to_inner();
+                secp.verify_schnorr(&sig.sig, &msg, &tweaked).unwrap()
+            });
+        }
+    }
+
+    /// a PSBT spending, by its only leaf `<key> CHECKSIG`, an output whose
+    /// internal key the oracle doesn't hold, so only the script path can be
+    /// signed. Returns the PSBT, the key, the leaf, and the internal key.
+    fn script_path_psbt(
+        oracle: &HDOracleEmulator,
+    ) -> (
+        PartiallySignedTransaction,
+        XOnlyPublicKey,
+        Script,
+        XOnlyPublicKey,
+    ) {
+        use bitcoin::blockdata::opcodes::all::OP_CHECKSIG;
+        use bitcoin::util::taproot::{LeafVersion, TaprootBuilder};
+        let mut psbt = signable_psbt(&oracle.roots[0]);
+        let (key, internal) = SECP.with(|secp| {
+            let c = hash_to_child_vec(psbt.unsigned_tx.get_ctv_hash(0)).unwrap();
+            let kp = oracle.roots[0]
+                .derive_priv(secp, &c)
+                .unwrap()
+                .to_keypair(secp);
+            let other = bitcoin::KeyPair::from_seckey_slice(secp, &[9u8; 32]).unwrap();
+            (
+                XOnlyPublicKey::from_keypair(&kp).0,
+                XOnlyPublicKey::from_keypair(&other).0,
+            )
+        });
+        let leaf = bitcoin::blockdata::script::Builder::new()
+            .push_slice(&key.serialize())
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let info = SECP.with(|secp| {
+            TaprootBuilder::new()
+                .add_leaf(0, leaf.clone())
+                .unwrap()
+                .finalize(secp, internal)
+                .unwrap()
+        });
+        let control = info
+            .control_block(&(leaf.clone(), LeafVersion::TapScript))
+            .unwrap();
+        let inp = &mut psbt.inputs[0];
+        inp.witness_utxo.as_mut().unwrap().script_pubkey =
+            SECP.with(|secp| Script::new_v1_p2tr(secp, internal, info.merkle_root()));
+        inp.tap_internal_key = Some(internal);
+        inp.tap_merkle_root = info.merkle_root();
+        inp.tap_scripts
+            .insert(control, (leaf.clone(), LeafVersion::TapScript));
+        (psbt, key, leaf, internal)
+    }
+
+    #[test]
+    fn test_sign_script_path() {
+        use bitcoin::util::taproot::LeafVersion;
+        let oracle = oracle();
+        let (psbt, key, leaf, _) = script_path_psbt(&oracle);
+        let signed = SECP.with(|secp| oracle.sign(psbt, secp)).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_none());
+        let tlh = TapLeafHash::from_script(&leaf, LeafVersion::TapScript);
+        let sig = signed.inputs[0].tap_script_sigs[&(key, tlh)];
+        let utxos = [signed.inputs[0].witness_utxo.clone().unwrap()];
+        let tx = signed.extract_tx();
+        let sighash = bitcoin::util::sighash::SighashCache::new(&tx)
+            .taproot_script_spend_signature_hash(0, &Prevouts::All(&utxos), tlh, sig.hash_ty)
+            .unwrap();
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..]).unwrap();
+        SECP.with(|secp| secp.verify_schnorr(&sig.sig, &msg, &key).unwrap());
+    }
+
+    #[test]
+    fn test_uncommitted_leaf_refused() {
+        let oracle = oracle();
+        let (mut psbt, _, _, internal) = script_path_psbt(&oracle);
+        // the spent output doesn't commit to the leaf holding the oracle's key
+        psbt.inputs[0].witness_utxo.as_mut().unwrap().script_pubkey =
+            SECP.with(|secp| Script::new_v1_p2tr(secp, internal, None));
+        assert!(SECP.with(|secp| oracle.sign(psbt.clone(), secp)).is_err());
+        // nor does a spent output which isn't Taproot at all
+        psbt.inputs[0].witness_utxo.as_mut().unwrap().script_pubkey =
+            Script::new_v0_p2wsh(&bitcoin::WScriptHash::hash(&[]));
+        assert!(SECP.with(|secp| oracle.sign(psbt, secp)).is_err());
+    }
+
+    #[test]
+    fn test_sighash_hint() {
+        let oracle = oracle();
+        let mut psbt = signable_psbt(&oracle.roots[0]);
+        psbt.inputs[0].sighash_type = Some(SchnorrSighashType::Single.into());
+        assert!(SECP.with(|secp| oracle.sign(psbt.clone(), secp)).is_err());
+        psbt.inputs[0].sighash_type = Some(SchnorrSighashType::AllPlusAnyoneCanPay.into());
+        let key = SECP.with(|secp| {
+            let c = hash_to_child_vec(psbt.unsigned_tx.get_ctv_hash(0)).unwrap();
+            oracle.roots[0]
+                .derive_priv(secp, &c)
+                .unwrap()
+                .to_keypair(secp)
+        });
+        let signed = SECP.with(|secp| oracle.sign(psbt, secp)).unwrap();
+        let sig = signed.inputs[0].tap_key_sig.unwrap();
+        assert_eq!(sig.hash_ty, SchnorrSighashType::AllPlusAnyoneCanPay);
+        let utxo = signed.inputs[0].witness_utxo.clone().unwrap();
+        // an input added to pay the fee doesn't invalidate the signature
+        let mut tx = signed.extract_tx();
+        tx.input.push(Default::default());
+        let sighash = bitcoin::util::sighash::SighashCache::new(&tx)
+            .taproot_key_spend_signature_hash(0, &Prevouts::One(0, &utxo), sig.hash_ty)
+            .unwrap();
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..]).unwrap();
+        SECP.with(|secp| {
+            let (xonly, _) = XOnlyPublicKey::from_keypair(&key);
+            let tweaked = xonly.tap_tweak(secp, None).0.to_inner();
+            secp.verify_schnorr(&sig.sig, &msg, &tweaked).unwrap()
+        });
+    }
+
+    #[test]
+    fn test_signature_size_is_fixed() {
+        for i in 0..16u8 {
+            let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[i; 32]).unwrap();
+            let oracle = HDOracleEmulator::new(root, false);
+            let psbt = signable_psbt(&oracle.roots[0]);
+            let signed = SECP.with(|secp| oracle.sign(psbt, secp)).unwrap();
+            let sig = signed.inputs[0].tap_key_sig.unwrap().to_vec();
+            // 64 byte schnorr signature + SIGHASH_ALL
+            assert_eq!(sig.len(), 65);
+        }
+    }
+
+    /// creates a PSBT spending a P2WSH output with a single key script,
+    /// which the oracle with key `root` is able to sign.
+    fn signable_wsh_psbt(root: &ExtendedPrivKey) -> PartiallySignedTransaction {
+        let mut psbt = signable_psbt(root);
+        let tx = psbt.clone().extract_tx();
+        let ws = SECP.with(|secp| {
+            let c = hash_to_child_vec(tx.get_ctv_hash(0)).unwrap();
+            let kp = root.derive_priv(secp, &c).unwrap().to_keypair(secp);
+            let mut even = [2u8; 33];
+            even[1..].copy_from_slice(&XOnlyPublicKey::from_keypair(&kp).0.serialize());
+            bitcoin::blockdata::script::Builder::new()
+                .push_slice(&even)
+                .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKSIG)
+                .into_script()
+        });
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 20_000,
+            script_pubkey: ws.to_v0_p2wsh(),
+        });
+        psbt.inputs[0].witness_script = Some(ws);
+        psbt
+    }
+
+    #[test]
+    fn test_low_r_signatures() {
+        let mut high_r = false;
+        for i in 0..16u8 {
+            let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[i; 32]).unwrap();
+            let psbt = signable_wsh_psbt(&root);
+            let tx = psbt.clone().extract_tx();
+            for low_r in [false, true] {
+                let oracle = HDOracleEmulator::new(root, false).with_low_r(low_r);
+                let signed = SECP.with(|secp| oracle.sign(psbt.clone(), secp)).unwrap();
+                assert_eq!(signed.inputs[0].partial_sigs.len(), 1);
+                let (pk, sig) = signed.inputs[0].partial_sigs.iter().next().unwrap();
+                let digest = bitcoin::util::sighash::SighashCache::new(&tx)
+                    .segwit_signature_hash(
+                        0,
+                        psbt.inputs[0].witness_script.as_ref().unwrap(),
+                        20_000,
+                        EcdsaSighashType::All,
+                    )
+                    .unwrap();
+                let msg = bitcoin::secp256k1::Message::from_digest_slice(&digest[..]).unwrap();
+                SECP.with(|secp| secp.verify_ecdsa(&msg, &sig.sig, &pk.inner))
+                    .unwrap();
+                let len = sig.to_vec().len();
+                if low_r {
+                    assert!(len <= 71);
+                } else {
+                    high_r |= len == 72;
+                }
+            }
+        }
+        // without grinding, about half of the signatures have a high R
+        assert!(high_r);
+    }
+
+    #[test]
+    fn test_uncommitted_witness_script() {
+        let oracle = oracle();
+        let mut psbt = signable_wsh_psbt(&oracle.roots[0]);
+        psbt.inputs[0].witness_utxo.as_mut().unwrap().script_pubkey = Script::new_op_return(&[]);
+        assert!(SECP.with(|secp| oracle.sign(psbt, secp)).is_err());
+    }
+
+    struct Payout;
+    impl Payout {
+        #[then]
+        fn pay(self, ctx: Context) {
+            ctx.template()
+                .add_output(
+                    Amount::from_sat(1000),
+                    &Compiled::from_op_return(&[1u8; 4][..])?,
+                    None,
+                )?
+                .into()
+        }
+    }
+    impl Contract for Payout {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_template_to_oracle_psbt() {
+        let oracle = oracle();
+        let rt = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let emulator = SECP.with(|secp| HDOracleEmulatorConnection {
+            handle: rt.handle().clone(),
+            runtime: Some(rt.clone()),
+            connection: Default::default(),
+            reconnect: "127.0.0.1:0".parse::<SocketAddr>().unwrap().into(),
+            root: ExtendedPubKey::from_priv(secp, &oracle.roots[0]),
+            secp: Arc::new(secp.clone()),
+            socket_options: Default::default(),
+            backoff: Default::default(),
+            max_frame: crate::msgs::MAX_FRAME,
+            entropy: Arc::new(crate::connections::hd::ThreadRngEntropy),
+        });
+        let compiled = Payout
+            .compile(Context::new(
+                bitcoin::Network::Regtest,
+                Amount::from_sat(10_000),
+                Arc::new(emulator),
+                EffectPath::try_from("oracle_psbt").unwrap(),
+                Arc::new(Default::default()),
+            ))
+            .unwrap();
+        let template = compiled.ctv_to_tx.values().next().unwrap();
+        let psbt = template.to_oracle_psbt();
+        let mut signed = SECP.with(|secp| oracle.sign(psbt, secp)).unwrap();
+        assert_eq!(signed.inputs[0].tap_script_sigs.len(), 1);
+        SECP.with(|secp| signed.finalize_mut(secp)).unwrap();
+        assert!(!signed.extract_tx().input[0].witness.is_empty());
+    }
+
+    #[test]
+    fn test_sign_and_finalize() {
+        let oracle = oracle();
+        let compiled = Payout
+            .compile(Context::new(
+                bitcoin::Network::Regtest,
+                Amount::from_sat(10_000),
+                Arc::new(oracle.clone()),
+                EffectPath::try_from("finalize").unwrap(),
+                Arc::new(Default::default()),
+            ))
+            .unwrap();
+        let template = compiled.ctv_to_tx.values().next().unwrap();
+        let psbt = template.to_oracle_psbt();
+        // without the oracle's signature the PSBT can't be finalized
+        assert!(matches!(
+            CTVAvailable.sign_and_finalize(psbt.clone()),
+            Err(EmulatorError::FinalizationIncomplete(_))
+        ));
+        let tx = oracle.sign_and_finalize(psbt).unwrap();
+        assert_eq!(tx.get_ctv_hash(0), template.hash());
+        // the oracle's key is the only branch, so it is also the internal
+        // key and the cheaper key path spend is used
+        assert_eq!(tx.input[0].witness.len(), 1);
+    }
+
+    #[test]
+    fn test_already_finalized() {
+        let oracle = oracle();
+        let psbt = signable_psbt(&oracle.roots[0]);
+        let mut finalized = SECP.with(|secp| oracle.sign(psbt, secp)).unwrap();
+        // finalize the key path spend, clearing the signature as a finalizer
+        // would
+        let sig = finalized.inputs[0].tap_key_sig.take().unwrap();
+        finalized.inputs[0].final_script_witness =
+            Some(bitcoin::Witness::from_vec(vec![sig.to_vec()]));
+        // the finalized input is left alone, rather than signed again
+        let resigned = CTVEmulator::sign(&oracle, finalized.clone()).unwrap();
+        assert!(resigned.inputs[0].tap_key_sig.is_none());
+        assert_eq!(resigned, finalized);
+        let mut merged = finalized.clone();
+        merged.combine(resigned).unwrap();
+        assert_eq!(merged, finalized);
+    }
+
+    #[test]
+    fn test_multiple_roots() {
+        let roots: Vec<ExtendedPrivKey> = [[7u8; 32], [8u8; 32]]
+            .iter()
+            .map(|s| ExtendedPrivKey::new_master(bitcoin::Network::Regtest, s).unwrap())
+            .collect();
+        let oracle = HDOracleEmulator::with_roots(roots, false);
+        let h = Sha256::hash(b"template");
+        assert!(matches!(
+            oracle.get_signer_for(h).unwrap(),
+            Clause::Threshold(2, v) if v.len() == 2
+        ));
+        let compiled = Payout
+            .compile(Context::new(
+                bitcoin::Network::Regtest,
+                Amount::from_sat(10_000),
+                Arc::new(oracle.clone()),
+                EffectPath::try_from("roots").unwrap(),
+                Arc::new(Default::default()),
+            ))
+            .unwrap();
+        let template = compiled.ctv_to_tx.values().next().unwrap();
+        let psbt = template.to_oracle_psbt();
+        let signed = CTVEmulator::sign(&oracle, psbt).unwrap();
+        let keys: Vec<XOnlyPublicKey> = SECP.with(|secp| {
+            let c = hash_to_child_vec(template.hash()).unwrap();
+            oracle
+                .roots
+                .iter()
+                .map(|r| {
+                    XOnlyPublicKey::from_keypair(&r.derive_priv(secp, &c).unwrap().to_keypair(secp))
+                        .0
+                })
+                .collect()
+        });
+        let sigs = &signed.inputs[0].tap_script_sigs;
+        assert_eq!(sigs.len(), 2);
+        for key in keys {
+            assert!(sigs.keys().any(|(pk, _)| *pk == key));
+        }
+        let mut signed = signed;
+        SECP.with(|secp| signed.finalize_mut(secp)).unwrap();
+    }
+
+    #[test]
+    fn test_custom_ctv_hash() {
+        let f: sapio_base::CTVHashFn = Arc::new(|tx, i| {
+            Sha256::hash(&[&b"alternative ctv"[..], &tx.get_ctv_hash(i)[..]].concat())
+        });
+        let bip119 = oracle();
+        let oracle = oracle().with_ctv_hash(f.clone());
+        let compiled = Payout
+            .compile(
+                Context::new(
+                    bitcoin::Network::Regtest,
+                    Amount::from_sat(10_000),
+                    Arc::new(oracle.clone()),
+                    EffectPath::try_from("custom").unwrap(),
+                    Arc::new(Default::default()),
+                )
+                .ctv_hash_fn(f.clone()),
+            )
+            .unwrap();
+        let template = compiled.ctv_to_tx.values().next().unwrap();
+        assert_eq!(template.hash(), f(&template.tx, 0));
+        assert_ne!(template.hash(), template.tx.get_ctv_hash(0));
+        let psbt = template.to_oracle_psbt();
+        // an oracle using BIP-119 derives a different key, so can't sign
+        assert!(bip119.sign_and_finalize(psbt.clone()).is_err());
+        let tx = oracle.sign_and_finalize(psbt).unwrap();
+        assert_eq!(tx.input[0].witness.len(), 1);
+    }
+
+    /// pays to `Chain(n - 1)`, ending in an OP_RETURN
+    struct Chain(u8);
+    impl Chain {
+        #[then]
+        fn pay(self, ctx: Context) {
+            let amt = ctx.funds() - Amount::from_sat(100);
+            if self.0 == 0 {
+                ctx.template()
+                    .add_output(amt, &Compiled::from_op_return(&[0u8; 4][..])?, None)?
+                    .into()
+            } else {
+                ctx.template()
+                    .add_output(amt, &Chain(self.0 - 1), None)?
+                    .into()
+            }
+        }
+    }
+    impl Contract for Chain {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_presign_all() {
+        use crate::connections::local::LocalSigningEmulator;
+        let emulator = LocalSigningEmulator::new(*oracle().roots[0]);
+        let compiled = Chain(2)
+            .compile(Context::new(
+                bitcoin::Network::Regtest,
+                Amount::from_sat(10_000),
+                Arc::new(LocalSigningEmulator::new(*oracle().roots[0])),
+                EffectPath::try_from("presign").unwrap(),
+                Arc::new(Default::default()),
+            ))
+            .unwrap();
+        let signed = compiled.presign_all(&emulator).unwrap();
+        assert_eq!(signed.len(), 3);
+        // the root PSBT is the first one presigned, before signing
+        let mut first = signed[0].clone();
+        first.inputs[0].tap_key_sig = None;
+        first.inputs[0].tap_script_sigs.clear();
+        assert_eq!(compiled.root_psbt().unwrap(), first);
+        let leaf = Compiled::from_op_return(&[1u8; 4][..]).unwrap();
+        assert!(matches!(
+            leaf.root_psbt(),
+            Err(CompilationError::MissingTemplates)
+        ));
+        // parents first: each spends the previous one
+        for (parent, child) in signed.iter().zip(signed.iter().skip(1)) {
+            assert_eq!(
+                parent.unsigned_tx.output[0].script_pubkey,
+                child.inputs[0].witness_utxo.as_ref().unwrap().script_pubkey
+            );
+        }
+        // every template is signed, under its CTV hash's key
+        for mut psbt in signed {
+            let utxos = vec![psbt.inputs[0].witness_utxo.clone().unwrap()];
+            let mut cache = bitcoin::util::sighash::SighashCache::new(&psbt.unsigned_tx);
+            let h = psbt.unsigned_tx.get_ctv_hash(0);
+            assert_eq!(psbt.inputs[0].tap_script_sigs.len(), 1);
+            for ((pk, leaf), sig) in &psbt.inputs[0].tap_script_sigs {
+                assert_eq!(emulator.get_signer_for(h).unwrap(), Clause::Key(*pk));
+                let sighash = cache
+                    .taproot_script_spend_signature_hash(
+                        0,
+                        &Prevouts::All(&utxos),
+                        *leaf,
+                        sig.hash_ty,
+                    )
+                    .unwrap();
+                let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..]).unwrap();
+                SECP.with(|secp| secp.verify_schnorr(&sig.sig, &msg, pk))
+                    .unwrap();
+            }
+            SECP.with(|secp| psbt.finalize_mut(secp)).unwrap();
+        }
+        // templates whose PSBTs can't be built are reported as such
+        let compiled = TwoInputs
+            .compile(Context::new(
+                bitcoin::Network::Regtest,
+                Amount::from_sat(10_000),
+                Arc::new(LocalSigningEmulator::new(*oracle().roots[0])),
+                EffectPath::try_from("presign").unwrap(),
+                Arc::new(Default::default()),
+            ))
+            .unwrap();
+        let template = *compiled.ctv_to_tx.keys().next().unwrap();
+        match compiled.presign_all(&emulator) {
+            Err(EmulatorError::UnsignableTemplate { template: t, .. }) => assert_eq!(t, template),
+            _ => panic!("expected UnsignableTemplate"),
+        }
+    }
+
+    /// pays to an OP_RETURN, spending an additional input
+    struct TwoInputs;
+    impl TwoInputs {
+        #[then]
+        fn pay(self, ctx: Context) {
+            ctx.template()
+                .add_sequence()
+                .add_output(
+                    Amount::from_sat(1000),
+                    &Compiled::from_op_return(&[1u8; 4][..])?,
+                    None,
+                )?
+                .into()
+        }
+    }
+    impl Contract for TwoInputs {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_idle_timeout() {
+        let oracle = oracle().with_idle_timeout(Duration::from_millis(200));
+        let psbt = signable_psbt(&oracle.roots[0]);
+        let (addr, server) = serve(oracle).await;
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        let mut busy = TcpStream::connect(addr).await.unwrap();
+        let req = serde_json::to_vec(&msgs::Request::SignPSBT(msgs::PSBT(psbt))).unwrap();
+        for _ in 0..8 {
+            tokio::time::sleep(Duration::from_millis(75)).await;
+            busy.write_u32(req.len() as u32).await.unwrap();
+            busy.write_all(&req[..]).await.unwrap();
+            let l = busy.read_u32().await.unwrap() as usize;
+            let mut v = vec![0u8; l];
+            busy.read_exact(&mut v[..]).await.unwrap();
+        }
+        // the idle connection was closed by the server
+        let mut buf = [0u8; 1];
+        assert_eq!(idle.read(&mut buf).await.unwrap(), 0);
+        // a request trickling in for longer than the timeout is still read
+        let mut slow = TcpStream::connect(addr).await.unwrap();
+        slow.write_u32(req.len() as u32).await.unwrap();
+        for chunk in req.chunks(req.len() / 4 + 1) {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            slow.write_all(chunk).await.unwrap();
+        }
+        let l = slow.read_u32().await.unwrap() as usize;
+        let mut v = vec![0u8; l];
+        slow.read_exact(&mut v[..]).await.unwrap();
+        server.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sign_retries_stale_connection() {
+        let oracle = oracle().with_idle_timeout(Duration::from_millis(100));
+        let psbt = signable_psbt(&oracle.roots[0]);
+        let secp = Arc::new(Secp256k1::new());
+        let root = ExtendedPubKey::from_priv(&secp, &oracle.roots[0]);
+        let (addr, server) = serve(oracle).await;
+        let conn = HDOracleEmulatorConnection::new(addr, root, None, secp)
+            .await
+            .unwrap();
+        assert!(conn.sign(psbt.clone()).is_ok());
+        // the oracle drops the now idle connection, so the next request
+        // fails on it and is retried on a fresh one
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let signed = conn.sign(psbt).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+        server.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_max_frame() {
+        let oracle = oracle();
+        let secp = Arc::new(Secp256k1::new());
+        let root = ExtendedPubKey::from_priv(&secp, &oracle.roots[0]);
+        let psbt = signable_psbt(&oracle.roots[0]);
+        // a request over the client's limit is refused before being sent
+        let (addr, server) = serve(oracle.clone()).await;
+        let conn = HDOracleEmulatorConnection::new(addr, root, None, secp.clone())
+            .await
+            .unwrap()
+            .with_max_frame(64);
+        let e = conn.sign(psbt.clone()).unwrap_err();
+        assert!(e.to_string().contains("exceeds maximum"));
+        server.abort();
+        // one over the oracle's limit is refused by the oracle
+        let (addr, server) = serve(oracle.with_max_frame(64)).await;
+        let conn = HDOracleEmulatorConnection::new(addr, root, None, secp.clone())
+            .await
+            .unwrap();
+        assert!(conn.sign(psbt).is_err());
+        server.abort();
+        // and an oversized response is rejected without reading it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let liar = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let l = socket.read_u32().await.unwrap() as usize;
+            socket.read_exact(&mut vec![0u8; l][..]).await.unwrap();
+            socket.write_u32(u32::MAX).await.unwrap();
+            socket
+        });
+        let conn = HDOracleEmulatorConnection::new(addr, root, None, secp)
+            .await
+            .unwrap();
+        let e = conn.attest(Sha256::hash(b"m")).unwrap_err();
+        assert!(e.to_string().contains("exceeds maximum"));
+        drop(liar.await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_close_reconnects() {
+        let oracle = oracle();
+        let psbt = signable_psbt(&oracle.roots[0]);
+        let secp = Arc::new(Secp256k1::new());
+        let root = ExtendedPubKey::from_priv(&secp, &oracle.roots[0]);
+        let (addr, server) = serve(oracle).await;
+        let conn = HDOracleEmulatorConnection::new(addr, root, None, secp)
+            .await
+            .unwrap();
+        assert!(conn.sign(psbt.clone()).is_ok());
+        assert!(conn.connection.lock().await.is_some());
+        conn.close().await;
+        assert!(conn.connection.lock().await.is_none());
+        // closing again is a no-op
+        conn.close().await;
+        let signed = conn.sign(psbt).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+        assert!(conn.connection.lock().await.is_some());
+        server.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_signing_under_load() {
+        let oracle = oracle().with_signing_threads(2);
+        let psbt = signable_psbt(&oracle.roots[0]);
+        // hold every signing slot, so that no signing request can complete
+        // until the test releases them
+        let slots = oracle.signers.clone();
+        let held = slots.clone().acquire_many_owned(2).await.unwrap();
+        let (addr, server) = serve(oracle).await;
+        let request =
+            |r: &msgs::Request| msgs::encode_frame(&msgs::Envelope::new(r).unwrap()).unwrap();
+        let sign = request(&msgs::Request::SignPSBT(msgs::PSBT(psbt)));
+        let load: Vec<_> = (0..16)
+            .map(|_| {
+                let frames = sign.repeat(32);
+                tokio::spawn(async move {
+                    let mut conn = TcpStream::connect(addr).await.unwrap();
+                    conn.write_all(&frames[..]).await.unwrap();
+                    for _ in 0..32 {
+                        let l = conn.read_u32().await.unwrap() as usize;
+                        let mut v = vec![0u8; l];
+                        conn.read_exact(&mut v[..]).await.unwrap();
+                        match serde_json::from_slice::<msgs::Response<msgs::PSBT>>(&v[..]).unwrap()
+                        {
+                            msgs::Response::Result(msgs::PSBT(signed)) => {
+                                assert!(signed.inputs[0].tap_key_sig.is_some())
+                            }
+                            msgs::Response::Error(e) => panic!("unexpected error {:?}", e),
+                        }
+                    }
+                })
+            })
+            .collect();
+        // a cheap request is answered while the signers are all busy
+        let mut health = TcpStream::connect(addr).await.unwrap();
+        health
+            .write_all(&request(&msgs::Request::Attest(Sha256::hash(b"ok"))))
+            .await
+            .unwrap();
+        let l = health.read_u32().await.unwrap() as usize;
+        let mut v = vec![0u8; l];
+        health.read_exact(&mut v[..]).await.unwrap();
+        assert!(matches!(
+            serde_json::from_slice::<msgs::Response<bitcoin::secp256k1::schnorr::Signature>>(
+                &v[..]
+            )
+            .unwrap(),
+            msgs::Response::Result(_)
+        ));
+        assert_eq!(slots.available_permits(), 0);
+        assert!(load.iter().all(|client| !client.is_finished()));
+        // once the slots are free, signing proceeds
+        drop(held);
+        for client in load {
+            client.await.unwrap();
+        }
+        assert_eq!(slots.available_permits(), 2);
+        server.abort();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unix_socket() {
+        let oracle = oracle();
+        let path = std::env::temp_dir().join(format!("sapio-oracle-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let secp = Arc::new(Secp256k1::new());
+        let root = ExtendedPubKey::from_priv(&secp, &oracle.roots[0]);
+        let psbt = signable_psbt(&oracle.roots[0]);
+        let server = tokio::spawn(oracle.bind_unix(path.clone()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let conn = HDOracleEmulatorConnection::new_unix(path.clone(), root, None, secp);
+        let signed = conn.sign(psbt).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+        server.abort();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_attestation() {
+        let oracle = oracle();
+        let secp = Arc::new(Secp256k1::new());
+        let root = ExtendedPubKey::from_priv(&secp, &oracle.roots[0]);
+        let (addr, server) = serve(oracle).await;
+        let conn = HDOracleEmulatorConnection::new(addr, root, None, secp.clone())
+            .await
+            .unwrap();
+        let m = Sha256::hash(b"outcome: heads");
+        let sig = conn.attest(m).unwrap();
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&m[..]).unwrap();
+        match conn.get_attester_for(m).unwrap() {
+            Clause::Key(k) => secp.verify_schnorr(&sig, &msg, &k).unwrap(),
+            _ => panic!("expected a key clause"),
+        }
+        assert_ne!(
+            conn.get_attester_for(m).unwrap(),
+            conn.get_signer_for(m).unwrap()
+        );
+        assert!(conn
+            .verify_attestation(Sha256::hash(b"outcome: tails"), &sig)
+            .is_err());
+        server.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_confirm_key() {
+        let oracle = oracle();
+        let secp = Arc::new(Secp256k1::new());
+        let root = ExtendedPubKey::from_priv(&secp, &oracle.roots[0]);
+        let other = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[8u8; 32]).unwrap();
+        let wrong = ExtendedPubKey::from_priv(&secp, &other);
+        let identity = oracle.identity();
+        let (addr, server) = serve(oracle).await;
+        let conn = |root| HDOracleEmulatorConnection::new(addr, root, None, secp.clone());
+        // a connection is identified as the oracle it connects to
+        assert_eq!(conn(root).await.unwrap().identity(), identity);
+        assert!(conn(root).await.unwrap().confirm_key().is_ok());
+        assert!(conn(wrong).await.unwrap().confirm_key().is_err());
+        server.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replayed_challenge_error() {
+        use crate::connections::hd::RngEntropy;
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+        let oracle = oracle();
+        let secp = Arc::new(Secp256k1::new());
+        let root = ExtendedPubKey::from_priv(&secp, &oracle.roots[0]);
+        let (addr, server) = serve(oracle).await;
+        // both connections draw the same challenge
+        let conn = || async {
+            HDOracleEmulatorConnection::new(addr, root, None, secp.clone())
+                .await
+                .unwrap()
+                .with_entropy(Arc::new(RngEntropy::new(StdRng::seed_from_u64(1))))
+        };
+        assert!(conn().await.confirm_key().is_ok());
+        match conn().await.confirm_key() {
+            Err(EmulatorError::NetworkIssue(e)) => {
+                assert!(matches!(
+                    msgs::ProtocolError::find(&e),
+                    Some(msgs::ProtocolError::ReplayedChallenge(_))
+                ));
+                assert!(e.to_string().contains("Already Answered"));
+            }
+            r => panic!("expected a replayed challenge, got {:?}", r),
+        }
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let oracle = oracle();
+        let secp = Secp256k1::new();
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let serving = {
+            let oracle = oracle.clone();
+            tokio::spawn(async move { oracle.serve(server).await })
+        };
+        let ms: Vec<Sha256> = (0u8..3).map(|i| Sha256::hash(&[i])).collect();
+        let mut frames = vec![];
+        for m in &ms {
+            let envelope = msgs::Envelope::new(&msgs::Request::Attest(*m)).unwrap();
+            frames.extend(msgs::encode_frame(&envelope).unwrap());
+        }
+        // all three requests are sent before reading any response
+        client.write_all(&frames[..]).await.unwrap();
+        for m in &ms {
+            let l = client.read_u32().await.unwrap() as usize;
+            let mut v = vec![0u8; l];
+            client.read_exact(&mut v[..]).await.unwrap();
+            match serde_json::from_slice::<msgs::Response<bitcoin::secp256k1::schnorr::Signature>>(
+                &v[..],
+            )
+            .unwrap()
+            {
+                msgs::Response::Result(sig) => {
+                    assert_eq!(sig, oracle.attest(*m, &secp).unwrap())
+                }
+                msgs::Response::Error(e) => panic!("unexpected error {:?}", e),
+            }
+        }
+        drop(client);
+        assert!(serving.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_request() {
+        let oracle = oracle();
+        let secp = Secp256k1::new();
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let serving = {
+            let oracle = oracle.clone();
+            tokio::spawn(async move { oracle.serve(server).await })
+        };
+        let garbage = b"{not json";
+        client.write_u32(garbage.len() as u32).await.unwrap();
+        client.write_all(&garbage[..]).await.unwrap();
+        let m = Sha256::hash(b"still connected");
+        let envelope = msgs::Envelope::new(&msgs::Request::Attest(m)).unwrap();
+        client
+            .write_all(&msgs::encode_frame(&envelope).unwrap())
+            .await
+            .unwrap();
+        let mut responses = vec![];
+        for _ in 0..2 {
+            let l = client.read_u32().await.unwrap() as usize;
+            let mut v = vec![0u8; l];
+            client.read_exact(&mut v[..]).await.unwrap();
+            responses.push(
+                serde_json::from_slice::<msgs::Response<bitcoin::secp256k1::schnorr::Signature>>(
+                    &v[..],
+                )
+                .unwrap(),
+            );
+        }
+        match &responses[..] {
+            [msgs::Response::Error(msgs::ProtocolError::MalformedRequest(_)), msgs::Response::Result(sig)] =>
+            {
+                assert_eq!(*sig, oracle.attest(m, &secp).unwrap())
+            }
+            _ => panic!("expected a malformed request error then an answer"),
+        }
+        drop(client);
+        assert!(serving.await.unwrap().is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replayed_confirmation() {
+        let oracle = oracle();
+        let secp = Arc::new(Secp256k1::new());
+        let root = ExtendedPubKey::from_priv(&secp, &oracle.roots[0]);
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let serving = {
+            let oracle = oracle.clone();
+            tokio::spawn(async move { oracle.serve(server).await })
+        };
+        let h = Sha256::hash(b"challenge");
+        let mut answers = vec![];
+        for _ in 0..2 {
+            let envelope = msgs::Envelope::new(&msgs::Request::ConfirmKey(h)).unwrap();
+            client
+                .write_all(&msgs::encode_frame(&envelope).unwrap())
+                .await
+                .unwrap();
+            let l = client.read_u32().await.unwrap() as usize;
+            let mut v = vec![0u8; l];
+            client.read_exact(&mut v[..]).await.unwrap();
+            answers.push(
+                serde_json::from_slice::<msgs::Response<bitcoin::secp256k1::schnorr::Signature>>(
+                    &v[..],
+                )
+                .unwrap(),
+            );
+        }
+        let conn = HDOracleEmulatorConnection::new("127.0.0.1:1", root, None, secp)
+            .await
+            .unwrap();
+        match &answers[..] {
+            [msgs::Response::Result(sig), msgs::Response::Error(e)] => {
+                assert!(conn
+                    .verify_attestation(confirmation_message(h), sig)
+                    .is_ok());
+                // the answer is no good for any other challenge
+                let other = confirmation_message(Sha256::hash(b"other"));
+                assert!(conn.verify_attestation(other, sig).is_err());
+                assert_eq!(*e, msgs::ProtocolError::ReplayedChallenge(h));
+            }
+            _ => panic!("expected an answer then a replay error"),
+        }
+        drop(client);
+        assert!(serving.await.unwrap().is_ok());
+
+        // only the most recent challenges are remembered
+        let oracle = oracle.with_challenge_cache(1);
+        let (h1, h2) = (Sha256::hash(b"1"), Sha256::hash(b"2"));
+        SECP.with(|secp| {
+            assert!(oracle.confirm_key(h1, secp).is_ok());
+            assert!(oracle.confirm_key(h1, secp).is_err());
+            assert!(oracle.confirm_key(h2, secp).is_ok());
+            assert!(oracle.confirm_key(h1, secp).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_derivation_cache() {
+        let oracle = oracle().with_derivation_cache(1);
+        let hits = || oracle.derivations.lock().unwrap().hits;
+        let derive = |h| -> Vec<ExtendedPrivKey> {
+            SECP.with(|secp| oracle.derive(h, secp))
+                .unwrap()
+                .iter()
+                .map(|k| **k)
+                .collect()
+        };
+        let (h1, h2) = (Sha256::hash(b"1"), Sha256::hash(b"2"));
+        let k1 = derive(h1);
+        assert_eq!(hits(), 0);
+        assert_eq!(derive(h1), k1);
+        assert_eq!(hits(), 1);
+        // h2 evicts h1, so h1 is derived again (to the same keys)
+        derive(h2);
+        assert_eq!(derive(h1), k1);
+        assert_eq!(hits(), 1);
+        assert!(oracle.derivations.lock().unwrap().evict());
+        assert!(!oracle.derivations.lock().unwrap().evict());
+
+        // using h1 makes h2 the least recently used, so h3 evicts it
+        let oracle = oracle.with_derivation_cache(2);
+        let h3 = Sha256::hash(b"3");
+        let derive = |h| SECP.with(|secp| oracle.derive(h, secp)).unwrap();
+        let hits = || oracle.derivations.lock().unwrap().hits;
+        derive(h1);
+        derive(h2);
+        for _ in 0..10 {
+            derive(h1);
+        }
+        assert_eq!(hits(), 10);
+        // the stale entries for h1 are compacted away
+        assert!(oracle.derivations.lock().unwrap().order.len() <= 4);
+        derive(h3);
+        derive(h1);
+        assert_eq!(hits(), 11);
+        derive(h2);
+        assert_eq!(hits(), 11);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_signed_receipt() {
+        let oracle = oracle();
+        let secp = Arc::new(Secp256k1::new());
+        let root = ExtendedPubKey::from_priv(&secp, &oracle.roots[0]);
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let serving = {
+            let oracle = oracle.clone();
+            tokio::spawn(async move { oracle.serve(server).await })
+        };
+        let psbt = signable_psbt(&oracle.roots[0]);
+        let envelope = msgs::Envelope::new(&msgs::Request::SignPSBTWithReceipt(msgs::PSBT(
+            psbt.clone(),
+        )))
+        .unwrap();
+        client
+            .write_all(&msgs::encode_frame(&envelope).unwrap())
+            .await
+            .unwrap();
+        let l = client.read_u32().await.unwrap() as usize;
+        let mut v = vec![0u8; l];
+        client.read_exact(&mut v[..]).await.unwrap();
+        let (signed, receipt) =
+            match serde_json::from_slice::<msgs::Response<msgs::Receipted>>(&v[..]).unwrap() {
+                msgs::Response::Result(msgs::Receipted {
+                    psbt: msgs::PSBT(signed),
+                    receipt,
+                }) => (signed, receipt),
+                msgs::Response::Error(e) => panic!("{}", e),
+            };
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+        // the receipt verifies under the root key itself
+        let msg =
+            bitcoin::secp256k1::Message::from_digest_slice(&receipt_message(&signed)[..]).unwrap();
+        assert!(secp
+            .verify_schnorr(&receipt, &msg, &root.to_x_only_pub())
+            .is_ok());
+        let conn = HDOracleEmulatorConnection::new("127.0.0.1:1", root, None, secp)
+            .await
+            .unwrap();
+        assert!(conn.verify_receipt(&signed, &receipt).is_ok());
+        // and only for the PSBT the oracle returned
+        assert!(conn.verify_receipt(&psbt, &receipt).is_err());
+        drop(client);
+        assert!(serving.await.unwrap().is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_envelope_errors() {
+        let (addr, server) = serve(oracle()).await;
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let cases = [
+            (
+                msgs::PROTOCOL_VERSION,
+                "launch_missiles",
+                msgs::ProtocolError::UnknownMethod("launch_missiles".into()),
+            ),
+            (
+                msgs::PROTOCOL_VERSION + 1,
+                "sign_psbt",
+                msgs::ProtocolError::VersionMismatch {
+                    expected: msgs::PROTOCOL_VERSION,
+                    got: msgs::PROTOCOL_VERSION + 1,
+                },
+            ),
+        ];
+        for (version, method, expected) in cases {
+            let req = serde_json::to_vec(&msgs::Envelope {
+                version,
+                method: method.into(),
+                params: serde_json::Value::Null,
+            })
+            .unwrap();
+            conn.write_u32(req.len() as u32).await.unwrap();
+            conn.write_all(&req[..]).await.unwrap();
+            let l = conn.read_u32().await.unwrap() as usize;
+            let mut v = vec![0u8; l];
+            conn.read_exact(&mut v[..]).await.unwrap();
+            match serde_json::from_slice::<msgs::Response<msgs::PSBT>>(&v[..]).unwrap() {
+                msgs::Response::Error(e) => assert_eq!(e, expected),
+                msgs::Response::Result(_) => panic!("expected an error"),
+            }
+        }
+        server.abort();
     }
 }
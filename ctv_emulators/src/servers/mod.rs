@@ -8,3 +8,4 @@
 
 use super::*;
 pub mod hd;
+pub mod secret;
@@ -0,0 +1,126 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! wiping private key material once an oracle is done with it
+use super::*;
+use bitcoin::secp256k1::{constants::ONE, SecretKey};
+use bitcoin::KeyPair;
+
+/// the secret key 1, which is valid but (being public knowledge) secret
+/// to no one
+fn blank_key() -> SecretKey {
+    SecretKey::from_slice(&ONE).expect("One is a valid secret key")
+}
+
+/// A type holding secrets, which can be replaced with a blank (but valid)
+/// value of the same type.
+pub trait Wipe {
+    /// a value holding no secrets, to overwrite `self` with
+    fn blank(&self) -> Self;
+}
+
+impl Wipe for SecretKey {
+    fn blank(&self) -> Self {
+        blank_key()
+    }
+}
+
+impl Wipe for KeyPair {
+    fn blank(&self) -> Self {
+        SECP.with(|secp| KeyPair::from_secret_key(secp, &blank_key()))
+    }
+}
+
+impl Wipe for ExtendedPrivKey {
+    fn blank(&self) -> Self {
+        ExtendedPrivKey {
+            network: self.network,
+            depth: 0,
+            parent_fingerprint: Default::default(),
+            child_number: ChildNumber::from(0),
+            private_key: blank_key(),
+            chain_code: ChainCode::from(&[0u8; 32][..]),
+        }
+    }
+}
+
+/// Holds a secret, overwriting it when dropped so it doesn't linger in freed
+/// memory (e.g. to be found in a memory dump).
+///
+/// The keys are `Copy`, so copies made while using them (e.g. by
+/// `tap_tweak`) are not wiped, but every key the oracle holds on to is.
+#[derive(Clone)]
+pub struct Zeroizing<T: Wipe>(T);
+
+impl<T: Wipe> Zeroizing<T> {
+    /// take ownership of the secret `t`
+    pub fn new(t: T) -> Self {
+        Zeroizing(t)
+    }
+
+    /// overwrite the held secret with a blank value
+    fn wipe(&mut self) {
+        let blank = self.0.blank();
+        // Safe: self.0 is valid and exclusively borrowed. The write is
+        // volatile so the compiler can't elide it as a dead store.
+        unsafe { std::ptr::write_volatile(&mut self.0, blank) }
+    }
+}
+
+impl<T: Wipe> std::ops::Deref for Zeroizing<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Wipe> Drop for Zeroizing<T> {
+    fn drop(&mut self) {
+        self.wipe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// counts how many times it has been wiped
+    struct Counted(Rc<Cell<usize>>);
+    impl Wipe for Counted {
+        fn blank(&self) -> Self {
+            self.0.set(self.0.get() + 1);
+            Counted(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_wipe() {
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[7u8; 32]).unwrap();
+        let child = SECP.with(|secp| root.derive_priv(secp, &[ChildNumber::from(1)]).unwrap());
+        let mut key = Zeroizing::new(child);
+        assert_eq!(*key, child);
+        key.wipe();
+        assert_ne!(*key, child);
+        assert_eq!(key.private_key, blank_key());
+        assert_eq!(key.chain_code, ChainCode::from(&[0u8; 32][..]));
+
+        let kp = SECP.with(|secp| child.to_keypair(secp));
+        let mut kp = Zeroizing::new(kp);
+        kp.wipe();
+        assert_eq!(SecretKey::from_keypair(&kp), blank_key());
+    }
+
+    #[test]
+    fn test_wipe_on_drop() {
+        let wipes = Rc::new(Cell::new(0));
+        let held = Zeroizing::new(Counted(wipes.clone()));
+        assert_eq!(wipes.get(), 0);
+        drop(held);
+        assert_eq!(wipes.get(), 1);
+    }
+}
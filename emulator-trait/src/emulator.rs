@@ -7,6 +7,8 @@
 //! definitions of emulator traits required to use as a trait object in low-level libraries.
 use bitcoin::hashes::sha256;
 use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::Transaction;
+use miniscript::psbt::PsbtExt;
 pub use sapio_base::Clause;
 use std::fmt;
 use std::sync::Arc;
@@ -18,13 +20,91 @@ pub enum EmulatorError {
     NetworkIssue(std::io::Error),
     /// Error was caused by BIP32
     BIP32Error(bitcoin::util::bip32::Error),
+    /// The emulator does not support attestations
+    AttestationUnsupported,
+    /// A signed PSBT could not be finalized, e.g. because signatures for some
+    /// inputs are missing
+    FinalizationIncomplete(Vec<miniscript::psbt::Error>),
+    /// No PSBT the emulator could sign could be built for a template, e.g.
+    /// because the template spends inputs whose prevouts aren't known
+    UnsignableTemplate {
+        /// the template's CTV hash
+        template: sha256::Hash,
+        /// why the PSBT could not be built
+        reason: String,
+    },
+    /// Fewer members of a federation signed than its policy requires
+    QuorumNotReached {
+        /// how many members signed
+        got: usize,
+        /// how many members are required to sign
+        needed: usize,
+    },
+    /// The members of a federation which signed don't satisfy its policy
+    PolicyNotSatisfied {
+        /// the members which signed, by their index in the federation
+        signed: Vec<usize>,
+    },
+    /// Members of a federation failed to confirm their keys, identified by
+    /// their index in the federation
+    ConfirmationFailed(Vec<(usize, EmulatorError)>),
 }
 impl fmt::Display for EmulatorError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            EmulatorError::NetworkIssue(e) => write!(f, "emulator network error: {}", e),
+            EmulatorError::BIP32Error(e) => write!(f, "emulator key derivation failed: {}", e),
+            EmulatorError::AttestationUnsupported => {
+                write!(f, "the emulator does not support attestations")
+            }
+            EmulatorError::FinalizationIncomplete(errors) => {
+                write!(f, "could not finalize the signed PSBT:")?;
+                for e in errors {
+                    write!(f, " {};", e)?;
+                }
+                Ok(())
+            }
+            EmulatorError::UnsignableTemplate { template, reason } => {
+                write!(
+                    f,
+                    "no PSBT could be built for template {}: {}",
+                    template, reason
+                )
+            }
+            EmulatorError::QuorumNotReached { got, needed } => write!(
+                f,
+                "federation quorum not reached: {} of {} required members signed",
+                got, needed
+            ),
+            EmulatorError::PolicyNotSatisfied { signed } => write!(
+                f,
+                "federation policy not satisfied by the members which signed: {:?}",
+                signed
+            ),
+            EmulatorError::ConfirmationFailed(failed) => {
+                write!(f, "federation members failed to confirm their keys:")?;
+                for (i, e) in failed {
+                    write!(f, " member {}: {};", i, e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl std::error::Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmulatorError::NetworkIssue(e) => Some(e),
+            EmulatorError::BIP32Error(e) => Some(e),
+            EmulatorError::AttestationUnsupported => None,
+            EmulatorError::FinalizationIncomplete(_) => None,
+            EmulatorError::UnsignableTemplate { .. } => None,
+            EmulatorError::QuorumNotReached { .. } => None,
+            EmulatorError::PolicyNotSatisfied { .. } => None,
+            EmulatorError::ConfirmationFailed(_) => None,
+        }
     }
 }
-impl std::error::Error for EmulatorError {}
 
 impl From<std::io::Error> for EmulatorError {
     fn from(e: std::io::Error) -> EmulatorError {
@@ -49,6 +129,48 @@ pub trait CTVEmulator: Sync + Send {
         &self,
         b: PartiallySignedTransaction,
     ) -> Result<PartiallySignedTransaction, EmulatorError>;
+    /// For a given message hash, gets the corresponding Clause that the
+    /// Emulator would satisfy once it attests to the message (e.g., the
+    /// outcome of an event, DLC style).
+    ///
+    /// Attestation keys are distinct from the keys returned by
+    /// `get_signer_for`, so an attestation can never be mistaken for a
+    /// template signature.
+    fn get_attester_for(&self, _m: sha256::Hash) -> Result<Clause, EmulatorError> {
+        Err(EmulatorError::AttestationUnsupported)
+    }
+    /// Identifies the emulator without contacting it, e.g. by its root key,
+    /// for caches of compiled contracts.
+    ///
+    /// Emulators with the same identity must return the same Clauses. The
+    /// default, None, means the emulator can't be identified locally, so
+    /// anything compiled with it is never cached.
+    fn identity(&self) -> Option<String> {
+        None
+    }
+    /// Check that the emulator controls the keys it reports, e.g. by having a
+    /// remote oracle answer a challenge with its root key.
+    ///
+    /// Emulators with nothing remote to confirm are trivially confirmed.
+    fn confirm_key(&self) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+    /// Sign the PSBT with [`CTVEmulator::sign`], then finalize it into a
+    /// transaction ready to broadcast.
+    ///
+    /// Errors with [`EmulatorError::FinalizationIncomplete`] if the signed
+    /// PSBT can't be finalized, e.g. because the PSBT needs signatures other
+    /// than the emulator's.
+    fn sign_and_finalize(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<Transaction, EmulatorError> {
+        let mut b = self.sign(b)?;
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        b.finalize_mut(&secp)
+            .map_err(EmulatorError::FinalizationIncomplete)?;
+        Ok(b.extract_tx())
+    }
 }
 
 /// A wrapper for an optional internal emulator trait object. If no emulator is
@@ -61,6 +183,9 @@ impl CTVEmulator for CTVAvailable {
     fn get_signer_for(&self, h: sha256::Hash) -> Result<Clause, EmulatorError> {
         Ok(Clause::TxTemplate(h))
     }
+    fn identity(&self) -> Option<String> {
+        Some("ctv".into())
+    }
     fn sign(
         &self,
         b: PartiallySignedTransaction,
@@ -68,3 +193,123 @@ impl CTVEmulator for CTVAvailable {
         Ok(b)
     }
 }
+
+/// Uses any external signer, such as a hardware wallet driven over USB or
+/// through an HWI subprocess, as a CTVEmulator, without implementing the
+/// whole trait.
+///
+/// Every template is guarded by the same `key`, so the signer (or its user)
+/// is responsible for only signing the intended templates.
+pub struct ExternalSigner<F> {
+    key: bitcoin::XOnlyPublicKey,
+    sign: F,
+}
+
+impl<F> ExternalSigner<F>
+where
+    F: Fn(PartiallySignedTransaction) -> Result<PartiallySignedTransaction, EmulatorError>
+        + Send
+        + Sync,
+{
+    /// create an emulator which guards templates with `key` and signs by
+    /// calling `sign`
+    pub fn new(key: bitcoin::XOnlyPublicKey, sign: F) -> Self {
+        ExternalSigner { key, sign }
+    }
+}
+
+impl<F> CTVEmulator for ExternalSigner<F>
+where
+    F: Fn(PartiallySignedTransaction) -> Result<PartiallySignedTransaction, EmulatorError>
+        + Send
+        + Sync,
+{
+    fn get_signer_for(&self, _h: sha256::Hash) -> Result<Clause, EmulatorError> {
+        Ok(Clause::Key(self.key))
+    }
+    fn identity(&self) -> Option<String> {
+        Some(format!("key {}", self.key))
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        (self.sign)(b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    #[test]
+    fn test_io_error_source() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "oracle down");
+        let e: EmulatorError = io.into();
+        let source = e.source().expect("io errors have a source");
+        assert_eq!(source.to_string(), "oracle down");
+        assert!(source.downcast_ref::<std::io::Error>().is_some());
+        assert!(EmulatorError::AttestationUnsupported.source().is_none());
+    }
+
+    #[test]
+    fn test_display() {
+        let quorum = EmulatorError::QuorumNotReached { got: 1, needed: 2 };
+        assert_eq!(
+            quorum.to_string(),
+            "federation quorum not reached: 1 of 2 required members signed"
+        );
+        assert_eq!(
+            EmulatorError::PolicyNotSatisfied { signed: vec![0, 2] }.to_string(),
+            "federation policy not satisfied by the members which signed: [0, 2]"
+        );
+        let failed = EmulatorError::ConfirmationFailed(vec![
+            (0, EmulatorError::AttestationUnsupported),
+            (2, quorum),
+        ]);
+        assert_eq!(
+            failed.to_string(),
+            "federation members failed to confirm their keys: \
+             member 0: the emulator does not support attestations; \
+             member 2: federation quorum not reached: 1 of 2 required members signed;"
+        );
+    }
+
+    #[test]
+    fn test_external_signer() {
+        use bitcoin::hashes::Hash;
+        use std::str::FromStr;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let key = bitcoin::XOnlyPublicKey::from_str(
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let signer: Arc<dyn CTVEmulator> = Arc::new(ExternalSigner::new(key, move |mut psbt| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            psbt.unsigned_tx.lock_time = 7;
+            Ok(psbt)
+        }));
+        for m in [&b"a"[..], &b"b"[..]] {
+            assert_eq!(
+                signer.get_signer_for(sha256::Hash::hash(m)).unwrap(),
+                Clause::Key(key)
+            );
+        }
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        let signed = signer.sign(psbt).unwrap();
+        assert_eq!(signed.unsigned_tx.lock_time, 7);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let failing = ExternalSigner::new(key, |_| Err(EmulatorError::AttestationUnsupported));
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(signed.unsigned_tx).unwrap();
+        assert!(failing.sign(psbt).is_err());
+    }
+}
@@ -9,7 +9,7 @@
 /// Extra functionality for working with Bitcoin types
 pub mod util;
 use bitcoin::XOnlyPublicKey;
-pub use util::CTVHash;
+pub use util::{CTVHash, CTVHashFn};
 pub mod plugin_args;
 pub mod simp;
 
@@ -8,6 +8,12 @@ use bitcoin::consensus::encode::Encodable;
 use bitcoin::hashes::sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::util::amount::Amount;
+use std::sync::Arc;
+
+/// Computes the CTV hash of a transaction for an input index, in place of
+/// [`CTVHash::get_ctv_hash`], e.g. for test vectors or deployments of a
+/// variant of BIP-119.
+pub type CTVHashFn = Arc<dyn Fn(&bitcoin::Transaction, u32) -> sha256::Hash + Send + Sync>;
 
 /// Any type which can generate a CTVHash. Allows some decoupling in the future if some types will
 /// not be literal transactions.
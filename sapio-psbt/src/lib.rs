@@ -6,7 +6,7 @@
 
 use bitcoin::consensus::serialize;
 use bitcoin::schnorr::TapTweak;
-use bitcoin::secp256k1::rand::Rng;
+use bitcoin::secp256k1::rand::RngCore;
 use bitcoin::secp256k1::{rand, Signing, Verification};
 use bitcoin::util::bip32::{ExtendedPubKey, Fingerprint, KeySource};
 use bitcoin::util::sighash::Prevouts;
@@ -29,8 +29,18 @@ impl SigningKey {
     pub fn read_key_from_buf(buf: &[u8]) -> Result<Self, bitcoin::util::bip32::Error> {
         ExtendedPrivKey::decode(buf).map(|k| SigningKey(vec![k]))
     }
+    /// Generate a new key seeded from `thread_rng`
     pub fn new_key(network: Network) -> Result<Self, bitcoin::util::bip32::Error> {
-        let seed: [u8; 32] = rand::thread_rng().gen();
+        Self::new_key_with_rng(network, &mut rand::thread_rng())
+    }
+    /// Generate a new key seeded from `rng`, so that a seeded RNG gives the
+    /// same key every run (e.g., for reproducible tests)
+    pub fn new_key_with_rng<R: RngCore + ?Sized>(
+        network: Network,
+        rng: &mut R,
+    ) -> Result<Self, bitcoin::util::bip32::Error> {
+        let mut seed = [0u8; 32];
+        rng.fill_bytes(&mut seed);
         let xpriv = ExtendedPrivKey::new_master(network, &seed)?;
         Ok(SigningKey(vec![xpriv]))
     }
@@ -284,3 +294,25 @@ fn get_sig<C: Signing>(
     let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
     SchnorrSig { sig, hash_ty }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::rand::rngs::StdRng;
+    use bitcoin::secp256k1::rand::SeedableRng;
+    #[test]
+    fn test_new_key_with_seeded_rng() {
+        let key = || {
+            SigningKey::new_key_with_rng(Network::Regtest, &mut StdRng::seed_from_u64(7))
+                .unwrap()
+                .0
+        };
+        assert_eq!(key(), key());
+        assert_ne!(
+            key(),
+            SigningKey::new_key_with_rng(Network::Regtest, &mut StdRng::seed_from_u64(8))
+                .unwrap()
+                .0
+        );
+    }
+}
@@ -10,26 +10,85 @@ use super::descriptors::*;
 pub use crate::contract::abi::studio::*;
 use crate::contract::object::Object;
 use crate::contract::object::ObjectError;
+use crate::contract::CompilationError;
 use crate::template::Template;
 
 use ::miniscript::*;
 
 use bitcoin::hashes::sha256::Hash as Sha256;
 
+use bitcoin::secp256k1::{All, Secp256k1, Verification};
+use bitcoin::util::psbt;
 use bitcoin::util::psbt::PartiallySignedTransaction;
 use bitcoin::util::taproot::TaprootBuilder;
 use bitcoin::util::taproot::TaprootSpendInfo;
 use bitcoin::OutPoint;
+use bitcoin::XOnlyPublicKey;
 
 use sapio_base::effects::EffectPath;
 
 use sapio_base::serialization_helpers::SArc;
 use sapio_base::txindex::TxIndex;
-use sapio_ctv_emulator_trait::CTVEmulator;
+use sapio_ctv_emulator_trait::{CTVEmulator, EmulatorError};
 
 use std::collections::BTreeMap;
 use std::rc::Rc;
 use std::sync::Arc;
+
+/// compute the taproot spend info (merkle tree and tweak) of `t`
+pub(crate) fn taproot_spend_info<C: Verification>(
+    t: &descriptor::Tr<XOnlyPublicKey>,
+    secp: &Secp256k1<C>,
+) -> Result<TaprootSpendInfo, ObjectError> {
+    let mut builder = TaprootBuilder::new();
+    let mut added = false;
+    for (depth, ms) in t.iter_scripts() {
+        added = true;
+        let script = ms.encode();
+        builder = builder.add_leaf(depth, script)?;
+    }
+    Ok(if added {
+        builder.finalize(secp, *t.internal_key())?
+    } else {
+        TaprootSpendInfo::new_key_spend(secp, *t.internal_key(), None)
+    })
+}
+
+/// fill in the script information for spending `descriptor` on `inp`.
+///
+/// For `Pk` descriptors and raw scripts this sets the `witness_script`, for
+/// Taproot descriptors the tap scripts, merkle root, and internal key.
+pub(crate) fn add_descriptor_info(
+    inp: &mut psbt::Input,
+    descriptor: &Option<SupportedDescriptors>,
+    secp: &Secp256k1<All>,
+) -> Result<(), ObjectError> {
+    match descriptor {
+        Some(SupportedDescriptors::Pk(d)) => {
+            inp.witness_script = Some(d.explicit_script()?);
+            if let Descriptor::Sh(sh) = d {
+                if let descriptor::ShInner::Wsh(wsh) = sh.as_inner() {
+                    inp.redeem_script = Some(wsh.script_pubkey());
+                }
+            }
+        }
+        Some(SupportedDescriptors::RawWsh(s)) => {
+            inp.witness_script = Some(s.clone());
+        }
+        Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
+            let info = taproot_spend_info(t, secp)?;
+            for item in info.as_script_map().keys() {
+                let cb = info.control_block(item).expect("Must be present");
+                inp.tap_scripts.insert(cb.clone(), item.clone());
+            }
+            inp.tap_merkle_root = info.merkle_root();
+            inp.tap_internal_key = Some(info.internal_key());
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
 impl Object {
     /// bind_psbt attaches and `Object` to a specific UTXO, returning a
     /// Vector of PSBTs and transaction metadata.
@@ -104,38 +163,7 @@ impl Object {
                                         blockdata.lookup_output(&tx_in.previous_output).ok();
                                 }
                                 // Missing other Witness Info.
-                                match descriptor {
-                                    Some(SupportedDescriptors::Pk(d)) => {
-                                        psbtx.inputs[0].witness_script = Some(d.explicit_script()?);
-                                    }
-                                    Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
-                                        let mut builder = TaprootBuilder::new();
-                                        let mut added = false;
-                                        for (depth, ms) in t.iter_scripts() {
-                                            added = true;
-                                            let script = ms.encode();
-                                            builder = builder.add_leaf(depth, script)?;
-                                        }
-                                        let info = if added {
-                                            builder.finalize(&secp, *t.internal_key())?
-                                        } else {
-                                            TaprootSpendInfo::new_key_spend(
-                                                &secp,
-                                                *t.internal_key(),
-                                                None,
-                                            )
-                                        };
-                                        let inp = &mut psbtx.inputs[0];
-                                        for item in info.as_script_map().keys() {
-                                            let cb =
-                                                info.control_block(item).expect("Must be present");
-                                            inp.tap_scripts.insert(cb.clone(), item.clone());
-                                        }
-                                        inp.tap_merkle_root = info.merkle_root();
-                                        inp.tap_internal_key = Some(info.internal_key());
-                                    }
-                                    _ => (),
-                                }
+                                add_descriptor_info(&mut psbtx.inputs[0], descriptor, &secp)?;
                                 psbtx = emulator.sign(psbtx)?;
                                 let final_tx = psbtx.clone().extract_tx();
                                 let txid = blockdata.add_tx(Arc::new(final_tx))?;
@@ -167,4 +195,58 @@ impl Object {
         }
         Ok(Program { program: result })
     }
+
+    /// Get the emulator's signature for every CTV template in the tree of
+    /// contracts this Object creates, e.g. to store them before the emulator
+    /// goes offline.
+    ///
+    /// Each template's PSBT is built with
+    /// [`Template::to_oracle_psbt_with_inputs`], without any additional
+    /// prevouts, so templates spending additional inputs, whose prevouts
+    /// aren't known yet, error with [`EmulatorError::UnsignableTemplate`].
+    /// The PSBTs are returned parents first.
+    pub fn presign_all(
+        &self,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<Vec<PartiallySignedTransaction>, EmulatorError> {
+        let mut signed = vec![];
+        let mut stack = vec![self];
+        while let Some(obj) = stack.pop() {
+            for t in obj.ctv_to_tx.values() {
+                let psbt = t.to_oracle_psbt_with_inputs(&[]).map_err(|e| {
+                    EmulatorError::UnsignableTemplate {
+                        template: t.hash(),
+                        reason: e.to_string(),
+                    }
+                })?;
+                signed.push(emulator.sign(psbt)?);
+            }
+            // push in reverse so that children are visited in order
+            stack.extend(
+                obj.ctv_to_tx
+                    .values()
+                    .chain(obj.suggested_txs.values())
+                    .flat_map(|t| t.outputs.iter())
+                    .map(|o| &o.contract)
+                    .rev(),
+            );
+        }
+        Ok(signed)
+    }
+
+    /// Get the oracle-ready PSBT of the template spending this Object's
+    /// funding output, e.g. when only the first transaction is needed rather
+    /// than the whole tree from [`Object::presign_all`].
+    ///
+    /// If there are several CTV templates, the first (by CTV hash) is used,
+    /// the same one `presign_all` signs first. Errors with
+    /// [`CompilationError::MissingTemplates`] if there are none.
+    pub fn root_psbt(&self) -> Result<PartiallySignedTransaction, CompilationError> {
+        let t = self
+            .ctv_to_tx
+            .values()
+            .next()
+            .ok_or(CompilationError::MissingTemplates)?;
+        Ok(t.to_oracle_psbt())
+    }
 }
@@ -18,12 +18,16 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 /// Multiple Types of Allowed Descriptor
-#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
 pub enum SupportedDescriptors {
     /// # ECDSA Descriptors
     Pk(Descriptor<PublicKey>),
     /// # Taproot Descriptors
     XOnly(Descriptor<XOnlyPublicKey>),
+    /// # Raw Witness Scripts
+    /// A hand-written witness script, paid to as P2WSH, which was not
+    /// compiled from (and may not be expressible as) miniscript
+    RawWsh(Script),
 }
 
 impl From<Descriptor<PublicKey>> for SupportedDescriptors {
@@ -42,6 +46,43 @@ impl SupportedDescriptors {
         match self {
             SupportedDescriptors::Pk(p) => p.script_pubkey(),
             SupportedDescriptors::XOnly(x) => x.script_pubkey(),
+            SupportedDescriptors::RawWsh(s) => s.to_v0_p2wsh(),
         }
     }
+    /// Regardless of descriptor type, get the largest weight a witness
+    /// satisfying it could have.
+    ///
+    /// Errors for raw scripts, which can't be analyzed.
+    pub fn max_satisfaction_weight(&self) -> Result<usize, Error> {
+        match self {
+            SupportedDescriptors::Pk(p) => p.max_satisfaction_weight(),
+            SupportedDescriptors::XOnly(x) => x.max_satisfaction_weight(),
+            SupportedDescriptors::RawWsh(_) => {
+                Err(Error::Unexpected("Raw Script Satisfactions Unknown".into()))
+            }
+        }
+    }
+    /// Regardless of descriptor type, get every key it contains (as x-only
+    /// keys, so the same key is recognized in ECDSA and Taproot descriptors).
+    ///
+    /// Raw scripts are not analyzed, so have no keys.
+    pub fn keys(&self) -> Vec<XOnlyPublicKey> {
+        let mut keys = vec![];
+        match self {
+            SupportedDescriptors::Pk(p) => p.for_each_key(|k| {
+                if let ForEach::Key(k) = k {
+                    keys.push(k.to_x_only_pubkey());
+                }
+                true
+            }),
+            SupportedDescriptors::XOnly(x) => x.for_each_key(|k| {
+                if let ForEach::Key(k) = k {
+                    keys.push(*k);
+                }
+                true
+            }),
+            SupportedDescriptors::RawWsh(_) => true,
+        };
+        keys
+    }
 }
@@ -0,0 +1,268 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! comparing two Objects, e.g. before and after editing a contract
+use crate::contract::object::Object;
+use crate::template::Template;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::Script;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A single difference between two Objects
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    /// The script of a contract changed for reasons other than a change to
+    /// its templates (e.g., different keys or timelocks)
+    ScriptChanged {
+        /// the script before
+        old: Script,
+        /// the script after
+        new: Script,
+    },
+    /// A template is only present in the new Object
+    TemplateAdded(sha256::Hash),
+    /// A template is only present in the old Object
+    TemplateRemoved(sha256::Hash),
+    /// A template's transaction changed other than in its outputs (e.g.,
+    /// locktime or sequences)
+    TransactionChanged,
+    /// An output is only present in the new Object
+    OutputAdded(#[serde(with = "bitcoin::util::amount::serde::as_sat")] Amount),
+    /// An output is only present in the old Object
+    OutputRemoved(#[serde(with = "bitcoin::util::amount::serde::as_sat")] Amount),
+    /// An output sends a different amount
+    AmountChanged {
+        /// the amount before
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        old: Amount,
+        /// the amount after
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        new: Amount,
+    },
+}
+
+/// A [`Change`] and where in the template tree it happened.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DiffEntry {
+    /// `/` separated location of the change, e.g. `ctv#0/output#1` for the
+    /// second output of the first CTV template. Templates with a label are
+    /// named by it instead, e.g. `ctv:payout/output#1`.
+    pub path: String,
+    /// what changed
+    pub change: Change,
+}
+
+/// The differences between two Objects' template trees, see [`Object::diff`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct ContractDiff {
+    /// all of the changes found
+    pub changes: Vec<DiffEntry>,
+}
+
+impl ContractDiff {
+    /// are the Objects equivalent?
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+    fn push(&mut self, path: &str, change: Change) {
+        self.changes.push(DiffEntry {
+            path: path.into(),
+            change,
+        })
+    }
+}
+
+impl Object {
+    /// Compute the differences between this Object (the old one) and `other`
+    /// (the new one).
+    ///
+    /// Templates with the same hash are treated as unchanged. The remaining
+    /// templates are paired by label, then by order, and compared output by
+    /// output. Changes that are only a consequence of a changed template (the
+    /// template's hash and the scripts of its parents) are not reported.
+    pub fn diff(&self, other: &Object) -> ContractDiff {
+        let mut diff = ContractDiff::default();
+        diff_object(self, other, "", &mut diff);
+        diff
+    }
+}
+
+fn join(path: &str, segment: &str) -> String {
+    if path.is_empty() {
+        segment.into()
+    } else {
+        format!("{}/{}", path, segment)
+    }
+}
+
+fn diff_object(old: &Object, new: &Object, path: &str, diff: &mut ContractDiff) {
+    let old_script: Script = old.address.clone().into();
+    let new_script: Script = new.address.clone().into();
+    if old_script == new_script {
+        // the script commits to all CTV templates, so there's nothing else
+        // worth reporting
+        return;
+    }
+    let n_changes = diff.changes.len();
+    diff_templates(&old.ctv_to_tx, &new.ctv_to_tx, &join(path, "ctv"), diff);
+    diff_templates(
+        &old.suggested_txs,
+        &new.suggested_txs,
+        &join(path, "suggested"),
+        diff,
+    );
+    if diff.changes.len() == n_changes {
+        diff.push(
+            path,
+            Change::ScriptChanged {
+                old: old_script,
+                new: new_script,
+            },
+        );
+    }
+}
+
+fn diff_templates(
+    old: &BTreeMap<sha256::Hash, Template>,
+    new: &BTreeMap<sha256::Hash, Template>,
+    prefix: &str,
+    diff: &mut ContractDiff,
+) {
+    let mut removed: Vec<&Template> = old
+        .iter()
+        .filter(|(h, _)| !new.contains_key(*h))
+        .map(|(_, t)| t)
+        .collect();
+    let mut added: Vec<&Template> = new
+        .iter()
+        .filter(|(h, _)| !old.contains_key(*h))
+        .map(|(_, t)| t)
+        .collect();
+    // pair by label first
+    let mut pairs = vec![];
+    removed.retain(|o| {
+        let label = match &o.metadata_map_s2s.label {
+            Some(l) => l,
+            None => return true,
+        };
+        match added
+            .iter()
+            .position(|n| n.metadata_map_s2s.label.as_ref() == Some(label))
+        {
+            Some(i) => {
+                pairs.push((format!("{}:{}", prefix, label), *o, added.remove(i)));
+                false
+            }
+            None => true,
+        }
+    });
+    // then by order
+    let unlabeled = removed.len().min(added.len());
+    for (i, (o, n)) in removed
+        .drain(..unlabeled)
+        .zip(added.drain(..unlabeled))
+        .enumerate()
+    {
+        pairs.push((format!("{}#{}", prefix, i), o, n));
+    }
+    for (name, o, n) in pairs {
+        diff_template(o, n, &name, diff);
+    }
+    for t in removed {
+        diff.push(prefix, Change::TemplateRemoved(t.hash()));
+    }
+    for t in added {
+        diff.push(prefix, Change::TemplateAdded(t.hash()));
+    }
+}
+
+fn diff_template(old: &Template, new: &Template, path: &str, diff: &mut ContractDiff) {
+    let strip = |t: &Template| {
+        let mut tx = t.tx.clone();
+        tx.output.clear();
+        tx
+    };
+    if strip(old) != strip(new) {
+        diff.push(path, Change::TransactionChanged);
+    }
+    for (i, (o, n)) in old.outputs.iter().zip(new.outputs.iter()).enumerate() {
+        let out_path = join(path, &format!("output#{}", i));
+        if o.amount != n.amount {
+            diff.push(
+                &out_path,
+                Change::AmountChanged {
+                    old: o.amount,
+                    new: n.amount,
+                },
+            );
+        }
+        diff_object(&o.contract, &n.contract, &out_path, diff);
+    }
+    for o in old.outputs.iter().skip(new.outputs.len()) {
+        diff.push(path, Change::OutputRemoved(o.amount));
+    }
+    for n in new.outputs.iter().skip(old.outputs.len()) {
+        diff.push(path, Change::OutputAdded(n.amount));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::test_ctx;
+    use crate::contract::{Compilable, Context, Contract};
+    use crate::then;
+
+    /// pays each amount to an OP_RETURN
+    struct Payouts(Vec<u64>);
+    impl Payouts {
+        #[then]
+        fn pay(self, ctx: Context) {
+            let mut tmpl = ctx.template();
+            for (i, amt) in self.0.iter().enumerate() {
+                tmpl = tmpl.add_output(
+                    Amount::from_sat(*amt),
+                    &Object::from_op_return(&[i as u8; 4][..])?,
+                    None,
+                )?;
+            }
+            tmpl.into()
+        }
+    }
+    impl Contract for Payouts {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    fn compile(amounts: &[u64]) -> Object {
+        Payouts(amounts.to_vec())
+            .compile(test_ctx("diff", 100_000))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = compile(&[1000, 2000, 3000]);
+        assert!(before.diff(&before).is_empty());
+        let after = compile(&[1000, 2500, 3000]);
+        let diff = before.diff(&after);
+        assert_eq!(
+            diff.changes,
+            vec![DiffEntry {
+                path: "ctv#0/output#1".into(),
+                change: Change::AmountChanged {
+                    old: Amount::from_sat(2000),
+                    new: Amount::from_sat(2500),
+                },
+            }]
+        );
+        let json = serde_json::to_string(&diff).unwrap();
+        assert_eq!(serde_json::from_str::<ContractDiff>(&json).unwrap(), diff);
+    }
+}
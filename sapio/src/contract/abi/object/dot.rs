@@ -0,0 +1,127 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! GraphViz export of a compiled contract's template tree
+use crate::contract::object::{FlowTarget, Object};
+use crate::template::Template;
+use bitcoin::hashes::sha256;
+use std::collections::{BTreeMap, BTreeSet};
+
+impl Object {
+    /// Render this Object's template tree as a GraphViz DOT digraph, e.g. to
+    /// draw a contract for documentation or debugging.
+    ///
+    /// Every template (CTV protected or suggested) is a node, labeled with
+    /// its label (conventionally the action creating it, see
+    /// [`crate::template::Builder::set_label`]) or else its hash. The edges
+    /// follow [`Object::sats_flow`]: from a template to the templates which
+    /// may spend each of its outputs, or to a leaf for an output with no
+    /// templates, labeled with the output index and amount.
+    pub fn to_dot(&self) -> String {
+        let mut templates = BTreeMap::new();
+        collect(self, &mut templates);
+        let mut dot = String::from("digraph {\n    \"root\" [shape=doublecircle];\n");
+        for (h, t) in &templates {
+            let label = t.label().map_or_else(|| h.to_string(), String::from);
+            dot.push_str(&format!("    \"{}\" [label=\"{}\"];\n", h, escape(&label)));
+        }
+        for h in self.ctv_to_tx.keys().chain(self.suggested_txs.keys()) {
+            dot.push_str(&format!("    \"root\" -> \"{}\";\n", h));
+        }
+        // a contract created by several templates has its edges traced once
+        // per template, so drop the repeats
+        let mut seen = BTreeSet::new();
+        for e in self.sats_flow() {
+            let label = format!("{}: {} sats", e.output, e.amount.as_sat());
+            let lines = match &e.to_output_or_child {
+                FlowTarget::Child(children) => children
+                    .iter()
+                    .map(|c| {
+                        format!(
+                            "    \"{}\" -> \"{}\" [label=\"{}\"];\n",
+                            e.from_template, c, label
+                        )
+                    })
+                    .collect(),
+                FlowTarget::Output(_) => {
+                    let leaf = format!("{}:{}", e.from_template, e.output);
+                    vec![
+                        format!("    \"{}\" [shape=box, label=\"output\"];\n", leaf),
+                        format!(
+                            "    \"{}\" -> \"{}\" [label=\"{}\"];\n",
+                            e.from_template, leaf, label
+                        ),
+                    ]
+                }
+            };
+            for line in lines {
+                if seen.insert(line.clone()) {
+                    dot.push_str(&line);
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// add the templates of `obj`, and of the contracts they create, to
+/// `templates`
+fn collect<'a>(obj: &'a Object, templates: &mut BTreeMap<sha256::Hash, &'a Template>) {
+    for (h, t) in obj.ctv_to_tx.iter().chain(obj.suggested_txs.iter()) {
+        templates.insert(*h, t);
+        for o in &t.outputs {
+            collect(&o.contract, templates);
+        }
+    }
+}
+
+/// escape `s` for use in a quoted DOT string
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::{test_compiled, test_template};
+
+    #[test]
+    fn test_to_dot() {
+        let leaf = |b: u8| Object::from_op_return(&[b; 4][..]).unwrap();
+        let paying = |label: &str, outputs: &[(u64, &Object)]| {
+            let mut t = test_template(outputs);
+            t.metadata_map_s2s.label = Some(label.into());
+            t
+        };
+        let child_tmpl = paying("split \"child\"", &[(1000, &leaf(1)), (3000, &leaf(2))]);
+        let child = test_compiled(vec![child_tmpl.clone()]);
+        let root_tmpl = paying("split", &[(4000, &child), (2000, &leaf(3))]);
+        let dot = test_compiled(vec![root_tmpl.clone()]).to_dot();
+        assert!(dot.starts_with("digraph {\n") && dot.ends_with("}\n"));
+        let (r, c) = (root_tmpl.hash(), child_tmpl.hash());
+        // a node per template, with quotes in labels escaped
+        assert!(dot.contains(&format!("\"{}\" [label=\"split\"];", r)));
+        assert!(dot.contains(&format!("\"{}\" [label=\"split \\\"child\\\"\"];", c)));
+        // the spends
+        assert!(dot.contains(&format!("\"root\" -> \"{}\";", r)));
+        assert!(dot.contains(&format!("\"{}\" -> \"{}\" [label=\"0: 4000 sats\"];", r, c)));
+        assert!(dot.contains(&format!(
+            "\"{}\" -> \"{}:1\" [label=\"1: 2000 sats\"];",
+            r, r
+        )));
+        assert!(dot.contains(&format!(
+            "\"{}\" -> \"{}:0\" [label=\"0: 1000 sats\"];",
+            c, c
+        )));
+        assert!(dot.contains(&format!(
+            "\"{}\" -> \"{}:1\" [label=\"1: 3000 sats\"];",
+            c, c
+        )));
+        assert_eq!(dot.matches(" -> ").count(), 5);
+        assert_eq!(dot.matches("[shape=box").count(), 3);
+    }
+}
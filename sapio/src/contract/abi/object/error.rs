@@ -28,6 +28,11 @@ pub enum ObjectError {
     UnknownScriptType(bitcoin::Script),
     /// OpReturn Too Long
     OpReturnTooLong,
+    /// No funding coins could be selected for the target amount
+    CoinSelectionFailed(bitcoin::util::amount::Amount),
+    /// The number of prevouts given (second) does not match the number of
+    /// additional inputs (first)
+    PrevoutCountMismatch(usize, usize),
     /// The Error was for an unknown/unhandled reason
     Custom(Box<dyn std::error::Error>),
 }
@@ -0,0 +1,131 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! exporting a compiled contract's descriptors for import into a wallet
+use super::descriptors::SupportedDescriptors;
+use crate::contract::object::Object;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// A descriptor of an output in a contract, in the form expected by Bitcoin
+/// Core's `importdescriptors`, see [`Object::wallet_export`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DescriptorExport {
+    /// the descriptor, including its `#checksum` suffix
+    pub descriptor: String,
+    /// the descriptor's checksum
+    pub checksum: String,
+    /// the address the descriptor pays to
+    pub address: bitcoin::Address,
+    /// a label for the output, the path of the contract it belongs to
+    pub label: String,
+}
+
+impl Object {
+    /// Export the descriptor of this Object and of every contract in its
+    /// template tree (CTV protected and suggested), e.g. so a wallet can
+    /// watch all of the contract's addresses on `network`.
+    ///
+    /// Each descriptor is exported once, labeled with the path it was first
+    /// found at. Contracts without a descriptor (e.g., OP_RETURNs or bare
+    /// addresses) are omitted.
+    pub fn wallet_export(&self, network: bitcoin::Network) -> Vec<DescriptorExport> {
+        let mut seen = BTreeSet::new();
+        let mut exports = vec![];
+        export(self, network, &mut seen, &mut exports);
+        exports
+    }
+}
+
+/// export `obj`'s descriptor if it is new, then those of the contracts its
+/// templates create.
+fn export(
+    obj: &Object,
+    network: bitcoin::Network,
+    seen: &mut BTreeSet<String>,
+    exports: &mut Vec<DescriptorExport>,
+) {
+    let descriptor = match &obj.descriptor {
+        Some(SupportedDescriptors::Pk(d)) => Some(d.to_string()),
+        Some(SupportedDescriptors::XOnly(d)) => Some(d.to_string()),
+        // raw scripts have no descriptor form
+        Some(SupportedDescriptors::RawWsh(_)) | None => None,
+    };
+    if let Some(descriptor) = descriptor {
+        if !seen.insert(descriptor.clone()) {
+            // the same contract as one already exported
+            return;
+        }
+        if let (Some((_, checksum)), Ok(address)) =
+            (descriptor.rsplit_once('#'), obj.address(network))
+        {
+            exports.push(DescriptorExport {
+                checksum: checksum.into(),
+                descriptor: descriptor.clone(),
+                address,
+                label: String::from(obj.root_path.0.as_ref().clone()),
+            });
+        }
+    }
+    for t in obj.ctv_to_tx.values().chain(obj.suggested_txs.values()) {
+        for o in &t.outputs {
+            export(&o.contract, network, seen, exports);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::test_ctx;
+    use crate::template::Template;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::XOnlyPublicKey;
+    use miniscript::{Descriptor, DescriptorTrait};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_wallet_export() {
+        let tr = |k| {
+            let key = XOnlyPublicKey::from_str(k).unwrap();
+            Object::from_descriptor(Descriptor::new_tr(key, None).unwrap(), None)
+        };
+        let mut root = tr("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798");
+        let child = tr("c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5");
+        let t: Template = test_ctx("export", 100_000)
+            .template()
+            .add_output(Amount::from_sat(1000), &child, None)
+            .unwrap()
+            .add_output(Amount::from_sat(2000), &child, None)
+            .unwrap()
+            .add_output(
+                Amount::from_sat(0),
+                &Object::from_op_return(&[0u8; 4][..]).unwrap(),
+                None,
+            )
+            .unwrap()
+            .into();
+        root.ctv_to_tx.insert(t.hash(), t);
+
+        let net = bitcoin::Network::Regtest;
+        let exports = root.wallet_export(net);
+        assert_eq!(exports.len(), 2);
+        for (e, o) in exports.iter().zip([&root, &child]) {
+            assert_eq!(e.address, o.address(net).unwrap());
+            assert_eq!(e.checksum.len(), 8);
+            assert!(e.descriptor.ends_with(&format!("#{}", e.checksum)));
+            // parsing checks the checksum
+            let d = Descriptor::<XOnlyPublicKey>::from_str(&e.descriptor).unwrap();
+            assert_eq!(d.address(net).unwrap(), e.address);
+            let bad = format!(
+                "{}#{}",
+                e.descriptor.rsplit_once('#').unwrap().0,
+                "00000000"
+            );
+            assert!(Descriptor::<XOnlyPublicKey>::from_str(&bad).is_err());
+        }
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! the fees paid across a compiled contract's template tree
+use crate::contract::object::Object;
+use crate::contract::CompilationError;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use std::collections::BTreeMap;
+
+/// How a template fares at a stress tested fee rate, see
+/// [`Object::stress_test_fees`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeeStressOutcome {
+    /// the template's fees cover the rate, or its outputs can make up the
+    /// shortfall
+    Ok,
+    /// making up the shortfall (e.g., by CPFP) leaves its outputs with less
+    /// than their dust thresholds
+    BelowDust,
+    /// the amount sent to the template doesn't even cover the fee
+    Negative,
+}
+
+/// A template's fees at a stress tested fee rate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateFeeStress {
+    /// the template's hash
+    pub template: sha256::Hash,
+    /// the fee committed to by the template
+    pub fee_budget: Amount,
+    /// the fee required at the stress tested rate
+    pub fee_required: Amount,
+    /// whether the template remains spendable
+    pub outcome: FeeStressOutcome,
+}
+
+/// The result of [`Object::stress_test_fees`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StressReport {
+    /// the fee rate, per vbyte, tested
+    pub fee_rate: Amount,
+    /// every template in the tree, by hash
+    pub templates: Vec<TemplateFeeStress>,
+}
+
+impl StressReport {
+    /// the templates which would go below dust or negative
+    pub fn failing(&self) -> Vec<&TemplateFeeStress> {
+        self.templates
+            .iter()
+            .filter(|t| t.outcome != FeeStressOutcome::Ok)
+            .collect()
+    }
+}
+
+impl Object {
+    /// The fees paid by every template in this Object's tree (CTV protected
+    /// and suggested), summed.
+    ///
+    /// Alternative templates can't all be confirmed, so this is an upper
+    /// bound on what the contract will actually pay.
+    pub fn total_fees(&self) -> Amount {
+        self.ctv_to_tx
+            .values()
+            .chain(self.suggested_txs.values())
+            .map(|t| {
+                t.max.checked_sub(t.total_amount()).unwrap_or(Amount::ZERO)
+                    + t.outputs
+                        .iter()
+                        .map(|o| o.contract.total_fees())
+                        .fold(Amount::ZERO, |a, b| a + b)
+            })
+            .fold(Amount::ZERO, |a, b| a + b)
+    }
+
+    /// Check [`Object::total_fees`] against `budget`, returning the total, or
+    /// [`CompilationError::FeeBudgetExceeded`] if it is over.
+    ///
+    /// Compilation checks this against [`crate::contract::Context::max_total_fees`].
+    pub fn total_fee_budget(&self, budget: Amount) -> Result<Amount, CompilationError> {
+        let total = self.total_fees();
+        if total > budget {
+            Err(CompilationError::FeeBudgetExceeded(total, budget))
+        } else {
+            Ok(total)
+        }
+    }
+
+    /// Check whether every template in this Object's tree would still be
+    /// spendable if fees rose to `high_rate` (per vbyte), since templates
+    /// committed to with CTV can't have their fees bumped.
+    ///
+    /// A template's vsize includes the largest witness spending the contract
+    /// it spends from, where that is known. Any shortfall against the fee
+    /// the template commits to is assumed to be paid out of its outputs
+    /// (e.g., by CPFP), so it fails if the amount sent to it can't pay the
+    /// fee, or if what is left is below the dust threshold of its outputs.
+    pub fn stress_test_fees(&self, high_rate: Amount) -> StressReport {
+        let mut templates = BTreeMap::new();
+        stress(self, high_rate, &mut templates);
+        StressReport {
+            fee_rate: high_rate,
+            templates: templates.into_values().collect(),
+        }
+    }
+}
+
+/// stress test the templates of `obj`, and of the contracts they create, at
+/// `rate`, adding them to `report`
+fn stress(obj: &Object, rate: Amount, report: &mut BTreeMap<sha256::Hash, TemplateFeeStress>) {
+    let witness = obj
+        .descriptor
+        .as_ref()
+        .and_then(|d| d.max_satisfaction_weight().ok())
+        .unwrap_or(0);
+    for (h, t) in obj.ctv_to_tx.iter().chain(obj.suggested_txs.iter()) {
+        let vsize = (t.tx.weight() + witness).div_ceil(4);
+        let fee_required = Amount::from_sat(rate.as_sat() * vsize as u64);
+        let dust = t
+            .outputs
+            .iter()
+            .map(|o| bitcoin::Script::from(o.contract.address.clone()).dust_value())
+            .fold(Amount::ZERO, |a, b| a + b);
+        let outcome = match t.max.checked_sub(fee_required) {
+            None => FeeStressOutcome::Negative,
+            Some(left) if left < t.total_amount() && left < dust => FeeStressOutcome::BelowDust,
+            Some(_) => FeeStressOutcome::Ok,
+        };
+        report.insert(
+            *h,
+            TemplateFeeStress {
+                template: *h,
+                fee_budget: t.max.checked_sub(t.total_amount()).unwrap_or(Amount::ZERO),
+                fee_required,
+                outcome,
+            },
+        );
+        for o in &t.outputs {
+            stress(&o.contract, rate, report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::test_ctx;
+    use crate::template::Template;
+
+    #[test]
+    fn test_stress_test_fees() {
+        // sends 1000 sats to a template paying 800 to a script, budgeting
+        // 200 for fees
+        let script = bitcoin::blockdata::script::Builder::new()
+            .push_int(1)
+            .into_script();
+        let t: Template = test_ctx("stress", 1000)
+            .template()
+            .add_raw_script_output(Amount::from_sat(800), script, None)
+            .unwrap()
+            .add_fees(Amount::from_sat(200))
+            .unwrap()
+            .into();
+        let mut o = Object::from_op_return(&[0u8; 4][..]).unwrap();
+        o.ctv_to_tx.insert(t.hash(), t.clone());
+        // at 1 sat/vbyte the fee required is the vsize
+        let calm = o.stress_test_fees(Amount::from_sat(1));
+        assert!(calm.failing().is_empty());
+        assert_eq!(calm.templates[0].fee_budget, Amount::from_sat(200));
+        let vsize = calm.templates[0].fee_required.as_sat();
+        // a rate leaving less than a vsize's worth of sats, well below dust
+        let tight = o.stress_test_fees(Amount::from_sat(1000 / vsize));
+        assert_eq!(tight.failing().len(), 1);
+        assert_eq!(tight.failing()[0].template, t.hash());
+        assert_eq!(tight.failing()[0].outcome, FeeStressOutcome::BelowDust);
+        let spike = o.stress_test_fees(Amount::from_sat(1000 / vsize + 1));
+        assert_eq!(spike.failing()[0].outcome, FeeStressOutcome::Negative);
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! the flow of value through a compiled contract's template tree
+use crate::contract::object::Object;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::Script;
+use serde::{Deserialize, Serialize};
+
+/// Where the value sent by a [`FlowEdge`] goes.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum FlowTarget {
+    /// a contract created by the output, with the hashes of its templates
+    /// (one of which will spend the output), which have edges of their own
+    Child(Vec<sha256::Hash>),
+    /// an output to a script which has no templates, e.g. a user's address
+    Output(Script),
+}
+
+/// The value a template sends to one of its outputs, see
+/// [`Object::sats_flow`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FlowEdge {
+    /// the hash of the template sending the value
+    pub from_template: sha256::Hash,
+    /// the index of the output in the template
+    pub output: usize,
+    /// where the value goes
+    pub to_output_or_child: FlowTarget,
+    /// the amount sent
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub amount: Amount,
+}
+
+impl Object {
+    /// Trace value through this Object's template tree (CTV protected and
+    /// suggested), with an edge for every output of every template, e.g. to
+    /// draw how the funds are split up.
+    ///
+    /// The edges out of a template sum to its total output amount. A
+    /// contract created by several templates has its edges listed once per
+    /// template.
+    pub fn sats_flow(&self) -> Vec<FlowEdge> {
+        let mut edges = vec![];
+        walk(self, &mut edges);
+        edges
+    }
+}
+
+/// add the edges out of `obj`'s templates, and their children's, to `edges`
+fn walk(obj: &Object, edges: &mut Vec<FlowEdge>) {
+    for t in obj.ctv_to_tx.values().chain(obj.suggested_txs.values()) {
+        for (i, o) in t.outputs.iter().enumerate() {
+            let children: Vec<sha256::Hash> = o
+                .contract
+                .ctv_to_tx
+                .keys()
+                .chain(o.contract.suggested_txs.keys())
+                .cloned()
+                .collect();
+            let to_output_or_child = if children.is_empty() {
+                FlowTarget::Output(o.contract.address.clone().into())
+            } else {
+                FlowTarget::Child(children)
+            };
+            edges.push(FlowEdge {
+                from_template: t.hash(),
+                output: i,
+                to_output_or_child,
+                amount: o.amount,
+            });
+            walk(&o.contract, edges);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::{test_compiled, test_template};
+
+    #[test]
+    fn test_sats_flow() {
+        let leaf = |b: u8| Object::from_op_return(&[b; 4][..]).unwrap();
+        // the root splits 6000 into a child and a leaf, and the child splits
+        // its 4000 into two leaves
+        let child_tmpl = test_template(&[(1000, &leaf(1)), (3000, &leaf(2))]);
+        let child = test_compiled(vec![child_tmpl.clone()]);
+        let root_tmpl = test_template(&[(4000, &child), (2000, &leaf(3))]);
+        let root = test_compiled(vec![root_tmpl.clone()]);
+        let edges = root.sats_flow();
+        assert_eq!(edges.len(), 4);
+        let out_of = |h: sha256::Hash| -> Amount {
+            edges
+                .iter()
+                .filter(|e| e.from_template == h)
+                .map(|e| e.amount)
+                .fold(Amount::ZERO, |a, b| a + b)
+        };
+        assert_eq!(out_of(root_tmpl.hash()), Amount::from_sat(6000));
+        assert_eq!(out_of(child_tmpl.hash()), root_tmpl.outputs[0].amount);
+        // the edge into the child names its template
+        let into_child = edges
+            .iter()
+            .find(|e| e.from_template == root_tmpl.hash() && e.output == 0)
+            .unwrap();
+        assert_eq!(
+            into_child.to_output_or_child,
+            FlowTarget::Child(vec![child_tmpl.hash()])
+        );
+        assert!(edges
+            .iter()
+            .filter(|e| e.from_template == child_tmpl.hash())
+            .all(|e| matches!(e.to_output_or_child, FlowTarget::Output(_))));
+    }
+}
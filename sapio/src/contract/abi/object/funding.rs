@@ -0,0 +1,241 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! selecting funding coins for an Object
+use crate::contract::object::Object;
+use crate::contract::object::ObjectError;
+use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut, Witness};
+
+/// Strategy used to pick funding coins from a set of candidates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoinSelection {
+    /// Take the largest coins first until the target is met
+    LargestFirst,
+    /// Find a set of coins summing to exactly the target, so no change is
+    /// required
+    Exact,
+}
+
+/// The nSequence [`Object::build_funding_psbt_rbf`] sets on each input, the
+/// highest which signals BIP-125 replaceability.
+pub const RBF_SEQUENCE: u32 = 0xfffffffd;
+
+/// The maximum number of candidates that `CoinSelection::Exact` will search
+/// over, to bound the (exponential) subset search.
+pub const MAX_EXACT_CANDIDATES: usize = 20;
+
+impl CoinSelection {
+    /// Select a subset of `candidates` worth at least (or, for
+    /// `CoinSelection::Exact`, exactly) `target`.
+    pub fn select(
+        self,
+        candidates: &[(OutPoint, TxOut)],
+        target: Amount,
+    ) -> Result<Vec<(OutPoint, TxOut)>, ObjectError> {
+        let mut sorted = candidates.to_vec();
+        sorted.sort_by_key(|(_, o)| std::cmp::Reverse(o.value));
+        match self {
+            CoinSelection::LargestFirst => {
+                let mut total = 0u64;
+                let chosen: Vec<_> = sorted
+                    .into_iter()
+                    .take_while(|(_, o)| {
+                        let need = total < target.as_sat();
+                        total += o.value;
+                        need
+                    })
+                    .collect();
+                if chosen.iter().map(|(_, o)| o.value).sum::<u64>() < target.as_sat() {
+                    return Err(ObjectError::CoinSelectionFailed(target));
+                }
+                Ok(chosen)
+            }
+            CoinSelection::Exact => {
+                if sorted.len() > MAX_EXACT_CANDIDATES {
+                    return Err(ObjectError::CoinSelectionFailed(target));
+                }
+                let mut chosen = vec![];
+                if exact_subset(&sorted, target.as_sat(), &mut chosen) {
+                    Ok(chosen.into_iter().map(|i| sorted[i].clone()).collect())
+                } else {
+                    Err(ObjectError::CoinSelectionFailed(target))
+                }
+            }
+        }
+    }
+}
+
+/// depth first search for a subset of `coins` (sorted largest first) summing to `remaining`.
+fn exact_subset(coins: &[(OutPoint, TxOut)], remaining: u64, chosen: &mut Vec<usize>) -> bool {
+    if remaining == 0 {
+        return true;
+    }
+    let start = chosen.last().map(|i| i + 1).unwrap_or(0);
+    for i in start..coins.len() {
+        let v = coins[i].1.value;
+        if v <= remaining {
+            chosen.push(i);
+            if exact_subset(coins, remaining - v, chosen) {
+                return true;
+            }
+            chosen.pop();
+        }
+    }
+    false
+}
+
+impl Object {
+    /// Creates an unsigned funding transaction sending `amount` to this
+    /// Object's address, spending coins picked from `candidates` with
+    /// `strategy`.
+    ///
+    /// Any excess over `amount` is sent to `change`. No fee is deducted, so
+    /// callers should include fees in `amount` or reduce the change output.
+    pub fn fund_from_candidates(
+        &self,
+        candidates: &[(OutPoint, TxOut)],
+        strategy: CoinSelection,
+        amount: Amount,
+        change: Script,
+    ) -> Result<Transaction, ObjectError> {
+        let coins = strategy.select(candidates, amount)?;
+        let total: u64 = coins.iter().map(|(_, o)| o.value).sum();
+        let mut output = vec![TxOut {
+            value: amount.as_sat(),
+            script_pubkey: self.address.clone().into(),
+        }];
+        if total > amount.as_sat() {
+            output.push(TxOut {
+                value: total - amount.as_sat(),
+                script_pubkey: change,
+            });
+        }
+        Ok(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: coins
+                .into_iter()
+                .map(|(previous_output, _)| TxIn {
+                    previous_output,
+                    script_sig: Default::default(),
+                    sequence: 0xffffffff,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output,
+        })
+    }
+
+    /// Creates an unsigned funding PSBT, as [`Object::fund_from_candidates`],
+    /// with every input signalling BIP-125 RBF (see [`RBF_SEQUENCE`]) so that
+    /// the wallet can bump the fee of the activation.
+    ///
+    /// Each input's `witness_utxo` is filled in from `candidates`.
+    pub fn build_funding_psbt_rbf(
+        &self,
+        candidates: &[(OutPoint, TxOut)],
+        strategy: CoinSelection,
+        amount: Amount,
+        change: Script,
+    ) -> Result<PartiallySignedTransaction, ObjectError> {
+        let mut tx = self.fund_from_candidates(candidates, strategy, amount, change)?;
+        for inp in tx.input.iter_mut() {
+            inp.sequence = RBF_SEQUENCE;
+        }
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx)
+            .expect("Unsigned Transaction Has No Witnesses");
+        for (inp, txin) in psbt.inputs.iter_mut().zip(psbt.unsigned_tx.input.iter()) {
+            inp.witness_utxo = candidates
+                .iter()
+                .find(|(o, _)| *o == txin.previous_output)
+                .map(|(_, txout)| txout.clone());
+        }
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+    fn candidates() -> Vec<(OutPoint, TxOut)> {
+        [1, 2, 5]
+            .iter()
+            .map(|v| {
+                (
+                    OutPoint::new(Txid::from_inner([*v as u8; 32]), 0),
+                    TxOut {
+                        value: v * 100_000,
+                        script_pubkey: Script::new(),
+                    },
+                )
+            })
+            .collect()
+    }
+    fn values(v: Vec<(OutPoint, TxOut)>) -> Vec<u64> {
+        v.into_iter().map(|(_, o)| o.value).collect()
+    }
+    #[test]
+    fn test_coin_selection() {
+        let target = Amount::from_sat(300_000);
+        assert_eq!(
+            values(
+                CoinSelection::LargestFirst
+                    .select(&candidates(), target)
+                    .unwrap()
+            ),
+            vec![500_000]
+        );
+        assert_eq!(
+            values(CoinSelection::Exact.select(&candidates(), target).unwrap()),
+            vec![200_000, 100_000]
+        );
+        assert!(CoinSelection::Exact
+            .select(&candidates(), Amount::from_sat(400_000))
+            .is_err());
+        assert_eq!(
+            values(
+                CoinSelection::LargestFirst
+                    .select(&candidates(), Amount::from_sat(600_000))
+                    .unwrap()
+            ),
+            vec![500_000, 200_000]
+        );
+        let o = Object::from_op_return(&[0u8; 4][..]).unwrap();
+        let tx = o
+            .fund_from_candidates(
+                &candidates(),
+                CoinSelection::LargestFirst,
+                target,
+                Script::new(),
+            )
+            .unwrap();
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(tx.output[1].value, 200_000);
+    }
+
+    #[test]
+    fn test_funding_psbt_rbf() {
+        let o = Object::from_op_return(&[0u8; 4][..]).unwrap();
+        let psbt = o
+            .build_funding_psbt_rbf(
+                &candidates(),
+                CoinSelection::Exact,
+                Amount::from_sat(300_000),
+                Script::new(),
+            )
+            .unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 2);
+        for (inp, txin) in psbt.inputs.iter().zip(psbt.unsigned_tx.input.iter()) {
+            // BIP-125 signalling is any sequence below 0xfffffffe
+            assert!(txin.sequence < 0xfffffffe);
+            assert!(inp.witness_utxo.is_some());
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! the Guards gating each of a compiled contract's actions
+use crate::contract::object::Object;
+use sapio_base::Clause;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// A Guard which gates an action, see [`Object::guards_for_path`].
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct GuardDescriptor {
+    /// the guard's position among the action's guards
+    pub index: usize,
+    /// the name of the guard function, if it was declared with `#[guard]` or
+    /// labeled with `Guard::named`
+    pub name: Option<String>,
+    /// the Clause the guard produced
+    pub clause: Clause,
+}
+
+impl Object {
+    /// The Guards which must be satisfied to take the action named `action`
+    /// (a `then` or `finish` function), in the order the action lists them.
+    ///
+    /// A guard shared by several actions is listed for each of them. Unknown
+    /// actions, and actions without guards, have none.
+    pub fn guards_for_path(&self, action: &str) -> Vec<GuardDescriptor> {
+        self.guards.get(action).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::actions::Guard;
+    use crate::contract::context::test_ctx;
+    use crate::contract::{Compilable, Compiled, Context, Contract, TxTmplIt};
+    use crate::{guard, then};
+    use bitcoin::XOnlyPublicKey;
+    use std::str::FromStr;
+
+    fn key(k: &str) -> Clause {
+        Clause::Key(XOnlyPublicKey::from_str(k).unwrap())
+    }
+    fn alice() -> Clause {
+        key("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+    }
+    fn bob() -> Clause {
+        key("c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5")
+    }
+
+    /// a contract whose `a` and `b` actions share a guard
+    struct Shared;
+    impl Shared {
+        fn common() -> Option<Guard<Self>> {
+            Some(Guard::Cache(|_, _| alice(), None))
+        }
+        #[guard]
+        fn extra(self, _ctx: Context) {
+            bob()
+        }
+        fn pay(&self, ctx: Context) -> TxTmplIt {
+            let amt = ctx.funds();
+            ctx.template()
+                .add_output(amt, &Compiled::from_op_return(&[0u8; 4][..])?, None)?
+                .into()
+        }
+        #[then(guarded_by = "[Self::common]")]
+        fn a(self, ctx: Context) {
+            self.pay(ctx)
+        }
+        #[then(guarded_by = "[Self::extra, Self::common]")]
+        fn b(self, ctx: Context) {
+            self.pay(ctx)
+        }
+        #[then]
+        fn c(self, ctx: Context) {
+            self.pay(ctx)
+        }
+    }
+    impl Contract for Shared {
+        declare! {then, Self::a, Self::b, Self::c}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_guards_for_path() {
+        let compiled = Shared.compile(test_ctx("guards", 100_000)).unwrap();
+        let clauses = |action| -> Vec<Clause> {
+            compiled
+                .guards_for_path(action)
+                .into_iter()
+                .map(|g| g.clause)
+                .collect()
+        };
+        assert_eq!(clauses("a"), vec![alice()]);
+        assert_eq!(clauses("b"), vec![bob(), alice()]);
+        let names: Vec<_> = compiled
+            .guards_for_path("b")
+            .into_iter()
+            .map(|g| (g.index, g.name))
+            .collect();
+        // only guards declared with #[guard] are named
+        assert_eq!(names, vec![(0, Some("extra".into())), (1, None)]);
+        assert!(clauses("c").is_empty());
+        assert!(clauses("missing").is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! checking that a compiled contract's templates chain together
+use crate::contract::object::Object;
+use crate::contract::CompilationError;
+use bitcoin::{OutPoint, Script};
+
+impl Object {
+    /// Check that every template in this Object's tree spends the output of
+    /// its parent template which creates the contract it spends, e.g. after
+    /// merging Objects or deserializing one from an untrusted source.
+    ///
+    /// Each template's transaction must pay each of its outputs' amount to
+    /// that output's contract, and the first input of each template of a
+    /// contract created at output `i` of a parent template must spend output
+    /// `i` of the parent's transaction. Templates which are not yet bound to
+    /// a funding outpoint (i.e., have a null first input) are allowed, as
+    /// the outpoint isn't known until the parent is.
+    ///
+    /// Errors with [`CompilationError::BrokenLinkage`] naming the first
+    /// template which does not chain.
+    pub fn validate_linkage(&self) -> Result<(), CompilationError> {
+        let mut stack = vec![self];
+        while let Some(obj) = stack.pop() {
+            for (h, t) in obj.ctv_to_tx.iter().chain(obj.suggested_txs.iter()) {
+                let broken = |reason: String| {
+                    CompilationError::BrokenLinkage(format!(
+                        "template {} of {}: {}",
+                        h,
+                        String::from(obj.root_path.0.as_ref().clone()),
+                        reason
+                    ))
+                };
+                if t.tx.output.len() != t.outputs.len() {
+                    return Err(broken(format!(
+                        "{} transaction outputs for {} contracts",
+                        t.tx.output.len(),
+                        t.outputs.len()
+                    )));
+                }
+                let txid = t.tx.txid();
+                for (vout, (txout, out)) in t.tx.output.iter().zip(t.outputs.iter()).enumerate() {
+                    if txout.script_pubkey != Script::from(out.contract.address.clone())
+                        || txout.value != out.amount.as_sat()
+                    {
+                        return Err(broken(format!("output {} does not pay its contract", vout)));
+                    }
+                    let parent = OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    };
+                    let children = out
+                        .contract
+                        .ctv_to_tx
+                        .iter()
+                        .chain(out.contract.suggested_txs.iter());
+                    for (child_h, child) in children {
+                        let spent = child.tx.input.first().map(|i| i.previous_output);
+                        if !matches!(spent, Some(o) if o.is_null() || o == parent) {
+                            return Err(broken(format!(
+                                "output {} is not spent by child template {}",
+                                vout, child_h
+                            )));
+                        }
+                    }
+                    stack.push(&out.contract);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::{test_compiled, test_template};
+
+    /// set the first input of the templates of the `i`th contract created by
+    /// `root` to spend `vout` of `root`'s template
+    fn bind_child(root: &mut Object, i: usize, vout: u32) {
+        let parent = root.ctv_to_tx.values_mut().next().unwrap();
+        let txid = parent.tx.txid();
+        for t in parent.outputs[i].contract.ctv_to_tx.values_mut() {
+            t.tx.input[0].previous_output = OutPoint { txid, vout };
+        }
+    }
+
+    #[test]
+    fn test_validate_linkage() {
+        let leaf = Object::from_op_return(&[1u8; 4][..]).unwrap();
+        let child = test_compiled(vec![test_template(&[(1000, &leaf)])]);
+        let mut root = test_compiled(vec![test_template(&[(1000, &leaf), (1000, &child)])]);
+        // unbound templates are allowed
+        assert!(root.validate_linkage().is_ok());
+        bind_child(&mut root, 1, 1);
+        assert!(root.validate_linkage().is_ok());
+
+        // the child spends its sibling's output
+        bind_child(&mut root, 1, 0);
+        assert!(matches!(
+            root.validate_linkage(),
+            Err(CompilationError::BrokenLinkage(_))
+        ));
+
+        // the parent doesn't pay the child
+        let mut root = test_compiled(vec![test_template(&[(1000, &leaf), (1000, &child)])]);
+        let parent = root.ctv_to_tx.values_mut().next().unwrap();
+        parent.tx.output[1].value -= 1;
+        match root.validate_linkage() {
+            Err(CompilationError::BrokenLinkage(e)) => {
+                assert!(e.contains("output 1"), "{}", e)
+            }
+            _ => panic!("expected BrokenLinkage"),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! matching transactions observed on chain to an Object's templates
+use crate::contract::object::Object;
+use bitcoin::hashes::sha256;
+use bitcoin::Transaction;
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::CTVHash;
+use serde::{Deserialize, Serialize};
+
+/// Which template of an Object a transaction matched, see
+/// [`Object::matches_transaction`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MatchInfo {
+    /// the hash of the matching template
+    pub template: sha256::Hash,
+    /// the root path of the (sub-)contract the template spends from
+    pub contract: SArc<EffectPath>,
+    /// true if the template is CTV protected, false if it was only suggested
+    pub committed: bool,
+}
+
+impl Object {
+    /// Find the template of this Object, or of any contract it creates, which
+    /// `tx` is an instance of.
+    ///
+    /// CTV protected templates match on the template hash, which ignores the
+    /// outpoints being spent and the witnesses. Suggested templates are not
+    /// committed to, so they match if the version, locktime, sequences, and
+    /// outputs are all the same.
+    ///
+    /// This Object's templates are checked before those of the contracts it
+    /// creates. If several created contracts share a template, the first one
+    /// found is reported.
+    pub fn matches_transaction(&self, tx: &Transaction) -> Option<MatchInfo> {
+        let h = tx.get_ctv_hash(0);
+        if let Some(t) = self.ctv_to_tx.get(&h) {
+            return Some(MatchInfo {
+                template: t.hash(),
+                contract: self.root_path.clone(),
+                committed: true,
+            });
+        }
+        if let Some(t) = self.suggested_txs.values().find(|t| {
+            t.tx.version == tx.version
+                && t.tx.lock_time == tx.lock_time
+                && t.tx.output == tx.output
+                && t.tx.input.len() == tx.input.len()
+                && t.tx
+                    .input
+                    .iter()
+                    .zip(tx.input.iter())
+                    .all(|(a, b)| a.sequence == b.sequence)
+        }) {
+            return Some(MatchInfo {
+                template: t.hash(),
+                contract: self.root_path.clone(),
+                committed: false,
+            });
+        }
+        self.ctv_to_tx
+            .values()
+            .chain(self.suggested_txs.values())
+            .flat_map(|t| t.outputs.iter())
+            .find_map(|o| o.contract.matches_transaction(tx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::test_ctx;
+    use crate::contract::{Compilable, Context, Contract};
+    use crate::then;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, Txid, Witness};
+
+    /// splits its funds into two, each half paid to another `Split` until
+    /// `depth` reaches 0
+    struct Split(u8);
+    impl Split {
+        #[then]
+        fn halve(self, ctx: Context) {
+            let amt = ctx.funds() / 2;
+            let mut tmpl = ctx.template();
+            for i in 0..2u8 {
+                tmpl = if self.0 == 0 {
+                    tmpl.add_output(amt, &Object::from_op_return(&[i; 4][..])?, None)?
+                } else {
+                    tmpl.add_output(amt, &Split(self.0 - 1), None)?
+                };
+            }
+            tmpl.into()
+        }
+    }
+    impl Contract for Split {
+        declare! {then, Self::halve}
+        declare! {non updatable}
+    }
+
+    /// what a template's transaction looks like once it is on chain
+    fn broadcast(mut tx: Transaction) -> Transaction {
+        tx.input[0].previous_output = OutPoint::new(Txid::hash(b"funding"), 1);
+        tx.input[0].witness = Witness::from_vec(vec![vec![1u8; 64]]);
+        tx
+    }
+
+    #[test]
+    fn test_matches_transaction() {
+        let o = Split(1).compile(test_ctx("watch", 100_000)).unwrap();
+        let top = o.ctv_to_tx.values().next().unwrap();
+        let m = o.matches_transaction(&broadcast(top.tx.clone())).unwrap();
+        assert_eq!(m.template, top.hash());
+        assert_eq!(m.contract, o.root_path);
+        assert!(m.committed);
+
+        // both children are identical, so the first is reported
+        let child = &top.outputs[0].contract;
+        let nested = child.ctv_to_tx.values().next().unwrap();
+        let m = o
+            .matches_transaction(&broadcast(nested.tx.clone()))
+            .unwrap();
+        assert_eq!(m.template, nested.hash());
+        assert_eq!(m.contract, child.root_path);
+        assert_ne!(m.contract, o.root_path);
+
+        let mut unrelated = broadcast(top.tx.clone());
+        unrelated.output[0].value -= 1;
+        assert!(o.matches_transaction(&unrelated).is_none());
+    }
+}
@@ -11,13 +11,39 @@ pub use error::*;
 pub mod bind;
 pub mod descriptors;
 pub use descriptors::*;
+pub mod funding;
+pub use funding::*;
+pub mod guards;
+pub use guards::*;
+pub mod diff;
+pub mod dot;
+pub mod fees;
+pub use fees::*;
+pub mod flow;
+pub use flow::*;
+pub mod linkage;
+pub mod policy;
+pub use diff::*;
+pub mod export;
+pub use export::*;
+pub mod matching;
+pub use matching::*;
+pub mod privacy;
+pub use privacy::*;
+pub mod satisfy;
+pub mod state_machine;
+pub mod stats;
 use sapio_base::simp::CompiledObjectLT;
 use sapio_base::simp::SIMPAttachableAt;
 use sapio_base::Clause;
+pub use satisfy::*;
 use serde_json::Value;
+pub use state_machine::*;
+pub use stats::*;
 
 use crate::contract::abi::continuation::ContinuationPoint;
 pub use crate::contract::abi::studio::*;
+use crate::contract::events::{Event, EventLog};
 use crate::contract::CompilationError;
 use crate::template::Template;
 use crate::util::amountrange::AmountRange;
@@ -69,10 +95,35 @@ impl ObjectMetadata {
         }
     }
 
+    /// Combine two metadata sets, failing with
+    /// [`CompilationError::MergeConflict`] if a key or SIMP is set in both.
+    /// SIMPs for guards are concatenated.
+    pub fn merge(mut self, other: ObjectMetadata) -> Result<Self, CompilationError> {
+        for (k, v) in other.extra {
+            if self.extra.contains_key(&k) {
+                return Err(CompilationError::MergeConflict(format!("metadata {}", k)));
+            }
+            self.extra.insert(k, v);
+        }
+        for (k, v) in other.simp {
+            if self.simp.contains_key(&k) {
+                return Err(CompilationError::MergeConflict(format!("simp {}", k)));
+            }
+            self.simp.insert(k, v);
+        }
+        for (clause, simps) in other.simps_for_guards {
+            let entry = self.simps_for_guards.entry(clause).or_default();
+            for (k, mut v) in simps {
+                entry.entry(k).or_default().append(&mut v);
+            }
+        }
+        Ok(self)
+    }
+
     pub(crate) fn add_guard_simps(
         mut self,
         all_guard_simps: BTreeMap<
-            policy::Concrete<bitcoin::XOnlyPublicKey>,
+            ::miniscript::policy::Concrete<bitcoin::XOnlyPublicKey>,
             Vec<Arc<dyn SIMPAttachableAt<sapio_base::simp::GuardLT>>>,
         >,
     ) -> Result<ObjectMetadata, CompilationError> {
@@ -149,6 +200,18 @@ pub struct Object {
     pub amount_range: AmountRange,
     /// metadata generated for this contract
     pub metadata: ObjectMetadata,
+    /// events emitted while compiling this contract, see
+    /// [`crate::contract::Context::emit`]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub events: EventLog,
+    /// the Guards gating each action, by action name, see
+    /// [`Object::guards_for_path`]
+    #[serde(
+        rename = "guards_by_action",
+        skip_serializing_if = "BTreeMap::is_empty",
+        default
+    )]
+    pub guards: BTreeMap<String, Vec<GuardDescriptor>>,
 }
 
 impl Object {
@@ -172,6 +235,8 @@ impl Object {
                 a
             }),
             metadata: Default::default(),
+            events: Default::default(),
+            guards: Default::default(),
         }
     }
 
@@ -203,9 +268,128 @@ impl Object {
             descriptor: None,
             amount_range: AmountRange::new(),
             metadata: Default::default(),
+            events: Default::default(),
+            guards: Default::default(),
         })
     }
 
+    /// Combine two independently compiled objects sharing a funding root (the
+    /// same address and descriptor) into one, keeping `self`'s root path.
+    ///
+    /// The template sets, continuation points, metadata, and amount ranges
+    /// are unioned. A differing address or descriptor, or any template hash
+    /// (CTV commitment), continuation point, or metadata key defined by both
+    /// objects is reported as a [`CompilationError::MergeConflict`]. Events,
+    /// and the guards of actions with the same name, are concatenated.
+    pub fn merge(mut self, other: Object) -> Result<Object, CompilationError> {
+        let Object {
+            ctv_to_tx,
+            suggested_txs,
+            continue_apis,
+            address,
+            descriptor,
+            amount_range,
+            metadata,
+            events,
+            guards,
+            ..
+        } = other;
+        if bitcoin::Script::from(self.address.clone()) != bitcoin::Script::from(address) {
+            return Err(CompilationError::MergeConflict("address".into()));
+        }
+        if self.descriptor != descriptor {
+            return Err(CompilationError::MergeConflict("descriptor".into()));
+        }
+        if let Some(h) = ctv_to_tx
+            .keys()
+            .chain(suggested_txs.keys())
+            .find(|h| self.ctv_to_tx.contains_key(*h) || self.suggested_txs.contains_key(*h))
+        {
+            return Err(CompilationError::MergeConflict(format!("template {}", h)));
+        }
+        self.ctv_to_tx.extend(ctv_to_tx);
+        self.suggested_txs.extend(suggested_txs);
+        for (k, v) in continue_apis {
+            if self.continue_apis.insert(k.clone(), v).is_some() {
+                return Err(CompilationError::MergeConflict(format!(
+                    "continuation {}",
+                    String::from(k.0.as_ref().clone())
+                )));
+            }
+        }
+        self.amount_range.merge(&amount_range);
+        self.metadata = self.metadata.merge(metadata)?;
+        for (name, mut v) in events {
+            self.events.entry(name).or_default().append(&mut v);
+        }
+        for (action, mut v) in guards {
+            self.guards.entry(action).or_default().append(&mut v);
+        }
+        Ok(self)
+    }
+
+    /// The events of type `E` emitted while compiling this contract, in the
+    /// order emitted, see [`crate::contract::Context::emit`].
+    pub fn events<E: Event>(&self) -> Result<Vec<E>, CompilationError> {
+        self.events
+            .get(E::NAME)
+            .map_or(&[][..], |v| &v[..])
+            .iter()
+            .map(|v| {
+                serde_json::from_value(v.clone()).map_err(CompilationError::DeserializationError)
+            })
+            .collect()
+    }
+
+    /// The address to send funds to in order to activate this Object, for
+    /// `network`.
+    ///
+    /// Errors if the Object's output has no address form (e.g., OP_RETURN).
+    pub fn address(&self, network: bitcoin::Network) -> Result<bitcoin::Address, CompilationError> {
+        let address = match (&self.descriptor, &self.address) {
+            (Some(SupportedDescriptors::Pk(d)), _) => d.address(network)?,
+            (Some(SupportedDescriptors::XOnly(d)), _) => d.address(network)?,
+            (Some(SupportedDescriptors::RawWsh(s)), _) => bitcoin::Address::p2wsh(s, network),
+            (None, ExtendedAddress::Address(a)) => bitcoin::Address {
+                payload: a.payload.clone(),
+                network,
+            },
+            (None, ExtendedAddress::Descriptor(d)) => d.address(network)?,
+            (None, a) => {
+                let script: bitcoin::Script = a.clone().into();
+                bitcoin::Address::from_script(&script, network)
+                    .ok_or(ObjectError::UnknownScriptType(script))?
+            }
+        };
+        Ok(address)
+    }
+
+    /// The address and amount of every output of this Object's templates (CTV
+    /// protected first, then suggested), for `network`, e.g. to show a user
+    /// where the funds go.
+    ///
+    /// Outputs with no address form (e.g., OP_RETURN) are omitted.
+    pub fn outputs(&self, network: bitcoin::Network) -> Vec<(bitcoin::Address, Amount)> {
+        self.ctv_to_tx
+            .values()
+            .chain(self.suggested_txs.values())
+            .flat_map(|t| t.outputs.iter())
+            .filter_map(|o| Some((o.contract.address(network).ok()?, o.amount)))
+            .collect()
+    }
+
+    /// The weight of the largest witness any spend of this Object could
+    /// require, across all of its spending paths, e.g. to reserve enough fees
+    /// for whichever path ends up being used.
+    ///
+    /// Errors if the Object has no known descriptor.
+    pub fn max_satisfaction_weight(&self) -> Result<usize, CompilationError> {
+        match &self.descriptor {
+            Some(d) => Ok(d.max_satisfaction_weight()?),
+            None => Err(ObjectError::UnknownScriptType(self.address.clone().into()).into()),
+        }
+    }
+
     /// converts a descriptor and an optional AmountRange to a Object object.
     /// This can be used for e.g. creating raw SegWit Scripts.
     pub fn from_descriptor<T>(d: Descriptor<T>, a: Option<AmountRange>) -> Self
@@ -230,6 +414,167 @@ impl Object {
                 a
             }),
             metadata: Default::default(),
+            events: Default::default(),
+            guards: Default::default(),
+        }
+    }
+
+    /// Creates an object paying (as P2WSH) to the hand-written witness
+    /// script `script`, bypassing miniscript entirely. The optional
+    /// AmountRange argument determines the safe bounds the contract can
+    /// receive, otherwise it is set to any.
+    ///
+    /// The script is carried verbatim into the `witness_script` of PSBTs
+    /// spending the object.
+    pub fn from_raw_script(script: bitcoin::Script, a: Option<AmountRange>) -> Self {
+        let address = bitcoin::Address::p2wsh(&script, bitcoin::Network::Bitcoin);
+        Object {
+            descriptor: Some(SupportedDescriptors::RawWsh(script)),
+            ..Object::from_address(address, a)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::test_ctx;
+    use crate::contract::Context;
+    use crate::guard;
+    use std::str::FromStr;
+    fn template(n: u8) -> Template {
+        test_ctx("merge", 100_000)
+            .template()
+            .add_output(
+                Amount::from_sat(1000),
+                &Object::from_op_return(&[n; 4][..]).unwrap(),
+                None,
+            )
+            .unwrap()
+            .into()
+    }
+    fn object(ts: &[u8]) -> Object {
+        let mut o = Object::from_op_return(&[0u8; 4][..]).unwrap();
+        for t in ts.iter().map(|n| template(*n)) {
+            o.ctv_to_tx.insert(t.hash(), t);
         }
+        o
+    }
+    #[test]
+    fn test_address() {
+        let key = bitcoin::XOnlyPublicKey::from_str(
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let d = Descriptor::<bitcoin::XOnlyPublicKey>::new_tr(key, None).unwrap();
+        let o = Object::from_descriptor(d.clone(), None);
+        let a = o.address(bitcoin::Network::Regtest).unwrap();
+        assert_eq!(a, d.address(bitcoin::Network::Regtest).unwrap());
+        assert!(a.to_string().starts_with("bcrt1p"));
+        assert!(object(&[]).address(bitcoin::Network::Regtest).is_err());
+    }
+    #[test]
+    fn test_outputs() {
+        let objects: Vec<Object> = [
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+        ]
+        .iter()
+        .map(|k| {
+            let key = bitcoin::XOnlyPublicKey::from_str(k).unwrap();
+            Object::from_descriptor(Descriptor::new_tr(key, None).unwrap(), None)
+        })
+        .collect();
+        let t: Template = test_ctx("outputs", 100_000)
+            .template()
+            .add_output(Amount::from_sat(1000), &objects[0], None)
+            .unwrap()
+            .add_output(Amount::from_sat(2000), &objects[1], None)
+            .unwrap()
+            .add_output(
+                Amount::from_sat(0),
+                &Object::from_op_return(&[0u8; 4][..]).unwrap(),
+                None,
+            )
+            .unwrap()
+            .into();
+        let mut o = object(&[]);
+        o.ctv_to_tx.insert(t.hash(), t);
+        let net = bitcoin::Network::Regtest;
+        assert_eq!(
+            o.outputs(net),
+            vec![
+                (objects[0].address(net).unwrap(), Amount::from_sat(1000)),
+                (objects[1].address(net).unwrap(), Amount::from_sat(2000)),
+            ]
+        );
+    }
+    /// spendable by any of the key sets in `self.0`, each using every key in
+    /// its set
+    struct Paths(Vec<Vec<u8>>);
+    fn key(i: u8) -> bitcoin::XOnlyPublicKey {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        bitcoin::KeyPair::from_seckey_slice(&secp, &[i; 32])
+            .unwrap()
+            .public_key()
+            .into()
+    }
+    impl Paths {
+        fn path(&self, i: usize) -> Clause {
+            let keys: Vec<Clause> = self.0[i].iter().map(|k| Clause::Key(key(*k))).collect();
+            match keys.len() {
+                1 => keys[0].clone(),
+                n => Clause::Threshold(n, keys),
+            }
+        }
+        #[guard]
+        fn first(self, _ctx: Context) {
+            self.path(0)
+        }
+        #[guard]
+        fn second(self, _ctx: Context) {
+            self.path(1)
+        }
+    }
+    impl crate::contract::Contract for Paths {
+        declare! {finish, Self::first, Self::second}
+        declare! {non updatable}
+    }
+    fn weight(paths: &[&[u8]]) -> usize {
+        use crate::contract::Compilable;
+        Paths(paths.iter().map(|p| p.to_vec()).collect())
+            .compile(test_ctx("weight", 100_000))
+            .unwrap()
+            .max_satisfaction_weight()
+            .unwrap()
+    }
+    #[test]
+    fn test_max_satisfaction_weight() {
+        // the 3 key path is the most expensive, wherever it is
+        let mixed = weight(&[&[1], &[2, 3, 4]]);
+        assert_eq!(mixed, weight(&[&[2, 3, 4], &[5]]));
+        assert!(mixed > weight(&[&[1], &[5]]));
+        assert!(object(&[]).max_satisfaction_weight().is_err());
+    }
+    #[test]
+    fn test_merge() {
+        let merged = object(&[1]).merge(object(&[2])).unwrap();
+        assert_eq!(merged.ctv_to_tx.len(), 2);
+        assert!(matches!(
+            object(&[1, 2]).merge(object(&[2, 3])),
+            Err(CompilationError::MergeConflict(_))
+        ));
+        // the objects must share a funding root
+        let elsewhere = Object::from_op_return(&[1u8; 4][..]).unwrap();
+        assert!(matches!(
+            object(&[1]).merge(elsewhere),
+            Err(CompilationError::MergeConflict(s)) if s == "address"
+        ));
+        let mut described = object(&[2]);
+        described.descriptor = Some(SupportedDescriptors::RawWsh(bitcoin::Script::new()));
+        assert!(matches!(
+            object(&[1]).merge(described),
+            Err(CompilationError::MergeConflict(s)) if s == "descriptor"
+        ));
     }
 }
@@ -0,0 +1,115 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! checking a compiled contract's script against the intended policy
+use crate::contract::compiler::default_internal_key;
+use crate::contract::object::{Object, ObjectError, SupportedDescriptors};
+use crate::contract::CompilationError;
+use ::miniscript::policy::semantic::Policy;
+use ::miniscript::policy::Liftable;
+use ::miniscript::*;
+use bitcoin::XOnlyPublicKey;
+use sapio_base::Clause;
+
+impl Object {
+    /// Check that this Object's script is semantically the policy
+    /// `expected`, e.g. to catch the compiler producing something other than
+    /// what was intended.
+    ///
+    /// Both are lifted to semantic policies and normalized before comparing,
+    /// so e.g. the order of branches doesn't matter. The Taproot internal key
+    /// is ignored when it is the (unspendable) default one. Mismatches are
+    /// reported as [`CompilationError::PolicyMismatch`].
+    ///
+    /// Only Taproot outputs can be checked, anything else is reported as
+    /// [`ObjectError::UnknownScriptType`].
+    pub fn verify_against_policy(&self, expected: &Clause) -> Result<(), CompilationError> {
+        let compiled = match &self.descriptor {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(t)))
+                if *t.internal_key() == default_internal_key() =>
+            {
+                Policy::Threshold(
+                    1,
+                    t.iter_scripts()
+                        .map(|(_, ms)| ms.lift())
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            Some(SupportedDescriptors::XOnly(d)) => d.lift()?,
+            // ECDSA descriptors lift to hashes of full public keys, which
+            // can't be compared to the x-only keys of a Clause
+            _ => return Err(ObjectError::UnknownScriptType(self.address.clone().into()).into()),
+        };
+        let compiled: Policy<XOnlyPublicKey> = compiled.normalized().sorted();
+        let expected = expected.lift()?.normalized().sorted();
+        if compiled == expected {
+            Ok(())
+        } else {
+            Err(CompilationError::PolicyMismatch(
+                expected.to_string(),
+                compiled.to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::test_ctx;
+    use crate::contract::{Compilable, Context, Contract};
+    use crate::guard;
+
+    fn key(i: u8) -> XOnlyPublicKey {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        bitcoin::KeyPair::from_seckey_slice(&secp, &[i; 32])
+            .unwrap()
+            .public_key()
+            .into()
+    }
+
+    /// three keys, each able to spend after its own timelock
+    struct Timelocked;
+    impl Timelocked {
+        #[guard]
+        fn after_300(self, _ctx: Context) {
+            Clause::And(vec![Clause::Key(key(1)), Clause::After(300)])
+        }
+        #[guard]
+        fn after_100(self, _ctx: Context) {
+            Clause::And(vec![Clause::Key(key(2)), Clause::After(100)])
+        }
+        #[guard]
+        fn after_200(self, _ctx: Context) {
+            Clause::And(vec![Clause::Key(key(3)), Clause::After(200)])
+        }
+    }
+    impl Contract for Timelocked {
+        declare! {finish, Self::after_300, Self::after_100, Self::after_200}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_verify_against_policy() {
+        let o = Timelocked.compile(test_ctx("policy", 1_000_000)).unwrap();
+        let policy = |first_lock| {
+            Clause::Threshold(
+                1,
+                vec![
+                    Clause::And(vec![Clause::Key(key(2)), Clause::After(100)]),
+                    Clause::And(vec![Clause::Key(key(1)), Clause::After(first_lock)]),
+                    Clause::And(vec![Clause::Key(key(3)), Clause::After(200)]),
+                ],
+            )
+        };
+        // the order of branches doesn't matter
+        assert!(o.verify_against_policy(&policy(300)).is_ok());
+        assert!(matches!(
+            o.verify_against_policy(&policy(301)),
+            Err(CompilationError::PolicyMismatch(..))
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! heuristics for privacy leaks in a compiled contract
+use crate::contract::compiler::default_internal_key;
+use crate::contract::object::Object;
+use bitcoin::Script;
+use bitcoin::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Output scripts and keys which appear more than once in a contract's
+/// template tree, see [`Object::privacy_report`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct PrivacyReport {
+    /// scripts paid to more than once, with how many times they are paid to
+    pub reused_scripts: Vec<(Script, usize)>,
+    /// keys used by more than one contract, with how many contracts use them
+    pub reused_keys: Vec<(XOnlyPublicKey, usize)>,
+}
+
+impl PrivacyReport {
+    /// was no reuse found?
+    pub fn is_clean(&self) -> bool {
+        self.reused_scripts.is_empty() && self.reused_keys.is_empty()
+    }
+}
+
+impl Object {
+    /// Find scriptPubKeys and keys which are reused across this Object's
+    /// template tree (CTV protected and suggested), which let an observer
+    /// link the transactions using them.
+    ///
+    /// This is a heuristic: a script paid to by two alternative templates is
+    /// flagged even though only one of them can be confirmed. OP_RETURN
+    /// outputs, and the default Taproot internal key of contracts with no
+    /// key path, are not considered.
+    pub fn privacy_report(&self) -> PrivacyReport {
+        let mut scripts = BTreeMap::new();
+        let mut keys = BTreeMap::new();
+        count_reuse(self, &mut scripts, &mut keys);
+        let default_key = default_internal_key();
+        PrivacyReport {
+            reused_scripts: scripts.into_iter().filter(|(_, n)| *n > 1).collect(),
+            reused_keys: keys
+                .into_iter()
+                .filter(|(k, n)| *n > 1 && *k != default_key)
+                .collect(),
+        }
+    }
+}
+
+/// count `obj`'s script and, the first time it is seen, its keys, then
+/// recurse into the contracts its templates create.
+///
+/// OP_RETURNs are not counted, but are still recursed into.
+fn count_reuse(
+    obj: &Object,
+    scripts: &mut BTreeMap<Script, usize>,
+    keys: &mut BTreeMap<XOnlyPublicKey, usize>,
+) {
+    let script: Script = obj.address.clone().into();
+    if !script.is_op_return() {
+        let seen = scripts.entry(script).or_default();
+        *seen += 1;
+        if *seen > 1 {
+            // same contract as one already counted
+            return;
+        }
+    }
+    if let Some(d) = &obj.descriptor {
+        let mut ks = d.keys();
+        ks.sort();
+        ks.dedup();
+        for k in ks {
+            *keys.entry(k).or_default() += 1;
+        }
+    }
+    for t in obj.ctv_to_tx.values().chain(obj.suggested_txs.values()) {
+        for o in &t.outputs {
+            count_reuse(&o.contract, scripts, keys);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::{test_compiled, test_template};
+    use bitcoin::PublicKey;
+    use miniscript::Descriptor;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_privacy_report() {
+        let pk = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let key = XOnlyPublicKey::from(pk.inner);
+        let other = XOnlyPublicKey::from_str(
+            "c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+        )
+        .unwrap();
+        let tr = |k| Object::from_descriptor(Descriptor::new_tr(k, None).unwrap(), None);
+        let wpkh = Object::from_descriptor(Descriptor::new_wpkh(pk).unwrap(), None);
+
+        let paying = |outputs: &[(u64, &Object)]| test_compiled(vec![test_template(outputs)]);
+        assert!(paying(&[(1000, &tr(key)), (2000, &tr(other))])
+            .privacy_report()
+            .is_clean());
+
+        // the same address twice, and the same key in two different scripts
+        let report = paying(&[(1000, &tr(key)), (2000, &tr(key)), (3000, &wpkh)]).privacy_report();
+        assert_eq!(
+            report.reused_scripts,
+            vec![(Script::from(tr(key).address), 2)]
+        );
+        assert_eq!(report.reused_keys, vec![(key, 2)]);
+    }
+}
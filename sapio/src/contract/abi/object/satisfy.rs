@@ -0,0 +1,283 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! producing the witness to spend an Object
+use super::bind::taproot_spend_info;
+use super::descriptors::SupportedDescriptors;
+use crate::contract::object::Object;
+use crate::contract::object::ObjectError;
+use crate::contract::CompilationError;
+use ::miniscript::*;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::taproot::LeafVersion;
+use bitcoin::{Script, Witness, XOnlyPublicKey};
+
+/// Which spending path of an Object a witness should be produced for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpendPath {
+    /// Spend with a signature for the (tweaked) Taproot internal key
+    KeyPath,
+    /// Spend via the Taproot leaf with this script
+    ScriptPath(Script),
+}
+
+impl Object {
+    /// Produce the witness spending this Object via `path`, using the
+    /// signatures, preimages, and timelocks made available by `satisfier`.
+    ///
+    /// For a script path spend the witness includes the leaf script and
+    /// control block. Only Taproot descriptors are supported.
+    pub fn satisfy<S: Satisfier<XOnlyPublicKey>>(
+        &self,
+        path: &SpendPath,
+        satisfier: S,
+    ) -> Result<Witness, CompilationError> {
+        let tr = match &self.descriptor {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => t,
+            _ => {
+                return Err(ObjectError::UnknownScriptType(self.address.clone().into()).into());
+            }
+        };
+        let stack = match path {
+            SpendPath::KeyPath => vec![satisfier
+                .lookup_tap_key_spend_sig()
+                .ok_or(::miniscript::Error::CouldNotSatisfy)?
+                .to_vec()],
+            SpendPath::ScriptPath(script) => {
+                let ms = tr
+                    .iter_scripts()
+                    .map(|(_, ms)| ms)
+                    .find(|ms| ms.encode() == *script)
+                    .ok_or_else(|| ObjectError::UnknownScriptType(script.clone()))?;
+                let info = taproot_spend_info(tr, &Secp256k1::verification_only())?;
+                let cb = info
+                    .control_block(&(script.clone(), LeafVersion::TapScript))
+                    .expect("Leaf must be present");
+                let mut stack = ms.satisfy(satisfier)?;
+                stack.push(script.to_bytes());
+                stack.push(cb.serialize());
+                stack
+            }
+        };
+        Ok(Witness::from_vec(stack))
+    }
+
+    /// Produce the smallest witness spending this Object, using whichever
+    /// spending paths `satisfier` can satisfy.
+    ///
+    /// When several paths (e.g., overlapping `finish` and `then` branches)
+    /// can be satisfied, the choice is deterministic: the witness with the
+    /// lowest serialized size wins, and ties are broken by preferring the
+    /// key path and then the leaf script which sorts first. The result
+    /// therefore doesn't depend on the order of leaves in the Taproot tree.
+    pub fn satisfy_cheapest<S: Satisfier<XOnlyPublicKey>>(
+        &self,
+        satisfier: S,
+    ) -> Result<(SpendPath, Witness), CompilationError> {
+        let tr = match &self.descriptor {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => t,
+            _ => {
+                return Err(ObjectError::UnknownScriptType(self.address.clone().into()).into());
+            }
+        };
+        std::iter::once(SpendPath::KeyPath)
+            .chain(
+                tr.iter_scripts()
+                    .map(|(_, ms)| SpendPath::ScriptPath(ms.encode())),
+            )
+            .filter_map(|path| {
+                let w = self.satisfy(&path, &satisfier).ok()?;
+                Some((path, w))
+            })
+            .min_by(|(p1, w1), (p2, w2)| {
+                let script = |p: &SpendPath| match p {
+                    SpendPath::KeyPath => None,
+                    SpendPath::ScriptPath(s) => Some(s.clone()),
+                };
+                (w1.serialized_len(), script(p1)).cmp(&(w2.serialized_len(), script(p2)))
+            })
+            .ok_or_else(|| ::miniscript::Error::CouldNotSatisfy.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::schnorr::TapTweak;
+    use bitcoin::secp256k1::Message;
+    use bitcoin::util::sighash::{Prevouts, SchnorrSighashType, SighashCache};
+    use bitcoin::util::taproot::TapLeafHash;
+    use bitcoin::{KeyPair, SchnorrSig, Transaction, TxIn, TxOut};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    struct KeySpend(SchnorrSig);
+    impl Satisfier<XOnlyPublicKey> for KeySpend {
+        fn lookup_tap_key_spend_sig(&self) -> Option<SchnorrSig> {
+            Some(self.0)
+        }
+    }
+
+    fn spend(o: &Object) -> (Transaction, TxOut) {
+        let prevout = TxOut {
+            value: 10_000,
+            script_pubkey: o.address.clone().into(),
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 9_000,
+                script_pubkey: Script::new_op_return(&[]),
+            }],
+        };
+        (tx, prevout)
+    }
+
+    fn sighash(tx: &Transaction, prevout: &TxOut, leaf: Option<TapLeafHash>) -> Message {
+        let mut cache = SighashCache::new(tx);
+        let prevouts = [prevout.clone()];
+        let h = match leaf {
+            Some(l) => cache.taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(&prevouts),
+                l,
+                SchnorrSighashType::Default,
+            ),
+            None => cache.taproot_key_spend_signature_hash(
+                0,
+                &Prevouts::All(&prevouts),
+                SchnorrSighashType::Default,
+            ),
+        }
+        .unwrap();
+        Message::from_digest_slice(&h.into_inner()[..]).unwrap()
+    }
+
+    fn check(tx: &mut Transaction, prevout: &TxOut, w: Witness) {
+        tx.input[0].witness = w;
+        let secp = Secp256k1::new();
+        let interp = Interpreter::from_txdata(
+            &prevout.script_pubkey,
+            &tx.input[0].script_sig,
+            &tx.input[0].witness,
+            0,
+            0,
+            Default::default(),
+        )
+        .unwrap();
+        let prevouts = [prevout.clone()];
+        let checked: Vec<_> = interp
+            .iter(&secp, tx, 0, &Prevouts::All(&prevouts))
+            .collect();
+        assert!(!checked.is_empty());
+        assert!(checked.iter().all(|r| r.is_ok()), "{:?}", checked);
+    }
+
+    #[test]
+    fn test_satisfy() {
+        let secp = Secp256k1::new();
+        let internal = KeyPair::from_seckey_slice(&secp, &[1u8; 32]).unwrap();
+        let leaf_key = KeyPair::from_seckey_slice(&secp, &[2u8; 32]).unwrap();
+        let leaf_pk = XOnlyPublicKey::from_keypair(&leaf_key).0;
+        let d = Descriptor::<XOnlyPublicKey>::from_str(&format!(
+            "tr({},pk({}))",
+            XOnlyPublicKey::from_keypair(&internal).0,
+            leaf_pk
+        ))
+        .unwrap();
+        let o = Object::from_descriptor(d, None);
+        let info = match &o.descriptor {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
+                taproot_spend_info(t, &secp).unwrap()
+            }
+            _ => unreachable!(),
+        };
+
+        // key path
+        let (mut tx, prevout) = spend(&o);
+        let tweaked = internal.tap_tweak(&secp, info.merkle_root()).into_inner();
+        let sig = SchnorrSig {
+            sig: secp.sign_schnorr_no_aux_rand(&sighash(&tx, &prevout, None), &tweaked),
+            hash_ty: SchnorrSighashType::Default,
+        };
+        let w = o.satisfy(&SpendPath::KeyPath, KeySpend(sig)).unwrap();
+        assert_eq!(w.len(), 1);
+        check(&mut tx, &prevout, w);
+
+        // script path
+        let (mut tx, prevout) = spend(&o);
+        let script = Script::from_str(&format!("20{}ac", leaf_pk)).unwrap();
+        let tlh = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+        let sig = SchnorrSig {
+            sig: secp.sign_schnorr_no_aux_rand(&sighash(&tx, &prevout, Some(tlh)), &leaf_key),
+            hash_ty: SchnorrSighashType::Default,
+        };
+        let sigs: HashMap<(XOnlyPublicKey, TapLeafHash), SchnorrSig> =
+            vec![((leaf_pk, tlh), sig)].into_iter().collect();
+        assert!(o
+            .satisfy(&SpendPath::ScriptPath(script.clone()), KeySpend(sig))
+            .is_err());
+        let w = o.satisfy(&SpendPath::ScriptPath(script), &sigs).unwrap();
+        assert_eq!(w.len(), 3);
+        check(&mut tx, &prevout, w);
+    }
+
+    #[test]
+    fn test_satisfy_cheapest() {
+        let secp = Secp256k1::new();
+        let internal =
+            XOnlyPublicKey::from_keypair(&KeyPair::from_seckey_slice(&secp, &[1u8; 32]).unwrap()).0;
+        let a = KeyPair::from_seckey_slice(&secp, &[2u8; 32]).unwrap();
+        let b = KeyPair::from_seckey_slice(&secp, &[3u8; 32]).unwrap();
+        let (a_pk, b_pk) = (
+            XOnlyPublicKey::from_keypair(&a).0,
+            XOnlyPublicKey::from_keypair(&b).0,
+        );
+        let small = format!("pk({})", a_pk);
+        let large = format!("and_v(v:pk({}),pk({}))", b_pk, a_pk);
+        let mut chosen = vec![];
+        // both leaf orders choose the same, smaller, satisfaction
+        for (l, r) in [(&small, &large), (&large, &small)] {
+            let d = Descriptor::<XOnlyPublicKey>::from_str(&format!(
+                "tr({},{{{},{}}})",
+                internal, l, r
+            ))
+            .unwrap();
+            let o = Object::from_descriptor(d.clone(), None);
+            let (mut tx, prevout) = spend(&o);
+            let mut sigs: HashMap<(XOnlyPublicKey, TapLeafHash), SchnorrSig> = HashMap::new();
+            if let Descriptor::Tr(t) = &d {
+                for (_, ms) in t.iter_scripts() {
+                    let tlh = TapLeafHash::from_script(&ms.encode(), LeafVersion::TapScript);
+                    for k in [&a, &b] {
+                        let sig = SchnorrSig {
+                            sig: secp
+                                .sign_schnorr_no_aux_rand(&sighash(&tx, &prevout, Some(tlh)), k),
+                            hash_ty: SchnorrSighashType::Default,
+                        };
+                        sigs.insert((XOnlyPublicKey::from_keypair(k).0, tlh), sig);
+                    }
+                }
+            }
+            let (path, w) = o.satisfy_cheapest(&sigs).unwrap();
+            assert_eq!(w.len(), 3);
+            check(&mut tx, &prevout, w);
+            chosen.push(path);
+        }
+        let script = Script::from_str(&format!("20{}ac", a_pk)).unwrap();
+        assert_eq!(chosen, vec![SpendPath::ScriptPath(script); 2]);
+
+        let none: HashMap<(XOnlyPublicKey, TapLeafHash), SchnorrSig> = HashMap::new();
+        let d =
+            Descriptor::<XOnlyPublicKey>::from_str(&format!("tr({},{})", internal, small)).unwrap();
+        assert!(Object::from_descriptor(d, None)
+            .satisfy_cheapest(&none)
+            .is_err());
+    }
+}
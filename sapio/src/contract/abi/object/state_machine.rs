@@ -0,0 +1,254 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! the states and transitions of a compiled contract, for documentation and
+//! verification
+use crate::contract::object::Object;
+use crate::util::extended_address::ExtendedAddress;
+use bitcoin::hashes::sha256;
+use bitcoin::Script;
+use sapio_base::effects::{EffectPath, PathFragment};
+use sapio_base::serialization_helpers::SArc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+
+/// The number of states [`Object::state_machine`] stops at.
+pub const DEFAULT_MAX_STATES: usize = 1000;
+
+/// A contract the state machine may be in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct State {
+    /// the contract's address. States are identified by their address, so a
+    /// contract reachable in several ways is a single state.
+    pub address: ExtendedAddress,
+    /// the path the contract was first found at
+    pub path: SArc<EffectPath>,
+}
+
+/// A move from one [`State`] to another through a template.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Transition {
+    /// the index of the state spent from
+    pub from: usize,
+    /// the index of the state created
+    pub to: usize,
+    /// the name of the action (the `then` or `finish_or` function) which
+    /// produced the template, if it can be determined
+    pub action: Option<String>,
+    /// the template making the transition
+    pub template: sha256::Hash,
+    /// is the template CTV committed, or only suggested (e.g., by an
+    /// updatable action)?
+    pub committed: bool,
+}
+
+/// The reachable states of a contract and the transitions between them, see
+/// [`Object::state_machine`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StateMachineGraph {
+    /// all of the states, starting with the contract itself
+    pub states: Vec<State>,
+    /// all of the transitions between `states`
+    pub transitions: Vec<Transition>,
+    /// set if the bound on the number of states was reached, in which case
+    /// transitions out of the last states found are missing
+    pub truncated: bool,
+}
+
+impl Object {
+    /// Enumerate the states reachable from this contract and the labeled
+    /// transitions between them, stopping after [`DEFAULT_MAX_STATES`]
+    /// states.
+    pub fn state_machine(&self) -> StateMachineGraph {
+        self.state_machine_bounded(DEFAULT_MAX_STATES)
+    }
+
+    /// Enumerate the states reachable from this contract and the labeled
+    /// transitions between them, stopping after `max_states` states.
+    ///
+    /// Every template (CTV committed or suggested) is a transition from the
+    /// contract it spends to each contract it creates.
+    pub fn state_machine_bounded(&self, max_states: usize) -> StateMachineGraph {
+        let mut graph = StateMachineGraph {
+            states: vec![],
+            transitions: vec![],
+            truncated: false,
+        };
+        let mut index: BTreeMap<Script, usize> = BTreeMap::new();
+        let mut queue = VecDeque::new();
+        if max_states == 0 {
+            graph.truncated = true;
+            return graph;
+        }
+        index.insert(self.address.clone().into(), 0);
+        graph.states.push(State {
+            address: self.address.clone(),
+            path: self.root_path.clone(),
+        });
+        queue.push_back((0, self));
+        while let Some((from, obj)) = queue.pop_front() {
+            let templates = obj
+                .ctv_to_tx
+                .iter()
+                .map(|t| (t, true))
+                .chain(obj.suggested_txs.iter().map(|t| (t, false)));
+            for ((h, tmpl), committed) in templates {
+                for out in &tmpl.outputs {
+                    let spk: Script = out.contract.address.clone().into();
+                    let to = match index.get(&spk) {
+                        Some(to) => *to,
+                        None if graph.states.len() >= max_states => {
+                            graph.truncated = true;
+                            continue;
+                        }
+                        None => {
+                            let to = graph.states.len();
+                            index.insert(spk, to);
+                            graph.states.push(State {
+                                address: out.contract.address.clone(),
+                                path: out.contract.root_path.clone(),
+                            });
+                            queue.push_back((to, &out.contract));
+                            to
+                        }
+                    };
+                    graph.transitions.push(Transition {
+                        from,
+                        to,
+                        action: action_name(&obj.root_path, &out.contract.root_path),
+                        template: *h,
+                        committed,
+                    });
+                }
+            }
+        }
+        graph
+    }
+}
+
+/// the name of the action of the contract at `from` which `to` was compiled
+/// under, i.e. the fragment following `from/action` in `to`'s path.
+fn action_name(from: &SArc<EffectPath>, to: &SArc<EffectPath>) -> Option<String> {
+    let from: Vec<PathFragment> = from.0.as_ref().clone().into();
+    let to: Vec<PathFragment> = to.0.as_ref().clone().into();
+    if !to.starts_with(&from) {
+        return None;
+    }
+    match &to[from.len()..] {
+        [PathFragment::Action, PathFragment::Named(name), ..] => Some(name.0.as_ref().clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::actions::*;
+    use crate::contract::context::test_ctx;
+    use crate::contract::{Compilable, CompilationError, Context, Contract, TxTmplIt};
+    use crate::{guard, then};
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::XOnlyPublicKey;
+    use sapio_base::Clause;
+    use std::sync::Arc;
+
+    fn key() -> XOnlyPublicKey {
+        let secp = Secp256k1::new();
+        SecretKey::from_slice(&[1; 32])
+            .unwrap()
+            .x_only_public_key(&secp)
+            .0
+    }
+
+    /// can only be spent by `key()`
+    struct Closed;
+    impl Closed {
+        #[guard]
+        fn owner(self, _ctx: Context) {
+            Clause::Key(key())
+        }
+    }
+    impl Contract for Closed {
+        declare! {finish, Self::owner}
+        declare! {non updatable}
+    }
+
+    /// moves to `Closed` through either a committed or an updatable action
+    struct Open;
+    impl Open {
+        #[guard]
+        fn owner(self, _ctx: Context) {
+            Clause::Key(key())
+        }
+        fn to_closed(&self, ctx: Context) -> TxTmplIt {
+            let amt = ctx.funds();
+            ctx.template().add_output(amt, &Closed, None)?.into()
+        }
+        #[then]
+        fn lock(self, ctx: Context) {
+            self.to_closed(ctx)
+        }
+        fn close(&self, ctx: Context, _: ()) -> TxTmplIt {
+            self.to_closed(ctx)
+        }
+        fn close_fn() -> Option<Box<dyn CallableAsFoF<Self, ()>>> {
+            let f: FinishOrFunc<_, _, _, WebAPIDisabled> = FinishOrFunc {
+                simp_gen: None,
+                coerce_args: |a| Ok::<_, CompilationError>(a),
+                guard: &[Self::owner],
+                conditional_compile_if: &[],
+                func: Self::close,
+                schema: None,
+                name: Arc::new("close".into()),
+                f: Default::default(),
+                returned_txtmpls_modify_guards: false,
+                extract_clause_from_txtmpl: default_extract_clause_from_txtmpl,
+            };
+            Some(Box::new(f))
+        }
+    }
+    impl Contract for Open {
+        declare! {then, Self::lock}
+        declare! {updatable<()>, Self::close_fn}
+    }
+
+    #[test]
+    fn test_state_machine() {
+        let compiled = Open.compile(test_ctx("open", 10_000)).unwrap();
+        let graph = compiled.state_machine();
+        assert!(!graph.truncated);
+        assert_eq!(graph.states.len(), 2);
+        let closed: Script = graph.states[1].address.clone().into();
+        assert_eq!(
+            closed,
+            Script::from(
+                compiled.ctv_to_tx.values().next().unwrap().outputs[0]
+                    .contract
+                    .address
+                    .clone()
+            )
+        );
+        let mut edges: Vec<_> = graph
+            .transitions
+            .iter()
+            .map(|t| (t.from, t.to, t.action.clone(), t.committed))
+            .collect();
+        edges.sort();
+        assert_eq!(
+            edges,
+            vec![
+                (0, 1, Some("close".into()), false),
+                (0, 1, Some("lock".into()), true)
+            ]
+        );
+
+        let bounded = compiled.state_machine_bounded(1);
+        assert!(bounded.truncated);
+        assert_eq!(bounded.states.len(), 1);
+        assert!(bounded.transitions.is_empty());
+    }
+}
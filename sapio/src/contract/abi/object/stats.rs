@@ -0,0 +1,84 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! structural metrics of a compiled contract's template tree
+use crate::contract::object::Object;
+use serde::{Deserialize, Serialize};
+
+/// The shape of an Object's template tree, see [`Object::tree_stats`].
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct TreeStats {
+    /// the most templates on any path from the root, 0 if it has none
+    pub depth: usize,
+    /// the number of templates (CTV protected and suggested) in the tree
+    pub total_templates: usize,
+    /// the most templates any one contract in the tree has
+    pub max_branching: usize,
+}
+
+impl Object {
+    /// Compute the depth, size, and branching of this Object's template tree
+    /// in a single walk, e.g. to catch a contract which grows exponentially.
+    ///
+    /// A contract created by several templates is counted once per template.
+    pub fn tree_stats(&self) -> TreeStats {
+        let mut stats = TreeStats::default();
+        stats.depth = walk(self, &mut stats);
+        stats
+    }
+}
+
+/// add `obj`'s templates to `stats`, returning the depth of its subtree
+fn walk(obj: &Object, stats: &mut TreeStats) -> usize {
+    let templates = obj.ctv_to_tx.len() + obj.suggested_txs.len();
+    stats.total_templates += templates;
+    stats.max_branching = stats.max_branching.max(templates);
+    obj.ctv_to_tx
+        .values()
+        .chain(obj.suggested_txs.values())
+        .map(|t| {
+            1 + t
+                .outputs
+                .iter()
+                .map(|o| walk(&o.contract, stats))
+                .max()
+                .unwrap_or(0)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::{test_compiled, test_template};
+    use bitcoin::XOnlyPublicKey;
+    use miniscript::Descriptor;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_tree_stats() {
+        let key = XOnlyPublicKey::from_str(
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let leaf = Object::from_descriptor(Descriptor::new_tr(key, None).unwrap(), None);
+        assert_eq!(leaf.tree_stats(), TreeStats::default());
+
+        let mid = test_compiled(vec![test_template(&[(1000, &leaf)])]);
+        let mut root = test_compiled(vec![test_template(&[(2000, &mid), (2000, &leaf)])]);
+        let suggested = test_template(&[(3000, &leaf)]);
+        root.suggested_txs.insert(suggested.hash(), suggested);
+        assert_eq!(
+            root.tree_stats(),
+            TreeStats {
+                depth: 2,
+                total_templates: 3,
+                max_branching: 2,
+            }
+        );
+    }
+}
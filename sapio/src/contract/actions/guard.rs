@@ -30,6 +30,20 @@ pub enum Guard<ContractSelf> {
         fn(&ContractSelf, Context) -> Clause,
         Option<SimpGen<ContractSelf>>,
     ),
+    /// A Guard labeled with the name of the function which declared it, see
+    /// [`Guard::named`]
+    Named(Arc<String>, Box<Guard<ContractSelf>>),
+}
+
+impl<ContractSelf> Guard<ContractSelf> {
+    /// label the guard with the name of the function which declared it, as
+    /// `#[guard]` does. The name is reported in each `GuardDescriptor`.
+    pub fn named(self, name: Arc<String>) -> Self {
+        match self {
+            Guard::Named(_, g) => Guard::Named(name, g),
+            g => Guard::Named(name, Box::new(g)),
+        }
+    }
 }
 
 /// A Function that can be used to generate metadata for a Guard
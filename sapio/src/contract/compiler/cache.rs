@@ -18,9 +18,15 @@ use std::collections::BTreeMap;
 use std::sync::Arc;
 
 pub type GuardSimps = Vec<Arc<dyn SIMPAttachableAt<GuardLT>>>;
+/// the name (if any), Clause, and metadata a guard produced
+pub(crate) type GuardClause = (Option<Arc<String>>, Clause, GuardSimps);
 pub(crate) enum CacheEntry<T> {
-    Cached(Clause, GuardSimps),
-    Fresh(fn(&T, Context) -> Clause, Option<SimpGen<T>>),
+    Cached(Option<Arc<String>>, Clause, GuardSimps),
+    Fresh(
+        Option<Arc<String>>,
+        fn(&T, Context) -> Clause,
+        Option<SimpGen<T>>,
+    ),
 }
 
 /// GuardCache assists with caching the computation of guard functions
@@ -36,16 +42,23 @@ impl<T> GuardCache<T> {
     }
     pub(crate) fn create_entry(
         g: Option<Guard<T>>,
+        name: Option<Arc<String>>,
         t: &T,
         ctx: Context,
         simp_ctx: Context,
     ) -> Result<Option<CacheEntry<T>>, CompilationError> {
         match g {
-            Some(Guard::Cache(f, Some(simp_gen))) => {
-                Ok(Some(CacheEntry::Cached(f(t, ctx), simp_gen(t, simp_ctx)?)))
+            Some(Guard::Cache(f, Some(simp_gen))) => Ok(Some(CacheEntry::Cached(
+                name,
+                f(t, ctx),
+                simp_gen(t, simp_ctx)?,
+            ))),
+            Some(Guard::Cache(f, None)) => Ok(Some(CacheEntry::Cached(name, f(t, ctx), vec![]))),
+            Some(Guard::Fresh(f, simp_gen)) => Ok(Some(CacheEntry::Fresh(name, f, simp_gen))),
+            // an outer label takes precedence
+            Some(Guard::Named(n, g)) => {
+                Self::create_entry(Some(*g), name.or(Some(n)), t, ctx, simp_ctx)
             }
-            Some(Guard::Cache(f, None)) => Ok(Some(CacheEntry::Cached(f(t, ctx), vec![]))),
-            Some(Guard::Fresh(f, simp_gen)) => Ok(Some(CacheEntry::Fresh(f, simp_gen))),
             None => Ok(None),
         }
     }
@@ -55,12 +68,13 @@ impl<T> GuardCache<T> {
         f: fn() -> Option<Guard<T>>,
         ctx: Context,
         simp_ctx: Context,
-    ) -> Result<Option<(Clause, GuardSimps)>, CompilationError> {
+    ) -> Result<Option<GuardClause>, CompilationError> {
         let mut entry = self.cache.entry(f as usize);
         let r = match entry {
             std::collections::btree_map::Entry::Vacant(v) => {
                 let ent = Self::create_entry(
                     f(),
+                    None,
                     t,
                     ctx.internal_clone(InternalCompilerTag { _secret: () }),
                     simp_ctx.internal_clone(InternalCompilerTag { _secret: () }),
@@ -71,8 +85,9 @@ impl<T> GuardCache<T> {
             std::collections::btree_map::Entry::Occupied(ref mut o) => o.get_mut(),
         };
         match r {
-            Some(CacheEntry::Cached(s, v)) => Ok(Some((s.clone(), v.to_vec()))),
-            Some(CacheEntry::Fresh(f, s)) => Ok(Some((
+            Some(CacheEntry::Cached(name, s, v)) => Ok(Some((name.clone(), s.clone(), v.to_vec()))),
+            Some(CacheEntry::Fresh(name, f, s)) => Ok(Some((
+                name.clone(),
                 f(t, ctx),
                 match s {
                     Some(f2) => f2(t, simp_ctx)?,
@@ -89,19 +104,26 @@ pub(crate) fn create_guards<T>(
     mut ctx: Context,
     guards: &[fn() -> Option<Guard<T>>],
     gc: &mut GuardCache<T>,
-) -> Result<(Clause, Vec<(Clause, GuardSimps)>), CompilationError> {
+) -> Result<(Clause, Vec<GuardClause>), CompilationError> {
+    // every guard's metadata shares the one `@metadata` context, which may
+    // only be derived once
+    let simp = ctx.derive(PathFragment::Metadata)?;
     let v = guards
         .iter()
-        .zip((0..).flat_map(|i| {
-            let new = ctx.derive(PathFragment::Branch(i)).ok()?;
-            let simp = ctx.derive(PathFragment::Metadata).ok()?;
-            Some((new, simp))
-        }))
-        .filter_map(|(x, (c, simp_c))| gc.get(self_ref, *x, c, simp_c).transpose())
+        .zip((0..).flat_map(|i| ctx.derive(PathFragment::Branch(i)).ok()))
+        .filter_map(|(x, c)| {
+            gc.get(
+                self_ref,
+                *x,
+                c,
+                simp.internal_clone(InternalCompilerTag { _secret: () }),
+            )
+            .transpose()
+        })
         .collect::<Result<Vec<_>, _>>()?;
     let mut clauses: Vec<_> = v
         .iter()
-        .map(|x| &x.0)
+        .map(|x| &x.1)
         .filter(|x| **x != Clause::Trivial)
         .cloned()
         .collect(); // no point in using any Trivials
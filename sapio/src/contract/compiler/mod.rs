@@ -13,8 +13,11 @@ use super::Context;
 use crate::contract::abi::continuation::ContinuationPoint;
 use crate::contract::actions::conditional_compile::CCILWrapper;
 use crate::contract::actions::CallableAsFoF;
+use crate::contract::context::ScriptType;
+use crate::contract::object::GuardDescriptor;
 use crate::contract::TxTmplIt;
 use crate::util::amountrange::AmountRange;
+use crate::util::standardness::{check_tap_leaf, check_witness_script};
 
 use ::miniscript::*;
 use bitcoin::schnorr::TweakedPublicKey;
@@ -31,6 +34,7 @@ use std::sync::Arc;
 mod cache;
 mod util;
 use cache::*;
+pub(crate) use util::default_internal_key;
 use util::*;
 /// Used to prevent unintended callers to internal_clone.
 pub struct InternalCompilerTag {
@@ -154,6 +158,7 @@ where
     fn compile(&self, mut ctx: Context) -> Result<Compiled, CompilationError> {
         let self_ref = self.get_inner_ref();
         let mut guard_clauses = GuardCache::new();
+        let events = ctx.new_event_log(InternalCompilerTag { _secret: () });
 
         // The below maps track metadata that is useful for consumers / verification.
         // track transactions that are *guaranteed* via CTV
@@ -241,7 +246,13 @@ where
                 // instead of just an empty iterator.
                 let txtmpl_clauses = transactions?
                     .map(|r_txtmpl| {
-                        let txtmpl = r_txtmpl?;
+                        let mut txtmpl = r_txtmpl?;
+                        if !ctx.is_unconfirmed_funding_allowed() {
+                            confirm_spend(&mut txtmpl, &ctx)?;
+                        }
+                        check_lock_time(&txtmpl.tx)?;
+                        ctx.use_template_budget()?;
+                        ctx.report_progress();
                         let h = txtmpl.hash();
                         amount_range.update_range(txtmpl.max);
                         // Add the addition guards to these clauses
@@ -266,6 +277,7 @@ where
                         None,
                         combine_txtmpls(nullability, txtmpl_clauses, guards)?,
                         guard_metadata,
+                        func.get_name().as_ref().clone(),
                     )
                 } else {
                     let mut cp =
@@ -274,19 +286,40 @@ where
                         cp = cp.add_simp(simp.as_ref())?;
                     }
                     let v = optimizer_flatten_and_compile(guards)?;
-                    (Some((SArc(effect_path), cp)), v, guard_metadata)
+                    (
+                        Some((SArc(effect_path), cp)),
+                        v,
+                        guard_metadata,
+                        func.get_name().as_ref().clone(),
+                    )
                 })
             })
-            .collect::<Result<Vec<(_, Vec<Miniscript<XOnlyPublicKey, Tap>>, _)>, CompilationError>>(
+            // when collecting errors, a failed branch is recorded and
+            // dropped so its siblings still get compiled
+            .filter_map(|r| r.map_or_else(|e| ctx.recover(e).map(Err), |v| Some(Ok(v))))
+            .collect::<Result<Vec<(_, Vec<Miniscript<XOnlyPublicKey, Tap>>, _, _)>, CompilationError>>(
             )?;
 
         let mut continue_apis = ContinueAPIs::default();
         let mut clause_accumulator = vec![];
         let mut all_guard_simps: BTreeMap<Clause, GuardSimps> = Default::default();
-        for (v, b, c) in all_values {
+        let mut guards_by_action = BTreeMap::new();
+        for (v, b, c, name) in all_values {
             continue_apis.extend(std::iter::once(v));
             clause_accumulator.push(b);
-            for (pol, mut simps) in c {
+            let descriptors = c
+                .iter()
+                .enumerate()
+                .map(|(index, (name, clause, _))| GuardDescriptor {
+                    index,
+                    name: name.as_deref().cloned(),
+                    clause: clause.clone(),
+                })
+                .collect::<Vec<_>>();
+            if !descriptors.is_empty() {
+                guards_by_action.insert(name, descriptors);
+            }
+            for (_, pol, mut simps) in c {
                 all_guard_simps.entry(pol).or_default().append(&mut simps)
             }
         }
@@ -310,10 +343,11 @@ where
                 .filter_map(|(func, (c, simp_c))| {
                     guard_clauses.get(self_ref, *func, c, simp_c).transpose()
                 })
+                .filter_map(|r| r.map_or_else(|e| ctx.recover(e).map(Err), |v| Some(Ok(v))))
                 .collect::<Result<Vec<_>, _>>()?;
             let all_g = guards
                 .into_iter()
-                .map(|(policy, _m)| optimizer_flatten_and_compile(policy))
+                .map(|(_, policy, _m)| optimizer_flatten_and_compile(policy))
                 .collect::<Result<Vec<_>, _>>()?;
 
             all_g
@@ -322,17 +356,68 @@ where
                 .flatten()
                 .collect()
         };
-        // TODO: Pick a better branch that is guaranteed to work!
-        let some_key = pick_key_from_miniscripts(branches.iter());
-        // Don't remove the key from the scripts in case it was bogus
-        let tree = branches_to_tree(branches);
-        let descriptor = Descriptor::Tr(descriptor::Tr::new(some_key, tree)?);
-        let estimated_max_size = descriptor.max_satisfaction_weight()?;
-        // TODO: Convert into an address instead of keeping descriptor,
-        // hot-fix workaround
-        let address = descriptor.clone().into();
-        let descriptor = Some(descriptor.into());
+        let branches = if ctx.is_unconfirmed_funding_allowed() {
+            branches
+        } else {
+            // N.B. this must happen before picking the Taproot internal key,
+            // so that no key can spend without the timelock
+            require_confirmation(branches)?
+        };
+        let branches = match ctx.domain() {
+            Some(domain) => {
+                let mut branches = branches;
+                branches.push(domain_branch(domain)?);
+                branches
+            }
+            None => branches,
+        };
+        let (address, descriptor, estimated_max_size) = match ctx.get_script_type() {
+            ScriptType::Taproot => {
+                // TODO: Pick a better branch that is guaranteed to work!
+                let some_key = pick_key_from_miniscripts(branches.iter());
+                // Don't remove the key from the scripts in case it was bogus
+                let tree = branches_to_tree(branches, ctx.height(), ctx.median_time_past());
+                if ctx.is_strict_standardness() {
+                    for (depth, leaf) in tree.iter().flat_map(|t| t.iter()) {
+                        check_tap_leaf(leaf, depth)?;
+                    }
+                }
+                let descriptor = Descriptor::Tr(descriptor::Tr::new(some_key, tree)?);
+                let estimated_max_size = descriptor.max_satisfaction_weight()?;
+                // TODO: Convert into an address instead of keeping descriptor,
+                // hot-fix workaround
+                let address = descriptor.clone().into();
+                (address, Some(descriptor.into()), estimated_max_size)
+            }
+            ScriptType::NestedSegwit => {
+                let script = branches_to_segwitv0(branches)?;
+                if ctx.is_strict_standardness() {
+                    check_witness_script(&script)?;
+                }
+                let descriptor = Descriptor::new_sh_wsh(script)?;
+                let estimated_max_size = descriptor.max_satisfaction_weight()?;
+                let address = descriptor.address(ctx.network)?.into();
+                (address, Some(descriptor.into()), estimated_max_size)
+            }
+        };
         let root_path = SArc(ctx.path().clone());
+        for t in comitted_txns.values_mut().chain(other_txns.values_mut()) {
+            t.spent_descriptor = descriptor.clone();
+        }
+
+        // witness space not scaled
+        if let Some(weight) = comitted_txns
+            .values()
+            .chain(other_txns.values())
+            .map(|a| a.tx.weight() + estimated_max_size)
+            .find(|w| *w > ctx.get_max_tx_weight())
+        {
+            return Err(CompilationError::TransactionTooLarge(
+                String::from(ctx.path().as_ref().clone()),
+                weight,
+                ctx.get_max_tx_weight(),
+            ));
+        }
 
         let failed_estimate = comitted_txns.values().any(|a| {
             // witness space not scaled
@@ -346,7 +431,11 @@ where
             Err(CompilationError::MinFeerateError)
         } else {
             let metadata_ctx = ctx.derive(PathFragment::Metadata)?;
-            Ok(Compiled {
+            let metadata = self
+                .metadata(metadata_ctx)?
+                .add_guard_simps(all_guard_simps)?;
+            let events = std::mem::take(&mut *events.lock().unwrap());
+            let compiled = Compiled {
                 ctv_to_tx: comitted_txns,
                 suggested_txs: other_txns,
                 continue_apis: continue_apis.inner,
@@ -354,20 +443,30 @@ where
                 address,
                 descriptor,
                 amount_range,
-                metadata: self
-                    .metadata(metadata_ctx)?
-                    .add_guard_simps(all_guard_simps)?,
-            })
+                metadata,
+                events,
+                guards: guards_by_action,
+            };
+            if let Some(budget) = ctx.get_max_total_fees() {
+                compiled.total_fee_budget(budget)?;
+            }
+            Ok(compiled)
         }
     }
 }
 
+/// compile a policy, keeping the policy in the error if it fails
+fn compile_policy(p: &Clause) -> Result<Miniscript<XOnlyPublicKey, Tap>, CompilationError> {
+    p.compile()
+        .map_err(|e| CompilationError::PolicyCompilation(p.to_string(), e))
+}
+
 fn optimizer_flatten_and_compile(
     guards: policy::Concrete<XOnlyPublicKey>,
 ) -> Result<Vec<Miniscript<XOnlyPublicKey, Tap>>, CompilationError> {
     let v = optimizer_flatten_policy(guards)
         .into_iter()
-        .map(|g| g.compile())
+        .map(|g| compile_policy(&g))
         .collect::<Result<Vec<_>, _>>()?;
     Ok(v)
 }
@@ -396,7 +495,7 @@ fn combine_txtmpls(
         // If the guard is trivial, return the hashes standalone
         (_, _, Clause::Trivial) => Ok(txtmpl_clauses
             .into_iter()
-            .map(|policy| policy.compile().map_err(Into::<CompilationError>::into))
+            .map(|policy| compile_policy(&policy))
             .collect::<Result<Vec<_>, _>>()?),
         // If the guard is non-trivial, zip it to each hash
         // TODO: Arc in miniscript to dedup memory?
@@ -404,11 +503,7 @@ fn combine_txtmpls(
         (_, _, guards) => Ok(txtmpl_clauses
             .into_iter()
             // extra_guards will contain any CTV
-            .map(|extra_guards| {
-                Clause::And(vec![guards.clone(), extra_guards])
-                    .compile()
-                    .map_err(Into::<CompilationError>::into)
-            })
+            .map(|extra_guards| compile_policy(&Clause::And(vec![guards.clone(), extra_guards])))
             .collect::<Result<Vec<_>, _>>()?),
     }
 }
@@ -425,3 +520,650 @@ fn optimizer_flatten_policy(p: Clause) -> Vec<Clause> {
         p => vec![p],
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::test_ctx;
+    use crate::contract::Contract;
+    use crate::{guard, then};
+    use bitcoin::util::amount::Amount;
+    use bitcoin::Script;
+    use sapio_base::timelocks::AbsHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+
+    /// a contract which fans out into two copies of itself, `depth` times
+    struct FanOut(u8);
+    impl FanOut {
+        #[then]
+        fn fan(self, ctx: Context) {
+            let amt = ctx.funds() / 2;
+            let mut tmpl = ctx.template();
+            for _ in 0..2 {
+                tmpl = if self.0 == 0 {
+                    tmpl.add_output(amt, &Compiled::from_op_return(&[0u8; 4][..])?, None)?
+                } else {
+                    tmpl.add_output(amt, &FanOut(self.0 - 1), None)?
+                };
+            }
+            tmpl.into()
+        }
+    }
+    impl Contract for FanOut {
+        declare! {then, Self::fan}
+        declare! {non updatable}
+    }
+
+    fn ctx() -> Context {
+        test_ctx("fanout", 1_000_000)
+    }
+
+    #[test]
+    fn test_max_templates() {
+        // depth 3 fans out into 1 + 2 + 4 + 8 = 15 templates
+        assert!(FanOut(3).compile(ctx().max_templates(15)).is_ok());
+        assert!(matches!(
+            FanOut(3).compile(ctx().max_templates(14)),
+            Err(CompilationError::TooManyTemplates)
+        ));
+        assert!(FanOut(3).compile(ctx()).is_ok());
+    }
+
+    #[test]
+    fn test_progress() {
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let s = seen.clone();
+        let ctx = ctx().on_progress(Arc::new(move |p| s.lock().unwrap().push(p)));
+        FanOut(3).compile(ctx).unwrap();
+        let seen = seen.lock().unwrap();
+        // one report per template, counted across all levels of the tree
+        assert_eq!(seen.len(), 15);
+        assert!(seen.iter().map(|p| p.templates).eq(1..=15));
+        let depths: std::collections::BTreeSet<_> = seen.iter().map(|p| p.depth).collect();
+        assert_eq!(depths.len(), 4);
+    }
+
+    /// a chain of `.0` contracts, each paying a fee of 1000 sats
+    struct Chain(u8);
+    impl Chain {
+        #[then]
+        fn step(self, ctx: Context) {
+            let tmpl = ctx.template().add_fees(Amount::from_sat(1000))?;
+            let amt = tmpl.ctx().funds();
+            if self.0 == 0 {
+                tmpl.add_output(amt, &Compiled::from_op_return(&[0u8; 4][..])?, None)?
+                    .into()
+            } else {
+                tmpl.add_output(amt, &Chain(self.0 - 1), None)?.into()
+            }
+        }
+    }
+    impl Contract for Chain {
+        declare! {then, Self::step}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_max_total_fees() {
+        // 4 templates, one per level, each paying 1000 sats
+        let compiled = Chain(3).compile(ctx()).unwrap();
+        assert_eq!(compiled.total_fees(), Amount::from_sat(4000));
+        assert!(Chain(3)
+            .compile(ctx().max_total_fees(Amount::from_sat(4000)))
+            .is_ok());
+        // each level is within budget except the root, which sums them all
+        match Chain(3).compile(ctx().max_total_fees(Amount::from_sat(3999))) {
+            Err(CompilationError::FeeBudgetExceeded(total, budget)) => {
+                assert_eq!(total, Amount::from_sat(4000));
+                assert_eq!(budget, Amount::from_sat(3999));
+            }
+            r => panic!("expected FeeBudgetExceeded, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    /// a template with an nLockTime, whose input is made final if `.0` is set
+    struct LockTimed(bool);
+    impl LockTimed {
+        #[then]
+        fn lock(self, ctx: Context) {
+            let amt = ctx.funds();
+            let mut t: crate::template::Template = ctx
+                .template()
+                .set_lock_time(AbsHeight::try_from(100)?.into())?
+                .add_output(amt, &Compiled::from_op_return(&[0u8; 4][..])?, None)?
+                .into();
+            if self.0 {
+                t.tx.input[0].sequence = u32::MAX;
+            }
+            Ok(Box::new(std::iter::once(Ok(t))))
+        }
+    }
+    impl Contract for LockTimed {
+        declare! {then, Self::lock}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_compile_many() {
+        let instances = ctx().compile_many(&FanOut(1), 3).unwrap();
+        assert_eq!(instances.len(), 3);
+        let single = FanOut(1).compile(ctx()).unwrap();
+        let mut addresses: Vec<_> = instances
+            .iter()
+            .chain(std::iter::once(&single))
+            .map(|o| o.address(bitcoin::Network::Regtest).unwrap().to_string())
+            .collect();
+        // the sub-contracts differ too, so the templates have different CTV
+        // hashes
+        let mut hashes: Vec<_> = instances
+            .iter()
+            .chain(std::iter::once(&single))
+            .flat_map(|o| o.ctv_to_tx.keys().cloned())
+            .collect();
+        addresses.sort();
+        addresses.dedup();
+        hashes.sort();
+        hashes.dedup();
+        assert_eq!(addresses.len(), 4);
+        assert_eq!(hashes.len(), 4);
+        // instances are reproducible
+        let again = ctx().compile_many(&FanOut(1), 3).unwrap();
+        assert_eq!(
+            again[2].address(bitcoin::Network::Regtest).unwrap(),
+            instances[2].address(bitcoin::Network::Regtest).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_lock_time_disabled() {
+        assert!(LockTimed(false).compile(ctx()).is_ok());
+        assert!(matches!(
+            LockTimed(true).compile(ctx()),
+            Err(CompilationError::LockTimeDisabled)
+        ));
+    }
+
+    /// a template paying `.0` outputs
+    struct Wide(usize);
+    impl Wide {
+        #[then]
+        fn pay(self, ctx: Context) {
+            let amt = ctx.funds() / self.0 as u64;
+            let mut tmpl = ctx.template();
+            for _ in 0..self.0 {
+                tmpl = tmpl.add_output(amt, &Compiled::from_op_return(&[0u8; 4][..])?, None)?;
+            }
+            tmpl.into()
+        }
+    }
+    impl Contract for Wide {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_transaction_too_large() {
+        assert!(Wide(100).compile(ctx()).is_ok());
+        // each OP_RETURN output weighs 60, so this is well over the default
+        match Wide(7000).compile(ctx()) {
+            Err(CompilationError::TransactionTooLarge(path, weight, limit)) => {
+                assert_eq!(path, "fanout");
+                assert!(weight > limit);
+                assert_eq!(limit, crate::util::standardness::MAX_STANDARD_TX_WEIGHT);
+            }
+            r => panic!("expected TransactionTooLarge, got {:?}", r.map(|_| ())),
+        }
+        assert!(matches!(
+            Wide(100).compile(ctx().max_tx_weight(5_000)),
+            Err(CompilationError::TransactionTooLarge(_, _, 5_000))
+        ));
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct ChannelOpened {
+        capacity: u64,
+    }
+    impl crate::contract::Event for ChannelOpened {
+        const NAME: &'static str = "channel_opened";
+    }
+
+    /// a contract which emits a [`ChannelOpened`], and pays to another if `.0`
+    struct Channel(bool);
+    impl Channel {
+        #[then]
+        fn open(self, ctx: Context) {
+            let amt = ctx.funds();
+            ctx.emit(ChannelOpened {
+                capacity: amt.as_sat(),
+            })?;
+            let tmpl = ctx.template();
+            if self.0 {
+                tmpl.add_output(amt, &Channel(false), None)?.into()
+            } else {
+                tmpl.add_output(amt, &Compiled::from_op_return(&[0u8; 4][..])?, None)?
+                    .into()
+            }
+        }
+    }
+    impl Contract for Channel {
+        declare! {then, Self::open}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_emit_events() {
+        let compiled = Channel(true).compile(ctx()).unwrap();
+        assert_eq!(
+            compiled.events::<ChannelOpened>().unwrap(),
+            vec![ChannelOpened {
+                capacity: 1_000_000
+            }]
+        );
+        // the contract created by the template records its own events
+        let child = &compiled.ctv_to_tx.values().next().unwrap().outputs[0].contract;
+        assert_eq!(child.events::<ChannelOpened>().unwrap().len(), 1);
+        assert!(FanOut(0)
+            .compile(ctx())
+            .unwrap()
+            .events::<ChannelOpened>()
+            .unwrap()
+            .is_empty());
+    }
+
+    /// a contract with one working and two independently failing branches
+    struct TwoFaults;
+    impl TwoFaults {
+        #[then]
+        fn ok(self, ctx: Context) {
+            let amt = ctx.funds();
+            ctx.template()
+                .add_output(amt, &Compiled::from_op_return(&[0u8; 4][..])?, None)?
+                .into()
+        }
+        #[then]
+        fn fault_a(self, _ctx: Context) {
+            Err(CompilationError::TerminateWith("a".into()))
+        }
+        #[then]
+        fn fault_b(self, _ctx: Context) {
+            Err(CompilationError::TerminateWith("b".into()))
+        }
+    }
+    impl Contract for TwoFaults {
+        declare! {then, Self::fault_a, Self::ok, Self::fault_b}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_compile_collect_errors() {
+        assert!(matches!(
+            TwoFaults.compile(ctx()),
+            Err(CompilationError::TerminateWith(_))
+        ));
+        let errors = ctx().compile_collect_errors(TwoFaults).unwrap_err();
+        let msgs: Vec<_> = errors
+            .iter()
+            .map(|e| match e {
+                CompilationError::TerminateWith(m) => m.as_str(),
+                _ => panic!("unexpected error {}", e),
+            })
+            .collect();
+        assert_eq!(msgs, vec!["a", "b"]);
+        assert!(ctx().compile_collect_errors(FanOut(1)).is_ok());
+    }
+
+    #[test]
+    fn test_policy_compilation_error() {
+        let e = compile_policy(&Clause::Older(10)).unwrap_err();
+        assert!(matches!(
+            e,
+            CompilationError::PolicyCompilation(
+                _,
+                policy::compiler::CompilerError::TopLevelNonSafe
+            )
+        ));
+        assert_eq!(
+            e.to_string(),
+            "could not compile policy older(10): the policy is not safe, some spending path \
+             requires no signature so anyone could spend it"
+        );
+    }
+
+    /// three ways to spend: with key 1 after height 300, with key 2 after
+    /// height 100, or with key 3 after height 200
+    struct Timelocked;
+    fn key(i: u8) -> XOnlyPublicKey {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        bitcoin::KeyPair::from_seckey_slice(&secp, &[i; 32])
+            .unwrap()
+            .public_key()
+            .into()
+    }
+    impl Timelocked {
+        #[guard]
+        fn after_300(self, _ctx: Context) {
+            Clause::And(vec![Clause::Key(key(1)), Clause::After(300)])
+        }
+        #[guard]
+        fn after_100(self, _ctx: Context) {
+            Clause::And(vec![Clause::Key(key(2)), Clause::After(100)])
+        }
+        #[guard]
+        fn after_200(self, _ctx: Context) {
+            Clause::And(vec![Clause::Key(key(3)), Clause::After(200)])
+        }
+    }
+    impl Contract for Timelocked {
+        declare! {finish, Self::after_300, Self::after_100, Self::after_200}
+        declare! {non updatable}
+    }
+
+    /// as [`Timelocked`], but locked until timestamps rather than heights
+    struct TimestampLocked;
+    const T: u32 = 1_600_000_000;
+    impl TimestampLocked {
+        #[guard]
+        fn after_300(self, _ctx: Context) {
+            Clause::And(vec![Clause::Key(key(1)), Clause::After(T + 300)])
+        }
+        #[guard]
+        fn after_100(self, _ctx: Context) {
+            Clause::And(vec![Clause::Key(key(2)), Clause::After(T + 100)])
+        }
+        #[guard]
+        fn after_200(self, _ctx: Context) {
+            Clause::And(vec![Clause::Key(key(3)), Clause::After(T + 200)])
+        }
+    }
+    impl Contract for TimestampLocked {
+        declare! {finish, Self::after_300, Self::after_100, Self::after_200}
+        declare! {non updatable}
+    }
+
+    /// the depth of each leaf, in order of the key it uses
+    fn depths(ctx: Context) -> Vec<u8> {
+        leaf_depths(Timelocked.compile(ctx).unwrap())
+    }
+
+    /// the depth of each leaf of `o`, in order of the key it uses
+    fn leaf_depths(o: Compiled) -> Vec<u8> {
+        let tr = match o.descriptor {
+            Some(crate::contract::object::SupportedDescriptors::XOnly(Descriptor::Tr(t))) => t,
+            _ => unreachable!(),
+        };
+        let mut leaves: Vec<(u8, u8)> = tr
+            .iter_scripts()
+            .map(|(depth, ms)| {
+                let i = (1..=3)
+                    .find(|i| ms.iter_pk().any(|k| k == key(*i)))
+                    .unwrap();
+                (i, depth)
+            })
+            .collect();
+        leaves.sort();
+        leaves.into_iter().map(|(_, d)| d).collect()
+    }
+
+    #[test]
+    fn test_at_height() {
+        // nothing is spendable yet, so the tree is the same as with no height
+        assert_eq!(depths(ctx().at_height(50)), depths(ctx()));
+        // only the after(100) branch is spendable, so it moves up
+        assert_eq!(depths(ctx().at_height(150)), vec![2, 1, 2]);
+        assert_ne!(depths(ctx().at_height(50)), depths(ctx().at_height(150)));
+    }
+
+    #[test]
+    fn test_at_median_time_past() {
+        let depths = |ctx| leaf_depths(TimestampLocked.compile(ctx).unwrap());
+        // the height is no help with timestamp locks
+        assert_eq!(depths(ctx().at_height(1_000_000)), depths(ctx()));
+        assert_eq!(depths(ctx().at_median_time_past(T + 50)), depths(ctx()));
+        // only the after(T + 100) branch is spendable, so it moves up
+        assert_eq!(depths(ctx().at_median_time_past(T + 150)), vec![2, 1, 2]);
+        assert_ne!(
+            depths(ctx().at_median_time_past(T + 50)),
+            depths(ctx().at_median_time_past(T + 150))
+        );
+    }
+
+    #[test]
+    fn test_nested_segwit() {
+        let nested = || {
+            Context::new(
+                bitcoin::Network::Bitcoin,
+                Amount::from_sat(1_000_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("nested").unwrap(),
+                Arc::new(Default::default()),
+            )
+            .script_type(ScriptType::NestedSegwit)
+        };
+        // a CTV branch
+        let o = FanOut(1).compile(nested()).unwrap();
+        let address = o.address(bitcoin::Network::Bitcoin).unwrap();
+        assert_eq!(address.address_type(), Some(bitcoin::AddressType::P2sh));
+        assert!(address.to_string().starts_with('3'));
+        let template = o.ctv_to_tx.values().next().unwrap();
+        // created contracts inherit the script type
+        let child: bitcoin::Script = template.outputs[0].contract.address.clone().into();
+        assert!(child.is_p2sh());
+        let psbt = template.to_oracle_psbt();
+        let redeem_script = psbt.inputs[0].redeem_script.clone().unwrap();
+        assert!(redeem_script.is_v0_p2wsh());
+        assert_eq!(
+            Script::new_p2sh(&redeem_script.script_hash()),
+            psbt.inputs[0].witness_utxo.as_ref().unwrap().script_pubkey
+        );
+        assert!(psbt.inputs[0].witness_script.is_some());
+        // several key and timelock branches
+        let o = Timelocked.compile(nested()).unwrap();
+        match o.descriptor {
+            Some(crate::contract::object::SupportedDescriptors::Pk(d)) => {
+                assert_eq!(d.desc_type(), descriptor::DescriptorType::ShWsh)
+            }
+            _ => panic!("expected a sh(wsh(...)) descriptor"),
+        }
+    }
+
+    #[test]
+    fn test_nested_segwit_odd_key() {
+        use bitcoin::secp256k1::{Message, Parity, Secp256k1, SecretKey};
+        use std::str::FromStr;
+        let secp = Secp256k1::new();
+        let sk = (1..=u8::MAX)
+            .map(|i| SecretKey::from_slice(&[i; 32]).unwrap())
+            .find(|sk| sk.x_only_public_key(&secp).1 == Parity::Odd)
+            .unwrap();
+        let (xonly, _) = sk.x_only_public_key(&secp);
+        let branch =
+            Miniscript::<XOnlyPublicKey, Tap>::from_str(&format!("pk({})", xonly)).unwrap();
+        let ms = branches_to_segwitv0(vec![branch]).unwrap();
+        let keys: Vec<bitcoin::PublicKey> = ms.iter_pk().collect();
+        // the script has the even point, so the odd key must sign negated
+        let even = bitcoin::PublicKey::new(bitcoin::secp256k1::PublicKey::from_secret_key(
+            &secp,
+            &sk.negate(),
+        ));
+        assert_eq!(keys, vec![even]);
+        let msg = Message::from_digest_slice(&[1u8; 32]).unwrap();
+        let sig = secp.sign_ecdsa(&msg, &sk);
+        assert!(secp.verify_ecdsa(&msg, &sig, &even.inner).is_err());
+        let sig = secp.sign_ecdsa(&msg, &sk.negate());
+        assert!(secp.verify_ecdsa(&msg, &sig, &even.inner).is_ok());
+        let sigs: std::collections::HashMap<_, _> = vec![(
+            even,
+            bitcoin::EcdsaSig {
+                sig,
+                hash_ty: bitcoin::EcdsaSighashType::All,
+            },
+        )]
+        .into_iter()
+        .collect();
+        assert!(ms.satisfy(sigs).is_ok());
+    }
+
+    /// spendable with the keys 1 through 101 together, more than a Segwit v0
+    /// spend may use under default policy
+    struct ManyKeys;
+    impl ManyKeys {
+        /// a balanced tree of Ands, so compiling it doesn't recurse deeply
+        fn and_keys(keys: &[u8]) -> Clause {
+            match keys {
+                [k] => Clause::Key(key(*k)),
+                _ => {
+                    let (l, r) = keys.split_at(keys.len() / 2);
+                    Clause::And(vec![Self::and_keys(l), Self::and_keys(r)])
+                }
+            }
+        }
+        #[guard]
+        fn all_keys(self, _ctx: Context) {
+            Self::and_keys(&(1..=101).collect::<Vec<_>>())
+        }
+    }
+    impl Contract for ManyKeys {
+        declare! {finish, Self::all_keys}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_strict_witness_standardness() {
+        use crate::util::standardness::StandardnessError;
+        let nested = || ctx().script_type(ScriptType::NestedSegwit);
+        assert!(ManyKeys.compile(nested()).is_ok());
+        assert!(matches!(
+            ManyKeys.compile(nested().strict_standardness(true)),
+            Err(CompilationError::NonStandard(
+                StandardnessError::TooManyStackItems(..)
+            ))
+        ));
+        // Taproot leaves may be larger, and use more stack items
+        assert!(ManyKeys.compile(ctx().strict_standardness(true)).is_ok());
+    }
+
+    /// satisfies a contract's leaves as a transaction spending it with an
+    /// input of nSequence `.0`, and CTV hash `.1`, would
+    struct Spends(u32, bitcoin::hashes::sha256::Hash);
+    impl Satisfier<XOnlyPublicKey> for Spends {
+        fn check_older(&self, n: u32) -> bool {
+            use ::miniscript::miniscript::satisfy::Older;
+            Satisfier::<XOnlyPublicKey>::check_older(&Older(self.0), n)
+        }
+        fn check_tx_template(&self, h: bitcoin::hashes::sha256::Hash) -> bool {
+            h == self.1
+        }
+    }
+
+    #[test]
+    fn test_allow_unconfirmed_funding() {
+        use crate::contract::object::SpendPath;
+        use sapio_base::CTVHash;
+        for allow in [true, false] {
+            let o = FanOut(0)
+                .compile(ctx().allow_unconfirmed_funding(allow))
+                .unwrap();
+            let leaves: Vec<_> = match &o.descriptor {
+                Some(crate::contract::object::SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
+                    t.iter_scripts().map(|(_, ms)| ms.clone()).collect()
+                }
+                _ => unreachable!(),
+            };
+            // only waits for a confirmation when asked to
+            assert!(leaves
+                .iter()
+                .all(|ms| ms.iter().any(|m| m.node == Terminal::Older(1)) != allow));
+            let spends = |sequence, h| {
+                leaves.iter().any(|ms| {
+                    o.satisfy(&SpendPath::ScriptPath(ms.encode()), Spends(sequence, h))
+                        .is_ok()
+                })
+            };
+            for t in o.ctv_to_tx.values() {
+                let h = t.tx.get_ctv_hash(0);
+                assert_eq!(t.hash(), h);
+                assert!(spends(t.tx.input[0].sequence, h));
+                // a spend of the unconfirmed output doesn't satisfy the leaf
+                assert_eq!(spends(0, h), allow);
+            }
+        }
+        // contracts created by the contract are not affected
+        let mut ctx = ctx().allow_unconfirmed_funding(false);
+        assert!(ctx
+            .derive_str(Arc::new("child".into()))
+            .unwrap()
+            .is_unconfirmed_funding_allowed());
+    }
+
+    /// pays `.0` and `.1` to two outputs, which must be equal
+    struct Split(u64, u64);
+    impl Split {
+        #[then]
+        fn pay(self, ctx: Context) {
+            ctx.assert(self.0 == self.1, "outputs must be of equal value")?;
+            let mut tmpl = ctx.template();
+            for amt in [self.0, self.1] {
+                tmpl = tmpl.add_output(
+                    Amount::from_sat(amt),
+                    &Compiled::from_op_return(&[0u8; 4][..])?,
+                    None,
+                )?;
+            }
+            tmpl.into()
+        }
+    }
+    impl Contract for Split {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_assert() {
+        assert!(Split(1000, 1000).compile(ctx()).is_ok());
+        match Split(1000, 999).compile(ctx()) {
+            Err(CompilationError::AssertionFailed(message)) => {
+                assert_eq!(message, "outputs must be of equal value")
+            }
+            _ => panic!("expected AssertionFailed"),
+        }
+    }
+
+    /// a single branch guarded by two keys
+    struct TwoGuards;
+    impl TwoGuards {
+        #[guard]
+        fn first(self, _ctx: Context) {
+            Clause::Key(key(1))
+        }
+        #[guard]
+        fn second(self, _ctx: Context) {
+            Clause::Key(key(2))
+        }
+        #[then(guarded_by = "[Self::first, Self::second]")]
+        fn spend(self, ctx: Context) {
+            let amt = ctx.funds();
+            ctx.template()
+                .add_output(amt, &Compiled::from_op_return(&[0u8; 4][..])?, None)?
+                .into()
+        }
+    }
+    impl Contract for TwoGuards {
+        declare! {then, Self::spend}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_two_guards() {
+        // the guards share the action's metadata context, which is only
+        // derived once
+        let o = TwoGuards.compile(ctx()).unwrap();
+        let tr = match o.descriptor {
+            Some(crate::contract::object::SupportedDescriptors::XOnly(Descriptor::Tr(t))) => t,
+            _ => unreachable!(),
+        };
+        let keys: Vec<_> = tr.iter_scripts().flat_map(|(_, ms)| ms.iter_pk()).collect();
+        assert!(keys.contains(&key(1)));
+        assert!(keys.contains(&key(2)));
+    }
+}
@@ -10,10 +10,19 @@ use ::miniscript::descriptor::TapTree;
 use ::miniscript::*;
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::Hash;
+use bitcoin::PublicKey;
 use bitcoin::XOnlyPublicKey;
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
 use std::sync::Arc;
+
+use crate::contract::{CompilationError, Context};
+use crate::template::builder::satisfies_rel_lock;
+use crate::template::Template;
+use sapio_base::timelocks::{AnyRelTimeLock, RelHeight, RelTime};
+
+/// values of nLockTime below this are block heights, above are timestamps
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
 /// picks a key from an iter of miniscripts, or returns a static default key
 pub fn pick_key_from_miniscripts<'a, I: Iterator<Item = &'a Miniscript<XOnlyPublicKey, Tap>>>(
     branches: I,
@@ -28,18 +37,122 @@ pub fn pick_key_from_miniscripts<'a, I: Iterator<Item = &'a Miniscript<XOnlyPubl
             None
         })
         .next()
-        .unwrap_or(
-            XOnlyPublicKey::from_slice(&Sha256::hash(&[1u8; 32]).into_inner()).expect("constant"),
-        )
+        .unwrap_or_else(default_internal_key)
+}
+
+/// the Taproot internal key used when no branch is a single key
+pub fn default_internal_key() -> XOnlyPublicKey {
+    XOnlyPublicKey::from_slice(&Sha256::hash(&[1u8; 32]).into_inner()).expect("constant")
+}
+
+/// An unsatisfiable branch, `and_v(v:sha256(domain),0)`, which commits an
+/// output to `domain`, see [`crate::contract::Context::in_domain`].
+pub fn domain_branch(domain: Sha256) -> Result<Miniscript<XOnlyPublicKey, Tap>, CompilationError> {
+    let commitment = Arc::new(Miniscript::from_ast(Terminal::Verify(Arc::new(
+        Miniscript::from_ast(Terminal::Sha256(domain))?,
+    )))?);
+    Ok(Miniscript::from_ast(Terminal::AndV(
+        commitment,
+        Arc::new(Miniscript::from_ast(Terminal::False)?),
+    ))?)
+}
+
+/// Require every branch to wait for a relative timelock of 1 block, i.e. for
+/// the output being spent to be confirmed. Templates spending the branches
+/// must be adjusted to match, see [`confirm_spend`].
+pub fn require_confirmation(
+    branches: Vec<Miniscript<XOnlyPublicKey, Tap>>,
+) -> Result<Vec<Miniscript<XOnlyPublicKey, Tap>>, CompilationError> {
+    let confirmed = Arc::new(Miniscript::from_ast(Terminal::Verify(Arc::new(
+        Miniscript::from_ast(Terminal::Older(1))?,
+    )))?);
+    Ok(branches
+        .into_iter()
+        .map(|b| Miniscript::from_ast(Terminal::AndV(confirmed.clone(), Arc::new(b))))
+        .collect::<Result<_, _>>()?)
+}
+
+/// Make the input of `t` which spends the contract satisfy the relative
+/// timelock added by [`require_confirmation`].
+///
+/// An input left with the builder's default nSequence (no relative timelock)
+/// is given a relative timelock of 1 block, and the template's CTV hash is
+/// recomputed. An input with a relative timelock that doesn't satisfy it
+/// (e.g., a time based one) is an error.
+pub fn confirm_spend(t: &mut Template, ctx: &Context) -> Result<(), CompilationError> {
+    if t.tx.version < 2 {
+        return Err(CompilationError::IncompatibleVersion);
+    }
+    let confirmed: AnyRelTimeLock = RelHeight::from(1).into();
+    let unlocked: AnyRelTimeLock = RelTime::from(0).into();
+    let input =
+        t.tx.input
+            .get_mut(t.ctv_index as usize)
+            .ok_or(CompilationError::NoSuchSequence)?;
+    if input.sequence == unlocked.get() {
+        input.sequence = confirmed.get();
+        t.ctv = ctx.ctv_hash(&t.tx, t.ctv_index);
+        Ok(())
+    } else if satisfies_rel_lock(input.sequence, confirmed) {
+        Ok(())
+    } else {
+        Err(CompilationError::IncompatibleSequence)
+    }
+}
+
+/// Has every absolute timelock in `branch` been reached, block heights at
+/// `height` and timestamps at median-time-past `mtp`? A timelock on a clock
+/// which is not known has not been reached.
+fn is_mature(
+    branch: &Miniscript<XOnlyPublicKey, Tap>,
+    height: Option<u32>,
+    mtp: Option<u32>,
+) -> bool {
+    branch.iter().all(|ms| match ms.node {
+        Terminal::After(t) if t < LOCKTIME_THRESHOLD => height.is_some_and(|h| t <= h),
+        // BIP-113: a timestamp lock must be strictly before median-time-past
+        Terminal::After(t) => mtp.is_some_and(|m| t < m),
+        _ => true,
+    })
+}
+
+/// Check that a nonzero nLockTime in `tx` is enforced, i.e., that some input
+/// has a non-final nSequence. Otherwise the lock time (e.g., one set for a
+/// CheckLockTimeVerify branch) would be silently ignored by consensus.
+pub fn check_lock_time(tx: &bitcoin::Transaction) -> Result<(), CompilationError> {
+    if tx.lock_time != 0 && !tx.input.is_empty() && tx.input.iter().all(|i| i.sequence == u32::MAX)
+    {
+        Err(CompilationError::LockTimeDisabled)
+    } else {
+        Ok(())
+    }
 }
 
 /// Convert the branches into a heap for taproot tree consumption
+///
+/// If the current `height` or median-time-past `mtp` is known, branches which
+/// are already spendable are weighted more heavily so that they are placed
+/// higher in the tree.
+///
+/// Branches of equal weight are ordered by their scripts, so the same
+/// branches always produce the same tree. Which branch is used to spend is
+/// chosen separately, see [`crate::contract::object::Object::satisfy_cheapest`].
 pub fn branches_to_tree(
     branches: Vec<Miniscript<XOnlyPublicKey, Tap>>,
+    height: Option<u32>,
+    mtp: Option<u32>,
 ) -> Option<TapTree<XOnlyPublicKey>> {
     let mut scripts: BinaryHeap<(Reverse<u64>, TapTree<XOnlyPublicKey>)> = branches
         .into_iter()
-        .map(|b| (Reverse(1), TapTree::Leaf(Arc::new(b))))
+        .map(|b| {
+            let known = height.is_some() || mtp.is_some();
+            let weight = if known && is_mature(&b, height, mtp) {
+                2
+            } else {
+                1
+            };
+            (Reverse(weight), TapTree::Leaf(Arc::new(b)))
+        })
         .collect();
     while scripts.len() > 1 {
         let (w1, v1) = scripts.pop().unwrap();
@@ -51,3 +164,43 @@ pub fn branches_to_tree(
     }
     scripts.pop().map(|v| v.1)
 }
+
+/// Combine the branches into a single Segwit v0 script, for outputs which
+/// can't use Taproot. The branches are joined with `or_i`, so the witness
+/// selects which one is used.
+///
+/// Each branch is re-parsed in the Segwit v0 context with its keys given even
+/// parity, so branches using Taproot only fragments (e.g., `multi_a`) are an
+/// error.
+///
+/// As with BIP-340, the holder of a key whose point has an odd y must sign
+/// with its secret key negated, or its signatures won't verify against the
+/// even key in the script.
+///
+/// The re-parse skips the sanity checks, as a branch which is only a CTV
+/// hash (and so requires no signature) is not sane, but is how most
+/// contracts are spent.
+pub fn branches_to_segwitv0(
+    branches: Vec<Miniscript<XOnlyPublicKey, Tap>>,
+) -> Result<Miniscript<PublicKey, Segwitv0>, CompilationError> {
+    branches
+        .iter()
+        .map(|b| {
+            let b: Miniscript<PublicKey, Tap> = b.translate_pk(
+                |k| -> Result<_, CompilationError> {
+                    let mut even = [2u8; 33];
+                    even[1..].copy_from_slice(&k.serialize());
+                    Ok(PublicKey::from_slice(&even).expect("valid x-only key"))
+                },
+                |h| Ok(*h),
+            )?;
+            Ok(Miniscript::from_str_insane(&b.to_string())?)
+        })
+        .reduce(|a, b| {
+            Ok(Miniscript::from_ast(Terminal::OrI(
+                Arc::new(a?),
+                Arc::new(b?),
+            ))?)
+        })
+        .unwrap_or(Err(CompilationError::EmptyPolicy))
+}
@@ -7,19 +7,32 @@
 //! general non-parameter compilation state required by all contracts
 use super::{Amount, Compilable, CompilationError, Compiled};
 use crate::contract::compiler::InternalCompilerTag;
+use crate::contract::events::{Event, EventLog};
+use crate::util::coin_amount::{amount_to_coins, coins_to_amount};
+use crate::util::standardness::MAX_STANDARD_TX_WEIGHT;
 
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::util::amount::CoinAmount;
 use bitcoin::Network;
+use bitcoin::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::PathFragment;
 pub use sapio_base::effects::{EffectDB, MapEffectDB};
+use sapio_base::timelocks::AbsHeight;
+use sapio_base::{CTVHash, CTVHashFn};
 
 use sapio_ctv_emulator_trait::CTVEmulator;
 use std::convert::TryInto;
 
+use std::collections::BTreeMap;
 use std::collections::HashSet;
 
-use std::sync::Arc;
+use std::any::{Any, TypeId};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 /// Context is used to track statet during compilation such as remaining value.
 pub struct Context {
@@ -32,8 +45,97 @@ pub struct Context {
     path: Arc<EffectPath>,
     already_derived: HashSet<PathFragment>,
     effects: Arc<MapEffectDB>,
+    config: Arc<CompileConfig>,
+    reservations: BTreeMap<String, Amount>,
+    template_budget: Option<Arc<AtomicUsize>>,
+    error_sink: Option<Arc<Mutex<Vec<CompilationError>>>>,
+    fee_estimator: Option<FeeEstimator>,
+    extensions: Arc<BTreeMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+    allow_unconfirmed_funding: bool,
+    progress: Option<(ProgressCallback, Arc<AtomicUsize>)>,
+    ctv_hash: Option<CTVHashFn>,
+    events: Arc<Mutex<EventLog>>,
 }
 
+/// The settings of a [`Context`], which are inherited by every context
+/// derived from it.
+///
+/// All of them affect compilation, so the whole struct is part of the key
+/// used by [`Context::compile_cached`].
+#[derive(Serialize, Clone)]
+struct CompileConfig {
+    strict_standardness: bool,
+    height: Option<u32>,
+    median_time_past: Option<u32>,
+    script_type: ScriptType,
+    keys: BTreeMap<String, XOnlyPublicKey>,
+    max_tx_weight: usize,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat::opt")]
+    max_total_fees: Option<Amount>,
+    domain: Option<sha256::Hash>,
+    anti_fee_sniping: Option<AbsHeight>,
+}
+
+/// The key of a [`Context::compile_cached`] entry.
+#[derive(Serialize)]
+struct CacheKey<'a, A> {
+    contract: &'a A,
+    network: Network,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    available_funds: Amount,
+    path: String,
+    already_derived: Vec<String>,
+    effects: &'a MapEffectDB,
+    reservations: Vec<(&'a String, u64)>,
+    allow_unconfirmed_funding: bool,
+    config: &'a CompileConfig,
+    /// the emulator, see [`CTVEmulator::identity`]
+    emulator: String,
+}
+
+/// A [`Context::compile_cached`] entry.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    compiled: Compiled,
+    /// the depth of each template, relative to the cached context, in the
+    /// order compilation produced them
+    progress: Vec<usize>,
+}
+
+/// The kind of output a contract is compiled to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ScriptType {
+    /// a Taproot output, with one leaf per spending branch
+    #[default]
+    Taproot,
+    /// a P2SH wrapped P2WSH output (`sh(wsh(...))`, a `3...` address on
+    /// mainnet), for wallets which can't pay to newer address types.
+    ///
+    /// All spending branches share a single script, and key spends are
+    /// ECDSA, so CTV emulators must be able to sign Segwit v0 spends (as the
+    /// HD oracle does).
+    NestedSegwit,
+}
+
+/// A callback estimating the fee a template of the given vsize should pay.
+pub type FeeEstimator = Arc<dyn Fn(usize) -> Amount + Send + Sync>;
+
+/// Reported to a [`ProgressCallback`] as each template is produced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompileProgress {
+    /// the number of templates produced so far, including this one
+    pub templates: usize,
+    /// the length of the path of the contract which produced the template
+    pub depth: usize,
+}
+
+/// A callback informed of compilation progress, see [`Context::on_progress`].
+pub type ProgressCallback = Arc<dyn Fn(CompileProgress) + Send + Sync>;
+
+/// The fee rate, in sats per vbyte, used by [`Context::estimate_fee`] when no
+/// [`FeeEstimator`] is set.
+pub const DEFAULT_FEE_RATE: u64 = 1;
+
 impl Context {
     /// create a context instance. Should only happen *once* at the very top
     /// level.
@@ -52,6 +154,26 @@ impl Context {
             path: Arc::new(path),
             already_derived: Default::default(),
             effects,
+            config: Arc::new(CompileConfig {
+                strict_standardness: false,
+                height: None,
+                median_time_past: None,
+                script_type: Default::default(),
+                keys: Default::default(),
+                max_tx_weight: MAX_STANDARD_TX_WEIGHT,
+                max_total_fees: None,
+                domain: None,
+                anti_fee_sniping: None,
+            }),
+            reservations: Default::default(),
+            template_budget: None,
+            error_sink: None,
+            fee_estimator: None,
+            extensions: Default::default(),
+            allow_unconfirmed_funding: true,
+            progress: None,
+            ctv_hash: None,
+            events: Default::default(),
         }
     }
     /// Get this Context's effect database, for clients
@@ -94,6 +216,17 @@ impl Context {
                 network: self.network,
                 already_derived: Default::default(),
                 effects: self.effects.clone(),
+                config: self.config.clone(),
+                reservations: self.reservations.clone(),
+                template_budget: self.template_budget.clone(),
+                error_sink: self.error_sink.clone(),
+                fee_estimator: self.fee_estimator.clone(),
+                extensions: self.extensions.clone(),
+                // contracts created by this one are funded by its templates
+                allow_unconfirmed_funding: true,
+                progress: self.progress.clone(),
+                ctv_hash: self.ctv_hash.clone(),
+                events: self.events.clone(),
             })
         }
     }
@@ -107,14 +240,375 @@ impl Context {
             network: self.network,
             already_derived: self.already_derived.clone(),
             effects: self.effects.clone(),
+            config: self.config.clone(),
+            reservations: self.reservations.clone(),
+            template_budget: self.template_budget.clone(),
+            error_sink: self.error_sink.clone(),
+            fee_estimator: self.fee_estimator.clone(),
+            extensions: self.extensions.clone(),
+            allow_unconfirmed_funding: self.allow_unconfirmed_funding,
+            progress: self.progress.clone(),
+            ctv_hash: self.ctv_hash.clone(),
+            events: self.events.clone(),
+        }
+    }
+
+    /// Enable or disable strict standardness checking. When enabled, any
+    /// output added to a template must pass
+    /// [`crate::util::standardness::check_output`], and the scripts spending
+    /// a contract must pass [`crate::util::standardness::check_tap_leaf`] (or
+    /// [`crate::util::standardness::check_witness_script`] for Segwit v0),
+    /// otherwise compilation fails with [`CompilationError::NonStandard`].
+    ///
+    /// The setting is inherited by all derived contexts.
+    pub fn strict_standardness(mut self, strict: bool) -> Self {
+        Arc::make_mut(&mut self.config).strict_standardness = strict;
+        self
+    }
+
+    /// is strict standardness checking enabled?
+    pub fn is_strict_standardness(&self) -> bool {
+        self.config.strict_standardness
+    }
+
+    /// Limit the weight of each template, including the witness spending
+    /// the contract, to `weight` (rather than [`MAX_STANDARD_TX_WEIGHT`]),
+    /// otherwise compilation fails with
+    /// [`CompilationError::TransactionTooLarge`].
+    ///
+    /// The setting is inherited by all derived contexts.
+    pub fn max_tx_weight(mut self, weight: usize) -> Self {
+        Arc::make_mut(&mut self.config).max_tx_weight = weight;
+        self
+    }
+
+    /// the template weight limit, see [`Context::max_tx_weight`]
+    pub fn get_max_tx_weight(&self) -> usize {
+        self.config.max_tx_weight
+    }
+
+    /// Limit the fees paid across all of a contract's templates (see
+    /// `Object::total_fees`) to `budget`, otherwise compilation fails with
+    /// [`CompilationError::FeeBudgetExceeded`].
+    ///
+    /// The setting is inherited by all derived contexts.
+    pub fn max_total_fees(mut self, budget: Amount) -> Self {
+        Arc::make_mut(&mut self.config).max_total_fees = Some(budget);
+        self
+    }
+
+    /// the fee budget set with [`Context::max_total_fees`], if any
+    pub fn get_max_total_fees(&self) -> Option<Amount> {
+        self.config.max_total_fees
+    }
+
+    /// Limit the total number of templates compilation may produce to `n`,
+    /// after which compilation fails with
+    /// [`CompilationError::TooManyTemplates`].
+    ///
+    /// The budget is shared with all derived contexts, so it bounds the whole
+    /// compilation, not just the contract compiled with this context.
+    pub fn max_templates(mut self, n: usize) -> Self {
+        self.template_budget = Some(Arc::new(AtomicUsize::new(n)));
+        self
+    }
+
+    /// take one template from the template budget, if there is one.
+    pub(crate) fn use_template_budget(&self) -> Result<(), CompilationError> {
+        match &self.template_budget {
+            Some(budget) => budget
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .map(|_| ())
+                .map_err(|_| CompilationError::TooManyTemplates),
+            None => Ok(()),
+        }
+    }
+
+    /// Call `f` each time compilation produces a template, e.g. to drive a
+    /// progress bar. The count of templates is shared with all derived
+    /// contexts, so it covers the whole compilation.
+    pub fn on_progress(mut self, f: ProgressCallback) -> Self {
+        self.progress = Some((f, Arc::new(AtomicUsize::new(0))));
+        self
+    }
+
+    /// count one more template towards the progress callback, if there is one.
+    pub(crate) fn report_progress(&self) {
+        if let Some((f, count)) = &self.progress {
+            f(CompileProgress {
+                templates: count.fetch_add(1, Ordering::SeqCst) + 1,
+                depth: self.path.iter().count(),
+            })
+        }
+    }
+
+    /// Compile assuming the current block height is `height`.
+    ///
+    /// Branches whose absolute height timelocks have already passed are
+    /// spendable now, so the compiler places them closer to the root of the
+    /// Taproot tree, making them cheaper to use than branches which are not
+    /// yet spendable.
+    pub fn at_height(mut self, height: u32) -> Self {
+        Arc::make_mut(&mut self.config).height = Some(height);
+        self
+    }
+
+    /// the block height set with [`Context::at_height`], if any
+    pub fn height(&self) -> Option<u32> {
+        self.config.height
+    }
+
+    /// Compile assuming the current median-time-past (the clock timestamp
+    /// timelocks are checked against, see BIP-113) is `mtp`.
+    ///
+    /// As with [`Context::at_height`], branches whose timestamp timelocks
+    /// have already passed are placed closer to the root of the Taproot
+    /// tree.
+    pub fn at_median_time_past(mut self, mtp: u32) -> Self {
+        Arc::make_mut(&mut self.config).median_time_past = Some(mtp);
+        self
+    }
+
+    /// the median-time-past set with [`Context::at_median_time_past`], if any
+    pub fn median_time_past(&self) -> Option<u32> {
+        self.config.median_time_past
+    }
+
+    /// Set every template's nLockTime to at least `height`, a recent block
+    /// height, so that the transactions look like wallets' anti-fee-sniping
+    /// transactions (and can't be mined in a reorg of earlier blocks).
+    ///
+    /// CTV commits to the lock time, so this changes the templates' hashes.
+    /// A template which sets a later height lock time keeps it, and one
+    /// which sets a timestamp lock time is an error, as a transaction can
+    /// only have one kind. Inherited by all derived contexts.
+    pub fn with_anti_fee_sniping(mut self, height: AbsHeight) -> Self {
+        Arc::make_mut(&mut self.config).anti_fee_sniping = Some(height);
+        self
+    }
+
+    /// the height set with [`Context::with_anti_fee_sniping`], if any
+    pub fn anti_fee_sniping(&self) -> Option<AbsHeight> {
+        self.config.anti_fee_sniping
+    }
+
+    /// Compile contracts in the domain `domain`, which every output commits
+    /// to (as an unspendable branch), so that the same contract compiled in
+    /// different domains gets different addresses, and so different CTV
+    /// hashes and emulator keys for the templates creating them.
+    ///
+    /// The domain is inherited by all derived contexts. See
+    /// [`Context::compile_many`].
+    pub fn in_domain(mut self, domain: sha256::Hash) -> Self {
+        Arc::make_mut(&mut self.config).domain = Some(domain);
+        self
+    }
+
+    /// the domain contracts are compiled in, if any, see
+    /// [`Context::in_domain`].
+    pub fn domain(&self) -> Option<sha256::Hash> {
+        self.config.domain
+    }
+
+    /// Compile contracts to outputs of type `script_type` (Taproot by
+    /// default).
+    ///
+    /// The setting is inherited by all derived contexts, so it applies to
+    /// every contract created, not just the one compiled with this context.
+    pub fn script_type(mut self, script_type: ScriptType) -> Self {
+        Arc::make_mut(&mut self.config).script_type = script_type;
+        self
+    }
+
+    /// the [`ScriptType`] contracts are compiled to
+    pub fn get_script_type(&self) -> ScriptType {
+        self.config.script_type
+    }
+
+    /// Allow (the default) or forbid spending the contract while the output
+    /// funding it is unconfirmed.
+    ///
+    /// When forbidden, every spending branch of the contract requires a
+    /// relative timelock of 1 block (and the Taproot key path, which can't be
+    /// timelocked, is disabled), so that the funding transaction can't be
+    /// replaced or pinned by a chain of unconfirmed spends.
+    ///
+    /// Unlike most settings, it only applies to the contract compiled with
+    /// this context: contracts it creates are funded by its own templates.
+    pub fn allow_unconfirmed_funding(mut self, allow: bool) -> Self {
+        self.allow_unconfirmed_funding = allow;
+        self
+    }
+
+    /// may the contract be spent while its funding is unconfirmed? see
+    /// [`Context::allow_unconfirmed_funding`].
+    pub fn is_unconfirmed_funding_allowed(&self) -> bool {
+        self.allow_unconfirmed_funding
+    }
+
+    /// Attach a deployment specific value (e.g., a service's key) which
+    /// contracts can read with [`Context::get`]. There is one value per type,
+    /// so inserting a second value of the same type replaces the first.
+    ///
+    /// Values are inherited by all derived contexts.
+    pub fn insert<T: Any + Send + Sync>(mut self, value: T) -> Self {
+        Arc::make_mut(&mut self.extensions).insert(TypeId::of::<T>(), Arc::new(value));
+        self
+    }
+
+    /// get the value of type `T` attached with [`Context::insert`], if any
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.extensions
+            .get(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_ref())
+    }
+
+    /// Provide the key `key` under `name`, e.g. a user's or a service's key,
+    /// so contracts can look it up with [`Context::key`] rather than storing
+    /// it themselves. Providing a `name` again replaces the previous key.
+    ///
+    /// Keys are inherited by all derived contexts.
+    pub fn with_key<S: Into<String>>(mut self, name: S, key: XOnlyPublicKey) -> Self {
+        Arc::make_mut(&mut self.config)
+            .keys
+            .insert(name.into(), key);
+        self
+    }
+
+    /// get the key provided under `name` with [`Context::with_key`], erroring
+    /// with [`CompilationError::MissingKey`] if there is none.
+    pub fn key(&self, name: &str) -> Result<XOnlyPublicKey, CompilationError> {
+        self.config
+            .keys
+            .get(name)
+            .copied()
+            .ok_or_else(|| CompilationError::MissingKey(name.into()))
+    }
+
+    /// Check an invariant of the contract being compiled, e.g. that two
+    /// outputs are of equal value, failing compilation with
+    /// [`CompilationError::AssertionFailed`] and `message` if it doesn't hold.
+    pub fn assert<S: Into<String>>(
+        &self,
+        condition: bool,
+        message: S,
+    ) -> Result<(), CompilationError> {
+        if condition {
+            Ok(())
+        } else {
+            Err(CompilationError::AssertionFailed(message.into()))
+        }
+    }
+
+    /// Record the event `e` in the compiled object of the contract being
+    /// compiled, for downstream tooling to read back with
+    /// [`super::Compiled::events`].
+    ///
+    /// Events emitted while compiling a contract created by this one are
+    /// recorded in that contract's compiled object instead.
+    pub fn emit<E: Event>(&self, e: E) -> Result<(), CompilationError> {
+        let v = serde_json::to_value(e).map_err(CompilationError::SerializationError)?;
+        self.events
+            .lock()
+            .unwrap()
+            .entry(E::NAME.into())
+            .or_default()
+            .push(v);
+        Ok(())
+    }
+
+    /// start recording emitted events in a fresh log, returning it, so that
+    /// a contract's events are kept apart from those of its parent.
+    pub(crate) fn new_event_log(&mut self, _i: InternalCompilerTag) -> Arc<Mutex<EventLog>> {
+        self.events = Default::default();
+        self.events.clone()
+    }
+
+    /// Use `f` to estimate the fee for each template, e.g. from current
+    /// mempool conditions, rather than [`DEFAULT_FEE_RATE`].
+    ///
+    /// The estimator is shared with all derived contexts.
+    pub fn fee_estimator(mut self, f: FeeEstimator) -> Self {
+        self.fee_estimator = Some(f);
+        self
+    }
+
+    /// estimate the fee for a template of `vsize` vbytes, see
+    /// [`Context::fee_estimator`].
+    pub fn estimate_fee(&self, vsize: usize) -> Amount {
+        match &self.fee_estimator {
+            Some(f) => f(vsize),
+            None => Amount::from_sat(DEFAULT_FEE_RATE * vsize as u64),
+        }
+    }
+
+    /// Use `f` in place of BIP-119 to compute the CTV hash of each template.
+    ///
+    /// The CTV emulator must use the same function (see e.g.
+    /// `HDOracleEmulator::with_ctv_hash`) or it will sign for the wrong
+    /// keys. The function is shared with all derived contexts.
+    pub fn ctv_hash_fn(mut self, f: CTVHashFn) -> Self {
+        self.ctv_hash = Some(f);
+        self
+    }
+
+    /// compute the CTV hash of `tx` for `input_index`, see
+    /// [`Context::ctv_hash_fn`].
+    pub fn ctv_hash(&self, tx: &bitcoin::Transaction, input_index: u32) -> sha256::Hash {
+        match &self.ctv_hash {
+            Some(f) => f(tx, input_index),
+            None => tx.get_ctv_hash(input_index),
         }
     }
 
-    /// return the available funds
+    /// return the available funds, including any reserved with
+    /// [`Context::reserve`] (see [`Context::available`])
     pub fn funds(&self) -> Amount {
         self.available_funds
     }
 
+    /// the available funds, as a `CoinAmount` (in sats), for APIs taking
+    /// one, see [`crate::util::coin_amount`].
+    pub fn available_coins(&self) -> CoinAmount {
+        amount_to_coins(self.available_funds)
+    }
+
+    /// Set aside `amount` of the available funds for the purpose `name`, e.g.
+    /// a fee buffer. Reserved funds may not be spent by this context or
+    /// handed to a sub-context via [`Context::with_amount`].
+    ///
+    /// Reserving an already reserved `name` replaces the previous amount.
+    /// Reservations are inherited by derived contexts.
+    pub fn reserve<S: Into<String>>(
+        mut self,
+        name: S,
+        amount: Amount,
+    ) -> Result<Self, CompilationError> {
+        let name = name.into();
+        let previous = self.reservations.remove(&name).unwrap_or(Amount::ZERO);
+        if self.available() < amount {
+            self.reservations.insert(name, previous);
+            return Err(self.out_of_funds(amount));
+        }
+        self.reservations.insert(name, amount);
+        Ok(self)
+    }
+
+    /// the total amount of funds set aside with [`Context::reserve`]
+    pub fn reserved_total(&self) -> Amount {
+        self.reservations
+            .values()
+            .fold(Amount::ZERO, |acc, amt| acc + *amt)
+    }
+
+    /// the available funds which are not reserved, i.e. what may still be
+    /// spent with [`Context::spend_amount`] or [`Context::with_amount`]
+    pub fn available(&self) -> Amount {
+        self.available_funds
+            .checked_sub(self.reserved_total())
+            .unwrap_or(Amount::ZERO)
+    }
+
     /// use the context's emulator to get a emulated (or not) clause
     pub fn ctv_emulator(
         &self,
@@ -123,16 +617,217 @@ impl Context {
         Ok(self.emulator.get_signer_for(b)?)
     }
 
+    /// use the context's emulator to get a clause which is satisfied once the
+    /// emulator attests to the message hash `m`, for contracts that branch on
+    /// oracle data.
+    pub fn attestation(
+        &self,
+        m: bitcoin::hashes::sha256::Hash,
+    ) -> Result<sapio_base::Clause, CompilationError> {
+        Ok(self.emulator.get_attester_for(m)?)
+    }
+
     /// Compile the compilable item with this context.
     pub fn compile<A: Compilable>(self, a: A) -> Result<Compiled, CompilationError> {
         a.compile(self)
     }
 
+    /// Compile `n` independent instances of `a`, e.g. to deploy the same
+    /// vault for `n` users, each to be funded separately.
+    ///
+    /// Instance `i` is compiled at the path `i` under this context's, in its
+    /// own domain (see [`Context::in_domain`]) derived from this context's
+    /// domain and `i`, so no two instances share an address.
+    pub fn compile_many<A: Compilable>(
+        mut self,
+        a: &A,
+        n: u64,
+    ) -> Result<Vec<Compiled>, CompilationError> {
+        let parent = self.config.domain.map_or([0u8; 32], |d| d.into_inner());
+        (0..n)
+            .map(|i| {
+                let domain = sha256::Hash::hash(
+                    &[&b"sapio/instance"[..], &parent, &i.to_be_bytes()].concat(),
+                );
+                let allow_unconfirmed_funding = self.allow_unconfirmed_funding;
+                a.compile(
+                    self.derive_num(i)?
+                        .allow_unconfirmed_funding(allow_unconfirmed_funding)
+                        .in_domain(domain),
+                )
+            })
+            .collect()
+    }
+
+    /// Compile the compilable item with this context, using the compilation
+    /// cache in the directory `dir` (which must exist).
+    ///
+    /// The cache is keyed by a hash of `a` and of everything in the context
+    /// that affects compilation: the network, funds, path (and the paths
+    /// already derived from it), effects, reservations, settings, and the
+    /// emulator's [`CTVEmulator::identity`]. On a
+    /// hit the cached result is loaded instead of compiling, and a
+    /// [`Context::on_progress`] callback is told of each template as if it
+    /// had been compiled. On a miss the result is compiled and stored. Errors
+    /// are never cached.
+    ///
+    /// Only `a`'s serialized fields are keyed, not its code: after changing
+    /// how a contract compiles (e.g. what its `then` functions pay), clear
+    /// the cache directory, otherwise the stale results will be loaded.
+    ///
+    /// Contexts with a [`Context::fee_estimator`], a
+    /// [`Context::ctv_hash_fn`], an emulator without an identity, values
+    /// attached with [`Context::insert`], or which collect errors (see [`Context::compile_collect_errors`])
+    /// can't be keyed, and a [`Context::max_templates`] budget must be
+    /// consumed by compiling, so they always compile without the cache.
+    pub fn compile_cached<A: Compilable + Serialize, P: AsRef<Path>>(
+        mut self,
+        a: A,
+        dir: P,
+    ) -> Result<Compiled, CompilationError> {
+        // every field is named, so that a new one can't be left out of the
+        // key by accident
+        let Context {
+            available_funds,
+            emulator,
+            network,
+            path,
+            already_derived,
+            effects,
+            config,
+            reservations,
+            template_budget,
+            error_sink,
+            fee_estimator,
+            extensions,
+            allow_unconfirmed_funding,
+            // only observes compilation
+            progress: _,
+            ctv_hash,
+            // recorded in the compiled object
+            events: _,
+        } = &self;
+        let emulator = match emulator.identity() {
+            Some(id) => id,
+            None => return self.compile(a),
+        };
+        if fee_estimator.is_some()
+            || ctv_hash.is_some()
+            || !extensions.is_empty()
+            || template_budget.is_some()
+            || error_sink.is_some()
+        {
+            return self.compile(a);
+        }
+        let mut already_derived: Vec<String> = already_derived.iter().map(String::from).collect();
+        already_derived.sort();
+        let key = serde_json::to_vec(&CacheKey {
+            contract: &a,
+            network: *network,
+            available_funds: *available_funds,
+            path: String::from(path.as_ref().clone()),
+            already_derived,
+            effects: effects.as_ref(),
+            reservations: reservations.iter().map(|(k, v)| (k, v.as_sat())).collect(),
+            allow_unconfirmed_funding: *allow_unconfirmed_funding,
+            config: config.as_ref(),
+            emulator,
+        })
+        .map_err(CompilationError::SerializationError)?;
+        let file = dir
+            .as_ref()
+            .join(format!("{}.json", sha256::Hash::hash(&key)));
+        let depth = self.path.iter().count();
+        // anything unreadable is treated as a miss and overwritten
+        if let Some(entry) = std::fs::read(&file)
+            .ok()
+            .and_then(|v| serde_json::from_slice::<CacheEntry>(&v).ok())
+        {
+            if let Some((f, count)) = &self.progress {
+                for d in entry.progress {
+                    f(CompileProgress {
+                        templates: count.fetch_add(1, Ordering::SeqCst) + 1,
+                        depth: depth + d,
+                    })
+                }
+            }
+            return Ok(entry.compiled);
+        }
+        // record the progress of compiling, to replay it on a hit
+        let recorded: Arc<Mutex<Vec<usize>>> = Default::default();
+        let (f, count) = self
+            .progress
+            .take()
+            .unwrap_or_else(|| (Arc::new(|_| ()), Default::default()));
+        let r = recorded.clone();
+        self.progress = Some((
+            Arc::new(move |p: CompileProgress| {
+                r.lock().unwrap().push(p.depth - depth);
+                f(p)
+            }),
+            count,
+        ));
+        let compiled = self.compile(a)?;
+        let progress = std::mem::take(&mut *recorded.lock().unwrap());
+        let entry = CacheEntry { compiled, progress };
+        let v = serde_json::to_vec(&entry).map_err(CompilationError::SerializationError)?;
+        std::fs::write(&file, v).map_err(CompilationError::custom)?;
+        Ok(entry.compiled)
+    }
+
+    /// Compile the compilable item with this context, continuing past errors
+    /// in independent branches (e.g., sibling sub-contracts) so that all of
+    /// them can be reported at once.
+    ///
+    /// The partial result is discarded if any error occurred.
+    pub fn compile_collect_errors<A: Compilable>(
+        mut self,
+        a: A,
+    ) -> Result<Compiled, Vec<CompilationError>> {
+        let sink: Arc<Mutex<Vec<CompilationError>>> = Default::default();
+        self.error_sink = Some(sink.clone());
+        let r = a.compile(self);
+        let mut errors = std::mem::take(&mut *sink.lock().unwrap());
+        match r {
+            Ok(c) if errors.is_empty() => Ok(c),
+            Ok(_) => Err(errors),
+            Err(e) => {
+                errors.push(e);
+                Err(errors)
+            }
+        }
+    }
+
+    /// If errors are being collected, record `e` and return None so the
+    /// caller may skip the failed branch, otherwise return `e`.
+    pub(crate) fn recover(&self, e: CompilationError) -> Option<CompilationError> {
+        match &self.error_sink {
+            Some(sink) => {
+                sink.lock().unwrap().push(e);
+                None
+            }
+            None => Some(e),
+        }
+    }
+
+    /// create an [`CompilationError::OutOfFundsDetail`] error pointing at this
+    /// context's path
+    fn out_of_funds(&self, requested: Amount) -> CompilationError {
+        CompilationError::OutOfFundsDetail {
+            path: String::from(self.path.as_ref().clone()),
+            requested,
+            available: self.available_funds,
+        }
+    }
+
     // TODO: Fix
     /// return a context with the new amount if amount is smaller or equal to available
+    ///
+    /// The new context has no reservations, and may only be given funds which
+    /// are not reserved in this one.
     pub fn with_amount(self, amount: Amount) -> Result<Self, CompilationError> {
-        if self.available_funds < amount {
-            Err(CompilationError::OutOfFunds)
+        if self.available() < amount {
+            Err(self.out_of_funds(amount))
         } else {
             Ok(Context {
                 available_funds: amount,
@@ -141,13 +836,50 @@ impl Context {
                 network: self.network,
                 already_derived: self.already_derived.clone(),
                 effects: self.effects.clone(),
+                config: self.config.clone(),
+                reservations: Default::default(),
+                template_budget: self.template_budget.clone(),
+                error_sink: self.error_sink.clone(),
+                fee_estimator: self.fee_estimator.clone(),
+                extensions: self.extensions.clone(),
+                allow_unconfirmed_funding: self.allow_unconfirmed_funding,
+                progress: self.progress.clone(),
+                ctv_hash: self.ctv_hash.clone(),
+                events: self.events.clone(),
             })
         }
     }
+    /// Like [`Context::with_amount`], but for a `CoinAmount`, which is
+    /// converted with [`crate::util::coin_amount::coins_to_amount`] (so
+    /// negative or impossibly large amounts are rejected).
+    pub fn with_coins(self, coins: CoinAmount) -> Result<Self, CompilationError> {
+        let amount = coins_to_amount(coins)?;
+        self.with_amount(amount)
+    }
+    /// return a context with the new amount, which must be exactly the
+    /// (unreserved) funds available, for contracts which must consume the
+    /// whole input.
+    ///
+    /// Any other amount, more or less, is a
+    /// [`CompilationError::AmountMismatch`]. Otherwise the same as
+    /// [`Context::with_amount`].
+    pub fn with_exact_amount(self, amount: Amount) -> Result<Self, CompilationError> {
+        if self.available() != amount {
+            Err(CompilationError::AmountMismatch(
+                String::from(self.path.as_ref().clone()),
+                amount,
+                self.available(),
+            ))
+        } else {
+            self.with_amount(amount)
+        }
+    }
     /// decrease the amount available in this context object.
+    ///
+    /// Funds set aside with [`Context::reserve`] may not be spent.
     pub fn spend_amount(mut self, amount: Amount) -> Result<Self, CompilationError> {
-        if self.available_funds < amount {
-            Err(CompilationError::OutOfFunds)
+        if self.available() < amount {
+            Err(self.out_of_funds(amount))
         } else {
             self.available_funds -= amount;
             Ok(self)
@@ -165,3 +897,371 @@ impl Context {
         crate::template::Builder::new(self)
     }
 }
+
+/// a Regtest [`Context`] at `path` with `sats` to spend and CTV available,
+/// shared by the crate's tests.
+#[cfg(test)]
+pub(crate) fn test_ctx(path: &str, sats: u64) -> Context {
+    use std::convert::TryFrom;
+    Context::new(
+        Network::Regtest,
+        Amount::from_sat(sats),
+        Arc::new(sapio_ctv_emulator_trait::CTVAvailable),
+        EffectPath::try_from(path).unwrap(),
+        Arc::new(Default::default()),
+    )
+}
+
+/// a [`Template`](crate::template::Template) from a [`test_ctx`] paying each
+/// of `outputs` its amount in sats, shared by the crate's tests.
+#[cfg(test)]
+pub(crate) fn test_template(outputs: &[(u64, &Compiled)]) -> crate::template::Template {
+    let mut builder = test_ctx("template", 100_000).template();
+    for (sats, o) in outputs {
+        builder = builder
+            .add_output(Amount::from_sat(*sats), *o, None)
+            .unwrap();
+    }
+    builder.into()
+}
+
+/// an op_return [`Compiled`] committing to each of `committed` with CTV,
+/// shared by the crate's tests.
+#[cfg(test)]
+pub(crate) fn test_compiled(committed: Vec<crate::template::Template>) -> Compiled {
+    let mut o = Compiled::from_op_return(&[0u8; 4][..]).unwrap();
+    o.ctv_to_tx = committed.into_iter().map(|t| (t.hash(), t)).collect();
+    o
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Contract;
+    use crate::then;
+    use bitcoin::util::psbt::PartiallySignedTransaction;
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::EmulatorError;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+    #[test]
+    fn test_out_of_funds_has_path() {
+        let mut ctx = test_ctx("top", 1000);
+        let nested = ctx.derive_str(Arc::new("nested".into())).unwrap();
+        let r = nested.template().add_output(
+            Amount::from_sat(1001),
+            &Compiled::from_op_return(&[0u8; 4][..]).unwrap(),
+            None,
+        );
+        match r {
+            Err(e @ CompilationError::OutOfFundsDetail { .. }) => {
+                assert!(e.to_string().contains("top/nested/#0"), "{}", e)
+            }
+            _ => panic!("expected OutOfFundsDetail"),
+        }
+    }
+
+    #[test]
+    fn test_with_amount_boundary() {
+        let ctx = || test_ctx("exact", 1000);
+        // the whole budget may be handed on
+        let all = ctx().with_amount(Amount::from_sat(1000)).unwrap();
+        assert_eq!(all.funds(), Amount::from_sat(1000));
+        assert_eq!(
+            ctx().with_amount(Amount::from_sat(999)).unwrap().funds(),
+            Amount::from_sat(999)
+        );
+        assert!(matches!(
+            ctx().with_amount(Amount::from_sat(1001)),
+            Err(CompilationError::OutOfFundsDetail { .. })
+        ));
+
+        let exact = ctx().with_exact_amount(Amount::from_sat(1000)).unwrap();
+        assert_eq!(exact.funds(), Amount::from_sat(1000));
+        for amount in [999, 1001] {
+            match ctx().with_exact_amount(Amount::from_sat(amount)) {
+                Err(CompilationError::AmountMismatch(path, requested, available)) => {
+                    assert_eq!(path, "exact");
+                    assert_eq!(requested, Amount::from_sat(amount));
+                    assert_eq!(available, Amount::from_sat(1000));
+                }
+                _ => panic!("expected AmountMismatch"),
+            }
+        }
+        // reserved funds are not available to consume
+        let reserved = || ctx().reserve("fees", Amount::from_sat(100)).unwrap();
+        assert!(reserved()
+            .with_exact_amount(Amount::from_sat(1000))
+            .is_err());
+        assert!(reserved().with_exact_amount(Amount::from_sat(900)).is_ok());
+    }
+
+    #[test]
+    fn test_available() {
+        let ctx = test_ctx("available", 1000);
+        assert_eq!(ctx.available(), Amount::from_sat(1000));
+        let ctx = ctx.spend_amount(Amount::from_sat(300)).unwrap();
+        assert_eq!(ctx.available(), Amount::from_sat(700));
+        let ctx = ctx.add_amount(Amount::from_sat(50));
+        assert_eq!(ctx.available(), Amount::from_sat(750));
+        // reserved funds count towards funds, but aren't available
+        let ctx = ctx.reserve("fees", Amount::from_sat(250)).unwrap();
+        assert_eq!(ctx.funds(), Amount::from_sat(750));
+        assert_eq!(ctx.available(), Amount::from_sat(500));
+        let ctx = ctx.spend_amount(Amount::from_sat(500)).unwrap();
+        assert_eq!(ctx.available(), Amount::ZERO);
+        assert_eq!(ctx.funds(), Amount::from_sat(250));
+    }
+
+    #[test]
+    fn test_with_coins() {
+        let ctx = || test_ctx("coins", 100_000_000);
+        assert_eq!(ctx().available_coins(), CoinAmount::Sats(100_000_000));
+        let half = ctx().with_coins(CoinAmount::Btc(0.5)).unwrap();
+        assert_eq!(half.funds(), Amount::from_sat(50_000_000));
+        assert_eq!(half.available_coins(), CoinAmount::Sats(50_000_000));
+        assert!(ctx().with_coins(CoinAmount::Sats(100_000_000)).is_ok());
+        assert!(matches!(
+            ctx().with_coins(CoinAmount::Sats(100_000_001)),
+            Err(CompilationError::OutOfFundsDetail { .. })
+        ));
+        assert!(matches!(
+            ctx().with_coins(CoinAmount::Btc(-0.5)),
+            Err(CompilationError::ParseAmountError(_))
+        ));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let ctx = || {
+            test_ctx("reserve", 10_000)
+                .reserve("fees", Amount::from_sat(3000))
+                .unwrap()
+                .reserve("buffer", Amount::from_sat(2000))
+                .unwrap()
+        };
+        assert_eq!(ctx().reserved_total(), Amount::from_sat(5000));
+        let out = Compiled::from_op_return(&[0u8; 4][..]).unwrap();
+        assert!(matches!(
+            ctx()
+                .template()
+                .add_output(Amount::from_sat(5001), &out, None),
+            Err(CompilationError::OutOfFundsDetail { .. })
+        ));
+        assert!(ctx().with_amount(Amount::from_sat(5001)).is_err());
+        assert!(ctx().reserve("more", Amount::from_sat(5001)).is_err());
+        // replacing a reservation frees up the difference
+        let ctx = ctx().reserve("fees", Amount::from_sat(1000)).unwrap();
+        assert!(ctx
+            .template()
+            .add_output(Amount::from_sat(7000), &out, None)
+            .is_ok());
+    }
+
+    /// pays its funds to an OP_RETURN, counting how often it is compiled
+    #[derive(Serialize, Clone, Default)]
+    struct Counted {
+        #[serde(skip)]
+        compilations: Arc<AtomicUsize>,
+    }
+    impl Counted {
+        #[then]
+        fn pay(self, ctx: Context) {
+            self.compilations.fetch_add(1, Ordering::SeqCst);
+            let amt = ctx.funds();
+            ctx.template()
+                .add_output(amt, &Compiled::from_op_return(&[0u8; 4][..])?, None)?
+                .into()
+        }
+    }
+    impl Contract for Counted {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    /// CTV, but without an identity to cache by
+    struct Anonymous;
+    impl CTVEmulator for Anonymous {
+        fn get_signer_for(&self, h: sha256::Hash) -> Result<Clause, EmulatorError> {
+            Ok(Clause::TxTemplate(h))
+        }
+        fn sign(
+            &self,
+            b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            Ok(b)
+        }
+    }
+
+    #[test]
+    fn test_compile_cached() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let ctx = |sats| test_ctx("cached", sats);
+        let counted = Counted::default();
+        let compilations = || counted.compilations.load(Ordering::SeqCst);
+        let first = ctx(10_000).compile_cached(counted.clone(), dir).unwrap();
+        assert_eq!(compilations(), 1);
+        let second = ctx(10_000).compile_cached(counted.clone(), dir).unwrap();
+        assert_eq!(compilations(), 1);
+        assert_eq!(
+            serde_json::to_value(&first).unwrap(),
+            serde_json::to_value(&second).unwrap()
+        );
+        // a different context misses
+        ctx(20_000).compile_cached(counted.clone(), dir).unwrap();
+        assert_eq!(compilations(), 2);
+        // as does one which places branches differently
+        ctx(10_000)
+            .at_median_time_past(1_600_000_000)
+            .compile_cached(counted.clone(), dir)
+            .unwrap();
+        assert_eq!(compilations(), 3);
+        // or has already derived some of its paths
+        let mut derived = ctx(10_000);
+        derived.derive_num(0u64).unwrap();
+        derived.compile_cached(counted.clone(), dir).unwrap();
+        assert_eq!(compilations(), 4);
+        // or checks it against other limits
+        assert!(matches!(
+            ctx(10_000)
+                .max_tx_weight(1)
+                .compile_cached(counted.clone(), dir),
+            Err(CompilationError::TransactionTooLarge(..))
+        ));
+        assert_eq!(compilations(), 5);
+        ctx(10_000)
+            .max_total_fees(Amount::ZERO)
+            .compile_cached(counted.clone(), dir)
+            .unwrap();
+        assert_eq!(compilations(), 6);
+        // a hit reports the same progress as compiling
+        let progress = |cached: bool| {
+            let seen: Arc<Mutex<Vec<CompileProgress>>> = Default::default();
+            let s = seen.clone();
+            let ctx = ctx(10_000).on_progress(Arc::new(move |p| s.lock().unwrap().push(p)));
+            if cached {
+                ctx.compile_cached(counted.clone(), dir).unwrap();
+            } else {
+                ctx.compile(counted.clone()).unwrap();
+            }
+            let v = seen.lock().unwrap().clone();
+            v
+        };
+        assert!(!progress(true).is_empty());
+        assert_eq!(progress(true), progress(false));
+        assert_eq!(compilations(), 7);
+        // a template budget is always checked, so bypasses the cache
+        assert!(matches!(
+            ctx(10_000)
+                .max_templates(0)
+                .compile_cached(counted.clone(), dir),
+            Err(CompilationError::TooManyTemplates)
+        ));
+        ctx(10_000)
+            .max_templates(1)
+            .compile_cached(counted.clone(), dir)
+            .unwrap();
+        assert_eq!(compilations(), 9);
+        // as does an emulator which can't be identified without asking it
+        for _ in 0..2 {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(10_000),
+                Arc::new(Anonymous),
+                EffectPath::try_from("cached").unwrap(),
+                Arc::new(Default::default()),
+            )
+            .compile_cached(counted.clone(), dir)
+            .unwrap();
+        }
+        assert_eq!(compilations(), 11);
+    }
+
+    /// a deployment specific parameter
+    struct ServiceTag([u8; 4]);
+    /// pays its funds to an OP_RETURN of the [`ServiceTag`] in its context
+    struct Tagged;
+    impl Tagged {
+        #[then]
+        fn pay(self, ctx: Context) {
+            let tag = ctx
+                .get::<ServiceTag>()
+                .ok_or_else(|| CompilationError::TerminateWith("no service tag".into()))?
+                .0;
+            let amt = ctx.funds();
+            ctx.template()
+                .add_output(amt, &Compiled::from_op_return(&tag[..])?, None)?
+                .into()
+        }
+    }
+    impl Contract for Tagged {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    /// pays its funds to a Taproot output spendable by the named keys "user"
+    /// and, in a script path, "service"
+    struct NamedKeys;
+    impl NamedKeys {
+        #[then]
+        fn pay(self, ctx: Context) {
+            let d = miniscript::Descriptor::<XOnlyPublicKey>::from_str(&format!(
+                "tr({},pk({}))",
+                ctx.key("user")?,
+                ctx.key("service")?
+            ))?;
+            let amt = ctx.funds();
+            ctx.template()
+                .add_output(amt, &Compiled::from_descriptor(d, None), None)?
+                .into()
+        }
+    }
+    impl Contract for NamedKeys {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_named_keys() {
+        let key = |s| XOnlyPublicKey::from_str(s).unwrap();
+        let user = key("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798");
+        let service = key("c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5");
+        let ctx = || test_ctx("keys", 10_000);
+        match ctx().with_key("user", user).compile(NamedKeys) {
+            Err(CompilationError::MissingKey(name)) => assert_eq!(name, "service"),
+            _ => panic!("expected MissingKey"),
+        }
+        let compiled = ctx()
+            .with_key("user", service)
+            .with_key("user", user)
+            .with_key("service", service)
+            .compile(NamedKeys)
+            .unwrap();
+        let out = &compiled.ctv_to_tx.values().next().unwrap().outputs[0].contract;
+        let mut keys = out.descriptor.as_ref().unwrap().keys();
+        keys.sort();
+        let mut expected = vec![user, service];
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_extensions() {
+        let ctx = || test_ctx("extensions", 10_000);
+        assert!(ctx().get::<ServiceTag>().is_none());
+        assert!(ctx().compile(Tagged).is_err());
+        let ctx = ctx()
+            .insert(ServiceTag([1; 4]))
+            .insert(ServiceTag([7; 4]))
+            .insert(42u32);
+        assert_eq!(ctx.get::<u32>(), Some(&42));
+        let compiled = ctx.compile(Tagged).unwrap();
+        let tmpl = compiled.ctv_to_tx.values().next().unwrap();
+        let expected: bitcoin::Script = Compiled::from_op_return(&[7u8; 4][..])
+            .unwrap()
+            .address
+            .into();
+        assert_eq!(tmpl.tx.output[0].script_pubkey, expected);
+    }
+}
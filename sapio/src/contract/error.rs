@@ -8,6 +8,7 @@
 //! Where possible, concrete error types are wrapped, but in order to handle
 //! errors created by the user we allow boxing an error trait.
 use crate::contract::object::ObjectError;
+use crate::util::standardness::StandardnessError;
 use sapio_base::effects::EffectDBError;
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::ValidFragmentError;
@@ -41,22 +42,82 @@ pub enum CompilationError {
     PathFragmentError(ValidFragmentError),
     /// Error when a `ThenFunc` returns no Templates.
     MissingTemplates,
+    /// Error when merging two Compiled objects which both define the same
+    /// template, continuation, or metadata entry
+    MergeConflict(String),
     /// Error if a Policy is empty
     EmptyPolicy,
+    /// Error when compilation produces more templates than allowed by
+    /// [`crate::contract::Context::max_templates`]
+    TooManyTemplates,
     /// Error if a contract does not have sufficient funds available
     OutOfFunds,
+    /// Error if a contract does not have sufficient funds available, with
+    /// where and by how much. Reported by [`crate::contract::Context`] in
+    /// place of [`CompilationError::OutOfFunds`].
+    OutOfFundsDetail {
+        /// the path of the context which overspent
+        path: String,
+        /// the amount requested
+        requested: bitcoin::util::amount::Amount,
+        /// the amount that was available
+        available: bitcoin::util::amount::Amount,
+    },
+    /// Error if a contract must consume exactly the funds available but was
+    /// given a different amount, see
+    /// [`crate::contract::Context::with_exact_amount`].
+    /// Contains the path of the context, the amount requested, and the amount
+    /// that was available.
+    AmountMismatch(
+        String,
+        bitcoin::util::amount::Amount,
+        bitcoin::util::amount::Amount,
+    ),
+    /// Error if a template (with the witness spending the contract) weighs
+    /// more than [`crate::contract::Context::max_tx_weight`] allows.
+    /// Contains the path of the contract, the weight of the template, and
+    /// the limit.
+    TransactionTooLarge(String, usize, usize),
+    /// Error if the fees paid across a compiled contract's templates (first)
+    /// exceed [`crate::contract::Context::max_total_fees`] (second)
+    FeeBudgetExceeded(bitcoin::util::amount::Amount, bitcoin::util::amount::Amount),
+    /// Error if a compiled contract's policy (second) is not semantically the
+    /// expected policy (first), see
+    /// [`crate::contract::object::Object::verify_against_policy`]
+    PolicyMismatch(String, String),
+    /// Error if an invariant checked with
+    /// [`crate::contract::Context::assert`] does not hold, with the
+    /// contract author's message
+    AssertionFailed(String),
+    /// Error if a key name requested with [`crate::contract::Context::key`]
+    /// was not provided with [`crate::contract::Context::with_key`]
+    MissingKey(String),
     /// Error if a CheckSequenceVerify clause is incompatible with the sequence already set.
     /// E.g., blocks and time
     IncompatibleSequence,
     /// Error if a CheckLockTime clause is incompatible with the locktime already set.
     /// E.g., blocks and time
     IncompatibleLockTime,
+    /// Error if a template in a compiled contract does not spend the output
+    /// of its parent template which creates its contract, see
+    /// [`crate::contract::object::Object::validate_linkage`]
+    BrokenLinkage(String),
+    /// Error if a template sets nLockTime but every input's nSequence is
+    /// final (0xFFFFFFFF), which disables the lock time
+    LockTimeDisabled,
+    /// Error if a template with a relative lock time has a version below 2,
+    /// which does not enable BIP-68 relative lock times
+    IncompatibleVersion,
     /// Error if a sequence at index j >= inputs.len() is attempted to be set
     NoSuchSequence,
+    /// Error if strict standardness is enabled and an output is non-standard
+    NonStandard(StandardnessError),
     /// Error if parsing an Amount failed
     ParseAmountError(bitcoin::util::amount::ParseAmountError),
     /// Error from the Policy Compiler
     Miniscript(miniscript::policy::compiler::CompilerError),
+    /// Error from the Policy Compiler, with the policy which failed to compile
+    PolicyCompilation(String, miniscript::policy::compiler::CompilerError),
     /// Error from the miniscript system
     MiniscriptE(miniscript::Error),
     /// Error with a Timelock
@@ -147,6 +208,12 @@ impl From<bitcoin::util::amount::ParseAmountError> for CompilationError {
     }
 }
 
+impl From<StandardnessError> for CompilationError {
+    fn from(e: StandardnessError) -> Self {
+        CompilationError::NonStandard(e)
+    }
+}
+
 impl From<sapio_base::timelocks::LockTimeError> for CompilationError {
     fn from(b: sapio_base::timelocks::LockTimeError) -> Self {
         CompilationError::TimeLockError(b)
@@ -168,9 +235,44 @@ impl From<ObjectError> for CompilationError {
     }
 }
 
+/// a human readable explanation of why a policy could not be compiled
+fn describe_compiler_error(e: &miniscript::policy::compiler::CompilerError) -> String {
+    use miniscript::policy::compiler::CompilerError::*;
+    match e {
+        TopLevelNonSafe => {
+            "the policy is not safe, some spending path requires no signature so anyone could spend it"
+                .into()
+        }
+        ImpossibleNonMalleableCompilation => {
+            "no non-malleable script exists for the policy, e.g. because a spending path can be satisfied in ways a third party could alter"
+                .into()
+        }
+        LimitsExceeded => {
+            "the script for a spending path exceeds consensus or standardness limits, try splitting up the policy"
+                .into()
+        }
+        PolicyError(e) => format!("the policy is invalid: {}", e),
+    }
+}
+
 impl fmt::Display for CompilationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            CompilationError::Miniscript(e) => {
+                write!(
+                    f,
+                    "could not compile policy: {}",
+                    describe_compiler_error(e)
+                )
+            }
+            CompilationError::PolicyCompilation(p, e) => write!(
+                f,
+                "could not compile policy {}: {}",
+                p,
+                describe_compiler_error(e)
+            ),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
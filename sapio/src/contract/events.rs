@@ -0,0 +1,27 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! side metadata which contracts emit during compilation for downstream
+//! tooling to consume
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A typed event a contract can emit with
+/// [`crate::contract::Context::emit`], e.g. that an output funds a lightning
+/// channel with some parameters.
+///
+/// Events are recorded in the compiled object of the contract which emitted
+/// them under [`Event::NAME`], and read back with
+/// [`crate::contract::Compiled::events`].
+pub trait Event: Serialize + DeserializeOwned {
+    /// the name events of this type are recorded under
+    const NAME: &'static str;
+}
+
+/// The events emitted while compiling a contract, by [`Event::NAME`], each in
+/// the order emitted.
+pub type EventLog = BTreeMap<String, Vec<serde_json::Value>>;
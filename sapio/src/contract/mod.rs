@@ -16,6 +16,8 @@ pub mod actions;
 pub mod compiler;
 pub mod error;
 pub use error::CompilationError;
+pub mod events;
+pub use events::Event;
 pub mod context;
 use bitcoin::util::amount::Amount;
 pub use compiler::Compilable;
@@ -111,6 +113,88 @@ where
     }
 }
 
+/// Builds a [`DynamicContract`] one function at a time, rather than
+/// populating its fields by hand.
+///
+/// Metadata defaults to none and the minimum amount to zero, unless set with
+/// [`DynamicContractBuilder::with_metadata`] or
+/// [`DynamicContractBuilder::with_ensure_amount`]. The contract is produced by
+/// [`DynamicContractBuilder::with_data`].
+pub struct DynamicContractBuilder<'a, T, S> {
+    then: Vec<fn() -> Option<actions::ThenFuncAsFinishOrFunc<'a, S, T>>>,
+    finish_or: Vec<fn() -> Option<Box<dyn actions::CallableAsFoF<S, T>>>>,
+    finish: Vec<fn() -> Option<actions::Guard<S>>>,
+    metadata_f: Box<dyn (Fn(&S, Context) -> Result<ObjectMetadata, CompilationError>)>,
+    ensure_amount_f: Box<dyn (Fn(&S, Context) -> Result<Amount, CompilationError>)>,
+}
+
+impl<'a, T, S> Default for DynamicContractBuilder<'a, T, S> {
+    fn default() -> Self {
+        DynamicContractBuilder {
+            then: vec![],
+            finish_or: vec![],
+            finish: vec![],
+            metadata_f: Box::new(|_, _| Ok(Default::default())),
+            ensure_amount_f: Box::new(|_, _| Ok(Amount::from_sat(0))),
+        }
+    }
+}
+
+impl<'a, T, S> DynamicContractBuilder<'a, T, S> {
+    /// create a builder for a contract with no functions
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// add a `ThenFunc`
+    pub fn add_then(
+        mut self,
+        f: fn() -> Option<actions::ThenFuncAsFinishOrFunc<'a, S, T>>,
+    ) -> Self {
+        self.then.push(f);
+        self
+    }
+    /// add a `FinishOrFunc`
+    pub fn add_finish_or(
+        mut self,
+        f: fn() -> Option<Box<dyn actions::CallableAsFoF<S, T>>>,
+    ) -> Self {
+        self.finish_or.push(f);
+        self
+    }
+    /// add a `Guard` the contract may finish with
+    pub fn add_guard(mut self, f: fn() -> Option<actions::Guard<S>>) -> Self {
+        self.finish.push(f);
+        self
+    }
+    /// generate the contract's metadata with `f`
+    pub fn with_metadata(
+        mut self,
+        f: impl Fn(&S, Context) -> Result<ObjectMetadata, CompilationError> + 'static,
+    ) -> Self {
+        self.metadata_f = Box::new(f);
+        self
+    }
+    /// generate the contract's minimum amount with `f`
+    pub fn with_ensure_amount(
+        mut self,
+        f: impl Fn(&S, Context) -> Result<Amount, CompilationError> + 'static,
+    ) -> Self {
+        self.ensure_amount_f = Box::new(f);
+        self
+    }
+    /// produce the contract, passing `data` to its functions
+    pub fn with_data(self, data: S) -> DynamicContract<'a, T, S> {
+        DynamicContract {
+            then: self.then,
+            finish_or: self.finish_or,
+            finish: self.finish,
+            metadata_f: self.metadata_f,
+            ensure_amount_f: self.ensure_amount_f,
+            data,
+        }
+    }
+}
+
 /// Catch all trait for things `StatefulArguments` must be required to do.
 pub trait StatefulArgumentsTrait: Default {}
 impl StatefulArgumentsTrait for () {}
@@ -191,3 +275,86 @@ where
         Self::Ref::ensure_amount(self, ctx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::actions::{FinishOrFunc, Guard, ThenFunc, WebAPIDisabled};
+    use super::*;
+    use crate::contract::context::test_ctx;
+    use bitcoin::XOnlyPublicKey;
+    use miniscript::Descriptor;
+    use sapio_base::Clause;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    fn key(k: &str) -> Clause {
+        Clause::Key(XOnlyPublicKey::from_str(k).unwrap())
+    }
+    fn alice() -> Clause {
+        key("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+    }
+    fn bob() -> Clause {
+        key("c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5")
+    }
+    fn alice_guard() -> Option<Guard<u64>> {
+        Some(Guard::Fresh(|_, _| alice(), None))
+    }
+    fn bob_guard() -> Option<Guard<u64>> {
+        Some(Guard::Fresh(|_, _| bob(), None))
+    }
+
+    #[test]
+    fn test_dynamic_contract_builder() {
+        let contract = DynamicContractBuilder::<(), u64>::new()
+            .add_then(|| {
+                Some(
+                    ThenFunc {
+                        guard: &[],
+                        conditional_compile_if: &[],
+                        func: |sats: &u64, ctx, _| {
+                            ctx.template()
+                                .add_output(
+                                    Amount::from_sat(*sats),
+                                    &Compiled::from_op_return(&[0u8; 4][..])?,
+                                    None,
+                                )?
+                                .into()
+                        },
+                        name: Arc::new("pay".into()),
+                    }
+                    .into(),
+                )
+            })
+            .add_finish_or(|| {
+                Some(Box::new(FinishOrFunc::<u64, (), (), WebAPIDisabled> {
+                    simp_gen: None,
+                    coerce_args: |_| Ok(()),
+                    guard: &[alice_guard],
+                    conditional_compile_if: &[],
+                    func: |_, _, _| empty(),
+                    schema: None,
+                    name: Arc::new("update".into()),
+                    f: Default::default(),
+                    returned_txtmpls_modify_guards: false,
+                    extract_clause_from_txtmpl: actions::default_extract_clause_from_txtmpl,
+                }))
+            })
+            .add_guard(bob_guard)
+            .with_data(5000);
+        assert_eq!(contract.data, 5000);
+        let compiled = contract.compile(test_ctx("dynamic", 100_000)).unwrap();
+        assert_eq!(compiled.ctv_to_tx.len(), 1);
+        assert_eq!(
+            compiled.ctv_to_tx.values().next().unwrap().total_amount(),
+            Amount::from_sat(5000)
+        );
+        assert_eq!(compiled.continue_apis.len(), 1);
+        // one leaf each for the then, the finish_or, and the guard
+        match compiled.descriptor {
+            Some(object::SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
+                assert_eq!(t.iter_scripts().count(), 3)
+            }
+            _ => panic!("expected a taproot descriptor"),
+        }
+    }
+}
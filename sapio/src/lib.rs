@@ -8,6 +8,10 @@
 #![cfg_attr(feature = "nightly", feature(associated_type_defaults))]
 #![deny(missing_docs)]
 
+// lets the `#[then]`/`#[guard]` macros resolve `sapio::` paths in this crate's tests
+#[cfg(test)]
+extern crate self as sapio;
+
 #[macro_use]
 pub mod contract;
 pub mod template;
@@ -8,10 +8,14 @@
 use super::input::InputMetadata;
 pub use super::{Output, OutputMeta};
 use super::{Template, TemplateMetadata};
-use crate::contract::{CompilationError, Context};
+use crate::contract::object::SupportedDescriptors;
+use crate::contract::{CompilationError, Compiled, Context};
+use crate::util::standardness::check_output;
 use bitcoin::util::amount::Amount;
+use bitcoin::Script;
 use bitcoin::VarInt;
 use bitcoin::Witness;
+use miniscript::{Descriptor, MiniscriptKey, ToPublicKey};
 use sapio_base::effects::PathFragment;
 use sapio_base::simp::SIMPAttachableAt;
 use sapio_base::simp::TemplateInputLT;
@@ -28,6 +32,9 @@ pub struct Builder {
     guards: Vec<Clause>,
     // TODO: Should be Comitted/Uncomitted if not CTV
     sequences: Vec<Option<AnyRelTimeLock>>,
+    /// nSequence values set with [`Builder::set_nsequence`], which override
+    /// the encoding of `sequences`
+    nsequences: Vec<Option<u32>>,
     outputs: Vec<Output>,
     inputs: Vec<InputMetadata>,
     version: i32,
@@ -41,14 +48,18 @@ pub struct Builder {
 
 impl Builder {
     /// Creates a new transaction template with 1 input and no outputs.
+    ///
+    /// The lock time starts at the context's
+    /// [`Context::with_anti_fee_sniping`] height, if set.
     pub fn new(ctx: Context) -> Builder {
         Builder {
             guards: Vec::new(),
             sequences: vec![None],
+            nsequences: vec![None],
             inputs: vec![InputMetadata::default()],
             outputs: vec![],
             version: 2,
-            lock_time: None,
+            lock_time: ctx.anti_fee_sniping().map(Into::into),
             metadata: TemplateMetadata::new(),
             fees: Amount::from_sat(0),
             min_feerate: None,
@@ -74,6 +85,17 @@ impl Builder {
         Ok(c)
     }
 
+    /// add the fee for this template as estimated by the context's
+    /// [`crate::contract::context::FeeEstimator`] (or the default fixed fee
+    /// rate) for the template's current size.
+    ///
+    /// Should be called after all outputs are added, since the size does not
+    /// account for later outputs. The size excludes the witness.
+    pub fn add_estimated_fees(self) -> Result<Self, CompilationError> {
+        let fee = self.ctx.estimate_fee(self.estimate_tx_size() as usize);
+        self.add_fees(fee)
+    }
+
     /// Creates a new Output, forcing the compilation of the compilable object and defaulting
     /// metadata if not provided to blank.
     pub fn add_output(
@@ -87,14 +109,60 @@ impl Builder {
             .derive(PathFragment::Branch(self.outputs.len() as u64))?
             .with_amount(amount)?;
         let mut ret = self.spend_amount(amount)?;
+        let contract = contract.compile(subctx)?;
+        if ret.ctx.is_strict_standardness() {
+            check_output(&contract.address.clone().into(), amount)?;
+        }
         ret.outputs.push(Output {
             amount,
-            contract: contract.compile(subctx)?,
+            contract,
             added_metadata: metadata.unwrap_or_default(),
         });
         Ok(ret)
     }
 
+    /// Creates a new Output paying directly to `script`, rather than to a
+    /// compiled contract. Useful for mixing script types across outputs, e.g.
+    /// paying a user's P2WPKH alongside sub-contract outputs.
+    pub fn add_output_script(
+        self,
+        amount: Amount,
+        script: Script,
+        metadata: Option<OutputMeta>,
+    ) -> Result<Self, CompilationError> {
+        let compiled = Compiled::from_script(script, None, self.ctx.network)?;
+        self.add_output(amount, &compiled, metadata)
+    }
+
+    /// Creates a new Output paying (as P2WSH) to the hand-written witness
+    /// script `script`, bypassing the miniscript compiler. The script is
+    /// carried verbatim into the `witness_script` of PSBTs spending it.
+    pub fn add_raw_script_output(
+        self,
+        amount: Amount,
+        script: Script,
+        metadata: Option<OutputMeta>,
+    ) -> Result<Self, CompilationError> {
+        let compiled = Compiled::from_raw_script(script, None);
+        self.add_output(amount, &compiled, metadata)
+    }
+
+    /// Creates a new Output paying to the descriptor `d`, e.g. a `wpkh` or
+    /// `wsh` descriptor, independent of the script type of other outputs.
+    pub fn add_output_descriptor<T>(
+        self,
+        amount: Amount,
+        d: Descriptor<T>,
+        metadata: Option<OutputMeta>,
+    ) -> Result<Self, CompilationError>
+    where
+        Descriptor<T>: Into<SupportedDescriptors>,
+        T: MiniscriptKey + ToPublicKey,
+    {
+        let compiled = Compiled::from_descriptor(d, None);
+        self.add_output(amount, &compiled, metadata)
+    }
+
     /// adds available funds to the builder's context object.
     /// TODO: Make guarantee there is some external input?
     pub fn add_amount(mut self, a: Amount) -> Self {
@@ -106,6 +174,7 @@ impl Builder {
     /// set_sequence(-1, ...) to fill in the back.
     pub fn add_sequence(mut self) -> Self {
         self.sequences.push(None);
+        self.nsequences.push(None);
         self.inputs.push(Default::default());
         self
     }
@@ -114,8 +183,15 @@ impl Builder {
     /// same kind. Differing kinds will throw an error. Otherwise, it will merge
     /// by taking the max of the argument.
     ///
+    /// Relative lock times are only enforced for transactions of version 2 or
+    /// higher (BIP-68), so this errors if [`Builder::set_version`] set a
+    /// lower version.
+    ///
     /// Negative indexing allows us to work from the back element easily
     pub fn set_sequence(mut self, ii: isize, s: AnyRelTimeLock) -> Result<Self, CompilationError> {
+        if self.version < 2 {
+            return Err(CompilationError::IncompatibleVersion);
+        }
         let i = if ii >= 0 {
             ii
         } else {
@@ -136,7 +212,42 @@ impl Builder {
             }
             None => return Err(CompilationError::NoSuchSequence),
         };
-        Ok(self)
+        match (self.sequences[i], self.nsequences[i]) {
+            (Some(lock), Some(n)) if !satisfies_rel_lock(n, lock) => {
+                Err(CompilationError::IncompatibleSequence)
+            }
+            _ => Ok(self),
+        }
+    }
+
+    /// set the exact nSequence of an input, e.g. to signal RBF
+    /// (`0xfffffffd`) as well as enforcing a relative timelock.
+    ///
+    /// The sequence must enforce any relative lock time set on the input with
+    /// [`Builder::set_sequence`], and may not be final (`0xffffffff`) if an
+    /// absolute lock time is set, as that would disable it. CTV commits to
+    /// the sequences, so the template's hash reflects the value set.
+    ///
+    /// Negative indexing allows us to work from the back element easily
+    pub fn set_nsequence(mut self, ii: isize, sequence: u32) -> Result<Self, CompilationError> {
+        let i = if ii >= 0 {
+            ii
+        } else {
+            self.sequences.len() as isize + ii
+        } as usize;
+        match self.sequences.get(i) {
+            None => Err(CompilationError::NoSuchSequence),
+            Some(Some(lock)) if !satisfies_rel_lock(sequence, *lock) => {
+                Err(CompilationError::IncompatibleSequence)
+            }
+            _ if self.lock_time.is_some() && sequence == u32::MAX => {
+                Err(CompilationError::IncompatibleLockTime)
+            }
+            _ => {
+                self.nsequences[i] = Some(sequence);
+                Ok(self)
+            }
+        }
     }
 
     /// attempts to add a SIMP to the output meta.
@@ -165,6 +276,9 @@ impl Builder {
     /// same kind. Differing kinds will throw an error. Otherwise, it will merge
     /// by taking the max of the argument.
     pub fn set_lock_time(mut self, lt_in: AnyAbsTimeLock) -> Result<Self, CompilationError> {
+        if self.nsequences.contains(&Some(u32::MAX)) {
+            return Err(CompilationError::IncompatibleLockTime);
+        }
         if let Some(lt) = self.lock_time.as_mut() {
             match (*lt, lt_in) {
                 (a @ AnyAbsTimeLock::AH(_), b @ AnyAbsTimeLock::AH(_)) => {
@@ -181,6 +295,20 @@ impl Builder {
         Ok(self)
     }
 
+    /// set the transaction's nVersion, which defaults to 2. CTV commits to the
+    /// version, so the template's hash reflects the value set.
+    ///
+    /// Versions below 2 do not enforce relative lock times (BIP-68), so they
+    /// are an error if a relative lock time has been set with
+    /// [`Builder::set_sequence`].
+    pub fn set_version(mut self, version: i32) -> Result<Self, CompilationError> {
+        if version < 2 && self.sequences.iter().any(Option::is_some) {
+            return Err(CompilationError::IncompatibleVersion);
+        }
+        self.version = version;
+        Ok(self)
+    }
+
     /// overwrite any existing label with the provided string,
     /// or set a label if none provided thus far.
     pub fn set_label(mut self, label: String) -> Self {
@@ -235,10 +363,11 @@ impl Builder {
             input: self
                 .sequences
                 .iter()
-                .map(|sequence| bitcoin::TxIn {
+                .zip(self.nsequences.iter())
+                .map(|(sequence, nsequence)| bitcoin::TxIn {
                     previous_output: Default::default(),
                     script_sig: Default::default(),
-                    sequence: sequence.unwrap_or(default_seq).get(),
+                    sequence: nsequence.unwrap_or_else(|| sequence.unwrap_or(default_seq).get()),
                     witness: Witness::new(),
                 })
                 .collect(),
@@ -312,6 +441,15 @@ impl Builder {
         }
     }
 }
+/// does the nSequence `n` enforce the relative lock time `lock` under BIP-68?
+pub(crate) fn satisfies_rel_lock(n: u32, lock: AnyRelTimeLock) -> bool {
+    const DISABLE_FLAG: u32 = 1 << 31;
+    const TYPE_FLAG: u32 = 1 << 22;
+    const MASK: u32 = 0xffff;
+    let l = lock.get();
+    n & DISABLE_FLAG == 0 && n & TYPE_FLAG == l & TYPE_FLAG && n & MASK >= l & MASK
+}
+
 impl From<Builder> for Template {
     fn from(t: Builder) -> Template {
         let tx = t.get_tx();
@@ -319,12 +457,13 @@ impl From<Builder> for Template {
             guards: t.guards,
             outputs: t.outputs,
             inputs: t.inputs,
-            ctv: tx.get_ctv_hash(0),
+            ctv: t.ctx.ctv_hash(&tx, 0),
             ctv_index: 0,
             max: tx.total_amount() + t.fees,
             min_feerate_sats_vbyte: t.min_feerate,
             tx,
             metadata_map_s2s: t.metadata,
+            spent_descriptor: None,
         }
     }
 }
@@ -335,3 +474,316 @@ impl From<Builder> for crate::contract::TxTmplIt {
         Ok(Box::new(std::iter::once(Ok(t.into()))))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::test_ctx;
+    use crate::contract::context::DEFAULT_FEE_RATE;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::PublicKey;
+    use miniscript::DescriptorTrait;
+    use std::sync::Arc;
+    #[test]
+    fn test_mixed_output_script_types() {
+        let secp = Secp256k1::new();
+        let key = |b| PublicKey::new(SecretKey::from_slice(&[b; 32]).unwrap().public_key(&secp));
+        let wpkh = Descriptor::new_wpkh(key(1)).unwrap();
+        let wsh: Descriptor<PublicKey> = Descriptor::new_wsh(
+            miniscript::Miniscript::from_str_insane(&format!("pk({})", key(2))).unwrap(),
+        )
+        .unwrap();
+        let ctx = test_ctx("mixed", 100_000);
+        let tx = ctx
+            .template()
+            .add_output_descriptor(Amount::from_sat(10_000), wpkh.clone(), None)
+            .unwrap()
+            .add_output_descriptor(Amount::from_sat(10_000), wsh.clone(), None)
+            .unwrap()
+            .add_output_script(Amount::from_sat(10_000), wpkh.script_pubkey(), None)
+            .unwrap()
+            .get_tx();
+        assert!(tx.output[0].script_pubkey.is_v0_p2wpkh());
+        assert_eq!(tx.output[0].script_pubkey, wpkh.script_pubkey());
+        assert!(tx.output[1].script_pubkey.is_v0_p2wsh());
+        assert_eq!(tx.output[1].script_pubkey, wsh.script_pubkey());
+        assert_eq!(tx.output[2].script_pubkey, wpkh.script_pubkey());
+    }
+
+    #[test]
+    fn test_raw_script_output() {
+        use bitcoin::blockdata::opcodes::all::{OP_ADD, OP_EQUAL};
+        let ctx = || test_ctx("raw", 100_000);
+        // <x> <y> such that x + y == 5, not a miniscript
+        let script = bitcoin::blockdata::script::Builder::new()
+            .push_opcode(OP_ADD)
+            .push_int(5)
+            .push_opcode(OP_EQUAL)
+            .into_script();
+        let t: Template = ctx()
+            .template()
+            .add_raw_script_output(Amount::from_sat(10_000), script.clone(), None)
+            .unwrap()
+            .into();
+        assert_eq!(t.tx.output[0].script_pubkey, script.to_v0_p2wsh());
+        let raw = &t.outputs[0].contract;
+        // spending the output carries the script through untouched
+        let mut spend: Template = ctx()
+            .template()
+            .add_output(
+                Amount::from_sat(5_000),
+                &Compiled::from_op_return(&[0u8; 4][..]).unwrap(),
+                None,
+            )
+            .unwrap()
+            .into();
+        spend.spent_descriptor = raw.descriptor.clone();
+        let psbt = spend.to_oracle_psbt();
+        assert_eq!(psbt.inputs[0].witness_script, Some(script.clone()));
+        assert_eq!(
+            psbt.inputs[0].witness_utxo.as_ref().unwrap().script_pubkey,
+            script.to_v0_p2wsh()
+        );
+    }
+
+    #[test]
+    fn test_oracle_psbt_additional_inputs() {
+        let secp = Secp256k1::new();
+        let key = PublicKey::new(SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp));
+        let wsh: Descriptor<PublicKey> = Descriptor::new_wsh(
+            miniscript::Miniscript::from_str_insane(&format!("pk({})", key)).unwrap(),
+        )
+        .unwrap();
+        let mut spend: Template = test_ctx("additional", 100_000)
+            .template()
+            .add_sequence()
+            .add_output(
+                Amount::from_sat(5_000),
+                &Compiled::from_op_return(&[0u8; 4][..]).unwrap(),
+                None,
+            )
+            .unwrap()
+            .into();
+        // built outside of a contract, the Template doesn't know what it spends
+        assert_eq!(spend.to_oracle_psbt().inputs[0], Default::default());
+        spend.spent_descriptor = Some(wsh.clone().into());
+        let psbt = spend.to_oracle_psbt();
+        assert_eq!(
+            psbt.inputs[0].witness_script,
+            Some(wsh.explicit_script().unwrap())
+        );
+        assert_eq!(
+            psbt.inputs[0].witness_utxo,
+            Some(bitcoin::TxOut {
+                value: spend.max.as_sat(),
+                script_pubkey: wsh.script_pubkey(),
+            })
+        );
+        // to_oracle_psbt leaves the additional input empty
+        assert_eq!(psbt.inputs[1], Default::default());
+        assert!(matches!(
+            spend.to_oracle_psbt_with_inputs(&[]),
+            Err(crate::contract::object::ObjectError::PrevoutCountMismatch(
+                1, 0
+            ))
+        ));
+        let prevout = bitcoin::TxOut {
+            value: 10_000,
+            script_pubkey: wsh.script_pubkey(),
+        };
+        // an additional input's descriptor is filled in like the funding's
+        let psbt = spend
+            .to_oracle_psbt_with_inputs(&[(prevout.clone(), Some(wsh.clone().into()))])
+            .unwrap();
+        assert_eq!(
+            psbt.inputs[1].witness_script,
+            Some(wsh.explicit_script().unwrap())
+        );
+        assert_eq!(psbt.inputs[1].witness_utxo, Some(prevout.clone()));
+        // and without one only the prevout is known
+        let psbt = spend
+            .to_oracle_psbt_with_inputs(&[(prevout.clone(), None)])
+            .unwrap();
+        assert_eq!(psbt.inputs[1].witness_script, None);
+        assert_eq!(psbt.inputs[1].witness_utxo, Some(prevout));
+    }
+
+    #[test]
+    fn test_set_nsequence() {
+        let ctx = || test_ctx("sequence", 100_000);
+        let build = |b: Builder| -> Template {
+            b.add_output(
+                Amount::from_sat(10_000),
+                &Compiled::from_op_return(&[0u8; 4][..]).unwrap(),
+                None,
+            )
+            .unwrap()
+            .into()
+        };
+        let plain = build(ctx().template());
+        let rbf = build(
+            ctx()
+                .template()
+                .add_sequence()
+                .set_nsequence(-1, 0xfffffffd)
+                .unwrap(),
+        );
+        assert_eq!(rbf.tx.input[1].sequence, 0xfffffffd);
+        assert_eq!(rbf.tx.input[0].sequence, plain.tx.input[0].sequence);
+        assert_eq!(rbf.hash(), rbf.tx.get_ctv_hash(0));
+
+        // a relative height lock of 10 blocks
+        let locked = || {
+            ctx()
+                .template()
+                .set_sequence(0, RelHeight::from(10).into())
+                .unwrap()
+        };
+        // disables the lock
+        assert!(locked().set_nsequence(0, 0xfffffffd).is_err());
+        // too short, or a time lock
+        assert!(locked().set_nsequence(0, 9).is_err());
+        assert!(locked().set_nsequence(0, (1 << 22) | 10).is_err());
+        // RBF signaling, with the lock enforced
+        let t = build(locked().set_nsequence(0, 10).unwrap());
+        assert_eq!(t.tx.input[0].sequence, 10);
+        // checked when the lock is set later too
+        assert!(ctx()
+            .template()
+            .set_nsequence(0, 5)
+            .unwrap()
+            .set_sequence(0, RelHeight::from(10).into())
+            .is_err());
+
+        // a final sequence disables absolute lock times
+        let abs: AnyAbsTimeLock = AbsHeight::try_from(100).unwrap().into();
+        assert!(ctx()
+            .template()
+            .set_lock_time(abs)
+            .unwrap()
+            .set_nsequence(0, u32::MAX)
+            .is_err());
+        assert!(ctx()
+            .template()
+            .set_nsequence(0, u32::MAX)
+            .unwrap()
+            .set_lock_time(abs)
+            .is_err());
+        assert!(matches!(
+            ctx().template().set_nsequence(1, 0),
+            Err(CompilationError::NoSuchSequence)
+        ));
+    }
+
+    #[test]
+    fn test_anti_fee_sniping() {
+        let ctx = || {
+            test_ctx("anti_fee_sniping", 100_000)
+                .with_anti_fee_sniping(AbsHeight::try_from(700_000).unwrap())
+        };
+        let t: Template = ctx().template().into();
+        assert_eq!(t.tx.lock_time, 700_000);
+        assert_eq!(t.hash(), t.tx.get_ctv_hash(0));
+        // a later height lock wins, an earlier one doesn't
+        for (lock, expected) in [(800_000, 800_000), (100, 700_000)] {
+            let abs: AnyAbsTimeLock = AbsHeight::try_from(lock).unwrap().into();
+            let t: Template = ctx().template().set_lock_time(abs).unwrap().into();
+            assert_eq!(t.tx.lock_time, expected);
+        }
+        // a timestamp lock conflicts with it
+        let time: AnyAbsTimeLock = AbsTime::try_from(1_600_000_000).unwrap().into();
+        assert!(ctx().template().set_lock_time(time).is_err());
+        // and nothing changes when it isn't set
+        let plain = test_ctx("anti_fee_sniping", 100_000);
+        assert_eq!(plain.template().get_tx().lock_time, 0);
+    }
+
+    #[test]
+    fn test_set_version() {
+        let ctx = || test_ctx("version", 100_000);
+        let csv: AnyRelTimeLock = RelHeight::from(10).into();
+        assert_eq!(ctx().template().get_tx().version, 2);
+        assert!(matches!(
+            ctx()
+                .template()
+                .set_sequence(0, csv)
+                .unwrap()
+                .set_version(1),
+            Err(CompilationError::IncompatibleVersion)
+        ));
+        assert!(matches!(
+            ctx()
+                .template()
+                .set_version(1)
+                .unwrap()
+                .set_sequence(0, csv),
+            Err(CompilationError::IncompatibleVersion)
+        ));
+        let v2 = ctx()
+            .template()
+            .set_sequence(0, csv)
+            .unwrap()
+            .set_version(2)
+            .unwrap()
+            .get_tx();
+        assert_eq!(v2.version, 2);
+        assert_eq!(v2.input[0].sequence, 10);
+        // without relative lock times any version may be used
+        let v1 = ctx().template().set_version(1).unwrap().get_tx();
+        assert_eq!(v1.version, 1);
+        assert_ne!(v1.get_ctv_hash(0), v2.get_ctv_hash(0));
+    }
+
+    #[test]
+    fn test_label_round_trip() {
+        let ctx = test_ctx("label", 100_000);
+        let t: Template = ctx
+            .template()
+            .add_output(
+                Amount::from_sat(10_000),
+                &Compiled::from_op_return(&[0u8; 4][..]).unwrap(),
+                None,
+            )
+            .unwrap()
+            .set_label("cold storage recovery".into())
+            .into();
+        assert_eq!(t.label(), Some("cold storage recovery"));
+        let json = serde_json::to_value(&t).unwrap();
+        assert_eq!(json["metadata_map_s2s"]["label"], "cold storage recovery");
+        let t: Template = serde_json::from_value(json).unwrap();
+        assert_eq!(t.label(), Some("cold storage recovery"));
+    }
+
+    #[test]
+    fn test_fee_estimator() {
+        let ctx = || test_ctx("fees", 100_000);
+        let build = |ctx: Context| {
+            ctx.template()
+                .add_output(
+                    Amount::from_sat(10_000),
+                    &Compiled::from_op_return(&[0u8; 4][..]).unwrap(),
+                    None,
+                )
+                .unwrap()
+        };
+        let vsize = build(ctx()).estimate_tx_size() as usize;
+        let calls: Arc<std::sync::Mutex<Vec<usize>>> = Default::default();
+        let recorder = calls.clone();
+        let estimator = Arc::new(move |vsize: usize| {
+            recorder.lock().unwrap().push(vsize);
+            Amount::from_sat(5 * vsize as u64)
+        });
+        let t: Template = build(ctx().fee_estimator(estimator))
+            .add_estimated_fees()
+            .unwrap()
+            .into();
+        assert_eq!(*calls.lock().unwrap(), vec![vsize]);
+        assert_eq!(t.max, Amount::from_sat(10_000 + 5 * vsize as u64));
+        // without an estimator, the default fixed rate applies
+        let t: Template = build(ctx()).add_estimated_fees().unwrap().into();
+        assert_eq!(
+            t.max,
+            Amount::from_sat(10_000 + DEFAULT_FEE_RATE * vsize as u64)
+        );
+    }
+}
@@ -5,9 +5,14 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! utilities for building Bitcoin transaction templates up programmatically
+use crate::contract::abi::object::bind::add_descriptor_info;
 use crate::contract::error::CompilationError;
+use crate::contract::object::{ObjectError, SupportedDescriptors};
 use bitcoin::hashes::sha256;
+use bitcoin::secp256k1::Secp256k1;
 use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::TxOut;
 use sapio_base::simp::SIMPAttachableAt;
 use sapio_base::simp::SIMPError;
 use sapio_base::simp::TemplateInputLT;
@@ -152,6 +157,14 @@ pub struct Template {
     /// sapio specific information about all the inputs in the `tx`.
     #[serde(rename = "inputs_info")]
     pub inputs: Vec<InputMetadata>,
+    /// the descriptor of the contract this Template spends (as its first
+    /// input), filled in when that contract is compiled
+    #[serde(
+        rename = "spent_descriptor",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub spent_descriptor: Option<SupportedDescriptors>,
 }
 
 impl Template {
@@ -160,6 +173,12 @@ impl Template {
         self.ctv
     }
 
+    /// the human-readable label for this Template, if one was set with
+    /// [`Builder::set_label`]
+    pub fn label(&self) -> Option<&str> {
+        self.metadata_map_s2s.label.as_deref()
+    }
+
     /// recompute the total amount spent in this template. This is the total
     /// amount required to be sent to this template for this transaction to
     /// succeed.
@@ -169,4 +188,57 @@ impl Template {
             .map(|o| o.amount)
             .fold(Amount::from_sat(0), |b, a| b + a)
     }
+
+    /// Create a PSBT for this Template with every field an oracle (e.g.
+    /// `HDOracleEmulator`) needs to sign it.
+    ///
+    /// The first input spends `self.max` from the contract this Template was
+    /// compiled for, and gets the witness script or taproot spend info of its
+    /// [`Template::spent_descriptor`]. Without one (e.g. for a Template built
+    /// directly from a [`Builder`]) the input is left empty, as are any
+    /// additional inputs, see [`Template::to_oracle_psbt_with_inputs`].
+    pub fn to_oracle_psbt(&self) -> PartiallySignedTransaction {
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(self.tx.clone())
+            .expect("Template transactions are unsigned");
+        if let (Some(inp), Some(descriptor)) = (psbt.inputs.first_mut(), &self.spent_descriptor) {
+            inp.witness_utxo = Some(TxOut {
+                value: self.max.as_sat(),
+                script_pubkey: descriptor.script_pubkey(),
+            });
+            add_descriptor_info(inp, &self.spent_descriptor, &Secp256k1::new())
+                .expect("Compiled descriptors have valid scripts");
+        }
+        psbt
+    }
+
+    /// Like [`Template::to_oracle_psbt`], also filling in the inputs after
+    /// the first.
+    ///
+    /// `additional` are the prevouts for those inputs, in order, as the
+    /// signature hash commits to every spent output, each with the
+    /// descriptor it is spent with, if known. Inputs without one get only
+    /// their prevout, so can't be signed by the oracle.
+    ///
+    /// A Template doesn't record the outputs its additional inputs spend,
+    /// which is why they must be passed in. Errors with
+    /// [`ObjectError::PrevoutCountMismatch`] unless there is one per
+    /// additional input.
+    pub fn to_oracle_psbt_with_inputs(
+        &self,
+        additional: &[(TxOut, Option<SupportedDescriptors>)],
+    ) -> Result<PartiallySignedTransaction, ObjectError> {
+        if additional.len() + 1 != self.tx.input.len() {
+            return Err(ObjectError::PrevoutCountMismatch(
+                self.tx.input.len().saturating_sub(1),
+                additional.len(),
+            ));
+        }
+        let mut psbt = self.to_oracle_psbt();
+        let secp = Secp256k1::new();
+        for (inp, (prevout, descriptor)) in psbt.inputs.iter_mut().skip(1).zip(additional) {
+            inp.witness_utxo = Some(prevout.clone());
+            add_descriptor_info(inp, descriptor, &secp)?;
+        }
+        Ok(psbt)
+    }
 }
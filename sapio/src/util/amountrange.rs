@@ -86,6 +86,12 @@ impl AmountRange {
         self.min = std::cmp::min(self.min, Some(amount.into()));
         self.max = std::cmp::max(self.max, Some(amount.into()));
     }
+    /// Widen this range to also cover `other`.
+    pub fn merge(&mut self, other: &AmountRange) {
+        for a in other.min.iter().chain(other.max.iter()) {
+            self.update_range((*a).into());
+        }
+    }
     /// Retreive the max value, if set, or return `Amount::min_value`.
     pub fn max(&self) -> Amount {
         self.max.unwrap_or(Amount::min_value().into()).0
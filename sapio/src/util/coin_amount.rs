@@ -0,0 +1,77 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checked conversions between `Amount` and `CoinAmount`
+use bitcoin::util::amount::{Amount, CoinAmount, ParseAmountError};
+use std::convert::TryFrom;
+
+/// The most sats which can ever exist, 21 million BTC
+pub const MAX_MONEY_SATS: u64 = 21_000_000 * 100_000_000;
+
+/// Convert `c` to an `Amount`.
+///
+/// BTC denominated amounts which are negative, not a number, or more precise
+/// than a sat are rejected, as is anything above [`MAX_MONEY_SATS`], so a
+/// value mistakenly given in sats where BTC was meant (or vice versa) is
+/// more likely to be caught.
+pub fn coins_to_amount(c: CoinAmount) -> Result<Amount, ParseAmountError> {
+    let a = Amount::try_from(c)?;
+    if a.as_sat() > MAX_MONEY_SATS {
+        Err(ParseAmountError::TooBig)
+    } else {
+        Ok(a)
+    }
+}
+
+/// Convert `a` to a `CoinAmount`, denominated in sats so that no precision
+/// is lost.
+pub fn amount_to_coins(a: Amount) -> CoinAmount {
+    CoinAmount::Sats(a.as_sat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_coin_amount_conversions() {
+        assert_eq!(coins_to_amount(CoinAmount::Sats(0)), Ok(Amount::ZERO));
+        assert_eq!(
+            coins_to_amount(CoinAmount::Sats(MAX_MONEY_SATS)),
+            Ok(Amount::from_sat(MAX_MONEY_SATS))
+        );
+        assert_eq!(
+            coins_to_amount(CoinAmount::Btc(21_000_000.0)),
+            Ok(Amount::from_sat(MAX_MONEY_SATS))
+        );
+        assert_eq!(
+            coins_to_amount(CoinAmount::Btc(0.00000001)),
+            Ok(Amount::from_sat(1))
+        );
+        // above the supply
+        assert_eq!(
+            coins_to_amount(CoinAmount::Sats(MAX_MONEY_SATS + 1)),
+            Err(ParseAmountError::TooBig)
+        );
+        assert_eq!(
+            coins_to_amount(CoinAmount::Sats(u64::MAX)),
+            Err(ParseAmountError::TooBig)
+        );
+        assert!(coins_to_amount(CoinAmount::Btc(21_000_000.00000001)).is_err());
+        assert!(coins_to_amount(CoinAmount::Btc(1e300)).is_err());
+        // negative, not a number, or finer than a sat
+        assert_eq!(
+            coins_to_amount(CoinAmount::Btc(-1.0)),
+            Err(ParseAmountError::Negative)
+        );
+        assert!(coins_to_amount(CoinAmount::Btc(f64::NAN)).is_err());
+        assert!(coins_to_amount(CoinAmount::Btc(0.000000001)).is_err());
+        // round trips
+        for sats in [0, 1, 100_000_000, MAX_MONEY_SATS] {
+            let a = Amount::from_sat(sats);
+            assert_eq!(coins_to_amount(amount_to_coins(a)), Ok(a));
+        }
+    }
+}
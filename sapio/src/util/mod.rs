@@ -6,4 +6,6 @@
 
 //! Basic functionality / structs for Sapio
 pub mod amountrange;
+pub mod coin_amount;
 pub mod extended_address;
+pub mod standardness;
@@ -0,0 +1,310 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks for the (default) relay policy standardness rules of outputs and
+//! the scripts which spend them
+use bitcoin::blockdata::opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_1, OP_PUSHNUM_16};
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::util::amount::Amount;
+use bitcoin::Script;
+use miniscript::{Miniscript, MiniscriptKey, Segwitv0, Tap, ToPublicKey};
+use std::fmt;
+
+/// Bitcoin Core's default `-datacarriersize`, including the OP_RETURN and
+/// push opcodes.
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+
+/// The most keys a bare multisig output may have to be relayed under default
+/// policy (Bitcoin Core's `-permitbaremultisig`).
+pub const MAX_STANDARD_BARE_MULTISIG_KEYS: u8 = 3;
+
+/// Bitcoin Core's `MAX_STANDARD_TX_WEIGHT`, the heaviest transaction relayed
+/// under default policy.
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+
+/// Bitcoin Core's `MAX_STANDARD_P2WSH_SCRIPT_SIZE`, the largest witness
+/// script relayed under default policy.
+pub const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+
+/// Bitcoin Core's `MAX_STANDARD_P2WSH_STACK_ITEMS`, the most stack items (not
+/// counting the witness script) relayed in a P2WSH spend.
+pub const MAX_STANDARD_P2WSH_STACK_ITEMS: usize = 100;
+
+/// The consensus `MAX_STACK_SIZE`, which limits the stack items (not
+/// counting the leaf script and control block) of a Taproot script spend.
+pub const MAX_TAPSCRIPT_STACK_ITEMS: usize = 1000;
+
+/// Reasons an output may be rejected by default relay policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StandardnessError {
+    /// Bare (non-P2SH / non-segwit) multisig output with more than
+    /// [`MAX_STANDARD_BARE_MULTISIG_KEYS`] keys, or which isn't a well formed
+    /// m-of-n
+    BareMultisig(Script),
+    /// Script is not one of the known standard output templates
+    NonStandardScript(Script),
+    /// OP_RETURN output contains something other than data pushes
+    OpReturnNotPushOnly(Script),
+    /// OP_RETURN output is larger than [`MAX_OP_RETURN_RELAY`]
+    OpReturnTooLarge(usize),
+    /// Output amount (first) is below the dust threshold (second) for the script
+    Dust(Amount, Amount),
+    /// Witness script is larger than [`MAX_STANDARD_P2WSH_SCRIPT_SIZE`]
+    WitnessScriptTooLarge(usize),
+    /// Spend needs more stack items (first) than are allowed (second)
+    TooManyStackItems(usize, usize),
+    /// Taproot leaf's spend (script, control block, and witness) weighs more
+    /// than [`MAX_STANDARD_TX_WEIGHT`]
+    TapLeafTooLarge(usize),
+}
+
+impl fmt::Display for StandardnessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for StandardnessError {}
+
+/// Check that an output with script `script` and value `amount` would be
+/// relayed under default policy.
+pub fn check_output(script: &Script, amount: Amount) -> Result<(), StandardnessError> {
+    if script.is_op_return() {
+        if script.len() > MAX_OP_RETURN_RELAY {
+            return Err(StandardnessError::OpReturnTooLarge(script.len()));
+        }
+        let push_only = script.instructions().skip(1).all(|i| match i {
+            Ok(Instruction::PushBytes(_)) => true,
+            Ok(Instruction::Op(op)) => op.into_u8() <= OP_PUSHNUM_16.into_u8(),
+            Err(_) => false,
+        });
+        if !push_only {
+            return Err(StandardnessError::OpReturnNotPushOnly(script.clone()));
+        }
+        return Ok(());
+    }
+    if !(script.is_p2pk() || script.is_p2pkh() || script.is_p2sh() || script.is_witness_program()) {
+        match bare_multisig(script) {
+            Some((_, n)) if n <= MAX_STANDARD_BARE_MULTISIG_KEYS => {}
+            _ if script.as_bytes().last() == Some(&OP_CHECKMULTISIG.into_u8()) => {
+                return Err(StandardnessError::BareMultisig(script.clone()))
+            }
+            _ => return Err(StandardnessError::NonStandardScript(script.clone())),
+        }
+    }
+    let dust = script.dust_value();
+    if amount < dust {
+        return Err(StandardnessError::Dust(amount, dust));
+    }
+    Ok(())
+}
+
+/// the `(m, n)` of `script`, if it is a bare m-of-n multisig of compressed
+/// or uncompressed keys
+fn bare_multisig(script: &Script) -> Option<(u8, u8)> {
+    let num = |i: &Instruction| match i {
+        Instruction::Op(op)
+            if (OP_PUSHNUM_1.into_u8()..=OP_PUSHNUM_16.into_u8()).contains(&op.into_u8()) =>
+        {
+            Some(op.into_u8() - OP_PUSHNUM_1.into_u8() + 1)
+        }
+        _ => None,
+    };
+    let instructions = script.instructions().collect::<Result<Vec<_>, _>>().ok()?;
+    match instructions.as_slice() {
+        [m, keys @ .., n, Instruction::Op(op)] if *op == OP_CHECKMULTISIG => {
+            let (m, n) = (num(m)?, num(n)?);
+            let well_formed = m <= n
+                && keys.len() == n as usize
+                && keys.iter().all(
+                    |k| matches!(k, Instruction::PushBytes(b) if b.len() == 33 || b.len() == 65),
+                );
+            well_formed.then_some((m, n))
+        }
+        _ => None,
+    }
+}
+
+/// Check that the Segwit v0 witness script `ms` could be spent under default
+/// policy.
+pub fn check_witness_script<Pk: MiniscriptKey + ToPublicKey>(
+    ms: &Miniscript<Pk, Segwitv0>,
+) -> Result<(), StandardnessError> {
+    let size = ms.script_size();
+    if size > MAX_STANDARD_P2WSH_SCRIPT_SIZE {
+        return Err(StandardnessError::WitnessScriptTooLarge(size));
+    }
+    // the count includes the witness script
+    let items = ms.max_satisfaction_witness_elements().unwrap_or(1) - 1;
+    if items > MAX_STANDARD_P2WSH_STACK_ITEMS {
+        return Err(StandardnessError::TooManyStackItems(
+            items,
+            MAX_STANDARD_P2WSH_STACK_ITEMS,
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the Taproot leaf `ms`, at `depth` in the tree, could be spent
+/// under default policy.
+pub fn check_tap_leaf<Pk: MiniscriptKey + ToPublicKey>(
+    ms: &Miniscript<Pk, Tap>,
+    depth: u8,
+) -> Result<(), StandardnessError> {
+    // the count includes the leaf script
+    let items = ms.max_satisfaction_witness_elements().unwrap_or(1) - 1;
+    if items > MAX_TAPSCRIPT_STACK_ITEMS {
+        return Err(StandardnessError::TooManyStackItems(
+            items,
+            MAX_TAPSCRIPT_STACK_ITEMS,
+        ));
+    }
+    let control_block = 33 + 32 * depth as usize;
+    let weight = ms.script_size() + control_block + ms.max_satisfaction_size().unwrap_or(0);
+    if weight > MAX_STANDARD_TX_WEIGHT {
+        return Err(StandardnessError::TapLeafTooLarge(weight));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::context::test_ctx;
+    use crate::contract::{CompilationError, Context};
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::XOnlyPublicKey;
+    use miniscript::Terminal;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    fn ctx() -> Context {
+        test_ctx("standardness", 100_000)
+    }
+
+    #[test]
+    fn test_strict_rejects_dust() {
+        let key = key();
+        assert!(ctx()
+            .template()
+            .add_output(Amount::from_sat(1), &key, None)
+            .is_ok());
+        assert!(matches!(
+            ctx()
+                .strict_standardness(true)
+                .template()
+                .add_output(Amount::from_sat(1), &key, None),
+            Err(CompilationError::NonStandard(StandardnessError::Dust(..)))
+        ));
+        assert!(ctx()
+            .strict_standardness(true)
+            .template()
+            .add_output(Amount::from_sat(1000), &key, None)
+            .is_ok());
+    }
+
+    /// `and_v(v:pk(key),...pk(key))` with `n` keys
+    fn and_keys<Pk: MiniscriptKey, Ctx: miniscript::ScriptContext>(
+        key: Pk,
+        n: usize,
+    ) -> Miniscript<Pk, Ctx> {
+        let pk = || {
+            let pk_k = Miniscript::from_ast(Terminal::PkK(key.clone())).unwrap();
+            Miniscript::from_ast(Terminal::Check(Arc::new(pk_k))).unwrap()
+        };
+        (1..n).fold(pk(), |ms, _| {
+            let v = Miniscript::from_ast(Terminal::Verify(Arc::new(pk()))).unwrap();
+            Miniscript::from_ast(Terminal::AndV(Arc::new(v), Arc::new(ms))).unwrap()
+        })
+    }
+    fn key() -> XOnlyPublicKey {
+        XOnlyPublicKey::from_slice(&sha256::Hash::hash(&[1u8; 32]).into_inner()).unwrap()
+    }
+    fn even_key() -> bitcoin::PublicKey {
+        let mut even = [2u8; 33];
+        even[1..].copy_from_slice(&key().serialize());
+        bitcoin::PublicKey::from_slice(&even).unwrap()
+    }
+
+    #[test]
+    fn test_check_witness_script() {
+        assert!(check_witness_script(&and_keys::<_, Segwitv0>(even_key(), 10)).is_ok());
+        // 101 * 35 bytes fits in a witness script, but not the stack
+        assert_eq!(
+            check_witness_script(&and_keys::<_, Segwitv0>(even_key(), 101)),
+            Err(StandardnessError::TooManyStackItems(
+                101,
+                MAX_STANDARD_P2WSH_STACK_ITEMS
+            ))
+        );
+        assert!(matches!(
+            check_witness_script(&and_keys::<_, Segwitv0>(even_key(), 110)),
+            Err(StandardnessError::WitnessScriptTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_tap_leaf() {
+        // too many for Segwit v0 is fine for Taproot
+        assert!(check_tap_leaf(&and_keys::<_, Tap>(key(), 110), 0).is_ok());
+        assert_eq!(
+            check_tap_leaf(&and_keys::<_, Tap>(key(), 1001), 0),
+            Err(StandardnessError::TooManyStackItems(
+                1001,
+                MAX_TAPSCRIPT_STACK_ITEMS
+            ))
+        );
+        // 2^18 (shared) after(1)s, too large for any standard transaction
+        let mut large = Miniscript::<XOnlyPublicKey, Tap>::from_ast(Terminal::After(1)).unwrap();
+        for _ in 0..18 {
+            let v = Miniscript::from_ast(Terminal::Verify(Arc::new(large.clone()))).unwrap();
+            large = Miniscript::from_ast(Terminal::AndV(Arc::new(v), Arc::new(large))).unwrap();
+        }
+        assert!(matches!(
+            check_tap_leaf(&large, 0),
+            Err(StandardnessError::TapLeafTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_output() {
+        let key = bitcoin::PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let multisig = |m: i64, n: usize| {
+            let mut b = bitcoin::blockdata::script::Builder::new().push_int(m);
+            for _ in 0..n {
+                b = b.push_key(&key);
+            }
+            b.push_int(n as i64)
+                .push_opcode(OP_CHECKMULTISIG)
+                .into_script()
+        };
+        // up to 3 keys are relayed
+        assert!(check_output(&multisig(2, 3), Amount::from_sat(10_000)).is_ok());
+        assert!(matches!(
+            check_output(&multisig(2, 4), Amount::from_sat(10_000)),
+            Err(StandardnessError::BareMultisig(_))
+        ));
+        let malformed = Script::from(vec![0x51, 0x21, 0x02, 0x51, 0xae]);
+        assert!(matches!(
+            check_output(&malformed, Amount::from_sat(10_000)),
+            Err(StandardnessError::BareMultisig(_))
+        ));
+        assert!(check_output(&Script::new_p2pk(&key), Amount::from_sat(10_000)).is_ok());
+        assert!(check_output(&Script::new_op_return(&[0u8; 40]), Amount::ZERO).is_ok());
+        assert_eq!(
+            check_output(&Script::new_op_return(&[0u8; 100]), Amount::ZERO),
+            Err(StandardnessError::OpReturnTooLarge(103))
+        );
+        let not_push = Script::from(vec![0x6a, 0x76]);
+        assert!(matches!(
+            check_output(&not_push, Amount::ZERO),
+            Err(StandardnessError::OpReturnNotPushOnly(_))
+        ));
+    }
+}
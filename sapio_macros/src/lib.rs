@@ -76,7 +76,10 @@ pub fn guard(args: TokenStream, input: TokenStream) -> TokenStream {
         fn #guard_name(&self, #context_arg) -> sapio::sapio_base::Clause
         #block
         fn  #name() -> Option<sapio::contract::actions::Guard<Self>> {
-            Some(sapio::contract::actions::Guard::#ty(Self::#guard_name, #simp_gen_f))
+            Some(
+                sapio::contract::actions::Guard::#ty(Self::#guard_name, #simp_gen_f)
+                    .named(std::sync::Arc::new(std::stringify!(#name).into())),
+            )
         }
     })
 }